@@ -1,15 +1,14 @@
 use crate::util::{AggregatorError, Result};
 
-use ed25519_dalek::{PublicKey, SecretKey};
+use ed25519_dalek::{verify_batch, PublicKey, SecretKey, Signature};
 extern crate rand;
 use rand::rngs::OsRng;
 
 use common::types::{
     AggRegistrationBlob, AggregatedMessage, SignMutable, Signable, SubmissionMessage,
 };
-use interface::{EntityId, RateLimitNonce, UserSubmissionMessage, Xor};
-use std::collections::BTreeSet;
-use std::iter::FromIterator;
+use interface::{EntityId, RateLimitNonce, UserRegistrationBlob, UserSubmissionMessage, Xor};
+use std::collections::{BTreeMap, BTreeSet};
 
 use log::{debug, error, warn};
 
@@ -30,65 +29,78 @@ pub fn new_aggregator() -> Result<(SecretKey, EntityId, AggRegistrationBlob)> {
     Ok((sk, EntityId::from(&pk), blob))
 }
 
-/// TODO: Consider removing this function
-/// Constructs an aggregate message from the given state. The returned blob is to be sent to
-/// the parent aggregator or an anytrust server.
-/// Note: this is an identity function because AggregatedMessage and AggregatedMessage
-/// are exact the same thing.
-pub fn finalize_aggregate(agg: &AggregatedMessage) -> Result<AggregatedMessage> {
-    return Ok(agg.clone());
+/// Constructs an aggregate message from the given partial aggregate. The returned blob is to be
+/// sent to the parent aggregator or an anytrust server. Partial aggregates aren't signed as they
+/// grow, so this is where the aggregate gets its one signature.
+pub fn finalize_aggregate(agg: &AggregatedMessage, sk: &SecretKey) -> Result<AggregatedMessage> {
+    let mut blob = agg.clone();
+    match blob.sign_mut(sk) {
+        Ok(()) => Ok(blob),
+        Err(e) => {
+            error!("can't sign on aggregation: {:?}", e);
+            Err(AggregatorError::InvalidParameter)
+        }
+    }
 }
 
-/// Adds the given input from a user to the given partial aggregate
-/// Note: if marshalled_current_aggregation is empty (len = 0), an empty aggregation is created
-///  and the signed message is aggregated into that.
+/// Adds the given input to the given partial aggregate, in place. User submissions are only
+/// accepted from users in `registered_users`. Nothing changes if the input is rejected.
+/// Note: the aggregate is not re-signed. Call `finalize_aggregate` once all inputs are in.
 pub fn add_to_aggregate(
     agg: &mut AggregatedMessage,
     observed_nonces: &mut Option<BTreeSet<RateLimitNonce>>,
+    registered_users: &BTreeMap<EntityId, UserRegistrationBlob>,
     new_input: &SubmissionMessage,
-    signing_key: &SecretKey,
 ) -> Result<()> {
     match new_input {
         SubmissionMessage::UserSubmission(new_input) => {
-            let res = add_to_agg_user_submit((new_input, agg, observed_nonces, signing_key))?;
-            // Update the agg and nonces
-            *agg = res.0;
-            *observed_nonces = res.1;
-        }
-        SubmissionMessage::AggSubmission(new_input) => {
-            let res = add_to_agg((new_input, agg, observed_nonces, signing_key))?;
-            // Update the agg and nonces
-            *agg = res.0;
-            *observed_nonces = res.1;
+            add_to_agg_user_submit(agg, observed_nonces, registered_users, new_input)
         }
+        SubmissionMessage::AggSubmission(new_input) => add_to_agg(agg, observed_nonces, new_input),
     }
-
-    Ok(())
 }
 
-fn add_to_agg(
-    input: (
-        &AggregatedMessage,
-        &AggregatedMessage,
-        &Option<BTreeSet<RateLimitNonce>>,
-        &SecretKey,
-    ),
-) -> Result<(AggregatedMessage, Option<BTreeSet<RateLimitNonce>>)> {
-    let (incoming_msg, current_aggregation, observed_nonces, sk) = input;
-
-    // Error if asked to add an empty msg to an empty aggregation
-    if current_aggregation.is_empty() && incoming_msg.is_empty() {
-        error!("cannot add an empty message to an emtpy aggregate");
-        return Err(AggregatorError::InvalidParameter);
+/// If the set of rate-limit nonces is Some, checks that the given nonce is present and doesn't
+/// appear in the set. The nonce isn't recorded. That's left until the input is known to be good.
+pub(crate) fn check_nonce(
+    observed_nonces: &Option<BTreeSet<RateLimitNonce>>,
+    nonce: &Option<RateLimitNonce>,
+) -> Result<()> {
+    if let Some(observed_nonces) = observed_nonces {
+        match nonce {
+            // We reject messages whose nonces have been seen before
+            Some(nonce) if observed_nonces.contains(nonce) => {
+                error!("duplicate rate limit nonce detected");
+                Err(AggregatorError::InvalidParameter)
+            }
+            Some(_) => Ok(()),
+            None => {
+                error!("no rate limit nonce provided");
+                Err(AggregatorError::InvalidParameter)
+            }
+        }
+    } else {
+        Ok(())
     }
+}
 
-    // if incoming_msg is empty we just return the current aggregation as is. No op.
-    if incoming_msg.is_empty() {
-        warn!("empty incoming_msg. not changing the aggregation");
-        return Ok((current_aggregation.clone(), observed_nonces.clone()));
+/// Records a nonce that passed `check_nonce`
+pub(crate) fn record_nonce(
+    observed_nonces: &mut Option<BTreeSet<RateLimitNonce>>,
+    nonce: &Option<RateLimitNonce>,
+) {
+    if let (Some(observed_nonces), Some(nonce)) = (observed_nonces.as_mut(), nonce) {
+        observed_nonces.insert(nonce.clone());
     }
+}
 
-    // now we are sure incoming_msg is not empty we treat it as untrusted input and verify signature
+fn add_to_agg(
+    current_aggregation: &mut AggregatedMessage,
+    observed_nonces: &mut Option<BTreeSet<RateLimitNonce>>,
+    incoming_msg: &AggregatedMessage,
+) -> Result<()> {
+    // incoming_msg is untrusted input, so verify its signature. Do this even if it's empty, since
+    // an empty aggregate still says that its sender has reported in
     match incoming_msg.verify() {
         Ok(()) => {
             debug!("signature verification succeeded");
@@ -99,108 +111,113 @@ fn add_to_agg(
         }
     }
 
-    // If the set of rate-limit nonces is Some, see if the given nonce appears in it. If so, this
-    // message is dropped. If not, add the nonce to the set. If no nonce is provided, error.
-    let new_observed_nonces = if let Some(observed_nonces) = observed_nonces {
-        if let Some(ref nonce) = incoming_msg.rate_limit_nonce {
-            // We reject messages whose nonces have been seen before
-            if observed_nonces.contains(nonce) {
-                error!("duplicate rate limit nonce detected");
-                return Err(AggregatorError::InvalidParameter);
-            }
+    // if incoming_msg is empty we just leave the current aggregation as is. No op.
+    if incoming_msg.is_empty() {
+        warn!("empty incoming_msg. not changing the aggregation");
+        return Ok(());
+    }
 
-            // No duplicate was found. Add this nonce to the set
-            let mut new_set = observed_nonces.clone();
-            new_set.insert(nonce.clone());
-            Some(new_set)
-        } else {
-            error!("no rate limit nonce provided");
-            return Err(AggregatorError::InvalidParameter);
-        }
-    } else {
-        None
-    };
+    check_nonce(observed_nonces, &incoming_msg.rate_limit_nonce)?;
 
-    // if the current aggregation is empty we create a single-msg aggregation
+    // if the current aggregation is empty, the incoming message becomes the aggregation
     if current_aggregation.is_empty() {
         debug!("current aggregation is emtpy");
-        let mut agg = incoming_msg.clone();
-        match agg.sign_mut(&sk) {
-            Ok(()) => (),
-            Err(e) => {
-                error!("can't sign on aggregation: {:?}", e);
-                return Err(AggregatorError::InvalidParameter);
-            }
-        };
-        return Ok((incoming_msg.clone(), new_observed_nonces));
-    } else {
-        // now that we know both current_aggregation and incoming_msg are not empty
-        // we first validate they match
-        let mut current_aggregation = current_aggregation.clone();
-        if current_aggregation.round != incoming_msg.round {
-            error!("current_aggregation.round != incoming_msg.round");
-            return Err(AggregatorError::InvalidParameter);
-        }
-
-        if current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id {
-            error!("current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id");
-            return Err(AggregatorError::InvalidParameter);
-        }
-
-        if !current_aggregation
+        record_nonce(observed_nonces, &incoming_msg.rate_limit_nonce);
+        current_aggregation.round = incoming_msg.round;
+        current_aggregation.anytrust_group_id = incoming_msg.anytrust_group_id;
+        current_aggregation
             .user_ids
-            .is_disjoint(&incoming_msg.user_ids)
-        {
-            error!("current_aggregation.user_ids overlap with incoming_msg.user_ids");
-            return Err(AggregatorError::InvalidParameter);
-        }
-
-        debug!("✅ various checks passed now we can aggregate");
-
-        // aggregate in the new message
-        current_aggregation.user_ids.extend(&incoming_msg.user_ids);
+            .extend(incoming_msg.user_ids.iter().cloned());
+        current_aggregation.rate_limit_nonce = incoming_msg.rate_limit_nonce.clone();
         current_aggregation
             .aggregated_msg
-            .xor_mut(&incoming_msg.aggregated_msg);
-
-        // sign
-        match current_aggregation.sign_mut(&sk) {
-            Ok(()) => (),
-            Err(e) => {
-                error!("can't sign on current aggregation: {:?}", e);
-                return Err(AggregatorError::InvalidParameter);
-            }
-        };
+            .clone_from(&incoming_msg.aggregated_msg);
+        return Ok(());
+    }
 
-        debug!("✅ new agg with users {:?}", current_aggregation.user_ids);
+    // now that we know both current_aggregation and incoming_msg are not empty
+    // we first validate they match
+    if current_aggregation.round != incoming_msg.round {
+        error!("current_aggregation.round != incoming_msg.round");
+        return Err(AggregatorError::InvalidParameter);
+    }
 
-        Ok((current_aggregation, new_observed_nonces))
+    if current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id {
+        error!("current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id");
+        return Err(AggregatorError::InvalidParameter);
     }
-}
 
-fn add_to_agg_user_submit(
-    input: (
-        &UserSubmissionMessage,
-        &AggregatedMessage,
-        &Option<BTreeSet<RateLimitNonce>>,
-        &SecretKey,
-    ),
-) -> Result<(AggregatedMessage, Option<BTreeSet<RateLimitNonce>>)> {
-    let (incoming_msg, current_aggregation, observed_nonces, sk) = input;
-
-    // Error if asked to add an empty msg to an empty aggregation
-    if current_aggregation.is_empty() && incoming_msg.is_empty() {
-        error!("cannot add an empty message to an empty aggregate");
+    if !current_aggregation
+        .user_ids
+        .is_disjoint(&incoming_msg.user_ids)
+    {
+        error!("current_aggregation.user_ids overlap with incoming_msg.user_ids");
         return Err(AggregatorError::InvalidParameter);
     }
 
-    // If incoming_msg is empty we just return the current aggregation as is. No op.
-    if incoming_msg.is_empty() {
-        warn!("empty incoming_msg. not changing the aggregation");
-        return Ok((current_aggregation.clone(), observed_nonces.clone()));
+    debug!("✅ various checks passed now we can aggregate");
+
+    // aggregate in the new message
+    record_nonce(observed_nonces, &incoming_msg.rate_limit_nonce);
+    current_aggregation
+        .user_ids
+        .extend(incoming_msg.user_ids.iter().cloned());
+    current_aggregation
+        .aggregated_msg
+        .xor_mut(&incoming_msg.aggregated_msg);
+
+    debug!("✅ new agg with users {:?}", current_aggregation.user_ids);
+
+    Ok(())
+}
+
+/// Merges a partial aggregate of user submissions that were already verified, along with the
+/// rate-limit nonces of those users, into the given aggregate. Signatures are not checked again.
+pub fn merge_verified(
+    current_aggregation: &mut AggregatedMessage,
+    observed_nonces: &mut Option<BTreeSet<RateLimitNonce>>,
+    partial: AggregatedMessage,
+    nonces: &[RateLimitNonce],
+) -> Result<()> {
+    if partial.is_empty() {
+        return Ok(());
+    }
+    if !current_aggregation.is_empty() {
+        if current_aggregation.round != partial.round {
+            error!("current_aggregation.round != partial.round");
+            return Err(AggregatorError::InvalidParameter);
+        }
+        if !current_aggregation.user_ids.is_disjoint(&partial.user_ids) {
+            error!("current_aggregation.user_ids overlap with partial.user_ids");
+            return Err(AggregatorError::InvalidParameter);
+        }
     }
 
-    // now we are sure incoming_msg is not empty we treat it as untrusted input and verify signature
+    if let Some(observed_nonces) = observed_nonces.as_mut() {
+        observed_nonces.extend(nonces.iter().cloned());
+    }
+    if current_aggregation.is_empty() {
+        current_aggregation.round = partial.round;
+        current_aggregation.anytrust_group_id = partial.anytrust_group_id;
+        current_aggregation.user_ids = partial.user_ids;
+        current_aggregation.rate_limit_nonce = partial.rate_limit_nonce;
+        current_aggregation.aggregated_msg = partial.aggregated_msg;
+    } else {
+        current_aggregation.user_ids.extend(partial.user_ids);
+        current_aggregation
+            .aggregated_msg
+            .xor_mut(&partial.aggregated_msg);
+    }
+
+    Ok(())
+}
+
+/// Checks that the given user submission is signed by the key the user registered with
+pub fn verify_user_submission(
+    registered_users: &BTreeMap<EntityId, UserRegistrationBlob>,
+    incoming_msg: &UserSubmissionMessage,
+) -> Result<()> {
+    // We treat incoming_msg as untrusted input and verify signature
     if incoming_msg.verify_sig() {
         debug!("signature verification succeeded");
     } else {
@@ -208,84 +225,324 @@ fn add_to_agg_user_submit(
         return Err(AggregatorError::InvalidParameter);
     }
 
-    // If the set of rate-limit nonces is Some, see if the given nonce appears in it. If so, this
-    // message is dropped. If not, add the nonce to the set. If no nonce is provided, error.
-    let new_observed_nonces = if let Some(observed_nonces) = observed_nonces {
-        if let Some(ref nonce) = incoming_msg.rate_limit_nonce {
-            // We reject messages whose nonces have been seen before
-            if observed_nonces.contains(nonce) {
-                error!("duplicate rate limit nonce detected");
-                return Err(AggregatorError::InvalidParameter);
+    check_user_key(registered_users, incoming_msg)
+}
+
+/// Checks the signatures on the given user submissions as one batch, which is much cheaper than
+/// checking them one by one. If the batch fails, every signature is checked on its own to find
+/// the bad ones. Returns whether each submission's signature is valid.
+pub fn verify_user_sigs_batched(msgs: &[&UserSubmissionMessage]) -> Vec<bool> {
+    let mut valid = vec![false; msgs.len()];
+
+    // Signatures that don't even parse are left out of the batch
+    let mut batch_idxs = Vec::with_capacity(msgs.len());
+    let mut digests = Vec::with_capacity(msgs.len());
+    let mut sigs = Vec::with_capacity(msgs.len());
+    let mut pks = Vec::with_capacity(msgs.len());
+    for (i, msg) in msgs.iter().enumerate() {
+        match Signature::from_bytes(&msg.tee_sig.0) {
+            Ok(sig) => {
+                batch_idxs.push(i);
+                digests.push(msg.digest());
+                sigs.push(sig);
+                pks.push(msg.tee_pk);
             }
-            // No duplicate was found. Add this nonce to the set
-            let mut new_set = observed_nonces.clone();
-            new_set.insert(nonce.clone());
-            Some(new_set)
-        } else {
-            error!("no rate limit nonce provided");
-            return Err(AggregatorError::InvalidParameter);
+            Err(_) => error!("malformed sig from user {}", msg.user_id),
         }
-    } else {
-        None
-    };
+    }
+    if batch_idxs.is_empty() {
+        return valid;
+    }
 
-    // if the current aggregation is empty we create a single-msg aggregation
-    if current_aggregation.is_empty() {
-        let mut agg = AggregatedMessage::default();
-        let incoming_msg_clone = incoming_msg.clone();
-        agg.round = incoming_msg_clone.round;
-        agg.anytrust_group_id = incoming_msg_clone.anytrust_group_id;
-        agg.user_ids = BTreeSet::from_iter(vec![incoming_msg_clone.user_id.clone()].into_iter());
-        agg.rate_limit_nonce = incoming_msg_clone.rate_limit_nonce;
-        agg.aggregated_msg = incoming_msg_clone.aggregated_msg;
-        match agg.sign_mut(&sk) {
-            Ok(()) => (),
-            Err(e) => {
-                error!("can't sign on aggregation: {:?}", e);
-                return Err(AggregatorError::InvalidParameter);
-            }
-        };
-        return Ok((agg.clone(), new_observed_nonces));
+    let digest_refs: Vec<&[u8]> = digests.iter().map(Vec::as_slice).collect();
+    if verify_batch(&digest_refs, &sigs, &pks).is_ok() {
+        debug!("batch of {} signatures verified", batch_idxs.len());
+        for i in batch_idxs {
+            valid[i] = true;
+        }
     } else {
-        // now that we know both current_aggregation and incoming_msg are not empty
-        // we first validate they match
-        let mut current_aggregation = current_aggregation.clone();
-        if current_aggregation.round != incoming_msg.round {
-            error!("current_aggregation.round_info != incoming_msg.round_info");
-            return Err(AggregatorError::InvalidParameter);
+        warn!(
+            "batch of {} signatures failed. Checking them one by one",
+            batch_idxs.len()
+        );
+        for i in batch_idxs {
+            valid[i] = msgs[i].verify_sig();
         }
+    }
+
+    valid
+}
 
-        if current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id {
-            error!("current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id");
+/// Checks that the given user submission comes from a registered user, under the key that user
+/// registered with. The signature isn't checked here.
+pub fn check_user_key(
+    registered_users: &BTreeMap<EntityId, UserRegistrationBlob>,
+    incoming_msg: &UserSubmissionMessage,
+) -> Result<()> {
+    // A signature only shows that whoever made the message holds tee_pk. Check that tee_pk is
+    // the key the user registered with the anytrust group, under the ID derived from it
+    if EntityId::from(&incoming_msg.tee_pk) != incoming_msg.user_id {
+        error!(
+            "user ID {} is not derived from its key",
+            incoming_msg.user_id
+        );
+        return Err(AggregatorError::InvalidParameter);
+    }
+    match registered_users.get(&incoming_msg.user_id) {
+        Some(reg) if reg.pk.0 == incoming_msg.tee_pk.to_bytes() => (),
+        Some(_) => {
+            error!(
+                "user {} did not sign with its registered key",
+                incoming_msg.user_id
+            );
             return Err(AggregatorError::InvalidParameter);
         }
-
-        if current_aggregation.user_ids.contains(&incoming_msg.user_id) {
-            error!("current_aggregation.user_ids overlap with incoming_msg.user_id");
+        None => {
+            error!("user {} is not registered", incoming_msg.user_id);
             return Err(AggregatorError::InvalidParameter);
         }
+    }
 
-        debug!("✅ various checks passed now we can aggregate");
+    Ok(())
+}
 
-        // aggregate in the new message
-        current_aggregation
-            .user_ids
-            .insert(incoming_msg.user_id.clone());
+fn add_to_agg_user_submit(
+    current_aggregation: &mut AggregatedMessage,
+    observed_nonces: &mut Option<BTreeSet<RateLimitNonce>>,
+    registered_users: &BTreeMap<EntityId, UserRegistrationBlob>,
+    incoming_msg: &UserSubmissionMessage,
+) -> Result<()> {
+    verify_user_submission(registered_users, incoming_msg)?;
+    check_nonce(observed_nonces, &incoming_msg.rate_limit_nonce)?;
+
+    // if the current aggregation is empty, the incoming message becomes the aggregation
+    if current_aggregation.is_empty() {
+        record_nonce(observed_nonces, &incoming_msg.rate_limit_nonce);
+        current_aggregation.round = incoming_msg.round;
+        current_aggregation.anytrust_group_id = incoming_msg.anytrust_group_id;
+        current_aggregation.user_ids.insert(incoming_msg.user_id);
+        current_aggregation.rate_limit_nonce = incoming_msg.rate_limit_nonce.clone();
         current_aggregation
             .aggregated_msg
-            .xor_mut(&incoming_msg.aggregated_msg);
-
-        // sign
-        match current_aggregation.sign_mut(&sk) {
-            Ok(()) => (),
-            Err(e) => {
-                error!("can't sign on aggregation: {:?}", e);
-                return Err(AggregatorError::InvalidParameter);
-            }
-        };
+            .clone_from(&incoming_msg.aggregated_msg);
+        return Ok(());
+    }
+
+    // now that we know current_aggregation is not empty we first validate the two match
+    if current_aggregation.round != incoming_msg.round {
+        error!("current_aggregation.round_info != incoming_msg.round_info");
+        return Err(AggregatorError::InvalidParameter);
+    }
+
+    if current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id {
+        error!("current_aggregation.anytrust_group_id != incoming_msg.anytrust_group_id");
+        return Err(AggregatorError::InvalidParameter);
+    }
+
+    if current_aggregation.user_ids.contains(&incoming_msg.user_id) {
+        error!("current_aggregation.user_ids overlap with incoming_msg.user_id");
+        return Err(AggregatorError::InvalidParameter);
+    }
+
+    debug!("✅ various checks passed now we can aggregate");
+
+    // aggregate in the new message
+    record_nonce(observed_nonces, &incoming_msg.rate_limit_nonce);
+    current_aggregation.user_ids.insert(incoming_msg.user_id);
+    current_aggregation
+        .aggregated_msg
+        .xor_mut(&incoming_msg.aggregated_msg);
+
+    debug!("✅ new agg with users {:?}", current_aggregation.user_ids);
+
+    Ok(())
+}
 
-        debug!("✅ new agg with users {:?}", current_aggregation.user_ids);
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, Signer};
+    use interface::{AttestedPublicKey, DcRoundMessage, SgxProtectedKeyPub, SignatureBytes};
+    use rand::RngCore;
+    use std::time::Instant;
+
+    /// Makes `n` registered users and a signed submission from each of them
+    pub(crate) fn make_submissions(
+        n: usize,
+    ) -> (
+        BTreeMap<EntityId, UserRegistrationBlob>,
+        Vec<UserSubmissionMessage>,
+    ) {
+        let mut rng = OsRng {};
+        let mut registered_users = BTreeMap::new();
+        let mut submissions = Vec::with_capacity(n);
+
+        for _ in 0..n {
+            let secret = SecretKey::generate(&mut rng);
+            let public: PublicKey = (&secret).into();
+            let keypair = Keypair { secret, public };
+            let user_id = EntityId::from(&public);
+            registered_users.insert(
+                user_id,
+                AttestedPublicKey {
+                    pk: SgxProtectedKeyPub(public.to_bytes()),
+                    xpk: SgxProtectedKeyPub(public.to_bytes()),
+                    role: "user".to_string(),
+                    tee_linkable_attestation: vec![],
+                },
+            );
+
+            let mut nonce = [0u8; 32];
+            rng.fill_bytes(&mut nonce);
+            let mut aggregated_msg = DcRoundMessage::default();
+            rng.fill_bytes(aggregated_msg.aggregated_msg.as_mut_slice());
+
+            let mut msg = UserSubmissionMessage {
+                round: 0,
+                anytrust_group_id: EntityId::default(),
+                user_id,
+                rate_limit_nonce: Some(RateLimitNonce::from_bytes(&nonce)),
+                aggregated_msg,
+                tee_sig: SignatureBytes(vec![]),
+                tee_pk: public,
+            };
+            msg.tee_sig = SignatureBytes(keypair.sign(&msg.digest()).to_bytes().to_vec());
+            submissions.push(msg);
+        }
+
+        (registered_users, submissions)
+    }
+
+    #[test]
+    fn aggregate_in_place() {
+        let (registered_users, submissions) = make_submissions(3);
+        let (sk, _, _) = new_aggregator().unwrap();
+        let mut agg = AggregatedMessage::default();
+        let mut observed_nonces = Some(BTreeSet::new());
+
+        for msg in submissions.iter() {
+            let input = SubmissionMessage::UserSubmission(msg.clone());
+            add_to_aggregate(&mut agg, &mut observed_nonces, &registered_users, &input).unwrap();
+        }
+        assert_eq!(agg.user_ids.len(), 3);
+        assert_eq!(observed_nonces.as_ref().unwrap().len(), 3);
+
+        let mut expected = submissions[0].aggregated_msg.clone();
+        expected.xor_mut(&submissions[1].aggregated_msg);
+        expected.xor_mut(&submissions[2].aggregated_msg);
+        assert_eq!(
+            agg.aggregated_msg.aggregated_msg.as_slice(),
+            expected.aggregated_msg.as_slice()
+        );
+
+        // A rejected submission leaves the aggregate and the nonces alone
+        let (more_users, more_submissions) = make_submissions(1);
+        let mut replay = more_submissions[0].clone();
+        replay.rate_limit_nonce = submissions[0].rate_limit_nonce.clone();
+        replay.tee_sig = SignatureBytes(vec![]);
+        let input = SubmissionMessage::UserSubmission(replay);
+        assert!(add_to_aggregate(&mut agg, &mut observed_nonces, &more_users, &input).is_err());
+        assert_eq!(agg.user_ids.len(), 3);
+        assert_eq!(observed_nonces.as_ref().unwrap().len(), 3);
+
+        // The aggregate is only signed when it's finalized
+        let finalized = finalize_aggregate(&agg, &sk).unwrap();
+        assert!(finalized.verify().is_ok());
+        assert_eq!(finalized.user_ids, agg.user_ids);
+    }
+
+    #[test]
+    fn empty_aggregates_are_verified() {
+        let (sk, _, _) = new_aggregator().unwrap();
+        let mut agg = AggregatedMessage::default();
+        let mut observed_nonces = None;
+        let no_users = BTreeMap::new();
+
+        // A signed empty aggregate changes nothing
+        let empty = finalize_aggregate(&AggregatedMessage::default(), &sk).unwrap();
+        let input = SubmissionMessage::AggSubmission(empty.clone());
+        add_to_aggregate(&mut agg, &mut observed_nonces, &no_users, &input).unwrap();
+        assert!(agg.is_empty());
+
+        // but one whose signature doesn't verify is rejected like any other aggregate
+        let mut forged = empty.clone();
+        forged.round = 1;
+        let input = SubmissionMessage::AggSubmission(forged);
+        assert!(add_to_aggregate(&mut agg, &mut observed_nonces, &no_users, &input).is_err());
+
+        let input = SubmissionMessage::AggSubmission(AggregatedMessage::default());
+        assert!(add_to_aggregate(&mut agg, &mut observed_nonces, &no_users, &input).is_err());
+    }
 
-        Ok((current_aggregation, new_observed_nonces))
+    #[test]
+    fn batched_sig_verification() {
+        let (_, mut submissions) = make_submissions(8);
+        let msgs: Vec<&UserSubmissionMessage> = submissions.iter().collect();
+        assert!(verify_user_sigs_batched(&msgs).into_iter().all(|v| v));
+
+        // A signature over other contents, and one that doesn't parse, are singled out
+        submissions[2].aggregated_msg.aggregated_msg.as_mut_slice()[0] ^= 1;
+        submissions[5].tee_sig = SignatureBytes(vec![1, 2, 3]);
+        let msgs: Vec<&UserSubmissionMessage> = submissions.iter().collect();
+        let valid = verify_user_sigs_batched(&msgs);
+        for (i, v) in valid.into_iter().enumerate() {
+            assert_eq!(v, i != 2 && i != 5);
+        }
+
+        assert!(verify_user_sigs_batched(&[]).is_empty());
+    }
+
+    /// Measures how many user submissions per second a leaf aggregator takes in, for a few numbers
+    /// of users. Signature checks on the submissions are included. Run with
+    /// `cargo test --release -p sgxdcnet-aggregator aggregation_throughput -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn aggregation_throughput() {
+        let (sk, _, _) = new_aggregator().unwrap();
+
+        for &num_users in [100usize, 1000, 5000].iter() {
+            let (registered_users, submissions) = make_submissions(num_users);
+            let inputs: Vec<SubmissionMessage> = submissions
+                .into_iter()
+                .map(SubmissionMessage::UserSubmission)
+                .collect();
+
+            let mut agg = AggregatedMessage::default();
+            let mut observed_nonces = Some(BTreeSet::new());
+            let start = Instant::now();
+            for input in inputs.iter() {
+                add_to_aggregate(&mut agg, &mut observed_nonces, &registered_users, input).unwrap();
+            }
+            let _ = finalize_aggregate(&agg, &sk).unwrap();
+            let elapsed = start.elapsed();
+
+            println!(
+                "{:>5} users: {:>8.2?} total, {:>8.0} submissions/s",
+                num_users,
+                elapsed,
+                num_users as f64 / elapsed.as_secs_f64()
+            );
+
+            // What worker threads spend on signatures when they check them in batches
+            let msgs: Vec<&UserSubmissionMessage> = inputs
+                .iter()
+                .map(|input| match input {
+                    SubmissionMessage::UserSubmission(msg) => msg,
+                    _ => unreachable!(),
+                })
+                .collect();
+            let start = Instant::now();
+            for batch in msgs.chunks(crate::workers::MAX_BATCH_SIZE) {
+                assert!(verify_user_sigs_batched(batch).into_iter().all(|v| v));
+            }
+            let elapsed = start.elapsed();
+
+            println!(
+                "{:>5} users: {:>8.2?} batched sig checks, {:>8.0} signatures/s",
+                num_users,
+                elapsed,
+                num_users as f64 / elapsed.as_secs_f64()
+            );
+        }
     }
 }
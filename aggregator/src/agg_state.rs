@@ -1,16 +1,19 @@
 use crate::util::{AggregatorError, Result};
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 use interface::{
-    compute_group_id, EntityId, RateLimitNonce, ServerPubKeyPackage, DC_NET_ROUNDS_PER_WINDOW,
+    agreed_dc_net_params, compute_anytrust_group_id, DcNetParams, EntityId, RateLimitNonce,
+    ServerPubKeyPackage, SgxProtectedKeyPub, UserRegistrationBlob, DC_NET_ROUNDS_PER_WINDOW,
 };
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 
 extern crate ed25519_dalek;
-use ed25519_dalek::SecretKey;
+use ed25519_dalek::{PublicKey, SecretKey};
 
-use crate::agg::{add_to_aggregate, finalize_aggregate, new_aggregator};
+use crate::agg::{add_to_aggregate, finalize_aggregate, merge_verified, new_aggregator};
+use common::attestation::{verify_user_registration, EnclaveAllowList};
 use common::types::{AggRegistrationBlob, AggregatedMessage, SubmissionMessage};
 
 #[derive(Serialize, Deserialize)]
@@ -19,6 +22,8 @@ pub struct AggregatorState {
     agg_id: EntityId,
     /// A unique for the set anytrust servers that this aggregator is registered with
     anytrust_group_id: EntityId,
+    /// The DC net parameters of the anytrust group. Every submission must have these dimensions
+    pub(crate) dc_net_params: DcNetParams,
     /// This aggregator's signing key.
     signing_key: SecretKey,
     /// A partial aggregate of received user messages
@@ -32,6 +37,11 @@ pub struct AggregatorState {
     /// The observed rate limiting nonces from this window. This is Some iff this aggregator is a
     /// leaf aggregator
     observed_nonces: Option<BTreeSet<RateLimitNonce>>,
+    /// The enclaves whose attestations this aggregator accepts from users
+    enclave_allow_list: EnclaveAllowList,
+    /// The users whose registrations have been verified. Maps entity ID to registration. Only
+    /// submissions from these users are aggregated
+    registered_users: BTreeMap<EntityId, UserRegistrationBlob>,
 }
 
 impl AggregatorState {
@@ -41,12 +51,19 @@ impl AggregatorState {
         pubkeys: Vec<ServerPubKeyPackage>,
         level: u32,
         agg_number: u32,
+        enclave_allow_list: EnclaveAllowList,
     ) -> Result<(AggregatorState, AggRegistrationBlob)> {
+        let dc_net_params = agreed_dc_net_params(&pubkeys).ok_or_else(|| {
+            error!("servers do not agree on valid DC net parameters");
+            AggregatorError::InvalidParameter
+        })?;
         let (sk, agg_id, reg_data) = new_aggregator()?;
 
-        let anytrust_ids: BTreeSet<EntityId> =
-            pubkeys.iter().map(|pk| EntityId::from(&pk.kem)).collect();
-        let anytrust_group_id = compute_group_id(&anytrust_ids);
+        let kem_pubkeys: Vec<SgxProtectedKeyPub> = pubkeys
+            .iter()
+            .map(|pk| SgxProtectedKeyPub(pk.kem.to_bytes()))
+            .collect();
+        let anytrust_group_id = compute_anytrust_group_id(&kem_pubkeys, &dc_net_params);
 
         // If this is a leaf aggregator, we collect nonces
         let observed_nonces = if level == 0 {
@@ -58,20 +75,46 @@ impl AggregatorState {
         let state = AggregatorState {
             agg_id,
             anytrust_group_id,
+            dc_net_params,
             signing_key: sk,
             partial_agg: None,
             level,
             agg_number: Some(agg_number),
             observed_nonces,
+            enclave_allow_list,
+            registered_users: BTreeMap::new(),
         };
 
         Ok((state, reg_data))
     }
 
+    /// Verifies the attestations on the given user registrations and records the users. Nothing
+    /// is recorded if any registration fails to verify.
+    pub(crate) fn register_users(&mut self, reg_blobs: &[UserRegistrationBlob]) -> Result<()> {
+        for reg_blob in reg_blobs {
+            if let Err(e) = verify_user_registration(&self.enclave_allow_list, reg_blob) {
+                error!("cannot verify user registration attestation: {}", e);
+                return Err(e.into());
+            }
+        }
+
+        for reg_blob in reg_blobs {
+            self.registered_users
+                .insert(EntityId::from(reg_blob), reg_blob.clone());
+        }
+        info!("{} users are registered", self.registered_users.len());
+
+        Ok(())
+    }
+
     /// Clears whatever aggregate exists and makes an empty one for the given round
     pub(crate) fn clear(&mut self, round: u32) -> Result<()> {
         // Make a new partial aggregate and put it in the local state
-        let partial_agg: AggregatedMessage = Default::default();
+        let partial_agg = AggregatedMessage {
+            round,
+            anytrust_group_id: self.anytrust_group_id,
+            ..Default::default()
+        };
 
         self.partial_agg = Some(partial_agg);
 
@@ -83,30 +126,112 @@ impl AggregatorState {
         Ok(())
     }
 
-    /// Adds the given input to the partial aggregate
+    /// This aggregator's signing key, which its parent checks its aggregates against
+    pub(crate) fn pk(&self) -> PublicKey {
+        PublicKey::from(&self.signing_key)
+    }
+
+    /// The users whose registrations have been verified
+    pub(crate) fn registered_users(&self) -> &BTreeMap<EntityId, UserRegistrationBlob> {
+        &self.registered_users
+    }
+
+    /// The rate-limit nonces seen so far this window. `None` if this aggregator doesn't track them
+    pub(crate) fn observed_nonces(&self) -> Option<&BTreeSet<RateLimitNonce>> {
+        self.observed_nonces.as_ref()
+    }
+
+    /// Checks that the given input can be XORed into the partial aggregate, i.e., that it's for
+    /// this anytrust group, the current round, and these DC net parameters. Empty aggregates carry
+    /// no message, so only their group and round are checked. Signatures and nonces aren't
+    /// checked here.
+    pub(crate) fn check_submission(&self, input_blob: &SubmissionMessage) -> Result<()> {
+        let (round, anytrust_group_id, dc_msg) = match input_blob {
+            SubmissionMessage::UserSubmission(m) => {
+                (m.round, &m.anytrust_group_id, Some(&m.aggregated_msg))
+            }
+            SubmissionMessage::AggSubmission(m) if m.is_empty() => {
+                (m.round, &m.anytrust_group_id, None)
+            }
+            SubmissionMessage::AggSubmission(m) => {
+                (m.round, &m.anytrust_group_id, Some(&m.aggregated_msg))
+            }
+        };
+        if *anytrust_group_id != self.anytrust_group_id {
+            error!("submission is for anytrust group {}", anytrust_group_id);
+            return Err(AggregatorError::InvalidParameter);
+        }
+        if dc_msg.map_or(false, |m| !m.matches_params(&self.dc_net_params)) {
+            error!("submission does not match the DC net parameters");
+            return Err(AggregatorError::InvalidParameter);
+        }
+
+        let partial_agg = self
+            .partial_agg
+            .as_ref()
+            .ok_or(AggregatorError::Uninitialized)?;
+
+        // Once a round is over, late submissions for it are dropped rather than mixed into the
+        // next round's aggregate
+        if round != partial_agg.round {
+            error!(
+                "submission is for round {}, but the current round is {}",
+                round, partial_agg.round
+            );
+            return Err(AggregatorError::InvalidParameter);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the given input to the partial aggregate. An empty aggregate doesn't change it, but
+    /// its signature is still checked
     pub(crate) fn add_to_aggregate(&mut self, input_blob: &SubmissionMessage) -> Result<()> {
+        self.check_submission(input_blob)?;
+
         let partial_agg = self
             .partial_agg
             .as_mut()
             .ok_or(AggregatorError::Uninitialized)?;
-        let _ = add_to_aggregate(
+        add_to_aggregate(
             partial_agg,
             &mut self.observed_nonces,
+            &self.registered_users,
             input_blob,
-            &self.signing_key,
-        )?;
-        Ok(())
+        )
+    }
+
+    /// Merges a partial aggregate of user submissions that were verified elsewhere, e.g., by a
+    /// worker thread, into the partial aggregate. `nonces` are the rate-limit nonces of those users.
+    pub(crate) fn merge_verified(
+        &mut self,
+        partial: AggregatedMessage,
+        nonces: &[RateLimitNonce],
+    ) -> Result<()> {
+        let partial_agg = self
+            .partial_agg
+            .as_mut()
+            .ok_or(AggregatorError::Uninitialized)?;
+        merge_verified(partial_agg, &mut self.observed_nonces, partial, nonces)
+    }
+
+    /// The users whose submissions are in the current aggregate
+    pub(crate) fn included_users(&self) -> Result<&BTreeSet<EntityId>> {
+        let partial_agg = self
+            .partial_agg
+            .as_ref()
+            .ok_or(AggregatorError::Uninitialized)?;
+        Ok(&partial_agg.user_ids)
     }
 
     /// Packages the current aggregate into a message that can be sent to the next aggregator or an
-    /// anytrust node
-    // use std::mem;
+    /// anytrust node. The aggregate is signed even if it's empty, so the receiver can tell which
+    /// aggregator it came from.
     pub(crate) fn finalize_aggregate(&self) -> Result<AggregatedMessage> {
         let partial_agg = self
             .partial_agg
             .as_ref()
             .ok_or(AggregatorError::Uninitialized)?;
-        let blob = finalize_aggregate(partial_agg)?;
-        Ok(blob)
+        finalize_aggregate(partial_agg, &self.signing_key)
     }
 }
@@ -4,18 +4,24 @@ extern crate interface;
 mod agg;
 mod agg_state;
 mod service;
+mod topology;
 mod util;
+mod workers;
 
 pub use crate::util::AggregatorError;
 use crate::{
     agg_state::AggregatorState,
     service::start_service,
-    util::{load_from_stdin, load_state, save_state, save_to_stdout, split_data_collection},
+    topology::{parse_agg_pk, Topology, TreePosition},
+    util::{
+        load_from_stdin, load_multi_from_stdin, load_state, save_state, save_to_stdout,
+        split_data_collection,
+    },
 };
 
-use common::cli_util;
 use common::types::{AggregatedMessage, SubmissionMessage};
-use interface::{ServerPubKeyPackage, UserSubmissionMessage};
+use common::{attestation, cli_util};
+use interface::{ServerPubKeyPackage, UserRegistrationBlob, UserSubmissionMessage};
 use std::{fs::File, time::SystemTime};
 
 use clap::{App, AppSettings, Arg, SubCommand};
@@ -89,8 +95,46 @@ fn main() -> Result<(), AggregatorError> {
                             "A file that contains newline-delimited pubkey packages of the \
                             servers that this user wishes to register with",
                         ),
+                )
+                .arg(
+                    Arg::with_name("allow-mrenclave")
+                        .long("allow-mrenclave")
+                        .value_name("HEX")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required_unless("allow-mrsigner")
+                        .help("An MRENCLAVE value whose attestations users may register with"),
+                )
+                .arg(
+                    Arg::with_name("allow-mrsigner")
+                        .long("allow-mrsigner")
+                        .value_name("HEX")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "An MRSIGNER value whose attestations users may register with. At \
+                            least one of this and --allow-mrenclave must be given.",
+                        ),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("get-pubkey")
+                .about(
+                    "Outputs this aggregator's signing key, hex-encoded. This is the key a \
+                    topology file or --child-keys lists for it",
+                )
+                .arg(state_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("register-user")
+                .about(
+                    "Verifies and records user registrations. STDIN is newline-separated user \
+                    registration blobs",
+                )
+                .arg(state_arg.clone()),
+        )
         .subcommand(
             SubCommand::with_name("start-round")
                 .about("Starts a fresh aggregate for the given round number")
@@ -135,13 +179,39 @@ fn main() -> Result<(), AggregatorError> {
                         .short("f")
                         .long("forward-to")
                         .value_name("FORWARD_ADDRS")
-                        .required(true)
+                        .required_unless("topology")
+                        .conflicts_with("topology")
                         .help(
                             "A comma-separated list URLs of the next-level servers or aggregators \
                             in the aggregation tree. Example: \
                             \"http://192.168.0.10:9000,http://192.168.0.11:3030\"",
                         ),
                 )
+                .arg(
+                    Arg::with_name("child-keys")
+                        .long("child-keys")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .conflicts_with("topology")
+                        .help(
+                            "A file with the signing keys of this aggregator's children, one \
+                            per line, as output by get-pubkey. Needed by a root aggregator with \
+                            leaves when there's no topology file. Aggregates signed by any other \
+                            key are rejected.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("topology")
+                        .long("topology")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help(
+                            "A JSON file that describes the whole aggregation tree. This \
+                            aggregator forwards to the parent listed there, or to the servers if \
+                            it's the root, and finishes a round once all its children have \
+                            reported.",
+                        ),
+                )
                 .arg(
                     Arg::with_name("round-duration")
                         .short("d")
@@ -167,6 +237,16 @@ fn main() -> Result<(), AggregatorError> {
                         .required(false)
                         .takes_value(false)
                         .help("If this is set, the service will not persist its state to disk"),
+                )
+                .arg(
+                    Arg::with_name("worker-threads")
+                        .long("worker-threads")
+                        .value_name("THREADS")
+                        .default_value("1")
+                        .help(
+                            "The number of threads that verify and aggregate user submissions. \
+                            Only used by aggregators without children",
+                        ),
                 ),
         )
         .subcommand(
@@ -202,13 +282,41 @@ fn main() -> Result<(), AggregatorError> {
         let level = cli_util::parse_u32(matches.value_of("level").unwrap())?;
         let agg_number = cli_util::parse_u32(matches.value_of("agg-number").unwrap())?;
 
+        let enclave_allow_list = cli_util::parse_allow_list(
+            matches.values_of("allow-mrenclave").into_iter().flatten(),
+            matches.values_of("allow-mrsigner").into_iter().flatten(),
+        )?;
+
         // Make a new state and agg registration. Save the state and and print the registration
-        let (state, reg_blob) = AggregatorState::new(pubkeys, level, agg_number)?;
+        let (state, reg_blob) =
+            AggregatorState::new(pubkeys, level, agg_number, enclave_allow_list)?;
         let state_path = matches.value_of("agg-state").unwrap();
         save_state(&state_path, &state)?;
         save_to_stdout(&reg_blob)?;
     }
 
+    if let Some(matches) = matches.subcommand_matches("get-pubkey") {
+        let state_path = matches.value_of("agg-state").unwrap();
+        let state = load_state(&state_path)?;
+        println!("{}", hex::encode(state.pk()));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("register-user") {
+        // Users' attestations are checked against the allow-list with the build's quote verifier
+        attestation::set_default_quote_verifier();
+
+        // Parse user registration blobs from stdin
+        let reg_blobs: Vec<UserRegistrationBlob> = load_multi_from_stdin()?;
+
+        // Feed them to the state and save the new state
+        let state_path = matches.value_of("agg-state").unwrap();
+        let mut state = load_state(&state_path)?;
+        state.register_users(&reg_blobs)?;
+        save_state(&state_path, &state)?;
+
+        println!("OK");
+    }
+
     if let Some(matches) = matches.subcommand_matches("start-round") {
         // Load the round
         let round = cli_util::parse_u32(matches.value_of("round").unwrap())?;
@@ -276,18 +384,6 @@ fn main() -> Result<(), AggregatorError> {
             )?);
             SystemTime::UNIX_EPOCH + secs_since_epoch
         };
-        let forward_urls: Vec<String> = matches
-            .value_of("forward-to")
-            .unwrap()
-            .split(",")
-            .map(String::from)
-            .collect();
-        // Check that the forward-to URLs are well-formed
-        for url in forward_urls.iter() {
-            info!("url:{}", url);
-            let _: actix_web::http::Uri =
-                url.parse().expect(&format!("{} is not a valid URL", url));
-        }
 
         // Load the aggregator state and clear it for this round
         let state_path = matches.value_of("agg-state").unwrap().to_string();
@@ -295,6 +391,43 @@ fn main() -> Result<(), AggregatorError> {
         agg_state.clear(round)?;
         info!("Initialized round {}", round);
 
+        // Find out where this aggregator forwards to and how many children it waits for
+        let position = match matches.value_of("topology") {
+            Some(path) => {
+                let topology = Topology::load(path)?;
+                topology.position(agg_state.agg_number.unwrap())?
+            }
+            None => {
+                let forward_urls: Vec<String> = matches
+                    .value_of("forward-to")
+                    .unwrap()
+                    .split(",")
+                    .map(String::from)
+                    .collect();
+                // Check that the forward-to URLs are well-formed
+                for url in forward_urls.iter() {
+                    info!("url:{}", url);
+                    let _: actix_web::http::Uri =
+                        url.parse().expect(&format!("{} is not a valid URL", url));
+                }
+                let child_pks = match matches.value_of("child-keys") {
+                    Some(path) => std::fs::read_to_string(path)?
+                        .lines()
+                        .filter(|line| !line.trim().is_empty())
+                        .map(parse_agg_pk)
+                        .collect::<Result<Vec<_>, _>>()?,
+                    None => Vec::new(),
+                };
+                TreePosition::from_level(
+                    agg_state.level,
+                    agg_state.agg_number.unwrap(),
+                    forward_urls,
+                    child_pks,
+                )?
+            }
+        };
+        info!("Position in the aggregation tree: {:?}", position);
+
         // If no-persist is set, then the state path is None
         let agg_state_path = if matches.is_present("no-persist") {
             None
@@ -302,9 +435,16 @@ fn main() -> Result<(), AggregatorError> {
             Some(state_path)
         };
 
-        let level = agg_state.level;
-        let state = service::ServiceState::new(agg_state, forward_urls, round, agg_state_path);
-        start_service(bind_addr, state, round_dur, start_time, level).unwrap();
+        let num_workers = cli_util::parse_u32(matches.value_of("worker-threads").unwrap())?;
+
+        let state = service::ServiceState::new(
+            agg_state,
+            position,
+            round,
+            agg_state_path,
+            num_workers as usize,
+        )?;
+        start_service(bind_addr, state, round_dur, start_time).unwrap();
     }
 
     if let Some(matches) = matches.subcommand_matches("split-dataset") {
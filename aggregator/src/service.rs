@@ -1,14 +1,14 @@
 use crate::{
+    agg::verify_user_submission,
+    topology::TreePosition,
     util::{save_state, AggregatorError},
+    workers::WorkerPool,
     AggregatorState,
 };
 use common::cli_util;
 use common::log_time::{log_detailed_time, log_time};
-use common::types::{AggregatedMessage, SubmissionMessage};
-use interface::{
-    UserSubmissionMessage, AGGREGATOR_THREAD_NUMBER, DC_NUM_USER, EVALUATION_FLAG, PARAMETER_FLAG,
-    RETRIES, TIMEOUT_SEC,
-};
+use common::types::{AggregatedMessage, Signable, SubmissionMessage};
+use interface::{EntityId, UserSubmissionMessage, EVALUATION_FLAG, RETRIES, TIMEOUT_SEC};
 
 use actix_rt::{
     spawn,
@@ -22,10 +22,13 @@ use actix_web::{
     post, rt as actix_rt, web, App, HttpResponse, HttpServer, ResponseError,
 };
 use core::ops::DerefMut;
-use futures::future::{join_all, FutureExt};
+use futures::{
+    channel::oneshot,
+    future::{join_all, FutureExt},
+};
 use log::{debug, error, info};
 use std::{
-    env,
+    collections::BTreeMap,
     fs::File,
     sync::{Arc, Mutex},
     time::{Duration, SystemTime},
@@ -35,6 +38,9 @@ use thiserror::Error;
 // We take 5 seconds at the end of every round for the aggregates to propagate up the tree
 const PROPAGATION_SECS: u64 = 5;
 
+// Submissions may be up to this many rounds ahead. They're held until their round starts
+const MAX_LOOKAHEAD_ROUNDS: u32 = 2;
+
 #[derive(Debug, Error)]
 enum ApiError {
     #[error("internal error")]
@@ -43,8 +49,26 @@ enum ApiError {
     Encoding(#[from] base64::DecodeError),
     #[error("error in serialization/deserialization")]
     Ser(#[from] cli_util::SerializationError),
+    #[error("round {0} is closed, the current round is {1}")]
+    StaleRound(u32, u32),
+    #[error("round {0} is too far ahead of the current round {1}")]
+    FutureRound(u32, u32),
+    #[error("no room left to hold submissions for round {0}")]
+    BufferFull(u32),
+    #[error("the aggregate is not from a child of this aggregator")]
+    UnknownChild,
+}
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::StaleRound(..) => StatusCode::CONFLICT,
+            ApiError::FutureRound(..) => StatusCode::BAD_REQUEST,
+            ApiError::BufferFull(..) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::UnknownChild => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
-impl ResponseError for ApiError {}
 impl From<std::io::Error> for ApiError {
     fn from(error: std::io::Error) -> Self {
         ApiError::Internal(AggregatorError::Io(error))
@@ -54,30 +78,198 @@ impl From<std::io::Error> for ApiError {
 // #[derive(Clone)]
 pub(crate) struct ServiceState {
     pub(crate) agg_state: AggregatorState,
-    /// The URLs of the next aggregators
-    pub(crate) forward_urls: Vec<String>,
-    /// [onlyevaluaton] for root aggregator to save the aggregate share from leaf aggregator
-    pub(crate) root_data_collection: Vec<AggregatedMessage>,
+    /// Where this aggregator sits in the aggregation tree, including where it forwards to
+    pub(crate) position: TreePosition,
+    /// The aggregates received from this aggregator's children
+    pub(crate) child_data_collection: Vec<AggregatedMessage>,
+    /// Submissions for the next few rounds, keyed by round and then by the user or child
+    /// aggregator that sent them. They're added to the aggregate once their round starts
+    pub(crate) pending_submissions: BTreeMap<u32, BTreeMap<EntityId, SubmissionMessage>>,
     pub(crate) round: u32,
+    /// When the current round started. The round ends a round duration after this, or earlier
+    /// once every user or child this aggregator expects has submitted
+    pub(crate) round_start: Instant,
     /// The path to this aggregator's state file. If `None`, state is not persisted to disk
     pub(crate) agg_state_path: Option<String>,
+    /// The threads that verify and aggregate user submissions. This is Some iff this aggregator
+    /// has no children
+    pub(crate) workers: Option<WorkerPool>,
 }
 
 impl ServiceState {
+    /// Makes the state of an aggregator that starts at the given round. If this aggregator has
+    /// no children, user submissions are verified and aggregated by `num_workers` threads.
     pub(crate) fn new(
         agg_state: AggregatorState,
-        forward_urls: Vec<String>,
+        position: TreePosition,
         round: u32,
         agg_state_path: Option<String>,
-    ) -> ServiceState {
-        ServiceState {
+        num_workers: usize,
+    ) -> Result<ServiceState, AggregatorError> {
+        let workers = if position.num_children == 0 {
+            let pool = WorkerPool::new(num_workers, agg_state.registered_users().clone())?;
+            pool.start_round(round, agg_state.observed_nonces().cloned());
+            Some(pool)
+        } else {
+            None
+        };
+
+        Ok(ServiceState {
             agg_state,
-            forward_urls,
-            root_data_collection: Vec::new(),
+            position,
+            child_data_collection: Vec::new(),
+            pending_submissions: BTreeMap::new(),
             round,
+            round_start: Instant::now(),
             agg_state_path,
+            workers,
+        })
+    }
+
+    /// The number of submissions this aggregator waits for in a round. These are the shares of its
+    /// children, or user submissions if it has no children.
+    fn expected_submissions(&self) -> usize {
+        if self.position.num_children > 0 {
+            self.position.num_children
+        } else {
+            self.position
+                .expected_users(self.agg_state.dc_net_params.num_users)
+        }
+    }
+
+    /// Whether every submission this aggregator waits for in the current round is in
+    fn round_complete(&self) -> Result<bool, AggregatorError> {
+        let num_submitted = if self.position.num_children > 0 {
+            self.child_data_collection.len()
+        } else {
+            let num_in_workers = self.workers.as_ref().map_or(0, WorkerPool::num_users);
+            self.agg_state.included_users()?.len() + num_in_workers
+        };
+        Ok(num_submitted >= self.expected_submissions())
+    }
+
+    /// Adds a submission for the current round to the aggregate. User submissions go to the worker
+    /// threads, if there are any. In that case, the returned receiver resolves once the
+    /// submission is aggregated or rejected.
+    fn add_submission(
+        &mut self,
+        submission: SubmissionMessage,
+    ) -> Result<Option<oneshot::Receiver<Result<(), AggregatorError>>>, AggregatorError> {
+        self.agg_state.check_submission(&submission)?;
+        match (submission, self.workers.as_ref()) {
+            (SubmissionMessage::UserSubmission(msg), Some(workers)) => {
+                Ok(Some(workers.submit(msg)?))
+            }
+            (submission, _) => {
+                self.agg_state.add_to_aggregate(&submission)?;
+                if let SubmissionMessage::AggSubmission(share) = submission {
+                    self.child_data_collection.push(share);
+                }
+                Ok(None)
+            }
         }
     }
+
+    /// Checks the signature on a submission for a later round, and that it's from a registered
+    /// user or one of our children. Returns who sent it.
+    fn authenticate_early(&self, submission: &SubmissionMessage) -> Result<EntityId, ApiError> {
+        match submission {
+            SubmissionMessage::UserSubmission(msg) => {
+                verify_user_submission(self.agg_state.registered_users(), msg)?;
+                Ok(msg.user_id)
+            }
+            SubmissionMessage::AggSubmission(share) => {
+                if share.verify().is_err() {
+                    error!("can't verify sig on share for round {}", share.round);
+                    return Err(AggregatorError::InvalidParameter.into());
+                }
+                Ok(EntityId::from(&share.pk))
+            }
+        }
+    }
+
+    /// Adds the given submission to the aggregate if it's for the current round. If it's for one
+    /// of the next `MAX_LOOKAHEAD_ROUNDS` rounds, it's authenticated and held until that round
+    /// starts. If the submission went to a worker thread, the returned receiver resolves once it's
+    /// aggregated.
+    fn accept_submission(
+        &mut self,
+        submission: SubmissionMessage,
+    ) -> Result<Option<oneshot::Receiver<Result<(), AggregatorError>>>, ApiError> {
+        let round = submission.round();
+        if round < self.round {
+            return Err(ApiError::StaleRound(round, self.round));
+        }
+        if round > self.round.saturating_add(MAX_LOOKAHEAD_ROUNDS) {
+            return Err(ApiError::FutureRound(round, self.round));
+        }
+
+        // A child retries until its share goes through, so we may see the same share twice
+        if let SubmissionMessage::AggSubmission(ref share) = submission {
+            // Only our children's shares are taken, or the round could be made to look complete
+            if !self.position.child_pks.contains(&share.pk) {
+                return Err(ApiError::UnknownChild);
+            }
+            if round == self.round && self.child_data_collection.iter().any(|c| c.pk == share.pk) {
+                info!("ignoring repeated share from child aggregator");
+                return Ok(None);
+            }
+        }
+
+        if round == self.round {
+            return Ok(self.add_submission(submission)?);
+        }
+
+        // Only hold what we'd take once the round starts, and at most one submission per sender,
+        // so the buffer can't be filled by anyone else
+        let sender = self.authenticate_early(&submission)?;
+        let max_pending = self.expected_submissions();
+        let pending = self.pending_submissions.entry(round).or_default();
+        if pending.contains_key(&sender) {
+            info!("ignoring repeated submission for round {}", round);
+            return Ok(None);
+        }
+        if pending.len() >= max_pending {
+            return Err(ApiError::BufferFull(round));
+        }
+        debug!("holding a submission for round {}", round);
+        pending.insert(sender, submission);
+
+        Ok(None)
+    }
+
+    /// Adds the submissions that arrived early for the current round to the aggregate. Returns the
+    /// receivers of the submissions that went to the worker threads.
+    fn add_pending_submissions(&mut self) -> Vec<oneshot::Receiver<Result<(), AggregatorError>>> {
+        // Anything held for an earlier round can't be used anymore
+        let mut pending = self.pending_submissions.split_off(&self.round);
+        std::mem::swap(&mut pending, &mut self.pending_submissions);
+        if !pending.is_empty() {
+            error!("dropping submissions held for rounds before {}", self.round);
+        }
+
+        let current = self
+            .pending_submissions
+            .remove(&self.round)
+            .unwrap_or_default();
+        if !current.is_empty() {
+            info!(
+                "adding {} submissions held for round {}",
+                current.len(),
+                self.round
+            );
+        }
+        let mut in_workers = Vec::new();
+        for submission in current.into_values() {
+            match self.add_submission(submission) {
+                Ok(Some(verified)) => in_workers.push(verified),
+                Ok(None) => (),
+                Err(e) => error!("dropping held submission for round {}: {}", self.round, e),
+            }
+        }
+
+        in_workers
+    }
 }
 
 struct CombinedData {
@@ -96,40 +288,26 @@ async fn submit_agg(
     let data_collection = &combined_data.data_collection;
     let payload = payload.split_whitespace().next().unwrap_or("");
 
-    // step 2: get the aggregator number and level
+    // step 2: get the aggregator number and position
     let mut handle = state.lock().unwrap();
-    let ServiceState {
-        ref mut agg_state,
-        ref forward_urls,
-        ..
-    } = handle.deref_mut();
-    let agg_number = agg_state.agg_number.unwrap();
-    let level = agg_state.level;
+    let agg_number = handle.agg_state.agg_number.unwrap();
+    let expected_users = handle.expected_submissions();
 
-    // step 3: load data and push to data_collection
+    // step 3: load data
     let data: UserSubmissionMessage = cli_util::load(&mut payload.as_bytes())?;
-    let mut data_collection_handle = data_collection.lock().unwrap();
-    data_collection_handle.push(data.clone());
-
-    let num_user = if PARAMETER_FLAG {
-        env::var("DC_NUM_USER")
-            .unwrap_or_else(|_| "100".to_string())
-            .parse::<usize>()
-            .expect("Invalid DC_NUM_USER value")
-    } else {
-        DC_NUM_USER
-    };
 
     // evaluation mode
     if EVALUATION_FLAG == true {
-        //step 4: judging whether all msg is sent; if so, save it to file.
+        //step 4: push to data_collection and judge whether all msg is sent; if so, save it to file.
+        let mut data_collection_handle = data_collection.lock().unwrap();
+        data_collection_handle.push(data);
         info!(
             "now aggregator No.{} have {}/{}",
             agg_number,
             data_collection_handle.len(),
-            num_user / AGGREGATOR_THREAD_NUMBER
+            expected_users
         );
-        if data_collection_handle.len() == num_user / AGGREGATOR_THREAD_NUMBER {
+        if data_collection_handle.len() == expected_users {
             info!("User finish sending all msg!");
             // Save data_collection to a file
             let save_path_prefix = "data_collection_";
@@ -140,24 +318,26 @@ async fn submit_agg(
             cli_util::save(file, &data_vec)?;
             info!("Data saved to {}", save_path);
         }
+    } else if handle.position.num_children > 0 {
+        error!("aggregators with child aggregators don't receive msg from client");
     } else {
-        if AGGREGATOR_THREAD_NUMBER == 1 {
-            let agg_data = SubmissionMessage::UserSubmission(data);
-            agg_state.add_to_aggregate(&agg_data)?;
-            if data_collection_handle.len() == num_user / AGGREGATOR_THREAD_NUMBER {
-                force_round_output(&*state).await;
-            }
-        } else if level == 0 {
-            error!("the root aggregator doesn't receive msg from client");
-        } else {
-            let agg_data = SubmissionMessage::UserSubmission(data);
-            agg_state.add_to_aggregate(&agg_data)?;
-            if data_collection_handle.len() == num_user / AGGREGATOR_THREAD_NUMBER {
-                let share: AggregatedMessage = agg_state
-                    .finalize_aggregate()
-                    .expect("could not finalize aggregate");
-                actix_rt::spawn(send_share_to_root(forward_urls.clone(), share));
-            }
+        let verified = handle.accept_submission(SubmissionMessage::UserSubmission(data))?;
+        drop(handle);
+
+        //step 4: wait for a worker thread to aggregate the submission
+        if let Some(verified) = verified {
+            verified
+                .await
+                .map_err(|_| AggregatorError::WorkerStopped)??;
+        }
+
+        //step 5: end the round early once every expected user is in
+        let (complete, round) = {
+            let handle = state.lock().unwrap();
+            (handle.round_complete()?, handle.round)
+        };
+        if complete {
+            finish_round(&*state, round, Instant::now()).await;
         }
     }
     Ok(HttpResponse::Ok().body("OK\n"))
@@ -198,7 +378,7 @@ async fn aggregate_eval(combined_data: web::Data<CombinedData>) -> Result<HttpRe
     let mut handle = state.lock().unwrap();
     let ServiceState {
         ref mut agg_state,
-        ref forward_urls,
+        ref position,
         ..
     } = handle.deref_mut();
 
@@ -233,7 +413,7 @@ async fn aggregate_eval(combined_data: web::Data<CombinedData>) -> Result<HttpRe
     //     agg_state.add_to_aggregate(&agg_data)?;
     // }
 
-    // step 4: send to root
+    // step 4: send to parent
     if logflag {
         let log_msg = format!("leaf-agg{} before finalize", agg_number);
         log_detailed_time(log_msg);
@@ -246,24 +426,123 @@ async fn aggregate_eval(combined_data: web::Data<CombinedData>) -> Result<HttpRe
         "{}'s share is:{:?}, forward-url is {:?}",
         agg_number,
         share,
-        forward_urls.clone()
+        position.forward_urls.clone()
     );
     if logflag {
-        let log_msg = format!("leaf-agg{} before sending to root", agg_number);
+        let log_msg = format!("leaf-agg{} before sending to parent", agg_number);
         log_detailed_time(log_msg);
     }
 
-    actix_rt::spawn(send_share_to_root(forward_urls.clone(), share));
+    actix_rt::spawn(send_share_to_parent(position.forward_urls.clone(), share));
 
     Ok(HttpResponse::Ok().body("OK\n"))
 }
 
-async fn send_share_to_root(base_url: Vec<String>, share: AggregatedMessage) {
+/// Ends the given round at this aggregator, unless it has ended already, and starts the next
+/// round at `next_start`. The root sends its aggregate to the servers. Every other aggregator
+/// sends it to its parent. If the submissions held for the next round already complete it, that
+/// round ends right away too.
+async fn finish_round(state: &Mutex<ServiceState>, mut round: u32, mut next_start: Instant) {
+    loop {
+        let (share, is_root, forward_urls, in_workers) = {
+            let mut handle = state.lock().unwrap();
+            if handle.round != round {
+                debug!("round {} has already ended", round);
+                return;
+            }
+
+            let ServiceState {
+                ref mut agg_state,
+                ref position,
+                ref child_data_collection,
+                ref workers,
+                ..
+            } = *handle;
+
+            // Merge in what the worker threads aggregated
+            if let Some(workers) = workers {
+                for partial in workers.drain().expect("could not drain worker threads") {
+                    if let Err(e) = agg_state.merge_verified(partial.agg, &partial.nonces) {
+                        error!("dropping a worker's partial aggregate: {}", e);
+                    }
+                }
+            }
+
+            let share: AggregatedMessage = agg_state
+                .finalize_aggregate()
+                .expect("could not finalize aggregate");
+            if position.num_children > 0 {
+                info!(
+                    "round {} ends with {}/{} children and {} users",
+                    round,
+                    child_data_collection.len(),
+                    position.num_children,
+                    share.user_ids.len()
+                );
+            } else {
+                info!(
+                    "round {} ends with {}/{} users",
+                    round,
+                    share.user_ids.len(),
+                    position.expected_users(agg_state.dc_net_params.num_users)
+                );
+            }
+            debug!("users included in round {}: {:?}", round, share.user_ids);
+            let is_root = position.is_root;
+            let forward_urls = position.forward_urls.clone();
+
+            // [onlyevaluation] as we will only do evaluation, we will not actually start nextround
+            let mut in_workers = Vec::new();
+            if !EVALUATION_FLAG {
+                in_workers = start_next_round(handle.deref_mut(), next_start);
+            }
+
+            (share, is_root, forward_urls, in_workers)
+        };
+
+        if is_root {
+            log_time();
+            let send_timeout = Duration::from_secs(20);
+            let mut payload = Vec::new();
+            cli_util::save(&mut payload, &share).expect("could not serialize aggregate");
+            spawn(
+                actix_rt::time::timeout(send_timeout, send_aggregate(payload, forward_urls)).map(
+                    |r| {
+                        if r.is_err() {
+                            error!("timeout for sending aggregation was hit");
+                        }
+                    },
+                ),
+            );
+        } else {
+            spawn(send_share_to_parent(forward_urls, share));
+        }
+
+        if EVALUATION_FLAG {
+            break;
+        }
+
+        // Wait for the workers without holding the lock, so the held submissions count towards
+        // the next round being complete. Workers log the submissions they reject.
+        join_all(in_workers).await;
+        let next_complete = {
+            let handle = state.lock().unwrap();
+            handle.round == round + 1 && handle.round_complete().unwrap_or(false)
+        };
+        if !next_complete {
+            break;
+        }
+        round += 1;
+        next_start = Instant::now();
+    }
+}
+
+async fn send_share_to_parent(base_url: Vec<String>, share: AggregatedMessage) {
     // step 1: serialize the share
     let mut body = Vec::new();
     cli_util::save(&mut body, &share).expect("could not serialize share");
 
-    // step 2: Send the serialized contents as an HTTP POST to leader/submit-share
+    // step 2: Send the serialized contents as an HTTP POST to parent/submit-agg-from-agg
     let base_url = &base_url[0];
     let client = Client::builder()
         .timeout(Duration::from_secs(TIMEOUT_SEC))
@@ -281,6 +560,10 @@ async fn send_share_to_root(base_url: Vec<String>, share: AggregatedMessage) {
                 if res.status() == StatusCode::OK {
                     debug!("Share sent successfully");
                     break;
+                } else if res.status() == StatusCode::CONFLICT {
+                    // The parent has closed the round. Retrying won't change that
+                    error!("Parent closed the round before the share arrived");
+                    break;
                 } else {
                     error!("Could not send share message error: {:?}", res);
                 }
@@ -307,58 +590,49 @@ async fn submit_agg_from_agg(
     let logflag: bool = false;
 
     if logflag {
-        let log_msg = format!("receiving leaf share");
+        let log_msg = format!("receiving child share");
         log_detailed_time(log_msg);
     }
 
     let data: AggregatedMessage = cli_util::load(&mut payload.as_bytes())?;
 
     if logflag {
-        let log_msg = format!("root already load");
+        let log_msg = format!("parent already load");
         log_detailed_time(log_msg);
     }
 
     // step 2: unwrap input
-    let mut flag: bool = false;
+    let mut flag: Option<u32> = None;
     let combined_data_ref = combined_data.get_ref();
     let state = &combined_data_ref.state;
     {
         let mut handle = state.lock().unwrap();
-        let ServiceState {
-            ref mut agg_state,
-            ref mut root_data_collection,
-            ..
-        } = handle.deref_mut();
-        root_data_collection.push(data.clone());
 
         if logflag {
-            let log_msg = format!("root finish unwrapping msg");
+            let log_msg = format!("parent finish unwrapping msg");
             log_detailed_time(log_msg);
         }
 
-        //step 3: add to aggregate
-        let agg_data = SubmissionMessage::AggSubmission(data);
-        agg_state.add_to_aggregate(&agg_data)?;
+        //step 3: add to aggregate, or hold it if it's for an upcoming round
+        handle.accept_submission(SubmissionMessage::AggSubmission(data))?;
 
         if logflag {
-            let log_msg = format!("root finish aggregating");
+            let log_msg = format!("parent finish aggregating");
             log_detailed_time(log_msg);
         }
 
-        //step 4: judge whether all shares are collected
-        if root_data_collection.len() == AGGREGATOR_THREAD_NUMBER {
-            flag = true;
+        //step 4: judge whether the shares of all children are collected
+        if handle.round_complete()? {
+            flag = Some(handle.round);
         }
     }
-    if flag {
+    if let Some(round) = flag {
         info!("collecting all shares!");
         if logflag {
-            let log_msg = format!("root start output");
+            let log_msg = format!("parent start output");
             log_detailed_time(log_msg);
         }
-        log_time();
-        force_round_output(&*state).await;
-        info!("root-agg successfully send msg to server");
+        finish_round(&*state, round, Instant::now()).await;
     }
     Ok(HttpResponse::Ok().body("OK\n"))
 }
@@ -373,35 +647,14 @@ async fn force_round_end(combined_data: web::Data<CombinedData>) -> Result<HttpR
     let state = &combined_data_ref.state;
 
     // step 2: force round output
-    force_round_output(&*state).await;
+    let round = state.lock().unwrap().round;
+    finish_round(&*state, round, Instant::now()).await;
     let duration = start.elapsed();
     debug!("[agg] force_round_end: {:?}", duration);
 
     Ok(HttpResponse::Ok().body("OK\n"))
 }
 
-/// root will trigger this function to send msg to server
-async fn force_round_output(state: &Mutex<ServiceState>) {
-    debug!("start round output!");
-    let send_timeout = Duration::from_secs(20);
-    let (agg_payload, forward_urls) =
-        get_agg_payload(<&std::sync::Mutex<ServiceState>>::clone(&state));
-    debug!("forward_urls is {:?}", forward_urls);
-    spawn(
-        actix_rt::time::timeout(send_timeout, send_aggregate(agg_payload, forward_urls)).map(|r| {
-            if r.is_err() {
-                error!("sending aggregation was hit");
-            }
-        }),
-    );
-
-    // [onlyevaluation]Start the next round immediately
-    // as we will only do evaluation, we will not actually start nextround
-    if !EVALUATION_FLAG {
-        start_next_round(<&std::sync::Mutex<ServiceState>>::clone(&state));
-    }
-}
-
 #[get("/round-num")]
 async fn round_num(combined_data: web::Data<CombinedData>) -> Result<HttpResponse, ApiError> {
     // Unwrap the round and make it a struct
@@ -414,31 +667,6 @@ async fn round_num(combined_data: web::Data<CombinedData>) -> Result<HttpRespons
     Ok(HttpResponse::Ok().body(body))
 }
 
-/// Finalizes and serializes the current aggregator state. Returns the pyaload nad all the
-/// forwarding URLs
-fn get_agg_payload(state: &Mutex<ServiceState>) -> (Vec<u8>, Vec<String>) {
-    let start = std::time::Instant::now();
-
-    let handle = state.lock().unwrap();
-    let ServiceState {
-        ref agg_state,
-        ref forward_urls,
-        ..
-    } = *handle;
-
-    // Finalize and serialize the aggregate
-    let agg = agg_state
-        .finalize_aggregate()
-        .expect("could not finalize aggregate");
-    let mut payload = Vec::new();
-    cli_util::save(&mut payload, &agg).expect("could not serialize aggregate");
-
-    let duration = start.elapsed();
-    debug!("[agg] get_agg_payload: {:?}", duration);
-    debug!("forward_urls is {:?}", forward_urls.clone());
-    (payload, forward_urls.clone())
-}
-
 /// Sends a finalized aggregate to base_url/submit-agg for all base_url in forward_urls
 async fn send_aggregate(payload: Vec<u8>, forward_urls: Vec<String>) {
     let start = std::time::Instant::now();
@@ -483,16 +711,22 @@ async fn send_to_url(base_url: String, payload: Vec<u8>) {
     }
 }
 
-// Saves the state and start the next round
-fn start_next_round(state: &Mutex<ServiceState>) {
+// Saves the state and start the next round at `next_start`. Returns the receivers of the held
+// submissions that went to the worker threads
+fn start_next_round(
+    state: &mut ServiceState,
+    next_start: Instant,
+) -> Vec<oneshot::Receiver<Result<(), AggregatorError>>> {
     let start = std::time::Instant::now();
-    let mut handle = state.lock().unwrap();
     let ServiceState {
         ref mut agg_state,
         ref mut round,
+        ref mut round_start,
         ref agg_state_path,
+        ref mut child_data_collection,
+        ref workers,
         ..
-    } = *handle;
+    } = *state;
 
     info!("round {} complete", round);
     // Save the state if a path is specified
@@ -506,10 +740,18 @@ fn start_next_round(state: &Mutex<ServiceState>) {
 
     // Increment the round and clear the state
     *round += 1;
+    *round_start = next_start;
     agg_state.clear(*round).expect("could not start new round");
+    child_data_collection.clear();
+    if let Some(workers) = workers {
+        workers.start_round(*round, agg_state.observed_nonces().cloned());
+    }
+    let in_workers = state.add_pending_submissions();
 
     let duration = start.elapsed();
     debug!("[agg] start_next_round: {:?}", duration);
+
+    in_workers
 }
 
 // This converts future system time to a monotonic instant. Doing this has weird edge cases in
@@ -523,79 +765,56 @@ fn systime_to_instant(time: SystemTime) -> Instant {
     Instant::from_std(now_inst + delta)
 }
 
-/// Every `round_dur`, ends the round and forwards the finalized aggregate to the next aggregator
-/// or anytrust server up the tree
-/// [problem] When the round is normally ended, the clock is not reset. We can set a end-time in the ServiceState.
-/// Currently, each round time is set to 100,000s in server_ctrl.sh
+/// Ends every round `round_dur` after it starts, unless it ended earlier because every expected
+/// participant submitted. The finalized aggregate goes to the next aggregator or anytrust server
+/// up the tree.
 async fn round_finalization_loop(
     state: Arc<Mutex<ServiceState>>,
     round_dur: Duration,
-    mut start_time: SystemTime,
-    level: u32,
+    height: u32,
 ) {
     let one_sec = Duration::from_secs(100);
-    let send_timeout = one_sec;
     let propagation_dur = Duration::from_secs(PROPAGATION_SECS);
 
     debug!("start round_finalization_loop");
 
-    // Wait until the start time, then start the round loop
-    delay_until(systime_to_instant(start_time)).await;
     loop {
-        // We send our aggregate `level` seconds after the official end of the round
-        let end_time = start_time + round_dur + level * one_sec;
-
-        debug!(
-            "systime_to_instant(end_time): {:?}",
-            systime_to_instant(end_time)
-        );
-
-        // Wait
-        delay_until(systime_to_instant(end_time)).await;
-
-        // The round has ended. Serialize the aggregate and forward it in the background. Time out
-        // after 1 second
-        let (agg_payload, forward_urls) = get_agg_payload(&state);
-        debug!("agg_payload.len: {}", agg_payload.len());
-        debug!("forward_urls: {:?}", forward_urls);
-
-        spawn(
-            actix_rt::time::timeout(send_timeout, send_aggregate(agg_payload, forward_urls)).map(
-                |r| {
-                    if r.is_err() {
-                        error!("timeout for sending aggregation was hit");
-                    }
-                },
-            ),
-        );
-
-        // Start the next round early
-        start_next_round(&state.clone());
-
-        // The official round start time is right after propagation terminates. We update this so
-        // that end_time is calculated correctly.
-        start_time = start_time + round_dur + propagation_dur;
+        let (round, round_start) = {
+            let handle = state.lock().unwrap();
+            (handle.round, handle.round_start)
+        };
+
+        // We send our aggregate `height` seconds after the official end of the round, so children
+        // send before their parents
+        let end_time = round_start + round_dur + height * one_sec;
+        delay_until(end_time).await;
+
+        // If the round already ended early, this does nothing and we wait for the next round's
+        // deadline. Otherwise, the next round officially starts right after propagation
+        // terminates.
+        finish_round(&state, round, round_start + round_dur + propagation_dur).await;
     }
 }
 
 #[actix_rt::main]
 pub(crate) async fn start_service(
     bind_addr: String,
-    state: ServiceState,
+    mut state: ServiceState,
     round_dur: Duration,
     start_time: SystemTime,
-    level: u32,
 ) -> std::io::Result<()> {
+    let TreePosition {
+        is_root, height, ..
+    } = state.position;
+    state.round_start = systime_to_instant(start_time);
     let state = Arc::new(Mutex::new(state));
     let state_copy = state.clone();
     let data_collection = Arc::new(Mutex::new(Vec::<UserSubmissionMessage>::new()));
 
-    Arbiter::spawn(round_finalization_loop(
-        state_copy, round_dur, start_time, level,
-    ));
+    Arbiter::spawn(round_finalization_loop(state_copy, round_dur, height));
 
     // Start the web server
-    if level == 0 {
+    if is_root {
         HttpServer::new(move || {
             App::new()
                 .data(CombinedData {
@@ -641,3 +860,148 @@ pub(crate) async fn start_service(
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::attestation::EnclaveAllowList;
+    use ed25519_dalek::{PublicKey, SecretKey};
+    use interface::{DcNetParams, ServerPubKeyPackage, SgxProtectedKeyPub};
+
+    fn server_keys() -> Vec<ServerPubKeyPackage> {
+        let key = |b: u8| PublicKey::from(&SecretKey::from_bytes(&[b; 32]).unwrap());
+        vec![ServerPubKeyPackage {
+            sig: key(0),
+            kem: key(1),
+            xkem: SgxProtectedKeyPub([2u8; 32]),
+            dc_net_params: DcNetParams::default(),
+        }]
+    }
+
+    /// Makes an aggregator in the same anytrust group as `server_keys`, at the given round
+    fn new_agg(round: u32) -> AggregatorState {
+        let (mut agg, _) =
+            AggregatorState::new(server_keys(), 1, 0, EnclaveAllowList::default()).unwrap();
+        agg.clear(round).unwrap();
+        agg
+    }
+
+    /// Makes the service state of a root aggregator at the given round with the given children
+    fn new_parent(round: u32, children: &[&AggregatorState]) -> ServiceState {
+        let position = TreePosition {
+            is_root: true,
+            forward_urls: vec![],
+            num_children: children.len(),
+            child_pks: children.iter().map(|c| c.pk()).collect(),
+            height: 1,
+            num_users: None,
+            assigned_users: 0,
+            num_leaves: children.len(),
+            leaf_index: 0,
+        };
+        ServiceState::new(new_agg(round), position, round, None, 1).unwrap()
+    }
+
+    /// The (empty) aggregate `agg` sends its parent for the given round
+    fn share(agg: &mut AggregatorState, round: u32) -> SubmissionMessage {
+        agg.clear(round).unwrap();
+        SubmissionMessage::AggSubmission(agg.finalize_aggregate().unwrap())
+    }
+
+    #[test]
+    fn only_children_are_accepted() {
+        let mut child = new_agg(0);
+        let mut outsider = new_agg(0);
+        let mut parent = new_parent(0, &[&child]);
+
+        assert!(matches!(
+            parent.accept_submission(share(&mut outsider, 0)),
+            Err(ApiError::UnknownChild)
+        ));
+        assert!(parent.child_data_collection.is_empty());
+
+        parent.accept_submission(share(&mut child, 0)).unwrap();
+        assert_eq!(parent.child_data_collection.len(), 1);
+
+        // A retried share doesn't count twice
+        assert!(parent
+            .accept_submission(share(&mut child, 0))
+            .unwrap()
+            .is_none());
+        assert_eq!(parent.child_data_collection.len(), 1);
+        assert!(parent.round_complete().unwrap());
+    }
+
+    #[test]
+    fn lookahead_window() {
+        let mut child = new_agg(0);
+        let mut parent = new_parent(5, &[&child]);
+
+        assert!(matches!(
+            parent.accept_submission(share(&mut child, 4)),
+            Err(ApiError::StaleRound(4, 5))
+        ));
+        let last = 5 + MAX_LOOKAHEAD_ROUNDS;
+        assert!(matches!(
+            parent.accept_submission(share(&mut child, last + 1)),
+            Err(ApiError::FutureRound(..))
+        ));
+        parent.accept_submission(share(&mut child, last)).unwrap();
+        assert_eq!(parent.pending_submissions[&last].len(), 1);
+        assert!(parent.child_data_collection.is_empty());
+
+        // The window stops at the last round rather than overflowing
+        parent.round = u32::MAX - 1;
+        parent
+            .accept_submission(share(&mut child, u32::MAX))
+            .unwrap();
+        assert_eq!(parent.pending_submissions[&u32::MAX].len(), 1);
+    }
+
+    #[test]
+    fn early_submissions_are_authenticated() {
+        let mut child = new_agg(0);
+        let mut parent = new_parent(0, &[&child]);
+
+        // A share whose signature doesn't cover its round isn't held
+        let mut forged = share(&mut child, 1);
+        if let SubmissionMessage::AggSubmission(ref mut m) = forged {
+            m.round = 2;
+        }
+        assert!(parent.accept_submission(forged).is_err());
+        assert!(parent
+            .pending_submissions
+            .get(&2)
+            .map_or(true, |p| p.is_empty()));
+
+        // A child's share is held once, however often it's sent
+        parent.accept_submission(share(&mut child, 1)).unwrap();
+        assert!(parent
+            .accept_submission(share(&mut child, 1))
+            .unwrap()
+            .is_none());
+        assert_eq!(parent.pending_submissions[&1].len(), 1);
+    }
+
+    #[test]
+    fn early_submissions_carry_forward() {
+        let mut child1 = new_agg(0);
+        let mut child2 = new_agg(0);
+        let mut parent = new_parent(0, &[&child1, &child2]);
+
+        parent.accept_submission(share(&mut child1, 0)).unwrap();
+        parent.accept_submission(share(&mut child2, 1)).unwrap();
+        assert_eq!(parent.child_data_collection.len(), 1);
+
+        // Once round 1 starts, the held share counts towards it
+        start_next_round(&mut parent, Instant::now());
+        assert_eq!(parent.round, 1);
+        assert!(parent.pending_submissions.is_empty());
+        assert_eq!(parent.child_data_collection.len(), 1);
+        assert_eq!(parent.child_data_collection[0].pk, child2.pk());
+        assert!(!parent.round_complete().unwrap());
+
+        parent.accept_submission(share(&mut child1, 1)).unwrap();
+        assert!(parent.round_complete().unwrap());
+    }
+}
@@ -0,0 +1,458 @@
+use crate::util::{AggregatorError, Result};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    path::Path,
+};
+
+use ed25519_dalek::PublicKey;
+use interface::AGGREGATOR_THREAD_NUMBER;
+use log::error;
+use serde::Deserialize;
+
+/// The shape of the aggregation tree, as read from a JSON topology file. Example:
+///
+/// ```json
+/// {
+///   "servers": ["http://10.0.0.1:8080", "http://10.0.0.2:8080"],
+///   "aggregators": [
+///     { "agg_number": 0, "url": "http://10.0.0.3:8785", "pk": "8a88...", "children": [1, 2] },
+///     { "agg_number": 1, "url": "http://10.0.0.4:8785", "pk": "3b6a...", "parent": 0,
+///       "users": 60 },
+///     { "agg_number": 2, "url": "http://10.0.0.5:8785", "pk": "d759...", "parent": 0,
+///       "users": 40 }
+///   ]
+/// }
+/// ```
+///
+/// Exactly one aggregator has no parent. That one is the root and forwards to the servers. Every
+/// other aggregator forwards to its parent. Leaves may say how many users submit to them, and the
+/// leaves that don't split the remaining users evenly among themselves. An aggregator only takes
+/// aggregates signed with the keys of its children.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Topology {
+    /// The URLs of the anytrust servers the root aggregator forwards to
+    servers: Vec<String>,
+    aggregators: Vec<TopologyEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopologyEntry {
+    /// The aggregator number given to `new --agg-number`
+    agg_number: u32,
+    /// The base URL this aggregator's service is reachable at
+    url: String,
+    /// The aggregator's signing key, hex-encoded. `get-pubkey` prints it
+    pk: String,
+    /// The aggregator number of this aggregator's parent. `None` for the root
+    #[serde(default)]
+    parent: Option<u32>,
+    /// The aggregator numbers of the aggregators that forward to this one
+    #[serde(default)]
+    children: Vec<u32>,
+    /// The number of users that submit to this aggregator every round. Only leaves take users
+    #[serde(default)]
+    users: Option<usize>,
+}
+
+/// Where a single aggregator sits in the aggregation tree
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TreePosition {
+    /// Whether this is the root aggregator, i.e., whether its aggregate goes to the servers
+    pub(crate) is_root: bool,
+    /// The URLs this aggregator sends its aggregate to. This is the parent aggregator, or the
+    /// anytrust servers if this is the root.
+    pub(crate) forward_urls: Vec<String>,
+    /// The number of child aggregates this aggregator waits for before finishing a round. If this
+    /// is 0, the aggregator takes user submissions instead.
+    pub(crate) num_children: usize,
+    /// The signing keys of this aggregator's children. Aggregates signed with any other key are
+    /// rejected
+    pub(crate) child_pks: Vec<PublicKey>,
+    /// The length of the longest path from this aggregator down to a leaf. Leaves have height 0.
+    pub(crate) height: u32,
+    /// The number of users that submit to this aggregator, if the topology says so
+    pub(crate) num_users: Option<usize>,
+    /// The number of users the topology assigns to leaves explicitly
+    pub(crate) assigned_users: usize,
+    /// The number of leaves that split the users the topology doesn't assign
+    pub(crate) num_leaves: usize,
+    /// The index of this aggregator among those leaves, ordered by aggregator number
+    pub(crate) leaf_index: usize,
+}
+
+impl TreePosition {
+    /// The position of an aggregator started without a topology file. The tree then has two
+    /// levels: a root at level 0 and `AGGREGATOR_THREAD_NUMBER` leaves below it that forward to
+    /// the first of `forward_urls`. With a single thread, the root takes user submissions itself.
+    /// A root with leaves must be given their keys in `child_pks`.
+    pub(crate) fn from_level(
+        level: u32,
+        agg_number: u32,
+        forward_urls: Vec<String>,
+        child_pks: Vec<PublicKey>,
+    ) -> Result<TreePosition> {
+        let num_children = if level == 0 && AGGREGATOR_THREAD_NUMBER > 1 {
+            AGGREGATOR_THREAD_NUMBER
+        } else {
+            0
+        };
+        if child_pks.len() != num_children {
+            error!(
+                "expected the keys of {} child aggregators, got {}",
+                num_children,
+                child_pks.len()
+            );
+            return Err(AggregatorError::InvalidParameter);
+        }
+        let forward_urls = if level == 0 {
+            forward_urls
+        } else {
+            forward_urls.into_iter().take(1).collect()
+        };
+
+        Ok(TreePosition {
+            is_root: level == 0,
+            forward_urls,
+            num_children,
+            child_pks,
+            height: if num_children > 0 { 1 } else { 0 },
+            num_users: None,
+            assigned_users: 0,
+            num_leaves: AGGREGATOR_THREAD_NUMBER,
+            // Leaves are numbered from 1
+            leaf_index: agg_number.saturating_sub(1) as usize,
+        })
+    }
+
+    /// The number of users this aggregator waits for before finishing a round, out of the
+    /// `total_users` in the DC net. Users the topology doesn't assign are split among the other
+    /// leaves as evenly as possible, with the first leaves taking one more user when the split
+    /// isn't even.
+    pub(crate) fn expected_users(&self, total_users: usize) -> usize {
+        if self.num_children > 0 {
+            return 0;
+        }
+        self.num_users.unwrap_or_else(|| {
+            let unassigned = total_users.saturating_sub(self.assigned_users);
+            let extra = if self.leaf_index < unassigned % self.num_leaves {
+                1
+            } else {
+                0
+            };
+            unassigned / self.num_leaves + extra
+        })
+    }
+}
+
+/// Parses a hex-encoded aggregator signing key, as printed by `get-pubkey`
+pub(crate) fn parse_agg_pk(s: &str) -> Result<PublicKey> {
+    hex::decode(s.trim())
+        .ok()
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| {
+            error!("{} is not a hex-encoded aggregator key", s);
+            AggregatorError::InvalidParameter
+        })
+}
+
+impl Topology {
+    /// Reads a topology file and checks that it describes a single tree
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Topology> {
+        let file = File::open(path)?;
+        let topology: Topology = serde_json::from_reader(file).map_err(|e| {
+            error!("could not parse topology file: {}", e);
+            AggregatorError::InvalidParameter
+        })?;
+        topology.validate()?;
+
+        Ok(topology)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| {
+            error!("invalid topology: {}", msg);
+            Err(AggregatorError::InvalidParameter)
+        };
+
+        let mut entries = BTreeMap::new();
+        let mut pks = BTreeSet::new();
+        for entry in self.aggregators.iter() {
+            if entries.insert(entry.agg_number, entry).is_some() {
+                return invalid(format!("aggregator {} is listed twice", entry.agg_number));
+            }
+            let pk = parse_agg_pk(&entry.pk)?;
+            if !pks.insert(pk.to_bytes()) {
+                return invalid(format!(
+                    "the key of aggregator {} is listed twice",
+                    entry.agg_number
+                ));
+            }
+        }
+        for url in self
+            .servers
+            .iter()
+            .chain(self.aggregators.iter().map(|e| &e.url))
+        {
+            if url.parse::<actix_web::http::Uri>().is_err() {
+                return invalid(format!("{} is not a valid URL", url));
+            }
+        }
+
+        let roots: Vec<u32> = self
+            .aggregators
+            .iter()
+            .filter(|e| e.parent.is_none())
+            .map(|e| e.agg_number)
+            .collect();
+        if roots.len() != 1 {
+            return invalid(format!("expected exactly one root, found {:?}", roots));
+        }
+        if self.servers.is_empty() {
+            return invalid("no servers to forward to".to_string());
+        }
+
+        // Parents and children have to agree with each other
+        for entry in self.aggregators.iter() {
+            if entry.users.is_some() && !entry.children.is_empty() {
+                return invalid(format!(
+                    "aggregator {} has children, so it takes no users",
+                    entry.agg_number
+                ));
+            }
+            if let Some(parent) = entry.parent {
+                match entries.get(&parent) {
+                    Some(p) if p.children.contains(&entry.agg_number) => (),
+                    Some(_) => {
+                        return invalid(format!(
+                            "aggregator {} is not listed as a child of its parent {}",
+                            entry.agg_number, parent
+                        ))
+                    }
+                    None => {
+                        return invalid(format!(
+                            "parent {} of aggregator {} is not in the topology",
+                            parent, entry.agg_number
+                        ))
+                    }
+                }
+            }
+            let unique_children: BTreeSet<&u32> = entry.children.iter().collect();
+            if unique_children.len() != entry.children.len() {
+                return invalid(format!(
+                    "aggregator {} lists a child twice",
+                    entry.agg_number
+                ));
+            }
+            for child in entry.children.iter() {
+                if entries.get(child).and_then(|c| c.parent) != Some(entry.agg_number) {
+                    return invalid(format!(
+                        "child {} of aggregator {} does not have it as its parent",
+                        child, entry.agg_number
+                    ));
+                }
+            }
+        }
+
+        // Every aggregator has to reach the root. Otherwise there's a cycle
+        for entry in self.aggregators.iter() {
+            let mut cur = entry;
+            let mut steps = 0;
+            while let Some(parent) = cur.parent {
+                steps += 1;
+                if steps > self.aggregators.len() {
+                    return invalid(format!("aggregator {} is in a cycle", entry.agg_number));
+                }
+                cur = entries[&parent];
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the position of the given aggregator in the tree
+    pub(crate) fn position(&self, agg_number: u32) -> Result<TreePosition> {
+        let entry = self.entry(agg_number).ok_or_else(|| {
+            error!("aggregator {} is not in the topology", agg_number);
+            AggregatorError::InvalidParameter
+        })?;
+
+        let forward_urls = match entry.parent {
+            Some(parent) => vec![self.entry(parent).unwrap().url.clone()],
+            None => self.servers.clone(),
+        };
+
+        let leaves = self.aggregators.iter().filter(|e| e.children.is_empty());
+        let assigned_users = leaves.clone().filter_map(|e| e.users).sum();
+        let mut split_leaves: Vec<u32> = leaves
+            .filter(|e| e.users.is_none())
+            .map(|e| e.agg_number)
+            .collect();
+        split_leaves.sort();
+
+        let child_pks = entry
+            .children
+            .iter()
+            .map(|&c| parse_agg_pk(&self.entry(c).unwrap().pk))
+            .collect::<Result<Vec<PublicKey>>>()?;
+
+        Ok(TreePosition {
+            is_root: entry.parent.is_none(),
+            forward_urls,
+            num_children: entry.children.len(),
+            child_pks,
+            height: self.height(entry),
+            num_users: entry.users,
+            assigned_users,
+            num_leaves: split_leaves.len(),
+            leaf_index: split_leaves
+                .iter()
+                .position(|&n| n == agg_number)
+                .unwrap_or(0),
+        })
+    }
+
+    fn entry(&self, agg_number: u32) -> Option<&TopologyEntry> {
+        self.aggregators.iter().find(|e| e.agg_number == agg_number)
+    }
+
+    fn height(&self, entry: &TopologyEntry) -> u32 {
+        entry
+            .children
+            .iter()
+            .map(|&c| self.height(self.entry(c).unwrap()) + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pk(i: u8) -> PublicKey {
+        PublicKey::from(&ed25519_dalek::SecretKey::from_bytes(&[i; 32]).unwrap())
+    }
+
+    /// Parses a topology in which "PK<i>" stands for the key `pk(i)`
+    fn parse(json: &str) -> Result<Topology> {
+        let json = (0..8).fold(json.to_string(), |json, i| {
+            json.replace(
+                &format!("\"PK{}\"", i),
+                &format!("\"{}\"", hex::encode(pk(i))),
+            )
+        });
+        let topology: Topology = serde_json::from_str(&json).unwrap();
+        topology.validate().map(|_| topology)
+    }
+
+    #[test]
+    fn three_level_topology() {
+        let topology = parse(
+            r#"{
+                "servers": ["http://s1:8080", "http://s2:8080"],
+                "aggregators": [
+                    { "agg_number": 0, "url": "http://a0:8785", "pk": "PK0", "children": [1, 2] },
+                    { "agg_number": 1, "url": "http://a1:8785", "pk": "PK1", "parent": 0, "children": [3, 4, 5] },
+                    { "agg_number": 2, "url": "http://a2:8785", "pk": "PK2", "parent": 0, "users": 4 },
+                    { "agg_number": 3, "url": "http://a3:8785", "pk": "PK3", "parent": 1 },
+                    { "agg_number": 4, "url": "http://a4:8785", "pk": "PK4", "parent": 1 },
+                    { "agg_number": 5, "url": "http://a5:8785", "pk": "PK5", "parent": 1 }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let root = topology.position(0).unwrap();
+        assert!(root.is_root);
+        assert_eq!(root.forward_urls, vec!["http://s1:8080", "http://s2:8080"]);
+        assert_eq!(root.num_children, 2);
+        assert_eq!(root.child_pks, vec![pk(1), pk(2)]);
+        assert_eq!(root.height, 2);
+
+        let mid = topology.position(1).unwrap();
+        assert!(!mid.is_root);
+        assert_eq!(mid.forward_urls, vec!["http://a0:8785"]);
+        assert_eq!(mid.num_children, 3);
+        assert_eq!(mid.height, 1);
+
+        let leaf = topology.position(4).unwrap();
+        assert_eq!(leaf.forward_urls, vec!["http://a1:8785"]);
+        assert_eq!(leaf.num_children, 0);
+        assert!(leaf.child_pks.is_empty());
+        assert_eq!(leaf.height, 0);
+
+        // Leaf 2 takes 4 users. The other leaves split the rest as evenly as they can
+        assert_eq!(root.expected_users(11), 0);
+        assert_eq!(topology.position(2).unwrap().expected_users(11), 4);
+        assert_eq!(topology.position(3).unwrap().expected_users(11), 3);
+        assert_eq!(leaf.expected_users(11), 2);
+        assert_eq!(topology.position(5).unwrap().expected_users(11), 2);
+
+        assert!(topology.position(6).is_err());
+    }
+
+    #[test]
+    fn inconsistent_topologies() {
+        // Two roots
+        assert!(parse(
+            r#"{
+                "servers": ["http://s1:8080"],
+                "aggregators": [
+                    { "agg_number": 0, "url": "http://a0:8785", "pk": "PK0" },
+                    { "agg_number": 1, "url": "http://a1:8785", "pk": "PK1" }
+                ]
+            }"#
+        )
+        .is_err());
+
+        // The parent doesn't list the child
+        assert!(parse(
+            r#"{
+                "servers": ["http://s1:8080"],
+                "aggregators": [
+                    { "agg_number": 0, "url": "http://a0:8785", "pk": "PK0" },
+                    { "agg_number": 1, "url": "http://a1:8785", "pk": "PK1", "parent": 0 }
+                ]
+            }"#
+        )
+        .is_err());
+
+        // Two aggregators with the same key
+        assert!(parse(
+            r#"{
+                "servers": ["http://s1:8080"],
+                "aggregators": [
+                    { "agg_number": 0, "url": "http://a0:8785", "pk": "PK0", "children": [1] },
+                    { "agg_number": 1, "url": "http://a1:8785", "pk": "PK0", "parent": 0 }
+                ]
+            }"#
+        )
+        .is_err());
+
+        // A key that isn't one
+        assert!(parse(
+            r#"{
+                "servers": ["http://s1:8080"],
+                "aggregators": [
+                    { "agg_number": 0, "url": "http://a0:8785", "pk": "00ff" }
+                ]
+            }"#
+        )
+        .is_err());
+
+        // A cycle next to a valid root
+        assert!(parse(
+            r#"{
+                "servers": ["http://s1:8080"],
+                "aggregators": [
+                    { "agg_number": 0, "url": "http://a0:8785", "pk": "PK0" },
+                    { "agg_number": 1, "url": "http://a1:8785", "pk": "PK1", "parent": 2, "children": [2] },
+                    { "agg_number": 2, "url": "http://a2:8785", "pk": "PK2", "parent": 1, "children": [1] }
+                ]
+            }"#
+        )
+        .is_err());
+    }
+}
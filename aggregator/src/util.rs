@@ -1,5 +1,5 @@
 use crate::agg_state::AggregatorState;
-use common::{cli_util, enclave::EnclaveError};
+use common::{attestation::AttestationError, cli_util, enclave::EnclaveError};
 use interface::UserSubmissionMessage;
 use log::info;
 use rayon::prelude::*;
@@ -21,6 +21,10 @@ pub enum AggregatorError {
     Ser(#[from] cli_util::SerializationError),
     #[error("invalid parameter")]
     InvalidParameter,
+    #[error("attestation did not verify")]
+    Attestation(#[from] AttestationError),
+    #[error("a worker thread has stopped")]
+    WorkerStopped,
 }
 
 pub(crate) fn load_state(save_path: &str) -> Result<AggregatorState> {
@@ -42,6 +46,11 @@ pub(crate) fn load_from_stdin<D: for<'a> Deserialize<'a>>() -> Result<D> {
     Ok(cli_util::load(stdin)?)
 }
 
+pub(crate) fn load_multi_from_stdin<D: for<'a> Deserialize<'a>>() -> Result<Vec<D>> {
+    let stdin = std::io::stdin();
+    Ok(cli_util::load_multi(stdin)?)
+}
+
 pub(crate) fn save_to_stdout<S: Serialize>(val: &S) -> Result<()> {
     let stdout = std::io::stdout();
     cli_util::save(stdout, val)?;
@@ -0,0 +1,324 @@
+use crate::agg::{check_nonce, check_user_key, record_nonce, verify_user_sigs_batched};
+use crate::util::{AggregatorError, Result};
+use common::types::AggregatedMessage;
+use interface::{EntityId, RateLimitNonce, UserRegistrationBlob, UserSubmissionMessage, Xor};
+
+use futures::channel::oneshot;
+use log::{debug, error};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+/// A worker checks the signatures of up to this many queued submissions at once
+pub(crate) const MAX_BATCH_SIZE: usize = 64;
+
+/// The submissions a worker thread verified and XORed together in a round. The aggregate is not
+/// signed.
+pub(crate) struct WorkerPartial {
+    pub(crate) agg: AggregatedMessage,
+    /// The rate-limit nonces of the users in `agg`
+    pub(crate) nonces: Vec<RateLimitNonce>,
+}
+
+/// What the workers agree on before a submission goes into a partial aggregate. Every user is
+/// admitted at most once per round, and every nonce at most once per window, no matter which
+/// worker verified it.
+struct Admission {
+    round: u32,
+    user_ids: BTreeSet<EntityId>,
+    /// The rate-limit nonces seen so far this window. `None` if nonces aren't tracked
+    observed_nonces: Option<BTreeSet<RateLimitNonce>>,
+}
+
+enum Job {
+    /// Verify a submission and XOR it into the worker's partial aggregate. The result is sent
+    /// back once the submission is in, or rejected.
+    Submit(UserSubmissionMessage, oneshot::Sender<Result<()>>),
+    /// Hand over the partial aggregate and start a new one
+    Drain(mpsc::Sender<Option<WorkerPartial>>),
+}
+
+/// A pool of threads that verify user submissions and aggregate them. Submissions are sharded
+/// across the threads by user ID. Each thread keeps its own partial aggregate, and the partials
+/// are merged when the round ends.
+pub(crate) struct WorkerPool {
+    senders: Vec<mpsc::Sender<Job>>,
+    admission: Arc<Mutex<Admission>>,
+}
+
+impl WorkerPool {
+    /// Spawns `num_workers` threads that accept submissions from the given registered users
+    pub(crate) fn new(
+        num_workers: usize,
+        registered_users: BTreeMap<EntityId, UserRegistrationBlob>,
+    ) -> Result<WorkerPool> {
+        if num_workers == 0 {
+            error!("need at least one worker thread");
+            return Err(AggregatorError::InvalidParameter);
+        }
+
+        let registered_users = Arc::new(registered_users);
+        let admission = Arc::new(Mutex::new(Admission {
+            round: 0,
+            user_ids: BTreeSet::new(),
+            observed_nonces: None,
+        }));
+
+        let mut senders = Vec::with_capacity(num_workers);
+        for i in 0..num_workers {
+            let (sender, receiver) = mpsc::channel();
+            let registered_users = registered_users.clone();
+            let admission = admission.clone();
+            thread::Builder::new()
+                .name(format!("agg-worker-{}", i))
+                .spawn(move || run_worker(receiver, registered_users, admission))?;
+            senders.push(sender);
+        }
+
+        Ok(WorkerPool { senders, admission })
+    }
+
+    /// Starts accepting submissions for the given round. `observed_nonces` are the rate-limit
+    /// nonces already seen this window, if nonces are tracked. Partials must be drained first.
+    pub(crate) fn start_round(
+        &self,
+        round: u32,
+        observed_nonces: Option<BTreeSet<RateLimitNonce>>,
+    ) {
+        let mut admission = self.admission.lock().unwrap();
+        admission.round = round;
+        admission.user_ids.clear();
+        admission.observed_nonces = observed_nonces;
+    }
+
+    /// The number of users admitted into the workers' partials this round
+    pub(crate) fn num_users(&self) -> usize {
+        self.admission.lock().unwrap().user_ids.len()
+    }
+
+    /// Hands the given submission to the worker responsible for its user. The returned receiver
+    /// resolves once the submission is aggregated or rejected.
+    pub(crate) fn submit(
+        &self,
+        msg: UserSubmissionMessage,
+    ) -> Result<oneshot::Receiver<Result<()>>> {
+        let shard = u64::from_le_bytes(msg.user_id.0[..8].try_into().unwrap());
+        let worker = (shard % self.senders.len() as u64) as usize;
+
+        let (sender, receiver) = oneshot::channel();
+        self.senders[worker]
+            .send(Job::Submit(msg, sender))
+            .map_err(|_| AggregatorError::WorkerStopped)?;
+        Ok(receiver)
+    }
+
+    /// Collects the partial aggregates of every worker. Every submission handed to a worker
+    /// before this call is either in a partial or rejected.
+    pub(crate) fn drain(&self) -> Result<Vec<WorkerPartial>> {
+        let (sender, receiver) = mpsc::channel();
+        for worker in self.senders.iter() {
+            worker
+                .send(Job::Drain(sender.clone()))
+                .map_err(|_| AggregatorError::WorkerStopped)?;
+        }
+        drop(sender);
+
+        // Workers with nothing aggregated reply with None
+        let mut partials = Vec::new();
+        for _ in 0..self.senders.len() {
+            let partial = receiver
+                .recv()
+                .map_err(|_| AggregatorError::WorkerStopped)?;
+            partials.extend(partial);
+        }
+        Ok(partials)
+    }
+}
+
+fn run_worker(
+    jobs: mpsc::Receiver<Job>,
+    registered_users: Arc<BTreeMap<EntityId, UserRegistrationBlob>>,
+    admission: Arc<Mutex<Admission>>,
+) {
+    let mut partial = WorkerPartial {
+        agg: AggregatedMessage::default(),
+        nonces: Vec::new(),
+    };
+
+    // The pool hangs up when it's dropped
+    let mut next = jobs.recv().ok();
+    while let Some(job) = next.take() {
+        match job {
+            Job::Submit(msg, result) => {
+                // Take whatever else is queued, up to a drain, and verify it all as one batch
+                let mut batch = vec![(msg, result)];
+                while batch.len() < MAX_BATCH_SIZE {
+                    match jobs.try_recv() {
+                        Ok(Job::Submit(msg, result)) => batch.push((msg, result)),
+                        Ok(drain) => {
+                            next = Some(drain);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                add_batch_to_partial(&mut partial, &registered_users, &admission, batch);
+            }
+            Job::Drain(reply) => {
+                let drained = std::mem::replace(
+                    &mut partial,
+                    WorkerPartial {
+                        agg: AggregatedMessage::default(),
+                        nonces: Vec::new(),
+                    },
+                );
+                let drained = if drained.agg.is_empty() {
+                    None
+                } else {
+                    Some(drained)
+                };
+                let _ = reply.send(drained);
+            }
+        }
+
+        if next.is_none() {
+            next = jobs.recv().ok();
+        }
+    }
+
+    debug!("worker thread stopping");
+}
+
+/// Verifies the given submissions and adds the good ones to the partial aggregate. Each
+/// submitter is told whether its submission went in.
+fn add_batch_to_partial(
+    partial: &mut WorkerPartial,
+    registered_users: &BTreeMap<EntityId, UserRegistrationBlob>,
+    admission: &Mutex<Admission>,
+    batch: Vec<(UserSubmissionMessage, oneshot::Sender<Result<()>>)>,
+) {
+    // Signatures are the expensive part, so they're checked before taking the lock
+    let msgs: Vec<&UserSubmissionMessage> = batch.iter().map(|(msg, _)| msg).collect();
+    let valid_sigs = verify_user_sigs_batched(&msgs);
+
+    for ((msg, result), valid_sig) in batch.into_iter().zip(valid_sigs) {
+        let res = if valid_sig {
+            add_to_partial(partial, registered_users, admission, &msg)
+        } else {
+            Err(AggregatorError::InvalidParameter)
+        };
+        if let Err(ref e) = res {
+            error!("rejecting submission from user {}: {}", msg.user_id, e);
+        }
+        // The submitter may have stopped waiting. That's fine
+        let _ = result.send(res);
+    }
+}
+
+/// Adds a submission whose signature checked out to the partial aggregate
+fn add_to_partial(
+    partial: &mut WorkerPartial,
+    registered_users: &BTreeMap<EntityId, UserRegistrationBlob>,
+    admission: &Mutex<Admission>,
+    msg: &UserSubmissionMessage,
+) -> Result<()> {
+    check_user_key(registered_users, msg)?;
+
+    {
+        let mut admission = admission.lock().unwrap();
+        if msg.round != admission.round {
+            error!(
+                "submission is for round {}, but the current round is {}",
+                msg.round, admission.round
+            );
+            return Err(AggregatorError::InvalidParameter);
+        }
+        if admission.user_ids.contains(&msg.user_id) {
+            error!("user {} already submitted this round", msg.user_id);
+            return Err(AggregatorError::InvalidParameter);
+        }
+        check_nonce(&admission.observed_nonces, &msg.rate_limit_nonce)?;
+
+        record_nonce(&mut admission.observed_nonces, &msg.rate_limit_nonce);
+        admission.user_ids.insert(msg.user_id);
+    }
+
+    if let Some(ref nonce) = msg.rate_limit_nonce {
+        partial.nonces.push(nonce.clone());
+    }
+    let agg = &mut partial.agg;
+    if agg.is_empty() {
+        agg.round = msg.round;
+        agg.anytrust_group_id = msg.anytrust_group_id;
+        agg.rate_limit_nonce = msg.rate_limit_nonce.clone();
+        agg.aggregated_msg.clone_from(&msg.aggregated_msg);
+    } else {
+        agg.aggregated_msg.xor_mut(&msg.aggregated_msg);
+    }
+    agg.user_ids.insert(msg.user_id);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agg::tests::make_submissions;
+
+    #[test]
+    fn partials_merge_to_sequential_aggregate() {
+        let (registered_users, mut submissions) = make_submissions(11);
+        let pool = WorkerPool::new(3, registered_users).unwrap();
+        pool.start_round(0, Some(BTreeSet::new()));
+
+        // A forged submission queued with good ones fails its batch, but only it is rejected
+        let mut forged = submissions.pop().unwrap();
+        forged.aggregated_msg.aggregated_msg.as_mut_slice()[0] ^= 1;
+        let forged = pool.submit(forged).unwrap();
+        let receivers: Vec<_> = submissions
+            .iter()
+            .map(|msg| pool.submit(msg.clone()).unwrap())
+            .collect();
+        assert!(futures::executor::block_on(forged).unwrap().is_err());
+        for receiver in receivers {
+            futures::executor::block_on(receiver).unwrap().unwrap();
+        }
+        assert_eq!(pool.num_users(), 10);
+
+        // Users are admitted once per round, whichever worker gets them
+        let repeat = pool.submit(submissions[4].clone()).unwrap();
+        assert!(futures::executor::block_on(repeat).unwrap().is_err());
+
+        let partials = pool.drain().unwrap();
+        assert!(!partials.is_empty() && partials.len() <= 3);
+        let mut user_ids = BTreeSet::new();
+        let mut num_nonces = 0;
+        let mut merged = submissions[0].aggregated_msg.clone();
+        for partial in partials.iter() {
+            assert!(user_ids.is_disjoint(&partial.agg.user_ids));
+            user_ids.extend(partial.agg.user_ids.iter().cloned());
+            num_nonces += partial.nonces.len();
+            merged.xor_mut(&partial.agg.aggregated_msg);
+        }
+        assert_eq!(user_ids.len(), 10);
+        assert_eq!(num_nonces, 10);
+
+        // XORing every submission into the merged partials cancels everything but the first
+        for msg in submissions.iter() {
+            merged.xor_mut(&msg.aggregated_msg);
+        }
+        assert_eq!(
+            merged.aggregated_msg.as_slice(),
+            submissions[0].aggregated_msg.aggregated_msg.as_slice()
+        );
+
+        // Submissions for a round that hasn't started are rejected, and draining starts afresh
+        pool.start_round(1, Some(BTreeSet::new()));
+        let stale = pool.submit(submissions[0].clone()).unwrap();
+        assert!(futures::executor::block_on(stale).unwrap().is_err());
+        assert!(pool.drain().unwrap().is_empty());
+    }
+}
@@ -11,28 +11,22 @@ use crate::{
     util::{base64_from_stdin, load_state, save_state, save_to_stdout, UserError},
 };
 
-use common::{cli_util, enclave::DcNetEnclave};
-use interface::{
-    DcMessage, RoundOutput, ServerPubKeyPackage, UserMsg, DC_NET_MESSAGE_LENGTH, PARAMETER_FLAG,
+use common::{
+    cli_util,
+    enclave::{DcNetEnclave, EnclaveBackend},
+    freshness::{set_counter_witness, HttpCounterWitness},
 };
-use std::{env, ffi::OsString, fs::File, path::Path};
+use interface::{DcMessage, DcRoundMessage, RoundOutput, ServerPubKeyPackage, UserMsg};
+use std::{ffi::OsString, fs::File, path::Path};
 
 use clap::{App, AppSettings, Arg, SubCommand};
+use log::error;
 
 fn main() -> Result<(), UserError> {
     // Do setup
     env_logger::init();
     let enclave = DcNetEnclave::init("/sgxdcnet/lib/enclave.signed.so")?;
 
-    let dc_net_message_length = if PARAMETER_FLAG {
-        env::var("DC_NET_MESSAGE_LENGTH")
-            .unwrap_or_else(|_| "160".to_string())
-            .parse::<usize>()
-            .expect("Invalid DC_NET_MESSAGE_LENGTH value")
-    } else {
-        DC_NET_MESSAGE_LENGTH
-    };
-
     let state_arg = Arg::with_name("user-state")
         .short("s")
         .long("user-state")
@@ -57,6 +51,17 @@ fn main() -> Result<(), UserError> {
         .takes_value(true)
         .help("The current round number of the DC net");
 
+    let server_urls_arg = Arg::with_name("server-urls")
+        .short("u")
+        .long("server-urls")
+        .value_name("URLS")
+        .required(true)
+        .takes_value(true)
+        .help(
+            "A comma-separated list of the URLs of every anytrust server. They witness the user's \
+            freshness counter. Example: \"http://192.168.0.10:9000,http://192.168.0.11:9000\"",
+        );
+
     let matches = App::new("SGX DCNet Client")
         .version("0.1.0")
         .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -103,25 +108,28 @@ fn main() -> Result<(), UserError> {
                 .about("Reserves a message slot for the next round")
                 .arg(state_arg.clone())
                 .arg(round_arg.clone())
+                .arg(server_urls_arg.clone())
         )
         .subcommand(
             SubCommand::with_name("send-empty")
                 .about(
                     "Constructs an empty message as cover traffic for the system. STDOUT is \
-                    newline-separated encrypted messages"
+                    newline-separated encrypted messages of the users that succeeded. Exits \
+                    with an error if any user failed"
                 )
                 .arg(states_arg.clone())
                 .arg(round_arg.clone())
+                .arg(server_urls_arg.clone())
         )
         .subcommand(
             SubCommand::with_name("encrypt-msg")
-                .about(format!(
+                .about(
                     "Encrypts a round message to the DC net. STDIN is a base64-encoded bytestring \
-                    of length at most {}",
-                    dc_net_message_length
-                ).as_str())
+                    no longer than the message length of the user's anytrust group"
+                )
                 .arg(state_arg.clone())
                 .arg(round_arg.clone())
+                .arg(server_urls_arg.clone())
                 .arg(
                     Arg::with_name("prev-round-output")
                     .short("p")
@@ -136,6 +144,7 @@ fn main() -> Result<(), UserError> {
                 .about("Starts a web service at BIND_ADDR")
                 .arg(state_arg.clone())
                 .arg(round_arg.clone())
+                .arg(server_urls_arg.clone())
                 .arg(
                     Arg::with_name("bind")
                         .short("b")
@@ -166,6 +175,15 @@ fn main() -> Result<(), UserError> {
         )
         .get_matches();
 
+    // Every submission has the servers witness the user's freshness counter
+    if let Some(urls) = matches
+        .subcommand()
+        .1
+        .and_then(|m| m.value_of("server-urls"))
+    {
+        set_counter_witness(Box::new(HttpCounterWitness::new(urls)));
+    }
+
     if let Some(matches) = matches.subcommand_matches("new") {
         // Load up the KEM keys
         let pubkeys_filename = matches.value_of("server-keys").unwrap();
@@ -225,29 +243,55 @@ fn main() -> Result<(), UserError> {
         let round = cli_util::parse_u32(matches.value_of("round").unwrap())?;
 
         // Expect a comma-separated list of state files
-        let state_paths = matches.value_of("user-state").unwrap().to_string();
-        for state_path in state_paths.split(',') {
-            // Now encrypt the message and output it
-            let mut state = load_state(&state_path)?;
-            let ciphertext = state.submit_round_msg(&enclave, round, msg.clone())?;
-            save_to_stdout(&ciphertext)?;
-
-            // The shared secrets were ratcheted, so we have to save the new state
-            save_state(&state_path, &state)?;
+        let state_paths: Vec<&str> = matches.value_of("user-state").unwrap().split(',').collect();
+        let mut states = state_paths
+            .iter()
+            .map(|state_path| load_state(state_path))
+            .collect::<Result<Vec<UserState>, UserError>>()?;
+
+        // Encrypt all the messages in one go
+        let msgs = vec![msg; states.len()];
+        let results = UserState::submit_round_msg_batch(&enclave, &mut states, round, msgs)?;
+
+        // Output the ciphertexts. A failed user's shared secrets weren't ratcheted, so only the
+        // successful users' states have to be saved
+        let mut num_failed = 0;
+        for ((state_path, state), res) in state_paths.iter().zip(states.iter()).zip(results) {
+            match res {
+                Ok(ciphertext) => {
+                    save_to_stdout(&ciphertext)?;
+                    save_state(state_path, state)?;
+                }
+                Err(e) => {
+                    error!("can't send cover traffic for {}: {:?}", state_path, e);
+                    num_failed += 1;
+                }
+            }
+        }
+
+        // The successful users' ciphertexts are still usable, but the caller has to know that
+        // some are missing
+        if num_failed > 0 {
+            return Err(UserError::CoverTrafficFailed(num_failed, states.len()));
         }
     }
 
     if let Some(matches) = matches.subcommand_matches("encrypt-msg") {
+        // Get the state
+        let state_path = matches.value_of("user-state").unwrap().to_string();
+        let mut state = load_state(&state_path)?;
+        let dc_net_params = *state.get_dc_net_params();
+
         // Load the message
         let msg = base64_from_stdin()?;
         assert!(
-            msg.len() <= dc_net_message_length,
+            msg.len() <= dc_net_params.message_length,
             "input message must be less than {} bytes long",
-            dc_net_message_length
+            dc_net_params.message_length
         );
 
         // Pad out the message and put it in the correct wrapper
-        let mut dc_msg = DcMessage::default();
+        let mut dc_msg = DcMessage::new(&dc_net_params);
         dc_msg.0[..msg.len()].copy_from_slice(&msg);
 
         // Load the round
@@ -260,18 +304,16 @@ fn main() -> Result<(), UserError> {
             let round_file = File::open(round_output_filename)?;
             cli_util::load(round_file)?
         } else {
-            RoundOutput::default()
+            RoundOutput {
+                dc_msg: DcRoundMessage::new(&dc_net_params),
+                ..Default::default()
+            }
         };
 
-        // Get the state
-        let state_path = matches.value_of("user-state").unwrap().to_string();
-        let mut state = load_state(&state_path)?;
-
         // Make the message for this round
         let msg = UserMsg::TalkAndReserve {
             msg: dc_msg,
             prev_round_output,
-            times_participated: state.get_times_participated(),
         };
 
         // Now encrypt the message and output it
@@ -289,9 +331,7 @@ fn main() -> Result<(), UserError> {
         // Get the state and make the reservation message
         let state_path = matches.value_of("user-state").unwrap().to_string();
         let mut state = load_state(&state_path)?;
-        let msg = UserMsg::Reserve {
-            times_participated: state.get_times_participated(),
-        };
+        let msg = UserMsg::Reserve;
 
         // Compute the reservation
         let ciphertext = state.submit_round_msg(&enclave, round, msg)?;
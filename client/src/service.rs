@@ -3,13 +3,10 @@ use crate::{
     UserState,
 };
 use common::{cli_util, enclave::DcNetEnclave, log_time::log_duration};
-use interface::{
-    DcMessage, RoundOutput, UserMsg, UserSubmissionBlob, DC_NET_MESSAGE_LENGTH, PARAMETER_FLAG,
-};
+use interface::{DcMessage, DcRoundMessage, RoundOutput, UserMsg, UserSubmissionBlob};
 
 use core::ops::DerefMut;
 use std::{
-    env,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
@@ -68,7 +65,9 @@ async fn encrypt_msg(
     let mut payload_it = payload.split(',');
     *round = 0;
 
-    // Load the message first. It's just a base64 string of length <= DC_NET_MESSAGE_LENGTH
+    let dc_net_params = *user_state.get_dc_net_params();
+
+    // Load the message first. It's just a base64 string of length <= message_length
     let dc_msg: DcMessage = {
         // Decode the message from the first comma-separated component of the input
         let msg_bytes = base64::decode(
@@ -80,27 +79,18 @@ async fn encrypt_msg(
         )
         .map_err(cli_util::SerializationError::from)?;
 
-        let dc_net_message_length = if PARAMETER_FLAG {
-            env::var("DC_NET_MESSAGE_LENGTH")
-                .unwrap_or_else(|_| "160".to_string())
-                .parse::<usize>()
-                .expect("Invalid DC_NET_MESSAGE_LENGTH value")
-        } else {
-            DC_NET_MESSAGE_LENGTH
-        };
-
         // Check the length
-        if msg_bytes.len() > dc_net_message_length {
+        if msg_bytes.len() > dc_net_params.message_length {
             return Err(ApiError::Malformed(format!(
                 "input message must be less than {} bytes long",
-                dc_net_message_length
+                dc_net_params.message_length
             )));
         }
 
         debug!("msg_bytes: {:?}", msg_bytes);
 
         // Copy into a DC net buffer
-        let mut buf = DcMessage::default();
+        let mut buf = DcMessage::new(&dc_net_params);
         buf.0[..msg_bytes.len()].copy_from_slice(&msg_bytes);
         buf
     };
@@ -108,13 +98,15 @@ async fn encrypt_msg(
     let encoded_round_output = payload_it.next();
     let prev_round_output: RoundOutput = match encoded_round_output {
         Some(s) => cli_util::load(s.trim().as_bytes())?,
-        None => RoundOutput::default(),
+        None => RoundOutput {
+            dc_msg: DcRoundMessage::new(&dc_net_params),
+            ..Default::default()
+        },
     };
 
     let msg = UserMsg::TalkAndReserve {
         msg: dc_msg,
         prev_round_output,
-        times_participated: user_state.get_times_participated(),
     };
 
     debug!("msg before submit: {:?}", msg);
@@ -168,9 +160,7 @@ async fn reserve_slot(
     } = handle.deref_mut();
 
     // Encrypt a reservation and send it
-    let msg = UserMsg::Reserve {
-        times_participated: user_state.get_times_participated(),
-    };
+    let msg = UserMsg::Reserve;
     let ciphertext = user_state.submit_round_msg(&enclave, *round, msg)?;
     send_ciphertext(&ciphertext, agg_url).await;
 
@@ -1,12 +1,12 @@
-use crate::util::Result;
+use crate::util::{Result, UserError};
 
-use common::enclave::DcNetEnclave;
+use common::enclave::{DcNetEnclave, EnclaveBackend};
 use serde::{Deserialize, Serialize};
 
 use interface::{
-    compute_anytrust_group_id, EntityId, SealedSharedSecretsDbClient, SealedSigPrivKey,
-    ServerPubKeyPackage, SgxProtectedKeyPub, UserMsg, UserRegistrationBlob, UserSubmissionBlob,
-    UserSubmissionReq, DC_NET_ROUNDS_PER_WINDOW,
+    agreed_dc_net_params, compute_anytrust_group_id, DcNetParams, EntityId,
+    SealedSharedSecretsDbClient, SealedUserPrivKeys, ServerPubKeyPackage, SgxProtectedKeyPub,
+    UserMsg, UserRegistrationBlob, UserSubmissionBlob, UserSubmissionReq,
 };
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -15,15 +15,16 @@ pub struct UserState {
     user_id: EntityId,
     /// A unique for the set anytrust servers that this client is registered with
     anytrust_group_id: EntityId,
-    /// This client's signing key. Can only be accessed from within the enclave.
-    signing_key: SealedSigPrivKey,
+    /// The DC net parameters of the anytrust group
+    dc_net_params: DcNetParams,
+    /// This client's signing, KEM and scheduling keys. Can only be accessed from within the
+    /// enclave.
+    private_keys: SealedUserPrivKeys,
     /// The secrets that this client shares with the anytrust servers. Maps entity ID to shared
     /// secret. Can only be accessed from within the enclave.
     shared_secrets: SealedSharedSecretsDbClient,
     /// The anytrust servers' KEM and signing pubkeys
     anytrust_group_keys: Vec<ServerPubKeyPackage>,
-    /// Times talked or reserved so far in this window
-    times_participated: u32,
 }
 
 impl UserState {
@@ -33,25 +34,26 @@ impl UserState {
         n: usize,
         pubkeys: Vec<ServerPubKeyPackage>,
     ) -> Result<Vec<(UserState, UserRegistrationBlob)>> {
+        let dc_net_params = agreed_dc_net_params(&pubkeys).ok_or(UserError::InvalidParams)?;
         let vec = enclave.new_user_batch(&pubkeys, n)?;
 
         let users_and_reg_blobs = vec
             .into_iter()
-            .map(|(sealed_shared_secrets, sealed_usk, reg_blob)| {
+            .map(|(sealed_shared_secrets, sealed_keys, reg_blob)| {
                 let user_id = EntityId::from(&reg_blob);
                 let kem_pubkeys: Vec<SgxProtectedKeyPub> = pubkeys
                     .iter()
                     .map(|p| SgxProtectedKeyPub(p.kem.to_bytes()))
                     .collect();
-                let anytrust_group_id = compute_anytrust_group_id(&kem_pubkeys);
+                let anytrust_group_id = compute_anytrust_group_id(&kem_pubkeys, &dc_net_params);
 
                 let state = UserState {
                     user_id,
                     anytrust_group_id,
-                    signing_key: sealed_usk.to_owned(),
+                    dc_net_params,
+                    private_keys: sealed_keys.to_owned(),
                     shared_secrets: sealed_shared_secrets.to_owned(),
                     anytrust_group_keys: pubkeys.clone(),
-                    times_participated: 0,
                 };
                 (state, reg_blob)
             })
@@ -60,8 +62,8 @@ impl UserState {
         Ok(users_and_reg_blobs)
     }
 
-    pub fn get_times_participated(&self) -> u32 {
-        self.times_participated
+    pub fn get_dc_net_params(&self) -> &DcNetParams {
+        &self.dc_net_params
     }
 
     pub fn submit_round_msg(
@@ -70,32 +72,62 @@ impl UserState {
         round: u32,
         msg: UserMsg,
     ) -> Result<UserSubmissionBlob> {
-        let msg_is_cover = msg.is_cover();
-        let req = UserSubmissionReq {
+        let req = self.submission_req(round, msg);
+
+        // Submit the message
+        let (blob, ratcheted_secrets) = enclave.user_submit_round_msg(&req, &self.private_keys)?;
+
+        // Ratchet the secrets forward. They also carry the enclave's count of how many times this
+        // user participated this window
+        self.shared_secrets = ratcheted_secrets;
+
+        Ok(blob)
+    }
+
+    /// Submits a message for each of the given users in a single ecall. Returns one result per
+    /// user, in order. A user whose submission fails keeps its shared secrets as they were, and
+    /// doesn't affect the other users.
+    pub fn submit_round_msg_batch(
+        enclave: &DcNetEnclave,
+        states: &mut [UserState],
+        round: u32,
+        msgs: Vec<UserMsg>,
+    ) -> Result<Vec<Result<UserSubmissionBlob>>> {
+        assert_eq!(
+            states.len(),
+            msgs.len(),
+            "need exactly one message per user"
+        );
+
+        let reqs: Vec<(UserSubmissionReq, SealedUserPrivKeys)> = states
+            .iter()
+            .zip(msgs)
+            .map(|(state, msg)| (state.submission_req(round, msg), state.private_keys.clone()))
+            .collect();
+
+        let results = enclave.user_submit_round_msg_batch(&reqs)?;
+
+        Ok(states
+            .iter_mut()
+            .zip(results)
+            .map(|(state, res)| {
+                let (blob, ratcheted_secrets) = res?;
+                state.shared_secrets = ratcheted_secrets;
+                Ok(blob)
+            })
+            .collect())
+    }
+
+    /// Makes the enclave request for submitting `msg` in the given round
+    fn submission_req(&self, round: u32, msg: UserMsg) -> UserSubmissionReq {
+        UserSubmissionReq {
             user_id: self.user_id,
             anytrust_group_id: self.anytrust_group_id,
             round,
+            dc_net_params: self.dc_net_params,
             msg,
             shared_secrets: self.shared_secrets.clone(),
             server_pks: self.anytrust_group_keys.clone(),
-        };
-
-        // If this round is a new window, clear the number of times participated
-        if round % DC_NET_ROUNDS_PER_WINDOW == 0 {
-            self.times_participated = 0;
         }
-
-        // Submit the message
-        let (blob, ratcheted_secrets) = enclave.user_submit_round_msg(&req, &self.signing_key)?;
-
-        // Ratchet the secrets forward
-        self.shared_secrets = ratcheted_secrets;
-
-        // If this message was participatory, increment times_participated
-        if !msg_is_cover {
-            self.times_participated += 1;
-        }
-
-        Ok(blob)
     }
 }
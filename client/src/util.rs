@@ -20,6 +20,10 @@ pub enum UserError {
     Io(#[from] std::io::Error),
     #[error("error in serialization/deserialization")]
     Ser(#[from] cli_util::SerializationError),
+    #[error("servers do not agree on valid DC net parameters")]
+    InvalidParams,
+    #[error("{0} of {1} users could not send cover traffic")]
+    CoverTrafficFailed(usize, usize),
 }
 
 pub(crate) fn load_state(save_path: &str) -> Result<UserState> {
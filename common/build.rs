@@ -18,6 +18,13 @@
 use std::env;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::compile_protos("src/dc_proto.proto")?;
+
+    // the software enclave needs neither the enclave wrapper nor the SGX runtime
+    if env::var("CARGO_FEATURE_SGX").is_err() {
+        return Ok(());
+    }
+
     let sdk_dir = env::var("SGX_SDK").unwrap_or_else(|_| "/opt/sgxsdk".to_string());
     let is_sim = env::var("SGX_MODE").unwrap_or_else(|_| "SW".to_string());
 
@@ -26,11 +33,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("cargo:rustc-link-search=native={}/lib64", sdk_dir);
     match is_sim.as_ref() {
-        "SW" => println!("cargo:rustc-link-lib=dylib=sgx_urts_sim"),
-        "HW" => println!("cargo:rustc-link-lib=dylib=sgx_urts"),
-        _ => println!("cargo:rustc-link-lib=dylib=sgx_urts"), // Treat undefined as HW
+        "SW" => {
+            println!("cargo:rustc-link-lib=dylib=sgx_urts_sim");
+            println!("cargo:rustc-link-lib=dylib=sgx_uae_service_sim");
+        }
+        _ => {
+            // Treat undefined as HW
+            println!("cargo:rustc-link-lib=dylib=sgx_urts");
+            println!("cargo:rustc-link-lib=dylib=sgx_uae_service");
+        }
     }
 
-    tonic_build::compile_protos("src/dc_proto.proto")?;
     Ok(())
 }
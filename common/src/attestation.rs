@@ -0,0 +1,262 @@
+#[cfg(any(test, feature = "mock-attestation"))]
+use interface::verify_mock_quote;
+use interface::{AttestedPublicKey, QuoteBody};
+use quick_error::quick_error;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum AttestationError {
+        BadQuote {
+            display("the quote is malformed or its signature does not verify")
+        }
+        KeyNotBound {
+            display("the quote does not bind the attested key")
+        }
+        WrongRole(role: String) {
+            display("the key is attested for role {}", role)
+        }
+        NotAllowed(mr_enclave: String, mr_signer: String) {
+            display("enclave {} signed by {} is not on the allow-list", mr_enclave, mr_signer)
+        }
+        NoVerifier {
+            display("no quote verifier is set")
+        }
+        EmptyAllowList {
+            display("no enclaves are allow-listed")
+        }
+        Ias(msg: String) {
+            display("attestation service error: {}", msg)
+        }
+        QuoteStatus(status: String) {
+            display("the attestation service reports the quote as {}", status)
+        }
+    }
+}
+
+/// Checks that a quote was produced by a genuine quoting enclave and returns what it attests to.
+/// A verifier for real SGX quotes (e.g., one that goes through IAS or DCAP) plugs in here.
+pub trait QuoteVerifier {
+    fn verify_quote(&self, quote: &[u8]) -> Result<QuoteBody, AttestationError>;
+}
+
+/// Verifies the mock quotes made by the software enclave. These are MACed with a public key, so
+/// this verifier must only be used for testing and simulation. Only builds with the
+/// `mock-attestation` feature have it.
+#[cfg(any(test, feature = "mock-attestation"))]
+#[derive(Clone, Debug, Default)]
+pub struct MockQuoteVerifier;
+
+#[cfg(any(test, feature = "mock-attestation"))]
+impl QuoteVerifier for MockQuoteVerifier {
+    fn verify_quote(&self, quote: &[u8]) -> Result<QuoteBody, AttestationError> {
+        verify_mock_quote(quote).ok_or(AttestationError::BadQuote)
+    }
+}
+
+/// The enclaves whose attestations are accepted. An enclave is accepted if either its MRENCLAVE
+/// or its MRSIGNER is listed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnclaveAllowList {
+    pub mr_enclaves: Vec<[u8; 32]>,
+    pub mr_signers: Vec<[u8; 32]>,
+}
+
+impl EnclaveAllowList {
+    /// Nothing is allowed by an empty allow-list, so no user can register
+    pub fn is_empty(&self) -> bool {
+        self.mr_enclaves.is_empty() && self.mr_signers.is_empty()
+    }
+
+    pub fn allows(&self, body: &QuoteBody) -> bool {
+        self.mr_enclaves.contains(&body.mr_enclave) || self.mr_signers.contains(&body.mr_signer)
+    }
+}
+
+/// Verifies that `key` was attested to by an allow-listed enclave, i.e., its quote verifies, the
+/// quote's report data binds the key, and the measurement is on the allow-list.
+pub fn verify_attested_key(
+    verifier: &dyn QuoteVerifier,
+    allow_list: &EnclaveAllowList,
+    key: &AttestedPublicKey,
+) -> Result<(), AttestationError> {
+    let body = verifier.verify_quote(&key.tee_linkable_attestation)?;
+
+    if body.report_data[..] != key.report_data()[..] {
+        return Err(AttestationError::KeyNotBound);
+    }
+
+    if !allow_list.allows(&body) {
+        return Err(AttestationError::NotAllowed(
+            hex::encode(&body.mr_enclave),
+            hex::encode(&body.mr_signer),
+        ));
+    }
+
+    Ok(())
+}
+
+static QUOTE_VERIFIER: RwLock<Option<Box<dyn QuoteVerifier + Send + Sync>>> = RwLock::new(None);
+
+/// Sets the verifier user registrations are checked with. Replaces the one set before
+pub fn set_quote_verifier(verifier: Box<dyn QuoteVerifier + Send + Sync>) {
+    *QUOTE_VERIFIER.write().unwrap() = Some(verifier);
+}
+
+/// Sets the verifier the binaries check user registrations with. SGX builds verify quotes with
+/// IAS (see [`IasQuoteVerifier::from_env`](crate::ias::IasQuoteVerifier::from_env)). Other builds
+/// can only verify the software enclave's mock quotes, and only with the `mock-attestation`
+/// feature. If no verifier can be set, registrations are rejected.
+pub fn set_default_quote_verifier() {
+    #[cfg(feature = "sgx")]
+    match crate::ias::IasQuoteVerifier::from_env() {
+        Ok(verifier) => set_quote_verifier(Box::new(verifier)),
+        Err(e) => warn!("can't verify quotes with IAS: {}", e),
+    }
+
+    #[cfg(all(not(feature = "sgx"), feature = "mock-attestation"))]
+    {
+        warn!("verifying mock quotes. user attestations prove nothing");
+        set_quote_verifier(Box::new(MockQuoteVerifier));
+    }
+
+    #[cfg(all(not(feature = "sgx"), not(feature = "mock-attestation")))]
+    warn!("this build has no quote verifier. user registrations will be rejected");
+}
+
+/// Checks a user registration with the verifier that was set (see [`set_quote_verifier`])
+pub fn verify_user_registration(
+    allow_list: &EnclaveAllowList,
+    reg_blob: &AttestedPublicKey,
+) -> Result<(), AttestationError> {
+    let verifier = QUOTE_VERIFIER.read().unwrap();
+    check_user_registration(verifier.as_deref(), allow_list, reg_blob)
+}
+
+/// Checks a user registration with the given verifier. Fails if there is none, or if the
+/// allow-list is empty
+fn check_user_registration(
+    verifier: Option<&(dyn QuoteVerifier + Send + Sync)>,
+    allow_list: &EnclaveAllowList,
+    reg_blob: &AttestedPublicKey,
+) -> Result<(), AttestationError> {
+    if allow_list.is_empty() {
+        error!("no enclaves are allow-listed. rejecting user registration");
+        return Err(AttestationError::EmptyAllowList);
+    }
+
+    if reg_blob.role != "user" {
+        return Err(AttestationError::WrongRole(reg_blob.role.clone()));
+    }
+
+    let verifier = verifier.ok_or_else(|| {
+        error!("no quote verifier is set. rejecting user registration");
+        AttestationError::NoVerifier
+    })?;
+    verify_attested_key(verifier, allow_list, reg_blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::{make_mock_quote, SgxProtectedKeyPub, SOFTWARE_ENCLAVE_MR_ENCLAVE};
+
+    fn mock_attested_key(mr_enclave: [u8; 32]) -> AttestedPublicKey {
+        let mut key = AttestedPublicKey {
+            pk: SgxProtectedKeyPub([1u8; 32]),
+            xpk: SgxProtectedKeyPub([2u8; 32]),
+            role: "user".to_string(),
+            tee_linkable_attestation: vec![],
+        };
+        key.tee_linkable_attestation = make_mock_quote(&QuoteBody {
+            mr_enclave,
+            mr_signer: [0u8; 32],
+            report_data: key.report_data(),
+        });
+        key
+    }
+
+    fn software_allow_list() -> EnclaveAllowList {
+        EnclaveAllowList {
+            mr_enclaves: vec![SOFTWARE_ENCLAVE_MR_ENCLAVE],
+            mr_signers: vec![],
+        }
+    }
+
+    #[test]
+    fn mock_attestation() {
+        let allow_list = software_allow_list();
+
+        let key = mock_attested_key(SOFTWARE_ENCLAVE_MR_ENCLAVE);
+        verify_attested_key(&MockQuoteVerifier, &allow_list, &key).unwrap();
+
+        // a measurement that isn't listed
+        let key = mock_attested_key([7u8; 32]);
+        assert!(matches!(
+            verify_attested_key(&MockQuoteVerifier, &allow_list, &key),
+            Err(AttestationError::NotAllowed(_, _))
+        ));
+
+        // ...unless its signer is
+        let signer_list = EnclaveAllowList {
+            mr_enclaves: vec![],
+            mr_signers: vec![[0u8; 32]],
+        };
+        verify_attested_key(&MockQuoteVerifier, &signer_list, &key).unwrap();
+    }
+
+    #[test]
+    fn registration_needs_verifier() {
+        let key = mock_attested_key(SOFTWARE_ENCLAVE_MR_ENCLAVE);
+
+        check_user_registration(Some(&MockQuoteVerifier), &software_allow_list(), &key).unwrap();
+        assert!(matches!(
+            check_user_registration(None, &software_allow_list(), &key),
+            Err(AttestationError::NoVerifier)
+        ));
+
+        // an empty allow-list allows no one, whatever the verifier
+        assert!(matches!(
+            check_user_registration(Some(&MockQuoteVerifier), &EnclaveAllowList::default(), &key),
+            Err(AttestationError::EmptyAllowList)
+        ));
+    }
+
+    #[test]
+    fn mock_attestation_tampered() {
+        let allow_list = software_allow_list();
+
+        // swapping in another key breaks the binding
+        let mut key = mock_attested_key(SOFTWARE_ENCLAVE_MR_ENCLAVE);
+        key.pk = SgxProtectedKeyPub([3u8; 32]);
+        assert!(matches!(
+            verify_attested_key(&MockQuoteVerifier, &allow_list, &key),
+            Err(AttestationError::KeyNotBound)
+        ));
+
+        // so does changing the role
+        let mut key = mock_attested_key(SOFTWARE_ENCLAVE_MR_ENCLAVE);
+        key.role = "aggregator".to_string();
+        assert!(matches!(
+            verify_attested_key(&MockQuoteVerifier, &allow_list, &key),
+            Err(AttestationError::KeyNotBound)
+        ));
+
+        // editing the quote breaks its MAC
+        let mut key = mock_attested_key([7u8; 32]);
+        key.tee_linkable_attestation[112..144].copy_from_slice(&SOFTWARE_ENCLAVE_MR_ENCLAVE);
+        assert!(matches!(
+            verify_attested_key(&MockQuoteVerifier, &allow_list, &key),
+            Err(AttestationError::BadQuote)
+        ));
+
+        // and so does truncating it
+        let mut key = mock_attested_key(SOFTWARE_ENCLAVE_MR_ENCLAVE);
+        key.tee_linkable_attestation.pop();
+        assert!(matches!(
+            verify_attested_key(&MockQuoteVerifier, &allow_list, &key),
+            Err(AttestationError::BadQuote)
+        ));
+    }
+}
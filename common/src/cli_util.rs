@@ -1,4 +1,6 @@
+use crate::attestation::EnclaveAllowList;
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 use std::io::{BufRead, BufReader, Read, Write};
 
 use thiserror::Error;
@@ -13,6 +15,10 @@ pub enum SerializationError {
     Io(#[from] std::io::Error),
     #[error("integer parsing")]
     Int(#[from] core::num::ParseIntError),
+    #[error("hex decoding error")]
+    Hex(#[from] hex::FromHexError),
+    #[error("enclave measurements must be 32 bytes")]
+    MeasurementLength,
     #[error("cannot deserialize empty string")]
     Empty,
 }
@@ -27,6 +33,25 @@ pub fn parse_u64(s: &str) -> Result<u64> {
     Ok(u64::from_str_radix(s, 10)?)
 }
 
+/// Parses a hex-encoded MRENCLAVE or MRSIGNER value
+pub fn parse_measurement(s: &str) -> Result<[u8; 32]> {
+    hex::decode(s)?
+        .as_slice()
+        .try_into()
+        .map_err(|_| SerializationError::MeasurementLength)
+}
+
+/// Makes an allow-list out of hex-encoded MRENCLAVE and MRSIGNER values
+pub fn parse_allow_list<'a>(
+    mr_enclaves: impl Iterator<Item = &'a str>,
+    mr_signers: impl Iterator<Item = &'a str>,
+) -> Result<EnclaveAllowList> {
+    Ok(EnclaveAllowList {
+        mr_enclaves: mr_enclaves.map(parse_measurement).collect::<Result<_>>()?,
+        mr_signers: mr_signers.map(parse_measurement).collect::<Result<_>>()?,
+    })
+}
+
 /// file -> base64::decode -> cbor::decode
 pub fn load<R, D>(f: R) -> Result<D>
 where
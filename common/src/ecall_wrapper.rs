@@ -17,7 +17,89 @@ extern "C" {
     ) -> sgx_status_t;
 }
 
-const ENCLAVE_OUTPUT_BUF_SIZE: usize = 30 * 1024000; // 5MB buffer should be enough?
+/// Ocall for the enclave to have a counter advance witnessed (see [`crate::freshness`]). The
+/// servers' signatures are written to `resp`. If they don't fit, `resp_used` is set to the size
+/// needed and SGX_ERROR_OUT_OF_MEMORY is returned.
+#[no_mangle]
+pub extern "C" fn ocall_witness_counter_advance(
+    req: *const u8,
+    req_len: usize,
+    resp: *mut u8,
+    resp_cap: usize,
+    resp_used: *mut usize,
+) -> sgx_status_t {
+    let req = unsafe { std::slice::from_raw_parts(req, req_len) };
+    let advance: interface::CounterAdvance = match serde_cbor::from_slice(req) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("can't unmarshal counter advance: {}", e);
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+
+    let sigs = match crate::freshness::witness_counter_advance(&advance) {
+        Ok(sigs) => sigs,
+        Err(e) => return e,
+    };
+    let marshaled = match serde_cbor::to_vec(&sigs) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("can't marshal witness response: {}", e);
+            return sgx_status_t::SGX_ERROR_UNEXPECTED;
+        }
+    };
+
+    unsafe {
+        *resp_used = marshaled.len();
+        if marshaled.len() > resp_cap {
+            return sgx_status_t::SGX_ERROR_OUT_OF_MEMORY;
+        }
+        std::ptr::copy_nonoverlapping(marshaled.as_ptr(), resp, marshaled.len());
+    }
+
+    SGX_SUCCESS
+}
+
+/// The size of the first output buffer an ecall gets. If the output doesn't fit, the enclave keeps
+/// it and reports how much space it needs, and the output is fetched into a buffer that big. The
+/// ecall itself is not run again, since ecalls can have effects (e.g., moving a freshness counter).
+/// Each ecall remembers the largest size it has needed so far and starts from there next time.
+const ENCLAVE_OUTPUT_BUF_INIT_SIZE: usize = 64 * 1024;
+
+/// Copies an output the enclave kept because it didn't fit into the ecall's buffer. `ticket` is
+/// what the enclave wrote to that buffer instead, and `out_buf` must be as big as the size it
+/// reported.
+fn fetch_output(
+    enclave_id: sgx_enclave_id_t,
+    ticket: u64,
+    out_buf: &mut [u8],
+    outbuf_used: &mut usize,
+) -> crate::EnclaveResult<()> {
+    let marshaled_input = serde_cbor::to_vec(&ticket)?;
+
+    let mut ret = SGX_SUCCESS;
+    let call_ret = unsafe {
+        ecall_entrypoint(
+            enclave_id,
+            &mut ret,
+            interface::EcallId::EcallFetchOutput as u8,
+            marshaled_input.as_ptr(),
+            marshaled_input.len(),
+            out_buf.as_mut_ptr(),
+            out_buf.len(),
+            outbuf_used,
+        )
+    };
+
+    if call_ret != SGX_SUCCESS {
+        return Err(crate::enclave::EnclaveError::SgxError(call_ret));
+    }
+    if ret != SGX_SUCCESS {
+        return Err(crate::enclave::EnclaveError::EnclaveLogicError(ret));
+    }
+
+    Ok(())
+}
 
 macro_rules! gen_ecall_stub {
     ( $name:expr, $type_i:ty, $type_o: ty, $fn_name: ident) => {
@@ -33,8 +115,14 @@ macro_rules! gen_ecall_stub {
             let time_marshal_input = start.elapsed();
             start = std::time::Instant::now();
 
+            static OUTPUT_SIZE_HINT: std::sync::atomic::AtomicUsize =
+                std::sync::atomic::AtomicUsize::new(
+                    crate::ecall_wrapper::ENCLAVE_OUTPUT_BUF_INIT_SIZE,
+                );
+
             let mut ret = crate::ecall_wrapper::SGX_SUCCESS;
-            let mut out_buf = vec![0u8; crate::ecall_wrapper::ENCLAVE_OUTPUT_BUF_SIZE];
+            let mut out_buf =
+                vec![0u8; OUTPUT_SIZE_HINT.load(std::sync::atomic::Ordering::Relaxed)];
             let mut outbuf_used = 0usize;
 
             let time_allocate_out_buf = start.elapsed();
@@ -54,17 +142,40 @@ macro_rules! gen_ecall_stub {
                 )
             };
 
-            let time_ecall = start.elapsed();
-            start = std::time::Instant::now();
-
             // Check for errors
             if call_ret != crate::ecall_wrapper::sgx_status_t::SGX_SUCCESS {
                 return Err(crate::enclave::EnclaveError::SgxError(call_ret));
             }
-            if ret != crate::ecall_wrapper::sgx_status_t::SGX_SUCCESS {
+
+            // The output didn't fit. The enclave kept it, told us how much space it needs, and
+            // wrote a ticket for it to the buffer. Fetch it with a buffer that big
+            if ret == crate::ecall_wrapper::sgx_status_t::SGX_ERROR_OUT_OF_MEMORY
+                && outbuf_used > out_buf.len()
+            {
+                debug!(
+                    "Ecall {:?} needs a {}B output buffer, got {}B. fetching",
+                    $name,
+                    outbuf_used,
+                    out_buf.len()
+                );
+                let mut ticket = [0u8; 8];
+                ticket.copy_from_slice(&out_buf[..8]);
+
+                OUTPUT_SIZE_HINT.fetch_max(outbuf_used, std::sync::atomic::Ordering::Relaxed);
+                out_buf = vec![0u8; outbuf_used];
+                crate::ecall_wrapper::fetch_output(
+                    enclave_id,
+                    u64::from_le_bytes(ticket),
+                    &mut out_buf,
+                    &mut outbuf_used,
+                )?;
+            } else if ret != crate::ecall_wrapper::sgx_status_t::SGX_SUCCESS {
                 return Err(crate::enclave::EnclaveError::EnclaveLogicError(ret));
             }
 
+            let time_ecall = start.elapsed();
+            start = std::time::Instant::now();
+
             let output: $type_o = serde_cbor::from_slice(&out_buf[..outbuf_used]).map_err(|e| {
                 log::error!("can't unmarshal: {}", e);
                 crate::enclave::EnclaveError::MarshallError(e)
@@ -105,27 +216,87 @@ macro_rules! match_ecall_ids {
 }
 
 pub mod ecall_allowed {
+    use ed25519_dalek::PublicKey;
     use interface::*;
+    use std::collections::BTreeSet;
     use EcallId::*;
 
     match_ecall_ids! {
         (
             EcallNewUser,
-            &[ServerPubKeyPackage],
-            (SealedSharedSecretsDbClient, SealedSigPrivKey, UserRegistrationBlob),
+            (&[ServerPubKeyPackage], &QuotingTarget),
+            (SealedSharedSecretsDbClient, SealedUserPrivKeys, UserRegistrationBlob),
             new_user
         ),
         (
             EcallNewUserBatch,
-            (&[ServerPubKeyPackage], usize), // input
-            Vec<(SealedSharedSecretsDbClient, SealedSigPrivKey, UserRegistrationBlob)>, // output
+            (&[ServerPubKeyPackage], &QuotingTarget, usize), // input
+            Vec<(SealedSharedSecretsDbClient, SealedUserPrivKeys, UserRegistrationBlob)>, // output
             new_user_batch
         ),
         (
             EcallUserSubmit,
-            (&UserSubmissionReq, &SealedSigPrivKey),
+            (&UserSubmissionReq, &SealedUserPrivKeys),
             (UserSubmissionBlob, SealedSharedSecretsDbClient),
             user_submit
         ),
+        (
+            EcallUserSubmitBatch,
+            &[(UserSubmissionReq, SealedUserPrivKeys)],
+            Vec<Result<(UserSubmissionBlob, SealedSharedSecretsDbClient), u32>>,
+            user_submit_batch
+        ),
+        (
+            EcallServerNew,
+            &DcNetParams,
+            (SealedSigPrivKey, SealedKemPrivKey, SealedSharedSecretsDbServer, ServerPubKeyPackage),
+            new_server
+        ),
+        (
+            EcallRecvUserReg,
+            (&SealedSharedSecretsDbServer, &SealedKemPrivKey, &[UserRegistrationBlob]),
+            SealedSharedSecretsDbServer,
+            recv_user_reg
+        ),
+        (
+            EcallRecvServerReg,
+            (&SealedSharedSecretsDbServer, &ServerPubKeyPackage),
+            SealedSharedSecretsDbServer,
+            recv_server_reg
+        ),
+        (
+            EcallRecvAggregatorReg,
+            (&SealedSharedSecretsDbServer, &PublicKey),
+            SealedSharedSecretsDbServer,
+            recv_aggregator_reg
+        ),
+        (
+            EcallUnblindAggregatePartial,
+            (u32, &SealedSharedSecretsDbServer, &DcNetParams, &BTreeSet<EntityId>),
+            SealedPartialRoundSecret,
+            unblind_aggregate_partial
+        ),
+        (
+            EcallUnblindAggregate,
+            (&ServerUnblindReq, &SealedSigPrivKey),
+            (RoundSecret, OutputSignature, SealedSharedSecretsDbServer),
+            unblind_aggregate
+        ),
+        (
+            EcallSignRoundOutput,
+            (&SignRoundOutputReq, &SealedSigPrivKey),
+            RoundOutput,
+            sign_round_output
+        ),
+        (
+            EcallSignCounterAdvance,
+            (
+                &CounterAdvance,
+                &SealedSharedSecretsDbServer,
+                &SealedSigPrivKey
+            ),
+            OutputSignature,
+            sign_counter_advance
+        ),
     }
 }
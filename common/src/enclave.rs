@@ -1,11 +1,9 @@
-use sgx_status_t::SGX_SUCCESS;
 use sgx_types;
 use sgx_types::*;
-use sgx_urts;
-use sgx_urts::SgxEnclave;
-use std::path::PathBuf;
 
+use ed25519_dalek::PublicKey;
 use interface::*;
+use std::collections::BTreeSet;
 
 // error type for enclave operations
 use quick_error::quick_error;
@@ -28,48 +26,24 @@ quick_error! {
 
 pub type EnclaveResult<T> = Result<T, EnclaveError>;
 
-#[derive(Clone, Debug)]
-pub struct DcNetEnclave {
-    enclave: sgx_urts::SgxEnclave,
-}
-
-use crate::ecall_wrapper::ecall_allowed;
-
-impl DcNetEnclave {
-    pub fn init(enclave_file: &'static str) -> EnclaveResult<Self> {
-        let enclave_path = PathBuf::from(enclave_file);
-
-        let mut launch_token: sgx_launch_token_t = [0; 1024];
-        let mut launch_token_updated: i32 = 0;
-        // call sgx_create_enclave to initialize an enclave instance
-        // Debug Support: set 2nd parameter to 1
-        let debug = 1;
-        let mut misc_attr = sgx_misc_attribute_t {
-            secs_attr: sgx_attributes_t { flags: 0, xfrm: 0 },
-            misc_select: 0,
-        };
-
-        let start_time = std::time::Instant::now();
-        let enclave = SgxEnclave::create(
-            enclave_path,
-            debug,
-            &mut launch_token,
-            &mut launch_token_updated,
-            &mut misc_attr,
+/// Batched ecalls report each item's failure as a raw `sgx_status_t`. This turns one item's result
+/// back into an [`EnclaveResult`]
+fn batch_result<T>(r: Result<T, u32>) -> EnclaveResult<T> {
+    r.map_err(|e| {
+        EnclaveError::EnclaveLogicError(
+            sgx_status_t::from_repr(e).unwrap_or(sgx_status_t::SGX_ERROR_UNEXPECTED),
         )
-        .map_err(EnclaveError::SgxError)?;
+    })
+}
 
-        debug!(
-            "enclave initiated. took {}us",
-            start_time.elapsed().as_micros()
-        );
-        Ok(Self { enclave })
-    }
+/// The ecalls a DC net enclave provides. [`SgxDcNetEnclave`] runs them inside a signed SGX
+/// enclave; [`SoftwareEnclave`] runs the same enclave code as ordinary Rust, so that users and
+/// whole rounds can be exercised on machines without SGX.
+pub trait EnclaveBackend: Sized {
+    /// Loads the enclave. `enclave_file` is the path to the signed enclave, if there is one.
+    fn init(enclave_file: &'static str) -> EnclaveResult<Self>;
 
-    pub fn destroy(self) {
-        self.enclave.destroy();
-        debug!("enclave destroyed.");
-    }
+    fn destroy(self);
 
     /// Given a message, constructs a round message for sending to an aggregator
     /// SGX will
@@ -85,61 +59,587 @@ impl DcNetEnclave {
     /// If scheduling failed (e.g., due to collision) this will return
     /// Err(EnclaveLogicError(SGX_ERROR_SERVICE_UNAVAILABLE)). Higher level application should
     /// retry, for example, in the next round.
-    pub fn user_submit_round_msg(
+    fn user_submit_round_msg(
         &self,
         submission_req: &UserSubmissionReq,
-        sealed_usk: &SealedSigPrivKey,
-    ) -> EnclaveResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)> {
-        Ok(ecall_allowed::user_submit(
-            self.enclave.geteid(),
-            (submission_req, sealed_usk),
-        )?)
-    }
+        sealed_keys: &SealedUserPrivKeys,
+    ) -> EnclaveResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)>;
+
+    /// Does [`EnclaveBackend::user_submit_round_msg`] for many users in one ecall. Returns one
+    /// result per request, in order. A request that fails, e.g., due to a scheduling collision,
+    /// doesn't affect the rest of the batch. The outer error is only for the ecall itself failing.
+    fn user_submit_round_msg_batch(
+        &self,
+        reqs: &[(UserSubmissionReq, SealedUserPrivKeys)],
+    ) -> EnclaveResult<Vec<EnclaveResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)>>>;
 
     /// Create a new TEE protected secret key. Derives shared secrets with all the given KEM pubkeys.
     /// This function
     /// 1. Verify the enclave attestations on the packages
     /// 2. Use the KEM pubkeys to derive the shared secrets.
-    /// TODO: what should it do with the signing keys?
-    pub fn new_user(
+    /// 3. Attest to the new public keys. The registration blob carries a quote whose report data
+    ///    binds them (see [`AttestedPublicKey::report_data`]).
+    /// The user's signing, KEM and scheduling keys are returned sealed together.
+    fn new_user(
         &self,
         server_pks: &[ServerPubKeyPackage],
     ) -> EnclaveResult<(
         SealedSharedSecretsDbClient,
+        SealedUserPrivKeys,
+        EntityId,
+        UserRegistrationBlob,
+    )>;
+
+    fn new_user_batch(
+        &self,
+        server_pks: &[ServerPubKeyPackage],
+        n_users: usize,
+    ) -> EnclaveResult<
+        Vec<(
+            SealedSharedSecretsDbClient,
+            SealedUserPrivKeys,
+            UserRegistrationBlob,
+        )>,
+    >;
+
+    /// Makes a new anytrust server. Returns its sealed signing and KEM keys, its (empty) sealed
+    /// database of shared secrets, and its pubkey package.
+    fn new_server(
+        &self,
+        dc_net_params: &DcNetParams,
+    ) -> EnclaveResult<(
         SealedSigPrivKey,
+        SealedKemPrivKey,
+        SealedSharedSecretsDbServer,
+        ServerPubKeyPackage,
+    )>;
+
+    /// Derives the secrets a server shares with the given users and adds them to the server's
+    /// sealed database. The users must already be checked against the server's allow list.
+    fn recv_user_registrations(
+        &self,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        decap_key: &SealedKemPrivKey,
+        user_regs: &[UserRegistrationBlob],
+    ) -> EnclaveResult<SealedSharedSecretsDbServer>;
+
+    /// Adds another server of the anytrust group to a server's sealed database. The server's
+    /// freshness counter is witnessed by every server added this way.
+    fn recv_server_registration(
+        &self,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        server_pk: &ServerPubKeyPackage,
+    ) -> EnclaveResult<SealedSharedSecretsDbServer>;
+
+    /// Adds a top-level aggregator to a server's sealed database. This fails once the server has
+    /// unblinded a round.
+    fn recv_aggregator_registration(
+        &self,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        agg_pk: &PublicKey,
+    ) -> EnclaveResult<SealedSharedSecretsDbServer>;
+
+    /// Derives the XOR of the given users' pads for a round, sealed for
+    /// [`unblind_aggregate`](Self::unblind_aggregate). Any number of these can run at once.
+    fn unblind_aggregate_partial(
+        &self,
+        round: u32,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        dc_net_params: &DcNetParams,
+        user_ids: &BTreeSet<EntityId>,
+    ) -> EnclaveResult<SealedPartialRoundSecret>;
+
+    /// Unblinds a top-level aggregate. Returns the server's key share, its signature over the
+    /// share, and the shared secrets ratcheted past the aggregate's round.
+    fn unblind_aggregate(
+        &self,
+        req: &ServerUnblindReq,
+        signing_sk: &SealedSigPrivKey,
+    ) -> EnclaveResult<(RoundSecret, OutputSignature, SealedSharedSecretsDbServer)>;
+
+    /// Derives a round output from the aggregate and every server's share of it. Returns the
+    /// output signed with the server's signing key
+    fn sign_round_output(
+        &self,
+        req: &SignRoundOutputReq,
+        signing_sk: &SealedSigPrivKey,
+    ) -> EnclaveResult<RoundOutput>;
+
+    /// Signs a counter advance of a registered user, or of another server in the group, with the
+    /// server's signing key
+    fn sign_counter_advance(
+        &self,
+        advance: &CounterAdvance,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        signing_sk: &SealedSigPrivKey,
+    ) -> EnclaveResult<OutputSignature>;
+}
+
+/// The enclave used by the binaries. This is the SGX enclave unless the `sgx` feature is off.
+#[cfg(feature = "sgx")]
+pub type DcNetEnclave = SgxDcNetEnclave;
+#[cfg(not(feature = "sgx"))]
+pub type DcNetEnclave = SoftwareEnclave;
+
+#[cfg(feature = "sgx")]
+pub use self::sgx_backend::SgxDcNetEnclave;
+
+#[cfg(feature = "sgx")]
+mod sgx_backend {
+    use super::*;
+    use sgx_status_t::SGX_SUCCESS;
+    use sgx_urts::SgxEnclave;
+    use std::path::PathBuf;
+
+    use crate::ecall_wrapper::ecall_allowed;
+
+    #[derive(Clone, Debug)]
+    pub struct SgxDcNetEnclave {
+        enclave: sgx_urts::SgxEnclave,
+    }
+
+    impl EnclaveBackend for SgxDcNetEnclave {
+        fn init(enclave_file: &'static str) -> EnclaveResult<Self> {
+            let enclave_path = PathBuf::from(enclave_file);
+
+            let mut launch_token: sgx_launch_token_t = [0; 1024];
+            let mut launch_token_updated: i32 = 0;
+            // call sgx_create_enclave to initialize an enclave instance
+            // Debug Support: set 2nd parameter to 1
+            let debug = 1;
+            let mut misc_attr = sgx_misc_attribute_t {
+                secs_attr: sgx_attributes_t { flags: 0, xfrm: 0 },
+                misc_select: 0,
+            };
+
+            let start_time = std::time::Instant::now();
+            let enclave = SgxEnclave::create(
+                enclave_path,
+                debug,
+                &mut launch_token,
+                &mut launch_token_updated,
+                &mut misc_attr,
+            )
+            .map_err(EnclaveError::SgxError)?;
+
+            debug!(
+                "enclave initiated. took {}us",
+                start_time.elapsed().as_micros()
+            );
+            Ok(Self { enclave })
+        }
+
+        fn destroy(self) {
+            self.enclave.destroy();
+            debug!("enclave destroyed.");
+        }
+
+        fn user_submit_round_msg(
+            &self,
+            submission_req: &UserSubmissionReq,
+            sealed_keys: &SealedUserPrivKeys,
+        ) -> EnclaveResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)> {
+            Ok(ecall_allowed::user_submit(
+                self.enclave.geteid(),
+                (submission_req, sealed_keys),
+            )?)
+        }
+
+        fn user_submit_round_msg_batch(
+            &self,
+            reqs: &[(UserSubmissionReq, SealedUserPrivKeys)],
+        ) -> EnclaveResult<Vec<EnclaveResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)>>>
+        {
+            let results = ecall_allowed::user_submit_batch(self.enclave.geteid(), reqs)?;
+            Ok(results.into_iter().map(batch_result).collect())
+        }
+
+        fn new_user(
+            &self,
+            server_pks: &[ServerPubKeyPackage],
+        ) -> EnclaveResult<(
+            SealedSharedSecretsDbClient,
+            SealedUserPrivKeys,
+            EntityId,
+            UserRegistrationBlob,
+        )> {
+            let target = quoting::quoting_target()?;
+            let mut u = ecall_allowed::new_user(self.enclave.geteid(), (server_pks, &target))?;
+            u.2.tee_linkable_attestation = quoting::quote_report(&u.2.tee_linkable_attestation)?;
+            Ok((u.0, u.1, EntityId::from(&u.2), u.2))
+        }
+
+        fn new_user_batch(
+            &self,
+            server_pks: &[ServerPubKeyPackage],
+            n_users: usize,
+        ) -> EnclaveResult<
+            Vec<(
+                SealedSharedSecretsDbClient,
+                SealedUserPrivKeys,
+                UserRegistrationBlob,
+            )>,
+        > {
+            let target = quoting::quoting_target()?;
+            let mut users = ecall_allowed::new_user_batch(
+                self.enclave.geteid(),
+                (server_pks, &target, n_users),
+            )?;
+            for u in users.iter_mut() {
+                u.2.tee_linkable_attestation =
+                    quoting::quote_report(&u.2.tee_linkable_attestation)?;
+            }
+            Ok(users)
+        }
+
+        fn new_server(
+            &self,
+            dc_net_params: &DcNetParams,
+        ) -> EnclaveResult<(
+            SealedSigPrivKey,
+            SealedKemPrivKey,
+            SealedSharedSecretsDbServer,
+            ServerPubKeyPackage,
+        )> {
+            Ok(ecall_allowed::new_server(
+                self.enclave.geteid(),
+                dc_net_params,
+            )?)
+        }
+
+        fn recv_user_registrations(
+            &self,
+            shared_secrets: &SealedSharedSecretsDbServer,
+            decap_key: &SealedKemPrivKey,
+            user_regs: &[UserRegistrationBlob],
+        ) -> EnclaveResult<SealedSharedSecretsDbServer> {
+            Ok(ecall_allowed::recv_user_reg(
+                self.enclave.geteid(),
+                (shared_secrets, decap_key, user_regs),
+            )?)
+        }
+
+        fn recv_server_registration(
+            &self,
+            shared_secrets: &SealedSharedSecretsDbServer,
+            server_pk: &ServerPubKeyPackage,
+        ) -> EnclaveResult<SealedSharedSecretsDbServer> {
+            Ok(ecall_allowed::recv_server_reg(
+                self.enclave.geteid(),
+                (shared_secrets, server_pk),
+            )?)
+        }
+
+        fn recv_aggregator_registration(
+            &self,
+            shared_secrets: &SealedSharedSecretsDbServer,
+            agg_pk: &PublicKey,
+        ) -> EnclaveResult<SealedSharedSecretsDbServer> {
+            Ok(ecall_allowed::recv_aggregator_reg(
+                self.enclave.geteid(),
+                (shared_secrets, agg_pk),
+            )?)
+        }
+
+        fn unblind_aggregate_partial(
+            &self,
+            round: u32,
+            shared_secrets: &SealedSharedSecretsDbServer,
+            dc_net_params: &DcNetParams,
+            user_ids: &BTreeSet<EntityId>,
+        ) -> EnclaveResult<SealedPartialRoundSecret> {
+            Ok(ecall_allowed::unblind_aggregate_partial(
+                self.enclave.geteid(),
+                (round, shared_secrets, dc_net_params, user_ids),
+            )?)
+        }
+
+        fn unblind_aggregate(
+            &self,
+            req: &ServerUnblindReq,
+            signing_sk: &SealedSigPrivKey,
+        ) -> EnclaveResult<(RoundSecret, OutputSignature, SealedSharedSecretsDbServer)> {
+            Ok(ecall_allowed::unblind_aggregate(
+                self.enclave.geteid(),
+                (req, signing_sk),
+            )?)
+        }
+
+        fn sign_round_output(
+            &self,
+            req: &SignRoundOutputReq,
+            signing_sk: &SealedSigPrivKey,
+        ) -> EnclaveResult<RoundOutput> {
+            Ok(ecall_allowed::sign_round_output(
+                self.enclave.geteid(),
+                (req, signing_sk),
+            )?)
+        }
+
+        fn sign_counter_advance(
+            &self,
+            advance: &CounterAdvance,
+            shared_secrets: &SealedSharedSecretsDbServer,
+            signing_sk: &SealedSigPrivKey,
+        ) -> EnclaveResult<OutputSignature> {
+            Ok(ecall_allowed::sign_counter_advance(
+                self.enclave.geteid(),
+                (advance, shared_secrets, signing_sk),
+            )?)
+        }
+    }
+
+    impl SgxDcNetEnclave {
+        pub fn run_enclave_tests(&self) -> SgxError {
+            let mut retval = SGX_SUCCESS;
+            unsafe {
+                test_main_entrance(self.enclave.geteid(), &mut retval);
+            }
+            if retval != SGX_SUCCESS {
+                return Err(retval);
+            }
+            Ok(())
+        }
+    }
+
+    extern "C" {
+        fn test_main_entrance(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
+    }
+
+    /// Talks to the EPID quoting enclave. The DC net enclave attests to keys with reports
+    /// targeted at the quoting enclave, and those are turned into quotes here.
+    mod quoting {
+        use super::*;
+        use std::{mem, ptr, slice};
+
+        /// Returns the quoting enclave's target info, for the DC net enclave to make reports
+        /// against
+        pub fn quoting_target() -> EnclaveResult<QuotingTarget> {
+            let mut target_info = sgx_target_info_t::default();
+            let mut gid = sgx_epid_group_id_t::default();
+            let ret = unsafe { sgx_init_quote(&mut target_info, &mut gid) };
+            if ret != SGX_SUCCESS {
+                error!("sgx_init_quote failed: {}", ret);
+                return Err(EnclaveError::SgxError(ret));
+            }
+
+            let bytes = unsafe {
+                slice::from_raw_parts(
+                    &target_info as *const sgx_target_info_t as *const u8,
+                    mem::size_of::<sgx_target_info_t>(),
+                )
+            };
+            Ok(QuotingTarget(bytes.to_vec()))
+        }
+
+        /// The SPID to request quotes with. Set SGX_SPID to the hex SPID registered with the
+        /// attestation service; a zero SPID is used otherwise.
+        fn spid() -> sgx_spid_t {
+            let mut spid = sgx_spid_t::default();
+            match std::env::var("SGX_SPID").map(hex::decode) {
+                Ok(Ok(id)) if id.len() == spid.id.len() => spid.id.copy_from_slice(&id),
+                Ok(_) => warn!("SGX_SPID is not a 16-byte hex string. using a zero SPID"),
+                Err(_) => debug!("SGX_SPID is not set. using a zero SPID"),
+            }
+            spid
+        }
+
+        /// Turns a serialized report from the DC net enclave into a linkable EPID quote
+        pub fn quote_report(report: &[u8]) -> EnclaveResult<Vec<u8>> {
+            if report.len() != mem::size_of::<sgx_report_t>() {
+                error!("enclave report has wrong length {}", report.len());
+                return Err(EnclaveError::SgxError(
+                    sgx_status_t::SGX_ERROR_INVALID_PARAMETER,
+                ));
+            }
+            let report: sgx_report_t =
+                unsafe { ptr::read_unaligned(report.as_ptr() as *const sgx_report_t) };
+
+            let mut quote_size = 0u32;
+            let ret = unsafe { sgx_calc_quote_size(ptr::null(), 0, &mut quote_size) };
+            if ret != SGX_SUCCESS {
+                error!("sgx_calc_quote_size failed: {}", ret);
+                return Err(EnclaveError::SgxError(ret));
+            }
+
+            let spid = spid();
+            let mut quote = vec![0u8; quote_size as usize];
+            let ret = unsafe {
+                sgx_get_quote(
+                    &report,
+                    sgx_quote_sign_type_t::SGX_LINKABLE_SIGNATURE,
+                    &spid,
+                    ptr::null(),
+                    ptr::null(),
+                    0,
+                    ptr::null_mut(),
+                    quote.as_mut_ptr() as *mut sgx_quote_t,
+                    quote_size,
+                )
+            };
+            if ret != SGX_SUCCESS {
+                error!("sgx_get_quote failed: {}", ret);
+                return Err(EnclaveError::SgxError(ret));
+            }
+
+            Ok(quote)
+        }
+    }
+}
+
+/// Runs the enclave code (`dcnetenclave` built with its `untrusted` feature) in this process.
+/// Secrets are "sealed" by serializing them in the clear, so this offers no protection at all
+/// and must only be used for testing and simulation.
+#[derive(Clone, Debug, Default)]
+pub struct SoftwareEnclave;
+
+use dcnetenclave::ecall::{server, submit, user};
+
+impl EnclaveBackend for SoftwareEnclave {
+    fn init(_enclave_file: &'static str) -> EnclaveResult<Self> {
+        warn!("using the software enclave. secrets are NOT protected");
+        dcnetenclave::freshness::set_witness_transport(crate::freshness::witness_counter_advance);
+        Ok(SoftwareEnclave)
+    }
+
+    fn destroy(self) {
+        debug!("software enclave destroyed.");
+    }
+
+    fn user_submit_round_msg(
+        &self,
+        submission_req: &UserSubmissionReq,
+        sealed_keys: &SealedUserPrivKeys,
+    ) -> EnclaveResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)> {
+        // the ecall takes ownership of its (deserialized) input, so we do the same
+        let input = (submission_req.clone(), sealed_keys.clone());
+        submit::user_submit_internal(&input).map_err(EnclaveError::EnclaveLogicError)
+    }
+
+    fn user_submit_round_msg_batch(
+        &self,
+        reqs: &[(UserSubmissionReq, SealedUserPrivKeys)],
+    ) -> EnclaveResult<Vec<EnclaveResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)>>> {
+        let results =
+            submit::user_submit_batch(&reqs.to_vec()).map_err(EnclaveError::EnclaveLogicError)?;
+        Ok(results.into_iter().map(batch_result).collect())
+    }
+
+    fn new_user(
+        &self,
+        server_pks: &[ServerPubKeyPackage],
+    ) -> EnclaveResult<(
+        SealedSharedSecretsDbClient,
+        SealedUserPrivKeys,
         EntityId,
         UserRegistrationBlob,
     )> {
-        let u = ecall_allowed::new_user(self.enclave.geteid(), server_pks)?;
+        // the software enclave makes mock quotes and needs no quoting target
+        let input = (server_pks.to_vec(), QuotingTarget::default());
+        let u = user::new_user(&input).map_err(EnclaveError::EnclaveLogicError)?;
         Ok((u.0, u.1, EntityId::from(&u.2), u.2))
     }
 
-    pub fn new_user_batch(
+    fn new_user_batch(
         &self,
         server_pks: &[ServerPubKeyPackage],
         n_users: usize,
     ) -> EnclaveResult<
         Vec<(
             SealedSharedSecretsDbClient,
-            SealedSigPrivKey,
+            SealedUserPrivKeys,
             UserRegistrationBlob,
         )>,
     > {
-        ecall_allowed::new_user_batch(self.enclave.geteid(), (server_pks, n_users))
+        user::new_user_batch(&(server_pks.to_vec(), QuotingTarget::default(), n_users))
+            .map_err(EnclaveError::EnclaveLogicError)
     }
 
-    pub fn run_enclave_tests(&self) -> SgxError {
-        let mut retval = SGX_SUCCESS;
-        unsafe {
-            test_main_entrance(self.enclave.geteid(), &mut retval);
-        }
-        if retval != SGX_SUCCESS {
-            return Err(retval);
-        }
-        Ok(())
+    fn new_server(
+        &self,
+        dc_net_params: &DcNetParams,
+    ) -> EnclaveResult<(
+        SealedSigPrivKey,
+        SealedKemPrivKey,
+        SealedSharedSecretsDbServer,
+        ServerPubKeyPackage,
+    )> {
+        server::new_server(dc_net_params).map_err(EnclaveError::EnclaveLogicError)
+    }
+
+    fn recv_user_registrations(
+        &self,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        decap_key: &SealedKemPrivKey,
+        user_regs: &[UserRegistrationBlob],
+    ) -> EnclaveResult<SealedSharedSecretsDbServer> {
+        let input = (
+            shared_secrets.clone(),
+            decap_key.clone(),
+            user_regs.to_vec(),
+        );
+        server::recv_user_registration_batch(&input).map_err(EnclaveError::EnclaveLogicError)
     }
-}
 
-extern "C" {
-    fn test_main_entrance(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
+    fn recv_server_registration(
+        &self,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        server_pk: &ServerPubKeyPackage,
+    ) -> EnclaveResult<SealedSharedSecretsDbServer> {
+        let input = (shared_secrets.clone(), server_pk.clone());
+        server::recv_server_registration(&input).map_err(EnclaveError::EnclaveLogicError)
+    }
+
+    fn recv_aggregator_registration(
+        &self,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        agg_pk: &PublicKey,
+    ) -> EnclaveResult<SealedSharedSecretsDbServer> {
+        let input = (shared_secrets.clone(), *agg_pk);
+        server::recv_aggregator_registration(&input).map_err(EnclaveError::EnclaveLogicError)
+    }
+
+    fn unblind_aggregate_partial(
+        &self,
+        round: u32,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        dc_net_params: &DcNetParams,
+        user_ids: &BTreeSet<EntityId>,
+    ) -> EnclaveResult<SealedPartialRoundSecret> {
+        let input = (
+            round,
+            shared_secrets.clone(),
+            *dc_net_params,
+            user_ids.clone(),
+        );
+        server::unblind_aggregate_partial(&input).map_err(EnclaveError::EnclaveLogicError)
+    }
+
+    fn unblind_aggregate(
+        &self,
+        req: &ServerUnblindReq,
+        signing_sk: &SealedSigPrivKey,
+    ) -> EnclaveResult<(RoundSecret, OutputSignature, SealedSharedSecretsDbServer)> {
+        let input = (req.clone(), signing_sk.clone());
+        server::unblind_aggregate(&input).map_err(EnclaveError::EnclaveLogicError)
+    }
+
+    fn sign_round_output(
+        &self,
+        req: &SignRoundOutputReq,
+        signing_sk: &SealedSigPrivKey,
+    ) -> EnclaveResult<RoundOutput> {
+        let input = (req.clone(), signing_sk.clone());
+        server::sign_round_output(&input).map_err(EnclaveError::EnclaveLogicError)
+    }
+
+    fn sign_counter_advance(
+        &self,
+        advance: &CounterAdvance,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        signing_sk: &SealedSigPrivKey,
+    ) -> EnclaveResult<OutputSignature> {
+        let input = (advance.clone(), shared_secrets.clone(), signing_sk.clone());
+        server::sign_counter_advance(&input).map_err(EnclaveError::EnclaveLogicError)
+    }
 }
@@ -0,0 +1,127 @@
+//! The host's side of the freshness counters of users and servers. The enclave can't reach the
+//! anytrust servers itself, so when it needs a counter advance witnessed, it hands the request to
+//! the [`CounterWitness`] set here. Without one, every submission is refused, as is every unblind
+//! by a server with others in its group.
+
+use crate::cli_util;
+use interface::{CounterAdvance, OutputSignature};
+use quick_error::quick_error;
+use sgx_types::sgx_status_t;
+use std::sync::RwLock;
+use std::{thread, time::Duration};
+
+use actix_web::{
+    client::Client,
+    http::{StatusCode, Uri},
+    rt::System,
+};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum WitnessError {
+        Refused(server: String, reason: String) {
+            display("server {} refused to witness the counter: {}", server, reason)
+        }
+        Unreachable(server: String, reason: String) {
+            display("could not reach server {}: {}", server, reason)
+        }
+    }
+}
+
+/// Gets every anytrust server's signature on a counter advance. This is called from inside an
+/// ecall, so it blocks until all the servers have answered.
+pub trait CounterWitness: Send + Sync {
+    fn witness(&self, advance: &CounterAdvance) -> Result<Vec<OutputSignature>, WitnessError>;
+}
+
+static WITNESS: RwLock<Option<Box<dyn CounterWitness>>> = RwLock::new(None);
+
+/// Sets the witness every enclave in this process uses. Replaces the one set before
+pub fn set_counter_witness(witness: Box<dyn CounterWitness>) {
+    *WITNESS.write().unwrap() = Some(witness);
+}
+
+/// Asks the witness that was set to sign `advance`. A refusal is SGX_ERROR_INVALID_STATE, like the
+/// enclave's own check. Fails with SGX_ERROR_SERVICE_UNAVAILABLE if no witness was set or a server
+/// couldn't be reached.
+pub fn witness_counter_advance(
+    advance: &CounterAdvance,
+) -> Result<Vec<OutputSignature>, sgx_status_t> {
+    let witness = WITNESS.read().unwrap();
+    let witness = witness.as_ref().ok_or_else(|| {
+        error!("no counter witness is set");
+        sgx_status_t::SGX_ERROR_SERVICE_UNAVAILABLE
+    })?;
+
+    witness.witness(advance).map_err(|e| {
+        error!("{}", e);
+        match e {
+            WitnessError::Refused(..) => sgx_status_t::SGX_ERROR_INVALID_STATE,
+            WitnessError::Unreachable(..) => sgx_status_t::SGX_ERROR_SERVICE_UNAVAILABLE,
+        }
+    })
+}
+
+/// Has the anytrust servers at the given URLs witness a freshness counter, by POSTing the advance
+/// to each server's `/witness-counter`. If a server fails after others have signed, the sealed
+/// state is stale until the same submission is retried
+pub struct HttpCounterWitness {
+    pub server_urls: Vec<String>,
+}
+
+impl HttpCounterWitness {
+    pub fn new(server_urls: &str) -> Self {
+        let server_urls = server_urls.split(',').map(|s| s.to_string()).collect();
+        HttpCounterWitness { server_urls }
+    }
+}
+
+impl CounterWitness for HttpCounterWitness {
+    fn witness(&self, advance: &CounterAdvance) -> Result<Vec<OutputSignature>, WitnessError> {
+        let mut body = Vec::new();
+        cli_util::save(&mut body, advance)
+            .map_err(|e| WitnessError::Unreachable("any".to_string(), e.to_string()))?;
+        let server_urls = self.server_urls.clone();
+
+        // This is called from inside an ecall, maybe on the service's own runtime. Block on the
+        // requests in a runtime of their own
+        thread::spawn(move || {
+            System::new("witness").block_on(async move {
+                let mut sigs = Vec::new();
+                for url in server_urls {
+                    sigs.push(request_witness(&url, body.clone()).await?);
+                }
+                Ok(sigs)
+            })
+        })
+        .join()
+        .unwrap()
+    }
+}
+
+async fn request_witness(base_url: &str, body: Vec<u8>) -> Result<OutputSignature, WitnessError> {
+    let unreachable = |reason: String| WitnessError::Unreachable(base_url.to_string(), reason);
+
+    let client = Client::builder().timeout(Duration::from_secs(5)).finish();
+    let post_path: Uri = [base_url, "/witness-counter"]
+        .concat()
+        .parse()
+        .map_err(|e: actix_web::http::uri::InvalidUri| unreachable(e.to_string()))?;
+
+    let mut res = client
+        .post(post_path)
+        .send_body(body)
+        .await
+        .map_err(|e| unreachable(e.to_string()))?;
+    let res_body = res.body().await.map_err(|e| unreachable(e.to_string()))?;
+
+    // The server only refuses with a client error. Anything else is a failure to reach it
+    match res.status() {
+        StatusCode::OK => cli_util::load(&res_body[..]).map_err(|e| unreachable(e.to_string())),
+        s if s.is_client_error() => Err(WitnessError::Refused(
+            base_url.to_string(),
+            String::from_utf8_lossy(&res_body).to_string(),
+        )),
+        s => Err(unreachable(s.to_string())),
+    }
+}
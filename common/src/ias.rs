@@ -0,0 +1,144 @@
+//! Verifies EPID quotes with the Intel Attestation Service (IAS). IAS checks the quote's signature
+//! and returns an attestation report signed by Intel. The report is trusted if its signing
+//! certificate is signed by Intel's report signing root CA, which the operator supplies.
+
+use crate::attestation::{AttestationError, QuoteVerifier};
+use interface::QuoteBody;
+use openssl::{hash::MessageDigest, sign::Verifier, x509::X509};
+use percent_encoding::percent_decode_str;
+use reqwest::blocking::{Client, Response};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::{env, fs};
+
+/// The development API. Subscriptions to the production API use
+/// https://api.trustedservices.intel.com/sgx
+const DEFAULT_IAS_URL: &str = "https://api.trustedservices.intel.com/sgx/dev";
+
+/// IAS echoes the quote back without its signature. This is how long that part is
+const QUOTE_BODY_LENGTH: usize = 432;
+
+/// The fields of an attestation report that are checked
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IasReport {
+    isv_enclave_quote_status: String,
+    isv_enclave_quote_body: String,
+}
+
+fn ias_err<E: Display>(e: E) -> AttestationError {
+    AttestationError::Ias(e.to_string())
+}
+
+/// Verifies quotes by sending them to IAS. Only quotes IAS reports as OK are accepted
+pub struct IasQuoteVerifier {
+    url: String,
+    api_key: String,
+    root_ca: X509,
+    client: Client,
+}
+
+impl IasQuoteVerifier {
+    /// Makes a verifier for the IAS API at `url`. `api_key` is the subscription key, and
+    /// `root_ca_pem` is Intel's report signing root CA certificate
+    pub fn new(url: &str, api_key: &str, root_ca_pem: &[u8]) -> Result<Self, AttestationError> {
+        Ok(IasQuoteVerifier {
+            url: url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            root_ca: X509::from_pem(root_ca_pem).map_err(ias_err)?,
+            client: Client::new(),
+        })
+    }
+
+    /// Makes a verifier from the environment. IAS_API_KEY is the subscription key and IAS_ROOT_CA
+    /// is the path of Intel's report signing root CA certificate in PEM. IAS_URL overrides the
+    /// development API
+    pub fn from_env() -> Result<Self, AttestationError> {
+        let api_key =
+            env::var("IAS_API_KEY").map_err(|e| ias_err(format!("IAS_API_KEY: {}", e)))?;
+        let root_ca_path =
+            env::var("IAS_ROOT_CA").map_err(|e| ias_err(format!("IAS_ROOT_CA: {}", e)))?;
+        let root_ca_pem = fs::read(&root_ca_path)
+            .map_err(|e| ias_err(format!("can't read {}: {}", root_ca_path, e)))?;
+        let url = env::var("IAS_URL").unwrap_or_else(|_| DEFAULT_IAS_URL.to_string());
+
+        IasQuoteVerifier::new(&url, &api_key, &root_ca_pem)
+    }
+
+    /// Checks that the report is signed by a certificate the root CA signed. `certs` is the
+    /// URL-encoded PEM chain IAS sends, starting with the signing certificate
+    fn verify_report(
+        &self,
+        report: &[u8],
+        sig: &[u8],
+        certs: &str,
+    ) -> Result<(), AttestationError> {
+        let certs = percent_decode_str(certs).decode_utf8().map_err(ias_err)?;
+        let chain = X509::stack_from_pem(certs.as_bytes()).map_err(ias_err)?;
+        let signing_cert = chain
+            .first()
+            .ok_or_else(|| ias_err("no report signing certificate"))?;
+
+        let root_pk = self.root_ca.public_key().map_err(ias_err)?;
+        if !signing_cert.verify(&root_pk).map_err(ias_err)? {
+            return Err(ias_err(
+                "report signing certificate is not signed by the root CA",
+            ));
+        }
+
+        let signing_pk = signing_cert.public_key().map_err(ias_err)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &signing_pk).map_err(ias_err)?;
+        verifier.update(report).map_err(ias_err)?;
+        if !verifier.verify(sig).map_err(ias_err)? {
+            return Err(ias_err("report signature does not verify"));
+        }
+
+        Ok(())
+    }
+}
+
+fn header<'a>(resp: &'a Response, name: &str) -> Result<&'a str, AttestationError> {
+    resp.headers()
+        .get(name)
+        .ok_or_else(|| ias_err(format!("response has no {} header", name)))?
+        .to_str()
+        .map_err(ias_err)
+}
+
+impl QuoteVerifier for IasQuoteVerifier {
+    fn verify_quote(&self, quote: &[u8]) -> Result<QuoteBody, AttestationError> {
+        let body = QuoteBody::parse(quote).ok_or(AttestationError::BadQuote)?;
+
+        let req = serde_json::json!({ "isvEnclaveQuote": base64::encode(quote) });
+        let resp = self
+            .client
+            .post(format!("{}/attestation/v4/report", self.url))
+            .header("Ocp-Apim-Subscription-Key", &self.api_key)
+            .header("Content-Type", "application/json")
+            .body(req.to_string())
+            .send()
+            .map_err(ias_err)?;
+        if !resp.status().is_success() {
+            return Err(ias_err(format!("IAS returned {}", resp.status())));
+        }
+
+        let sig = base64::decode(header(&resp, "X-IASReport-Signature")?).map_err(ias_err)?;
+        let certs = header(&resp, "X-IASReport-Signing-Certificate")?.to_string();
+        let report = resp.bytes().map_err(ias_err)?;
+        self.verify_report(&report, &sig, &certs)?;
+
+        let report: IasReport = serde_json::from_slice(&report).map_err(ias_err)?;
+        if report.isv_enclave_quote_status != "OK" {
+            return Err(AttestationError::QuoteStatus(
+                report.isv_enclave_quote_status,
+            ));
+        }
+        let echoed = base64::decode(&report.isv_enclave_quote_body).map_err(ias_err)?;
+        // QuoteBody::parse checked that the quote is longer than this
+        if echoed[..] != quote[..QUOTE_BODY_LENGTH] {
+            return Err(ias_err("the report is for another quote"));
+        }
+
+        Ok(body)
+    }
+}
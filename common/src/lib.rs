@@ -3,14 +3,20 @@ extern crate quick_error;
 extern crate serde;
 extern crate serde_cbor;
 extern crate sgx_types;
+#[cfg(feature = "sgx")]
 extern crate sgx_urts;
 extern crate tonic;
 
+pub mod attestation;
 pub mod cli_util;
 pub mod enclave;
+pub mod freshness;
+#[cfg(feature = "sgx")]
+pub mod ias;
 pub mod log_time;
 pub mod types;
 
+#[cfg(feature = "sgx")]
 mod ecall_wrapper;
 use enclave::EnclaveResult;
 
@@ -4,20 +4,16 @@ use ed25519_dalek::{
 };
 
 use interface::{
-    compute_anytrust_group_id, AttestedPublicKey, DcRoundMessage, DiffieHellmanSharedSecret,
-    EntityId, RateLimitNonce, RoundSecret, ServerPubKeyPackage, SgxProtectedKeyPub,
+    aggregate_digest, unblinded_share_digest, AttestedPublicKey, DcRoundMessage, EntityId,
+    OutputSignature, RateLimitNonce, RoundOutput, RoundSecret, ServerPubKeyPackage,
     UserSubmissionMessage,
 };
-use sha2::{Digest, Sha256};
 
 use std::collections::{BTreeMap, BTreeSet};
-use std::convert::TryInto;
 use std::prelude::v1::*;
 
 use core::fmt::Debug;
 
-use x25519_dalek::{PublicKey as xPublicKey, StaticSecret};
-
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_cbor;
@@ -92,16 +88,13 @@ pub trait Signable {
 
 impl Signable for AggregatedMessage {
     fn digest(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.input(b"Begin AggregatedMessage");
-        hasher.input(&self.anytrust_group_id);
-        for id in self.user_ids.iter() {
-            hasher.input(id);
-        }
-        hasher.input(&self.aggregated_msg.digest());
-        hasher.input(b"End AggregatedMessage");
-
-        hasher.result().to_vec()
+        // servers sign shares of aggregates in their enclaves, so this is defined in interface
+        aggregate_digest(
+            self.round,
+            &self.anytrust_group_id,
+            &self.user_ids,
+            &self.aggregated_msg,
+        )
     }
 
     fn get_sig(&self) -> Signature {
@@ -132,78 +125,12 @@ pub enum SubmissionMessage {
     AggSubmission(AggregatedMessage),
 }
 
-/// Secrets shared between anytrust servers and users.
-/// This data structure is used only by servers.
-/// This is the server side, the key is user's signing key
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct SharedSecretsDbServer {
-    pub round: u32,
-    /// a dictionary of keys
-    /// We use DiffieHellmanSharedSecret to store SharedSecret, since SharedSecret is ephemeral
-    pub db: BTreeMap<SgxProtectedKeyPub, DiffieHellmanSharedSecret>,
-}
-
-impl SharedSecretsDbServer {
-    pub fn anytrust_group_id(&self) -> EntityId {
-        let keys: Vec<SgxProtectedKeyPub> = self.db.keys().cloned().collect();
-        compute_anytrust_group_id(&keys)
-    }
-
-    pub fn derive_shared_secrets(
-        my_sk: &SecretKey,
-        other_pks: &BTreeMap<SgxProtectedKeyPub, SgxProtectedKeyPub>, // todo: what is the first and second SgxProtectedKeyPub?
-    ) -> Result<Self, SignatureError> {
-        // 1. Generate StaticSecret from server's secret key
-        let my_secret = StaticSecret::from(my_sk.to_bytes());
-        let mut server_secrets: BTreeMap<SgxProtectedKeyPub, DiffieHellmanSharedSecret> =
-            BTreeMap::new();
-
-        for (client_xpk, client_pk) in other_pks {
-            // 2. Derive the exchange pk from the client_xpk
-            let xpk = xPublicKey::from(client_xpk.0);
-            // 3. Compute the DH shared secret from client exchange pk and server secret
-            let shared_secret = my_secret.diffie_hellman(&xpk);
-            // 4. Save the ephemeral SharedSecret into DiffieHellmanSharedSecret
-            let shared_secret_bytes: [u8; 32] = shared_secret.to_bytes();
-            server_secrets.insert(
-                client_pk.to_owned(),
-                DiffieHellmanSharedSecret(shared_secret_bytes),
-            );
-        }
-
-        Ok(SharedSecretsDbServer {
-            db: server_secrets,
-            ..Default::default()
-        })
-    }
-
-    pub fn ratchet(&self) -> SharedSecretsDbServer {
-        let a = self
-            .db
-            .iter()
-            .map(|(&k, v)| {
-                let new_key = Sha256::digest(&v.0);
-                let secret_bytes: [u8; 32] = new_key
-                    .try_into()
-                    .expect("cannot convert Sha256 digest to [u8; 32");
-                let new_sec = DiffieHellmanSharedSecret(secret_bytes);
-
-                (k, new_sec)
-            })
-            .collect();
-
-        SharedSecretsDbServer {
-            round: self.round + 1,
-            db: a,
-        }
-    }
-}
-
-impl Default for SharedSecretsDbServer {
-    fn default() -> Self {
-        SharedSecretsDbServer {
-            round: 0,
-            db: BTreeMap::new(),
+impl SubmissionMessage {
+    /// The round this submission is for
+    pub fn round(&self) -> u32 {
+        match self {
+            SubmissionMessage::UserSubmission(m) => m.round,
+            SubmissionMessage::AggSubmission(m) => m.round,
         }
     }
 }
@@ -234,13 +161,7 @@ pub struct UnblindedAggregateShare {
 
 impl Signable for UnblindedAggregateShare {
     fn digest(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.input(b"Begin UnblindedAggregateShare");
-        hasher.input(self.encrypted_msg.digest());
-        hasher.input(self.key_share.digest());
-        hasher.input(b"End UnblindedAggregateShare");
-
-        hasher.result().to_vec()
+        unblinded_share_digest(&self.encrypted_msg.digest(), &self.key_share)
     }
 
     fn get_sig(&self) -> Signature {
@@ -277,9 +198,24 @@ pub fn deserialize_from_vec<T: DeserializeOwned>(bin: &[u8]) -> Result<T, serde_
 }
 
 /// The unblinded aggregate output by a single anytrust node
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct UnblindedAggregateShareBlob(pub Vec<u8>);
 
+/// A round output the anytrust leader has derived but not yet published, along with the shares
+/// it was derived from. Every server checks the output against the shares before co-signing it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoundOutputCandidate {
+    pub output: RoundOutput,
+    pub shares: Vec<UnblindedAggregateShareBlob>,
+}
+
+/// An anytrust server's signature on the output of the given round
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoundOutputSignature {
+    pub round: u32,
+    pub sig: OutputSignature,
+}
+
 pub trait MarshallAs<T> {
     fn marshal(&self) -> Result<T, serde_cbor::Error>;
 }
@@ -303,14 +239,12 @@ impl UnmarshalledAs<UnblindedAggregateShare> for UnblindedAggregateShareBlob {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::rngs::OsRng;
 
     #[test]
     fn test_sign_verify_agg_msg() -> Result<(), SignatureError> {
-        // generate secret key first
-        let mut csprng = OsRng::new().unwrap();
-
-        let sk = SecretKey::generate(&mut csprng);
+        // rand 0.6's OsRng does not implement the rand_core traits ed25519-dalek wants, so use
+        // a fixed secret key
+        let sk = SecretKey::from_bytes(&[7u8; SECRET_KEY_LENGTH])?;
         let mut agg_msg = AggregatedMessage::default();
 
         // sign the aggregated message using secret key
@@ -1,8 +1,8 @@
-// Ecalls
+// Ecalls. Without the `sgx` feature these run against the software enclave.
 
 const TEST_ENCLAVE_PATH: &'static str = "/sgxdcnet/lib/enclave.signed.so";
 
-use common::enclave::DcNetEnclave;
+use common::enclave::{DcNetEnclave, EnclaveBackend};
 
 extern crate base64;
 extern crate hex;
@@ -10,11 +10,18 @@ extern crate hexdump;
 extern crate interface;
 extern crate sgx_types;
 
+use common::freshness::{set_counter_witness, CounterWitness, WitnessError};
+use ed25519_dalek::{PublicKey, SecretKey};
 use env_logger::{Builder, Env};
-use interface::{DcMessage, EntityId, DC_NET_MESSAGE_LENGTH};
-use log::*;
-use std::time::Instant;
-use std::{collections::BTreeSet, vec};
+use interface::{
+    CounterAdvance, DcMessage, DcNetParams, DcRoundMessage, EntityId, MultiSignable,
+    OutputSignature, RoundOutput, SealedSharedSecretsDbServer, SealedSigPrivKey,
+    ServerPubKeyPackage, SgxProtectedKeyPub, SignRoundOutputReq, UserMsg, UserSubmissionReq,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, Once};
+use std::vec;
+use x25519_dalek::{PublicKey as xPublicKey, StaticSecret};
 
 fn init_logger() {
     let env = Env::default()
@@ -25,26 +32,161 @@ fn init_logger() {
     let _ = env_logger::builder().is_test(true).try_init();
 }
 
+/// What the anytrust servers know about the users' freshness counters. Shared by every test
+#[derive(Default)]
+struct WitnessState {
+    users: BTreeSet<EntityId>,
+    /// Each server's copy of each user's counter, with the nonce of the advance that moved it there
+    counters: BTreeMap<(usize, EntityId), (u32, [u8; 32])>,
+    /// Servers that fail the next advance for a user, as if they couldn't be reached
+    unreachable: BTreeSet<(usize, EntityId)>,
+    /// The sealed signing key and shared secrets of the server in an enclave a user registered
+    /// with. It witnesses that user's counter too
+    sealed_servers: BTreeMap<EntityId, (SealedSigPrivKey, SealedSharedSecretsDbServer)>,
+}
+
+static WITNESS_STATE: Mutex<Option<WitnessState>> = Mutex::new(None);
+static INSTALL_WITNESS: Once = Once::new();
+
+/// Stands in for the anytrust servers. Each key `create_server_pubkeys` uses is a server that
+/// witnesses the counters of registered users only, as is each sealed server a user registered
+/// with. Like the
+/// real servers, they're asked one after another, and sign their last advance again if it's
+/// repeated
+struct TestWitness;
+
+impl CounterWitness for TestWitness {
+    fn witness(&self, advance: &CounterAdvance) -> Result<Vec<OutputSignature>, WitnessError> {
+        let refused = |reason: &str| WitnessError::Refused("test".to_string(), reason.to_string());
+
+        let mut state = WITNESS_STATE.lock().unwrap();
+        let state = state.get_or_insert_with(WitnessState::default);
+        if !state.users.contains(&advance.user_id) {
+            return Err(refused("unknown user"));
+        }
+
+        let sealed_server = state.sealed_servers.get(&advance.user_id).cloned();
+        let enc = sealed_server
+            .as_ref()
+            .map(|_| DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap());
+        let mut sigs = Vec::new();
+        for i in 0..10 + enc.iter().count() {
+            if state.unreachable.remove(&(i, advance.user_id)) {
+                enc.map(DcNetEnclave::destroy);
+                return Err(WitnessError::Unreachable(i.to_string(), "down".to_string()));
+            }
+
+            let counter = state
+                .counters
+                .entry((i, advance.user_id))
+                .or_insert((0, [0u8; 32]));
+            let repeated = *counter == (advance.from + 1, advance.nonce);
+            if counter.0 != advance.from && !repeated {
+                enc.map(DcNetEnclave::destroy);
+                return Err(refused("stale counter"));
+            }
+            *counter = (advance.from + 1, advance.nonce);
+
+            sigs.push(match (&sealed_server, enc.as_ref()) {
+                (Some((sk, secrets)), Some(enc)) if i == 10 => {
+                    enc.sign_counter_advance(advance, secrets, sk).unwrap()
+                }
+                _ => {
+                    let sk = SecretKey::from_bytes(&[2 * i as u8; 32]).unwrap();
+                    let (sig, pk) = advance.sign(&sk).unwrap();
+                    OutputSignature { pk, sig }
+                }
+            });
+        }
+        enc.map(DcNetEnclave::destroy);
+
+        Ok(sigs)
+    }
+}
+
+/// Registers a user with the test witness, installing it first if needed
+fn register_with_witness(user_id: EntityId) {
+    INSTALL_WITNESS.call_once(|| set_counter_witness(Box::new(TestWitness)));
+    let mut state = WITNESS_STATE.lock().unwrap();
+    state
+        .get_or_insert_with(WitnessState::default)
+        .users
+        .insert(user_id);
+}
+
+/// Has the given test witness server fail the next advance for the given user
+fn make_unreachable(server: usize, user_id: EntityId) {
+    let mut state = WITNESS_STATE.lock().unwrap();
+    state
+        .get_or_insert_with(WitnessState::default)
+        .unreachable
+        .insert((server, user_id));
+}
+
+/// Signs an aggregate the way a top-level aggregator does
+fn sign_aggregate(
+    agg_sk: &SecretKey,
+    round: u32,
+    anytrust_group_id: &EntityId,
+    user_ids: &BTreeSet<EntityId>,
+    aggregated_msg: &DcRoundMessage,
+) -> OutputSignature {
+    use ed25519_dalek::{ExpandedSecretKey, PublicKey};
+    use interface::{aggregate_digest, SignatureBytes};
+
+    let pk = PublicKey::from(agg_sk);
+    let digest = aggregate_digest(round, anytrust_group_id, user_ids, aggregated_msg);
+    let sig = ExpandedSecretKey::from(agg_sk).sign(&digest, &pk);
+    OutputSignature {
+        pk,
+        sig: SignatureBytes(sig.to_bytes().to_vec()),
+    }
+}
+
+/// Makes `n` server key packages the same way `new_server` does, from fixed secrets
+fn create_server_pubkeys(
+    _enc: &DcNetEnclave,
+    n: usize,
+    params: &DcNetParams,
+) -> Vec<ServerPubKeyPackage> {
+    (0..n)
+        .map(|i| {
+            let sig_key = SecretKey::from_bytes(&[2 * i as u8; 32]).unwrap();
+            let kem_key = SecretKey::from_bytes(&[2 * i as u8 + 1; 32]).unwrap();
+            let kem_xpk = xPublicKey::from(&StaticSecret::from(kem_key.to_bytes()));
+
+            ServerPubKeyPackage {
+                sig: PublicKey::from(&sig_key),
+                kem: PublicKey::from(&kem_key),
+                xkem: SgxProtectedKeyPub(kem_xpk.to_bytes()),
+                dc_net_params: *params,
+            }
+        })
+        .collect()
+}
+
 #[test]
 fn user_submit_round_msg() {
     init_logger();
     let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
 
     // create server public keys
-    let spks = create_server_pubkeys(&enc, 10);
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 10, &params);
     let (user_reg_shared_secrets, user_reg_sealed_key, user_reg_uid, _) =
         enc.new_user(&spks).unwrap();
+    register_with_witness(user_reg_uid);
 
     let msg = UserMsg::TalkAndReserve {
-        msg: DcMessage(vec![1u8; DC_NET_MESSAGE_LENGTH]),
+        msg: DcMessage(vec![1u8; params.message_length]),
         prev_round_output: RoundOutput::default(),
-        times_participated: 0,
     };
 
     let req_1 = UserSubmissionReq {
         user_id: user_reg_uid,
-        anytrust_group_id: user_reg_shared_secrets.anytrust_group_id(),
+        anytrust_group_id: user_reg_shared_secrets.anytrust_group_id(&params),
         round: 0,
+        dc_net_params: params,
         msg,
         shared_secrets: user_reg_shared_secrets,
         server_pks: spks,
@@ -71,18 +213,19 @@ fn user_reserve_slot() {
     let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
 
     // create server public keys
-    let spks = create_server_pubkeys(&enc, 10);
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 10, &params);
     let (user_reg_shared_secrets, user_reg_sealed_key, user_reg_uid, _) =
         enc.new_user(&spks).unwrap();
+    register_with_witness(user_reg_uid);
 
-    let msg = UserMsg::Reserve {
-        times_participated: 0,
-    };
+    let msg = UserMsg::Reserve;
 
     let req_1 = UserSubmissionReq {
         user_id: user_reg_uid,
-        anytrust_group_id: user_reg_shared_secrets.anytrust_group_id(),
+        anytrust_group_id: user_reg_shared_secrets.anytrust_group_id(&params),
         round: 0,
+        dc_net_params: params,
         msg,
         shared_secrets: user_reg_shared_secrets,
         server_pks: spks,
@@ -95,18 +238,763 @@ fn user_reserve_slot() {
     enc.destroy();
 }
 
+#[test]
+fn user_submit_with_custom_params() {
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams {
+        num_users: 5,
+        n_slots: 5,
+        message_length: 32,
+        footprint_n_slots: 20,
+        sig_threshold: 2,
+    };
+    let spks = create_server_pubkeys(&enc, 3, &params);
+    let (user_reg_shared_secrets, user_reg_sealed_key, user_reg_uid, _) =
+        enc.new_user(&spks).unwrap();
+    register_with_witness(user_reg_uid);
+
+    let msg = UserMsg::TalkAndReserve {
+        msg: DcMessage::new(&params),
+        prev_round_output: RoundOutput {
+            dc_msg: DcRoundMessage::new(&params),
+            ..Default::default()
+        },
+    };
+
+    let req = UserSubmissionReq {
+        user_id: user_reg_uid,
+        anytrust_group_id: user_reg_shared_secrets.anytrust_group_id(&params),
+        round: 0,
+        dc_net_params: params,
+        msg,
+        shared_secrets: user_reg_shared_secrets,
+        server_pks: spks,
+    };
+
+    // The submission is shaped by the group's parameters, not the defaults
+    let (resp, _) = enc
+        .user_submit_round_msg(&req, &user_reg_sealed_key)
+        .unwrap();
+    assert!(resp.aggregated_msg.matches_params(&params));
+
+    // Changing the parameters without changing the group ID is rejected
+    let mut bad_req = req.clone();
+    bad_req.dc_net_params.n_slots = 4;
+    assert!(enc
+        .user_submit_round_msg(&bad_req, &user_reg_sealed_key)
+        .is_err());
+
+    // So is a group ID recomputed over parameters the servers didn't agree to
+    bad_req.anytrust_group_id = req.shared_secrets.anytrust_group_id(&bad_req.dc_net_params);
+    assert!(enc
+        .user_submit_round_msg(&bad_req, &user_reg_sealed_key)
+        .is_err());
+
+    enc.destroy();
+}
+
+#[test]
+fn user_submit_round_msg_batch() {
+    use common::enclave::EnclaveError;
+    use interface::SealedUserPrivKeys;
+    use sgx_types::sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 2, &params);
+    let users = enc.new_user_batch(&spks, 3).unwrap();
+    for (_, _, reg_blob) in users.iter() {
+        register_with_witness(EntityId::from(reg_blob));
+    }
+
+    let mut reqs: Vec<(UserSubmissionReq, SealedUserPrivKeys)> = users
+        .iter()
+        .map(|(shared_secrets, sealed_key, reg_blob)| {
+            let req = UserSubmissionReq {
+                user_id: EntityId::from(reg_blob),
+                anytrust_group_id: shared_secrets.anytrust_group_id(&params),
+                round: 0,
+                dc_net_params: params,
+                msg: UserMsg::Cover,
+                shared_secrets: shared_secrets.clone(),
+                server_pks: spks.clone(),
+            };
+            (req, sealed_key.clone())
+        })
+        .collect();
+
+    // Give the second user the first user's key. Only its submission should fail
+    reqs[1].1 = reqs[0].1.clone();
+
+    let results = enc.user_submit_round_msg_batch(&reqs).unwrap();
+    assert_eq!(results.len(), reqs.len());
+    match results[1] {
+        Err(EnclaveError::EnclaveLogicError(e)) => assert_eq!(e, SGX_ERROR_INVALID_PARAMETER),
+        _ => panic!("submission with the wrong key should fail"),
+    }
+
+    for i in [0, 2] {
+        let (blob, secrets) = results[i].as_ref().unwrap();
+        assert_eq!(blob.user_id, reqs[i].0.user_id);
+        assert_eq!(secrets.round, 1);
+    }
+
+    // Retrying the same submissions gives the same output again
+    let retried = enc.user_submit_round_msg_batch(&reqs).unwrap();
+    for i in [0, 2] {
+        let (blob, _) = retried[i].as_ref().unwrap();
+        assert_eq!(blob.digest(), results[i].as_ref().unwrap().0.digest());
+    }
+
+    // The failed user's secrets are still good, the others' old secrets are not
+    for req in reqs.iter_mut() {
+        req.0.round = 1;
+    }
+    let again = enc.user_submit_round_msg_batch(&reqs).unwrap();
+    assert!(again[0].is_err() && again[2].is_err());
+    reqs[1].1 = users[1].1.clone();
+    enc.user_submit_round_msg(&reqs[1].0, &reqs[1].1).unwrap();
+
+    enc.destroy();
+}
+
+/// A batch whose output doesn't fit in the first output buffer an ecall gets (64KiB). The
+/// submission must only happen once, so the users' new secrets must be good for the next round.
+#[test]
+fn user_submit_round_msg_batch_large_output() {
+    use interface::SealedUserPrivKeys;
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 2, &params);
+    let users = enc.new_user_batch(&spks, 8).unwrap();
+    for (_, _, reg_blob) in users.iter() {
+        register_with_witness(EntityId::from(reg_blob));
+    }
+
+    let mut reqs: Vec<(UserSubmissionReq, SealedUserPrivKeys)> = users
+        .iter()
+        .map(|(shared_secrets, sealed_key, reg_blob)| {
+            let req = UserSubmissionReq {
+                user_id: EntityId::from(reg_blob),
+                anytrust_group_id: shared_secrets.anytrust_group_id(&params),
+                round: 0,
+                dc_net_params: params,
+                msg: UserMsg::Cover,
+                shared_secrets: shared_secrets.clone(),
+                server_pks: spks.clone(),
+            };
+            (req, sealed_key.clone())
+        })
+        .collect();
+
+    for round in 0..2 {
+        let results = enc.user_submit_round_msg_batch(&reqs).unwrap();
+        let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert!(serde_cbor::to_vec(&results).unwrap().len() > 64 * 1024);
+
+        for ((req, _), (_, secrets)) in reqs.iter_mut().zip(results) {
+            assert_eq!(secrets.round, round + 1);
+            req.round = round + 1;
+            req.shared_secrets = secrets;
+        }
+    }
+
+    enc.destroy();
+}
+
 #[test]
 fn new_user() {
     init_logger();
 
     let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
-    let pks = create_server_pubkeys(&enc, 2);
-    let (_, user_reg_sealed_key, user_reg_uid, _) = enc.new_user(&pks).unwrap();
+    let pks = create_server_pubkeys(&enc, 2, &DcNetParams::default());
+    let (user_reg_shared_secrets, _, user_reg_uid, user_reg_blob) = enc.new_user(&pks).unwrap();
+
+    assert_eq!(EntityId::from(&user_reg_blob), user_reg_uid);
+    assert_eq!(user_reg_blob.role, "user");
+    assert_eq!(user_reg_shared_secrets.db.len(), pks.len());
+    // signing and key agreement use different keys
+    assert_ne!(user_reg_blob.pk, user_reg_blob.xpk);
+
+    enc.destroy();
+}
+
+/// The software enclave's registrations carry mock quotes that bind the user's keys
+#[cfg(not(feature = "sgx"))]
+#[test]
+fn new_user_attestation() {
+    use common::attestation::{verify_attested_key, EnclaveAllowList, MockQuoteVerifier};
+    use interface::SOFTWARE_ENCLAVE_MR_ENCLAVE;
+
+    init_logger();
+
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+    let pks = create_server_pubkeys(&enc, 2, &DcNetParams::default());
+    let (_, _, _, user_reg_blob) = enc.new_user(&pks).unwrap();
+
+    let allow_list = EnclaveAllowList {
+        mr_enclaves: vec![SOFTWARE_ENCLAVE_MR_ENCLAVE],
+        mr_signers: vec![],
+    };
+    verify_attested_key(&MockQuoteVerifier, &allow_list, &user_reg_blob).unwrap();
+
+    // a different enclave is not accepted
+    let other_enclave = EnclaveAllowList {
+        mr_enclaves: vec![[1u8; 32]],
+        mr_signers: vec![],
+    };
+    assert!(verify_attested_key(&MockQuoteVerifier, &other_enclave, &user_reg_blob).is_err());
+
+    enc.destroy();
+}
+
+#[test]
+fn user_submit_refuses_stale_secrets() {
+    use common::enclave::EnclaveError;
+    use sgx_types::sgx_status_t::{SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_INVALID_STATE};
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 2, &params);
+    let (secrets_0, user_sk, user_id, _) = enc.new_user(&spks).unwrap();
+    register_with_witness(user_id);
+
+    let req_for = |round: u32, shared_secrets| UserSubmissionReq {
+        user_id,
+        anytrust_group_id: secrets_0.anytrust_group_id(&params),
+        round,
+        dc_net_params: params,
+        msg: UserMsg::Cover,
+        shared_secrets,
+        server_pks: spks.clone(),
+    };
+    let refused_with = |r: Result<_, EnclaveError>, status| match r {
+        Err(EnclaveError::EnclaveLogicError(e)) => e == status,
+        _ => false,
+    };
+
+    let (_, secrets_1) = enc
+        .user_submit_round_msg(&req_for(0, secrets_0.clone()), &user_sk)
+        .unwrap();
+    assert_eq!(secrets_1.counter_value, secrets_0.counter_value + 1);
+
+    // Replaying the secrets from before the submission is refused, even for a later round
+    assert!(refused_with(
+        enc.user_submit_round_msg(&req_for(1, secrets_0.clone()), &user_sk),
+        SGX_ERROR_INVALID_STATE
+    ));
+
+    // The counter value is authenticated, so it can't be bumped to look fresh
+    let mut forged = secrets_0.clone();
+    forged.counter_value = secrets_1.counter_value;
+    assert!(refused_with(
+        enc.user_submit_round_msg(&req_for(1, forged), &user_sk),
+        SGX_ERROR_INVALID_PARAMETER
+    ));
+
+    // The latest secrets still work
+    let (_, secrets_2) = enc
+        .user_submit_round_msg(&req_for(1, secrets_1.clone()), &user_sk)
+        .unwrap();
+    assert!(refused_with(
+        enc.user_submit_round_msg(&req_for(5, secrets_1), &user_sk),
+        SGX_ERROR_INVALID_STATE
+    ));
+    enc.user_submit_round_msg(&req_for(5, secrets_2), &user_sk)
+        .unwrap();
+
+    enc.destroy();
+}
+
+/// The counters live with the servers, so an old copy of the secrets is still refused once the
+/// enclave has restarted. A user the servers don't know can't submit at all
+#[test]
+fn user_submit_refuses_stale_secrets_after_restart() {
+    use common::enclave::EnclaveError;
+    use sgx_types::sgx_status_t::SGX_ERROR_INVALID_STATE;
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
 
-    let pk = enc
-        .unseal_to_public_key_on_p256(&user_reg_sealed_key.0)
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 2, &params);
+    let (secrets_0, user_sk, user_id, _) = enc.new_user(&spks).unwrap();
+    register_with_witness(user_id);
+
+    let req_for = |user_id, round: u32, shared_secrets| UserSubmissionReq {
+        user_id,
+        anytrust_group_id: secrets_0.anytrust_group_id(&params),
+        round,
+        dc_net_params: params,
+        msg: UserMsg::Cover,
+        shared_secrets,
+        server_pks: spks.clone(),
+    };
+    let refused = |r: Result<_, EnclaveError>| match r {
+        Err(EnclaveError::EnclaveLogicError(e)) => e == SGX_ERROR_INVALID_STATE,
+        _ => false,
+    };
+
+    let (_, secrets_1) = enc
+        .user_submit_round_msg(&req_for(user_id, 0, secrets_0.clone()), &user_sk)
         .unwrap();
-    assert_eq!(EntityId::from(&pk), user_reg_uid);
+    enc.destroy();
+
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+    assert!(refused(enc.user_submit_round_msg(
+        &req_for(user_id, 1, secrets_0.clone()),
+        &user_sk
+    )));
+    enc.user_submit_round_msg(&req_for(user_id, 1, secrets_1), &user_sk)
+        .unwrap();
+
+    let (stranger_secrets, stranger_sk, stranger_id, _) = enc.new_user(&spks).unwrap();
+    assert!(refused(enc.user_submit_round_msg(
+        &req_for(stranger_id, 0, stranger_secrets),
+        &stranger_sk
+    )));
+
+    enc.destroy();
+}
+
+#[test]
+fn user_submit_recovers_from_witness_failure() {
+    use common::enclave::EnclaveError;
+    use sgx_types::sgx_status_t::{SGX_ERROR_INVALID_STATE, SGX_ERROR_SERVICE_UNAVAILABLE};
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 2, &params);
+    let (secrets_0, user_sk, user_id, _) = enc.new_user(&spks).unwrap();
+    register_with_witness(user_id);
+
+    let req_for = |round: u32, shared_secrets| UserSubmissionReq {
+        user_id,
+        anytrust_group_id: secrets_0.anytrust_group_id(&params),
+        round,
+        dc_net_params: params,
+        msg: UserMsg::Cover,
+        shared_secrets,
+        server_pks: spks.clone(),
+    };
+    let fails_with = |r: Result<_, EnclaveError>, status| match r {
+        Err(EnclaveError::EnclaveLogicError(e)) => e == status,
+        _ => false,
+    };
+
+    // The first server signs, then the second can't be reached
+    make_unreachable(1, user_id);
+    assert!(fails_with(
+        enc.user_submit_round_msg(&req_for(0, secrets_0.clone()), &user_sk),
+        SGX_ERROR_SERVICE_UNAVAILABLE
+    ));
+
+    // The first server has moved on, so only the same submission gets through
+    assert!(fails_with(
+        enc.user_submit_round_msg(&req_for(1, secrets_0.clone()), &user_sk),
+        SGX_ERROR_INVALID_STATE
+    ));
+    let (_, secrets_1) = enc
+        .user_submit_round_msg(&req_for(0, secrets_0.clone()), &user_sk)
+        .unwrap();
+
+    // Both servers are in line again
+    enc.user_submit_round_msg(&req_for(1, secrets_1), &user_sk)
+        .unwrap();
+    assert!(fails_with(
+        enc.user_submit_round_msg(&req_for(1, secrets_0.clone()), &user_sk),
+        SGX_ERROR_INVALID_STATE
+    ));
+
+    enc.destroy();
+}
+
+#[test]
+fn user_submit_enforces_rate_limit() {
+    use common::enclave::EnclaveError;
+    use interface::{DC_NET_MSGS_PER_WINDOW, DC_NET_ROUNDS_PER_WINDOW};
+    use sgx_types::sgx_status_t::SGX_ERROR_SERVICE_UNAVAILABLE;
+    use std::collections::BTreeSet;
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 2, &params);
+    let (mut secrets, user_sk, user_id, _) = enc.new_user(&spks).unwrap();
+    register_with_witness(user_id);
+    let anytrust_group_id = secrets.anytrust_group_id(&params);
+
+    let mut submit = |round: u32, msg: UserMsg| {
+        let req = UserSubmissionReq {
+            user_id,
+            anytrust_group_id,
+            round,
+            dc_net_params: params,
+            msg,
+            shared_secrets: secrets.clone(),
+            server_pks: spks.clone(),
+        };
+        let (blob, ratcheted) = enc.user_submit_round_msg(&req, &user_sk)?;
+        secrets = ratcheted;
+        Ok::<_, EnclaveError>(blob.rate_limit_nonce.unwrap())
+    };
+
+    // The enclave counts the reservations itself and gives each one a new nonce
+    let mut nonces = BTreeSet::new();
+    for round in 0..DC_NET_MSGS_PER_WINDOW {
+        assert!(nonces.insert(submit(round, UserMsg::Reserve).unwrap()));
+    }
+
+    // The window's budget is spent. Cover traffic doesn't count against it
+    let round = DC_NET_MSGS_PER_WINDOW;
+    match submit(round, UserMsg::Reserve) {
+        Err(EnclaveError::EnclaveLogicError(e)) => assert_eq!(e, SGX_ERROR_SERVICE_UNAVAILABLE),
+        _ => panic!("the rate limit should have been hit"),
+    }
+    submit(round, UserMsg::Cover).unwrap();
+
+    // The count starts over in the next window
+    let round = DC_NET_ROUNDS_PER_WINDOW;
+    assert!(nonces.insert(submit(round, UserMsg::Reserve).unwrap()));
+
+    enc.destroy();
+}
+
+#[test]
+fn user_submit_checks_prev_round_sigs() {
+    use common::enclave::EnclaveError;
+    use interface::{MultiSignable, OutputSignature};
+    use sgx_types::sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    // 2 of the 3 servers must sign each round output
+    let params = DcNetParams {
+        sig_threshold: 2,
+        ..DcNetParams::default()
+    };
+    let spks = create_server_pubkeys(&enc, 3, &params);
+    // the signing keys create_server_pubkeys uses
+    let server_sks: Vec<SecretKey> = (0..3)
+        .map(|i| SecretKey::from_bytes(&[2 * i as u8; 32]).unwrap())
+        .collect();
+    let (user_reg_shared_secrets, user_reg_sealed_key, user_reg_uid, _) =
+        enc.new_user(&spks).unwrap();
+    register_with_witness(user_reg_uid);
+
+    let submit_after = |signers: &[usize], tamper: bool| {
+        let mut prev_round_output = RoundOutput {
+            round: 0,
+            dc_msg: DcRoundMessage::new(&params),
+            server_sigs: vec![],
+        };
+        for &i in signers {
+            let (sig, pk) = prev_round_output.sign(&server_sks[i]).unwrap();
+            prev_round_output
+                .server_sigs
+                .push(OutputSignature { pk, sig });
+        }
+        if tamper {
+            prev_round_output.server_sigs[0].sig.0[0] ^= 1;
+        }
+
+        let req = UserSubmissionReq {
+            user_id: user_reg_uid,
+            anytrust_group_id: user_reg_shared_secrets.anytrust_group_id(&params),
+            round: 1,
+            dc_net_params: params,
+            msg: UserMsg::TalkAndReserve {
+                msg: DcMessage::new(&params),
+                prev_round_output,
+            },
+            shared_secrets: user_reg_shared_secrets.clone(),
+            server_pks: spks.clone(),
+        };
+        enc.user_submit_round_msg(&req, &user_reg_sealed_key)
+    };
+    let sigs_rejected = |r: Result<_, EnclaveError>| match r {
+        Err(EnclaveError::EnclaveLogicError(e)) => e == SGX_ERROR_INVALID_PARAMETER,
+        _ => false,
+    };
+
+    // Enough signatures get past the signature check. The submission may still fail if the
+    // user's reservation collided, but not because of the signatures
+    assert!(!sigs_rejected(submit_after(&[0, 1], false)));
+    assert!(!sigs_rejected(submit_after(&[0, 1, 2], true)));
+
+    // Too few signers, a repeated signer, or a forged signature is not enough
+    assert!(sigs_rejected(submit_after(&[], false)));
+    assert!(sigs_rejected(submit_after(&[2], false)));
+    assert!(sigs_rejected(submit_after(&[1, 1], false)));
+    assert!(sigs_rejected(submit_after(&[0, 1], true)));
+
+    enc.destroy();
+}
+
+#[test]
+fn user_submit_skips_rounds() {
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams::default();
+    let spks = create_server_pubkeys(&enc, 3, &params);
+    let (user_reg_shared_secrets, user_reg_sealed_key, user_reg_uid, _) =
+        enc.new_user(&spks).unwrap();
+    register_with_witness(user_reg_uid);
+
+    let req_for = |round: u32, shared_secrets| UserSubmissionReq {
+        user_id: user_reg_uid,
+        anytrust_group_id: user_reg_shared_secrets.anytrust_group_id(&params),
+        round,
+        dc_net_params: params,
+        msg: UserMsg::Cover,
+        shared_secrets,
+        server_pks: spks.clone(),
+    };
+
+    // A user that sat out every round so far, across an epoch boundary, can still submit
+    let (_, secrets) = enc
+        .user_submit_round_msg(
+            &req_for(150, user_reg_shared_secrets.clone()),
+            &user_reg_sealed_key,
+        )
+        .unwrap();
+    assert_eq!(secrets.round, 151);
+
+    // but can't go back to a round it has already submitted in
+    assert!(enc
+        .user_submit_round_msg(&req_for(150, secrets.clone()), &user_reg_sealed_key)
+        .is_err());
+    enc.user_submit_round_msg(&req_for(151, secrets), &user_reg_sealed_key)
+        .unwrap();
+
+    enc.destroy();
+}
+
+#[test]
+fn server_unblinds_user_submission() {
+    use ed25519_dalek::{Signature, Verifier};
+    use interface::{
+        aggregate_digest, unblinded_share_digest, MultiSignable, ServerUnblindReq, Xor,
+    };
+    use std::collections::BTreeSet;
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    let params = DcNetParams::default();
+    let (server_sk, server_kem_sk, server_secrets, server_pk) = enc.new_server(&params).unwrap();
+    let (user_secrets, user_sk, user_id, user_reg) = enc.new_user(&[server_pk.clone()]).unwrap();
+    register_with_witness(user_id);
+    let server_secrets = enc
+        .recv_user_registrations(&server_secrets, &server_kem_sk, &[user_reg])
+        .unwrap();
+    WITNESS_STATE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(WitnessState::default)
+        .sealed_servers
+        .insert(user_id, (server_sk.clone(), server_secrets.clone()));
+    let agg_sk = SecretKey::from_bytes(&[1u8; 32]).unwrap();
+    let server_secrets = enc
+        .recv_aggregator_registration(&server_secrets, &PublicKey::from(&agg_sk))
+        .unwrap();
+
+    // The user sits out the first epoch, so both sides have to ratchet their secrets
+    let round = 150;
+    let req = UserSubmissionReq {
+        user_id,
+        anytrust_group_id: user_secrets.anytrust_group_id(&params),
+        round,
+        dc_net_params: params,
+        msg: UserMsg::Cover,
+        shared_secrets: user_secrets,
+        server_pks: vec![server_pk.clone()],
+    };
+    let (submission, _) = enc.user_submit_round_msg(&req, &user_sk).unwrap();
+
+    let user_ids: BTreeSet<EntityId> = vec![user_id].into_iter().collect();
+    let unblind_req = ServerUnblindReq {
+        round,
+        anytrust_group_id: submission.anytrust_group_id,
+        user_ids: user_ids.clone(),
+        aggregated_msg: submission.aggregated_msg.clone(),
+        aggregator_sig: sign_aggregate(
+            &agg_sk,
+            round,
+            &submission.anytrust_group_id,
+            &user_ids,
+            &submission.aggregated_msg,
+        ),
+        dc_net_params: params,
+        shared_secrets: server_secrets,
+        partial_secrets: vec![],
+    };
+    let (key_share, share_sig, ratcheted) =
+        enc.unblind_aggregate(&unblind_req, &server_sk).unwrap();
+    assert_eq!(ratcheted.round, round + 1);
+
+    // With one server, its key share is the user's whole pad. Cover traffic is all zeros
+    let mut output = submission.aggregated_msg.clone();
+    output.xor_mut(&key_share);
+    assert!(output == DcRoundMessage::new(&params));
+
+    // The share is signed with the server's signing key
+    assert_eq!(share_sig.pk, server_pk.sig);
+    let agg_digest = aggregate_digest(
+        round,
+        &submission.anytrust_group_id,
+        &user_ids,
+        &submission.aggregated_msg,
+    );
+    server_pk
+        .sig
+        .verify(
+            &unblinded_share_digest(&agg_digest, &key_share),
+            &Signature::from_bytes(&share_sig.sig.0).unwrap(),
+        )
+        .unwrap();
+
+    // and so is the round output the enclave derives from the share
+    let output_req = SignRoundOutputReq {
+        round,
+        anytrust_group_id: submission.anytrust_group_id,
+        user_ids: user_ids.clone(),
+        aggregated_msg: submission.aggregated_msg.clone(),
+        key_shares: vec![(key_share.clone(), share_sig.clone())],
+        shared_secrets: ratcheted.clone(),
+    };
+    let round_output = enc.sign_round_output(&output_req, &server_sk).unwrap();
+    assert!(round_output.dc_msg == output);
+    assert_eq!(
+        round_output.verify_multisig(&[server_pk.sig], 1),
+        Ok(vec![0])
+    );
+
+    // It won't sign an output from a share it didn't sign, or before the round is unblinded
+    let mut bad_share = key_share.clone();
+    bad_share.aggregated_msg[(0, 0)] ^= 1;
+    let forged = SignRoundOutputReq {
+        key_shares: vec![(bad_share, share_sig.clone())],
+        ..output_req.clone()
+    };
+    assert!(enc.sign_round_output(&forged, &server_sk).is_err());
+    let early = SignRoundOutputReq {
+        shared_secrets: unblind_req.shared_secrets.clone(),
+        ..output_req
+    };
+    assert!(enc.sign_round_output(&early, &server_sk).is_err());
+
+    // The round can't be unblinded again with the ratcheted secrets
+    let again = ServerUnblindReq {
+        shared_secrets: ratcheted,
+        ..unblind_req
+    };
+    assert!(enc.unblind_aggregate(&again, &server_sk).is_err());
+
+    enc.destroy();
+}
+
+#[test]
+fn server_unblind_is_witnessed() {
+    use common::enclave::EnclaveError;
+    use interface::ServerUnblindReq;
+    use sgx_types::sgx_status_t::{SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_INVALID_STATE};
+
+    init_logger();
+    let enc = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+
+    // The other server in the group is the test witness' first
+    let params = DcNetParams::default();
+    let peer_pk = create_server_pubkeys(&enc, 1, &params).remove(0);
+    let (server_sk, server_kem_sk, server_secrets, server_pk) = enc.new_server(&params).unwrap();
+    register_with_witness(EntityId::from(&server_pk));
+    let server_secrets = enc
+        .recv_server_registration(&server_secrets, &peer_pk)
+        .unwrap();
+
+    let (_, _, user_id, user_reg) = enc.new_user(&[server_pk.clone(), peer_pk.clone()]).unwrap();
+    let server_secrets = enc
+        .recv_user_registrations(&server_secrets, &server_kem_sk, &[user_reg])
+        .unwrap();
+    let agg_sk = SecretKey::from_bytes(&[1u8; 32]).unwrap();
+    let secrets_0 = enc
+        .recv_aggregator_registration(&server_secrets, &PublicKey::from(&agg_sk))
+        .unwrap();
+
+    let anytrust_group_id = interface::compute_anytrust_group_id(
+        &[
+            SgxProtectedKeyPub(server_pk.kem.to_bytes()),
+            SgxProtectedKeyPub(peer_pk.kem.to_bytes()),
+        ],
+        &params,
+    );
+    let user_ids: BTreeSet<EntityId> = vec![user_id].into_iter().collect();
+    let req_for = |round: u32, signer: &SecretKey, shared_secrets| {
+        let aggregated_msg = DcRoundMessage::new(&params);
+        ServerUnblindReq {
+            round,
+            anytrust_group_id,
+            user_ids: user_ids.clone(),
+            aggregator_sig: sign_aggregate(
+                signer,
+                round,
+                &anytrust_group_id,
+                &user_ids,
+                &aggregated_msg,
+            ),
+            aggregated_msg,
+            dc_net_params: params,
+            shared_secrets,
+            partial_secrets: vec![],
+        }
+    };
+    let fails_with = |r: Result<_, EnclaveError>, status| match r {
+        Err(EnclaveError::EnclaveLogicError(e)) => e == status,
+        _ => false,
+    };
+
+    // Only the registered aggregator's aggregates are unblinded
+    let stranger = SecretKey::from_bytes(&[3u8; 32]).unwrap();
+    assert!(fails_with(
+        enc.unblind_aggregate(&req_for(0, &stranger, secrets_0.clone()), &server_sk),
+        SGX_ERROR_INVALID_PARAMETER
+    ));
+    let (share, _, secrets_1) = enc
+        .unblind_aggregate(&req_for(0, &agg_sk, secrets_0.clone()), &server_sk)
+        .unwrap();
+
+    // The same aggregate can be unblinded again from the same secrets, with the same result
+    let (again, _, _) = enc
+        .unblind_aggregate(&req_for(0, &agg_sk, secrets_0.clone()), &server_sk)
+        .unwrap();
+    assert!(again == share);
+
+    // but the old secrets can't unblind anything else, since the witness has moved on
+    assert!(fails_with(
+        enc.unblind_aggregate(&req_for(1, &agg_sk, secrets_0.clone()), &server_sk),
+        SGX_ERROR_INVALID_STATE
+    ));
+    enc.unblind_aggregate(&req_for(1, &agg_sk, secrets_1.clone()), &server_sk)
+        .unwrap();
+
+    // No aggregators can be added once a round is unblinded
+    assert!(enc
+        .recv_aggregator_registration(&secrets_1, &PublicKey::from(&stranger))
+        .is_err());
 
     enc.destroy();
 }
@@ -1,5 +1,8 @@
-/// everything about verifying attestations
-use interface::{AttestedPublicKey, ServerPubKeyPackage};
+/// everything about producing and checking attestations
+use cfg_if::cfg_if;
+use interface::*;
+use sgx_types::SgxResult;
+use std::vec::Vec;
 
 pub trait Attested {
     fn verify_attestation(&self) -> bool;
@@ -7,14 +10,64 @@ pub trait Attested {
 
 impl Attested for ServerPubKeyPackage {
     fn verify_attestation(&self) -> bool {
-        // warn!("TODO: actually check attestation for ServerPubKeyPackage");
+        // server keys are not attested yet. users trust the keys they are given
         true
     }
 }
 
 impl Attested for AttestedPublicKey {
+    /// Checks that the attestation binds this key. An enclave cannot check the quote signature
+    /// itself; that is left to a quote verifier outside (see common::attestation).
     fn verify_attestation(&self) -> bool {
-        // warn!("TODO: actually check attestation for ServerPubKeyPackage");
-        true
+        match QuoteBody::parse(&self.tee_linkable_attestation) {
+            Some(body) => body.report_data[..] == self.report_data()[..],
+            None => false,
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "trusted")] {
+        use core::{mem, ptr, slice};
+        use sgx_tse::rsgx_create_report;
+        use sgx_types::sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        use sgx_types::{sgx_report_data_t, sgx_report_t, sgx_target_info_t};
+
+        /// Creates an SGX report over the given report data, targeted at the quoting enclave.
+        /// The untrusted side turns the report into a quote.
+        pub fn attest_report_data(
+            report_data: [u8; REPORT_DATA_LENGTH],
+            target: &QuotingTarget,
+        ) -> SgxResult<Vec<u8>> {
+            if target.0.len() != mem::size_of::<sgx_target_info_t>() {
+                error!("quoting target info has wrong length {}", target.0.len());
+                return Err(SGX_ERROR_INVALID_PARAMETER);
+            }
+            let target_info: sgx_target_info_t =
+                unsafe { ptr::read_unaligned(target.0.as_ptr() as *const sgx_target_info_t) };
+
+            let report = rsgx_create_report(&target_info, &sgx_report_data_t { d: report_data })?;
+
+            let report_bytes = unsafe {
+                slice::from_raw_parts(
+                    &report as *const sgx_report_t as *const u8,
+                    mem::size_of::<sgx_report_t>(),
+                )
+            };
+            Ok(report_bytes.to_vec())
+        }
+    } else {
+        /// Outside of SGX there is no report to make. Returns a mock quote for the software
+        /// enclave's measurement instead.
+        pub fn attest_report_data(
+            report_data: [u8; REPORT_DATA_LENGTH],
+            _target: &QuotingTarget,
+        ) -> SgxResult<Vec<u8>> {
+            Ok(make_mock_quote(&QuoteBody {
+                mr_enclave: SOFTWARE_ENCLAVE_MR_ENCLAVE,
+                mr_signer: [0u8; 32],
+                report_data,
+            }))
+        }
     }
 }
@@ -20,15 +20,23 @@ use std::fmt::Debug;
 use ed25519_dalek::PublicKey;
 use x25519_dalek::{PublicKey as xPublicKey, StaticSecret};
 
-use std::convert::TryInto;
-
+use sgx_rand;
 use sgx_rand::Rng;
 
 /// A SharedSecretsDbClient is a map of entity public keys to DH secrets
 /// This is used by users only, the keys are server pks
+#[cfg_attr(feature = "untrusted", serde(crate = "serde"))]
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SharedSecretsDbClient {
+    /// The first round these secrets can be used for. The secrets are those of this round's epoch
     pub round: u32,
+    /// The user's freshness counter when these secrets were last sealed, and the signing keys of
+    /// the servers that witness it
+    pub counter_value: u32,
+    pub witness_pks: Vec<PublicKey>,
+    /// The number of times the user has talked or reserved in `participation_window`
+    pub participation_window: u32,
+    pub times_participated: u32,
     /// a dictionary of keys
     /// We use DiffieHellmanSharedSecret to store SharedSecret, since SharedSecret is ephemeral
     pub db: BTreeMap<SgxProtectedKeyPub, DiffieHellmanSharedSecret>,
@@ -38,15 +46,19 @@ impl Default for SharedSecretsDbClient {
     fn default() -> Self {
         SharedSecretsDbClient {
             round: 0,
+            counter_value: 0,
+            witness_pks: Vec::new(),
+            participation_window: 0,
+            times_participated: 0,
             db: BTreeMap::new(),
         }
     }
 }
 
 impl SharedSecretsDbClient {
-    pub fn anytrust_group_id(&self) -> EntityId {
+    pub fn anytrust_group_id(&self, params: &DcNetParams) -> EntityId {
         let keys: Vec<SgxProtectedKeyPub> = self.db.keys().cloned().collect();
-        compute_anytrust_group_id(&keys)
+        compute_anytrust_group_id(&keys, params)
     }
 
     /// Derive shared secrets (using DH). Used at registration time
@@ -78,55 +90,198 @@ impl SharedSecretsDbClient {
         })
     }
 
-    /// Return ratcheted keys
-    pub fn ratchet(&self) -> SharedSecretsDbClient {
-        let a = self
-            .db
+    /// Returns the secrets for the given round, ratcheted forward to the round's epoch. Returns
+    /// None if the round is earlier than `self.round`, since the secrets of past epochs are gone.
+    pub fn ratchet_to(&self, round: u32) -> Option<SharedSecretsDbClient> {
+        if round < self.round {
+            return None;
+        }
+
+        Some(SharedSecretsDbClient {
+            round,
+            counter_value: self.counter_value,
+            witness_pks: self.witness_pks.clone(),
+            participation_window: self.participation_window,
+            times_participated: self.times_participated,
+            db: ratchet_db(&self.db, self.round, round),
+        })
+    }
+
+    /// Returns the number of times the user has talked or reserved in the given window
+    pub fn times_participated_in(&self, window: u32) -> u32 {
+        if self.participation_window == window {
+            self.times_participated
+        } else {
+            0
+        }
+    }
+
+    /// Counts one more talk or reservation in the given window
+    pub fn record_participation(&mut self, window: u32) {
+        self.times_participated = self.times_participated_in(window) + 1;
+        self.participation_window = window;
+    }
+}
+
+/// A SharedSecretsDbServer is a map of entity public keys to DH secrets
+/// This is used by servers only, the keys are user signing pks
+#[cfg_attr(feature = "untrusted", serde(crate = "serde"))]
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct SharedSecretsDbServer {
+    /// The first round these secrets can be used for. The secrets are those of this round's epoch
+    pub round: u32,
+    /// The server's freshness counter. It moves on with every round the server unblinds, and the
+    /// other servers in the group witness it, so an old copy of these secrets can't be used to
+    /// unblind a round again
+    pub counter_value: u32,
+    /// This server's KEM pubkey and DC net parameters. With the peers' KEM pubkeys, these make up
+    /// the ID of the anytrust group
+    pub kem_pk: PublicKey,
+    pub dc_net_params: DcNetParams,
+    /// The other servers in the anytrust group
+    pub peers: Vec<ServerPubKeyPackage>,
+    /// The aggregators whose aggregates this server unblinds
+    pub toplevel_aggregators: Vec<PublicKey>,
+    /// a dictionary of keys
+    pub db: BTreeMap<SgxProtectedKeyPub, DiffieHellmanSharedSecret>,
+}
+
+impl SharedSecretsDbServer {
+    /// Derive shared secrets (using DH) with users as they register. `pk_db` maps each user's KEM
+    /// pubkey to its signing pubkey. The secrets are those of the first epoch
+    pub fn derive_shared_secrets(
+        my_sk: &SgxPrivateKey,
+        pk_db: &BTreeMap<SgxProtectedKeyPub, SgxProtectedKeyPub>,
+    ) -> SgxResult<Self> {
+        let my_secret = StaticSecret::from(my_sk.r);
+        let mut server_secrets = BTreeMap::new();
+
+        for (user_xpk, user_pk) in pk_db {
+            let shared_secret = my_secret.diffie_hellman(&xPublicKey::from(user_xpk.0));
+            server_secrets.insert(
+                user_pk.to_owned(),
+                DiffieHellmanSharedSecret(shared_secret.to_bytes()),
+            );
+        }
+
+        Ok(SharedSecretsDbServer {
+            db: server_secrets,
+            ..Default::default()
+        })
+    }
+
+    /// Returns the secrets for the given round, ratcheted forward to the round's epoch. Returns
+    /// None if the round is earlier than `self.round`. This must match
+    /// [`SharedSecretsDbClient::ratchet_to`].
+    pub fn ratchet_to(&self, round: u32) -> Option<SharedSecretsDbServer> {
+        if round < self.round {
+            return None;
+        }
+
+        Some(SharedSecretsDbServer {
+            round,
+            counter_value: self.counter_value,
+            kem_pk: self.kem_pk,
+            dc_net_params: self.dc_net_params,
+            peers: self.peers.clone(),
+            toplevel_aggregators: self.toplevel_aggregators.clone(),
+            db: ratchet_db(&self.db, self.round, round),
+        })
+    }
+
+    /// The ID of the server's anytrust group
+    pub fn anytrust_group_id(&self) -> EntityId {
+        let mut kem_pks = vec![SgxProtectedKeyPub(self.kem_pk.to_bytes())];
+        kem_pks.extend(
+            self.peers
+                .iter()
+                .map(|p| SgxProtectedKeyPub(p.kem.to_bytes())),
+        );
+        compute_anytrust_group_id(&kem_pks, &self.dc_net_params)
+    }
+
+    /// The signing keys of the other servers, which witness the freshness counter
+    pub fn witness_pks(&self) -> Vec<PublicKey> {
+        self.peers.iter().map(|p| p.sig).collect()
+    }
+
+    /// Maps the entity ID of each user to the secret shared with it
+    pub fn by_entity_id(&self) -> BTreeMap<EntityId, DiffieHellmanSharedSecret> {
+        self.db
             .iter()
-            .map(|(&k, v)| {
-                let new_key = Sha256::digest(&v.0);
-                let secret_bytes: [u8; 32] = new_key
-                    .try_into()
-                    .expect("cannot convert Sha256 digest to [u8; 32]");
-                let new_sec = DiffieHellmanSharedSecret(secret_bytes);
+            .map(|(pk, secret)| (EntityId::from(pk), *secret))
+            .collect()
+    }
+}
 
-                (k, new_sec)
-            })
-            .collect();
+/// The XOR of some users' pads for one round, as sealed in a SealedPartialRoundSecret
+#[cfg_attr(feature = "untrusted", serde(crate = "serde"))]
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PartialRoundSecret {
+    pub round: u32,
+    pub dc_net_params: DcNetParams,
+    pub user_ids: BTreeSet<EntityId>,
+    /// A hash of the users' shared secrets. A pad is useless once its user's secret changes,
+    /// e.g., because the user registered again
+    pub secrets_digest: [u8; 32],
+    pub round_secret: RoundSecret,
+}
 
-        SharedSecretsDbClient {
-            round: self.round + 1,
-            db: a,
+/// Ratchets the secrets in `db` from the epoch of round `from` to the epoch of round `to`
+fn ratchet_db(
+    db: &BTreeMap<SgxProtectedKeyPub, DiffieHellmanSharedSecret>,
+    from: u32,
+    to: u32,
+) -> BTreeMap<SgxProtectedKeyPub, DiffieHellmanSharedSecret> {
+    let mut db = db.clone();
+    for epoch in round_epoch(from)..round_epoch(to) {
+        for v in db.values_mut() {
+            *v = ratchet_epoch_secret(v, epoch);
         }
     }
+    db
+}
+
+/// Derives the secret of epoch `epoch + 1` from that of epoch `epoch`. This is one-way, so a
+/// leaked secret does not reveal the pads of earlier epochs.
+fn ratchet_epoch_secret(
+    secret: &DiffieHellmanSharedSecret,
+    epoch: u32,
+) -> DiffieHellmanSharedSecret {
+    let hk = Hkdf::<Sha256>::new(None, &secret.0);
+
+    // info is the label followed by the epoch
+    let mut info = [0u8; 24];
+    info[..20].copy_from_slice(b"zipnet epoch ratchet");
+    LittleEndian::write_u32(&mut info[20..], epoch);
+
+    let mut next = [0u8; SHARED_SECRET_LENGTH];
+    hk.expand(&info, &mut next)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    DiffieHellmanSharedSecret(next)
 }
 
 /// Derives the rate limit nonce for this round. This will be random if the user is submitting
-/// cover traffic. Otherwise it will be a pseudorandom function of the the window, private key, and
-/// times talked.
-/// Derives the rate limit nonce for this round. This will be random if the user is submitting
-/// cover traffic. Otherwise it will be a pseudorandom function of the the window, private key, and
-/// times talked.
+/// cover traffic. Otherwise it will be a pseudorandom function of the the window, the user's
+/// scheduling PRF key, and times talked. `times_participated` must come from the user's sealed
+/// state, not from the host.
 pub fn derive_round_nonce(
     anytrust_group_id: &EntityId,
     round: u32,
-    signing_sk: &SgxPrivateKey,
+    prf_key: &SgxPrivateKey,
     msg: &UserMsg,
+    times_participated: u32,
 ) -> SgxResult<RateLimitNonce> {
-    // Extract the talking counter. If this is cover traffic, return a random nonce immediately
-    let times_participated = match msg {
-        UserMsg::TalkAndReserve {
-            times_participated, ..
-        } => *times_participated,
-        UserMsg::Reserve { times_participated } => *times_participated,
-        UserMsg::Cover => {
-            let mut rand = sgx_rand::SgxRng::new().map_err(|e| {
-                error!("cant create rand {}", e);
-                SGX_ERROR_UNEXPECTED
-            })?;
-            return Ok(rand.gen::<RateLimitNonce>());
-        }
-    };
+    // If this is cover traffic, return a random nonce immediately
+    if msg.is_cover() {
+        let mut rand = sgx_rand::SgxRng::new().map_err(|e| {
+            error!("cant create rand {}", e);
+            SGX_ERROR_UNEXPECTED
+        })?;
+        let mut nonce = [0u8; 32];
+        rand.fill_bytes(&mut nonce);
+        return Ok(RateLimitNonce::from_bytes(&nonce));
+    }
 
     // Check that the times talked is less than the per-window limit
     if times_participated >= DC_NET_MSGS_PER_WINDOW {
@@ -136,28 +291,50 @@ pub fn derive_round_nonce(
 
     let window = round_window(round);
 
-    // Now deterministically make the nonce. nonce = H(sk, group_id, window, times_participated)
+    // Now deterministically make the nonce. nonce = H(prf_key, group_id, window, times_participated)
     let mut h = Sha256::new();
     h.input(b"rate-limit-nonce");
     h.input(anytrust_group_id);
-    h.input(signing_sk);
+    h.input(prf_key);
     h.input(window.to_le_bytes());
     h.input(times_participated.to_le_bytes());
 
     Ok(RateLimitNonce::from_bytes(&h.result()))
 }
 
+/// Derives the pad that the two holders of the given shared secret XOR into a round's messages.
+/// Users and servers must derive the same pad.
+pub fn derive_pad(
+    round: u32,
+    shared_secret: &DiffieHellmanSharedSecret,
+    params: &DcNetParams,
+) -> CryptoResult<RoundSecret> {
+    type MyRng = Aes128Rng; // This is defined in interface::aes_rng
+
+    let hk = Hkdf::<Sha256>::new(None, &shared_secret.as_ref());
+    // For cryptographic RNG's a seed of 256 bits is recommended, [u8; 32].
+    let mut seed = <MyRng as SeedableRng>::Seed::default();
+
+    // info contains round and window
+    let mut info = [0; 32];
+    let cursor = &mut info;
+    LittleEndian::write_u32(cursor, round);
+    hk.expand(&info, &mut seed)?;
+
+    let mut rng = MyRng::from_seed(seed);
+    Ok(DcRoundMessage::rand_from_csprng(params, &mut rng))
+}
+
 /// Derives a RoundSecret as the XOR of `HKDF(shared_secrets[i], round)` for all `i` in `Some(entity_ids_to_use)`,
 /// if entity_ids_to_use is None, for all `i` in `shared_secrets.keys()`.
 /// This function is used only by the clients
 pub fn derive_round_secret_client(
     round: u32,
     shared_secrets: &SharedSecretsDbClient,
+    params: &DcNetParams,
     entity_ids_to_use: Option<&BTreeSet<EntityId>>,
 ) -> CryptoResult<RoundSecret> {
-    type MyRng = Aes128Rng; // This is defined in interface::aes_rng
-
-    let mut round_secret = RoundSecret::default();
+    let mut round_secret = RoundSecret::new(params);
 
     for (pk, shared_secret) in shared_secrets.db.iter() {
         // skip entries not in entity_ids_to_use
@@ -168,18 +345,7 @@ pub fn derive_round_secret_client(
             }
         }
 
-        let hk = Hkdf::<Sha256>::new(None, &shared_secret.as_ref());
-        // For cryptographic RNG's a seed of 256 bits is recommended, [u8; 32].
-        let mut seed = <MyRng as SeedableRng>::Seed::default();
-
-        // info contains round and window
-        let mut info = [0; 32];
-        let cursor = &mut info;
-        LittleEndian::write_u32(cursor, round);
-        hk.expand(&info, &mut seed)?;
-
-        let mut rng = MyRng::from_seed(seed);
-        round_secret.xor_mut(&DcRoundMessage::rand_from_csprng(&mut rng));
+        round_secret.xor_mut(&derive_pad(round, shared_secret, params)?);
     }
 
     Ok(round_secret)
@@ -1,11 +1,14 @@
 use ed25519_dalek::SECRET_KEY_LENGTH;
 
+#[cfg_attr(feature = "untrusted", serde(crate = "serde"))]
 #[derive(Copy, Clone, Default, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
 pub struct SgxPrivateKey {
     pub r: [u8; SECRET_KEY_LENGTH],
 }
 
+use hkdf::Hkdf;
 use sgx_rand::{Rand, Rng};
+use sha2::Sha256;
 impl Rand for SgxPrivateKey {
     fn rand<R: Rng>(rng: &mut R) -> Self {
         let mut r = [0 as u8; SECRET_KEY_LENGTH];
@@ -15,6 +18,42 @@ impl Rand for SgxPrivateKey {
     }
 }
 
+/// A user's private keys: one for signing submissions, one for key agreement with the anytrust
+/// servers, and one for the PRF that picks scheduling slots and rate-limit nonces. They all come
+/// from one random seed, but each is derived under its own label, so none says anything about the
+/// others.
+#[cfg_attr(feature = "untrusted", serde(crate = "serde"))]
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+pub struct UserPrivKeys {
+    pub sig: SgxPrivateKey,
+    pub kem: SgxPrivateKey,
+    pub prf: SgxPrivateKey,
+}
+
+impl UserPrivKeys {
+    pub fn from_seed(seed: &[u8; SECRET_KEY_LENGTH]) -> Self {
+        let hk = Hkdf::<Sha256>::new(None, seed);
+        let derive = |label: &[u8]| {
+            let mut r = [0u8; SECRET_KEY_LENGTH];
+            hk.expand(label, &mut r)
+                .expect("32 bytes is a valid HKDF-SHA256 output length");
+            SgxPrivateKey { r }
+        };
+
+        UserPrivKeys {
+            sig: derive(b"user-sig-key"),
+            kem: derive(b"user-kem-key"),
+            prf: derive(b"user-sched-prf-key"),
+        }
+    }
+}
+
+impl Rand for UserPrivKeys {
+    fn rand<R: Rng>(rng: &mut R) -> Self {
+        UserPrivKeys::from_seed(&SgxPrivateKey::rand(rng).r)
+    }
+}
+
 use std::fmt::{Debug, Display, Formatter, Result};
 
 impl Debug for SgxPrivateKey {
@@ -57,8 +96,11 @@ pub fn sign_submission(
     msg: &UserSubmissionMessage,
     ssk: &SgxPrivateKey,
 ) -> CryptoResult<(SignatureBytes, PublicKey)> {
-    let dig = msg.digest();
+    sign_digest(&msg.digest(), ssk)
+}
 
+/// Signs the given digest. Returns the signature and the signing pubkey
+pub fn sign_digest(dig: &[u8], ssk: &SgxPrivateKey) -> CryptoResult<(SignatureBytes, PublicKey)> {
     // todo: expect is used
     let pk: PublicKey =
         (&SecretKey::from_bytes(&ssk.r).expect("Failed to generate pk from sk bytes")).into();
@@ -70,7 +112,7 @@ pub fn sign_submission(
 
     let keypair: Keypair =
         Keypair::from_bytes(&keypair_bytes).expect("Failed to generate keypair from bytes");
-    let sig = SignatureBytes(keypair.sign(dig.as_slice()).to_bytes().to_vec());
+    let sig = SignatureBytes(keypair.sign(dig).to_bytes().to_vec());
 
     Ok((sig, pk))
 }
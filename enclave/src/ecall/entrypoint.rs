@@ -0,0 +1,341 @@
+use super::{server, submit, user};
+
+use ed25519_dalek::PublicKey;
+use interface::*;
+use sgx_status_t::{
+    SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_OUT_OF_MEMORY, SGX_ERROR_UNEXPECTED, SGX_SUCCESS,
+};
+use sgx_types::{sgx_status_t, SgxResult};
+
+use std::boxed::Box;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::SgxMutex;
+
+macro_rules! match_ecall_ids {
+    (
+        $ecall_id:ident,$inp:ident,$inp_len:ident,$out:ident,$out_cap:ident,$out_used:ident,
+        $(($name:ident, $type_i:ty, $type_o: ty, $impl:expr), )+
+    ) => {
+        match $ecall_id {
+            EcallId::EcallFetchOutput => fetch_output($inp,$inp_len,$out,$out_cap,$out_used),
+            $(EcallId::$name => generic_ecall::<$type_i,$type_o>($ecall_id,$inp,$inp_len,$out,$out_cap,$out_used,$impl),)+
+        }
+    }
+}
+
+use std::vec::Vec;
+
+#[no_mangle]
+pub extern "C" fn ecall_entrypoint(
+    ecall_id_raw: u8,
+    inp: *const u8,
+    inp_len: usize,
+    output: *mut u8,
+    output_cap: usize,
+    output_used: *mut usize,
+) -> sgx_status_t {
+    // let start = Instant::now();
+
+    let env = Env::default()
+        .filter_or("ENCLAVE_LOG_LEVEL", interface::ENCLAVE_LOG_LEVEL)
+        .write_style_or("ENCLAVE_LOG_STYLE", "always");
+    let _ = Builder::from_env(env).try_init();
+
+    let ecall_id = match EcallId::from_repr(ecall_id_raw) {
+        Some(i) => i,
+        None => {
+            error!("wrong ecall id {}", ecall_id_raw);
+            return SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+
+    // make sure this matches exact with that in enclave_wrapper.rs
+    let r = match_ecall_ids! {
+        ecall_id, inp, inp_len, output, output_cap, output_used,
+        (
+            EcallNewUser,
+            // input
+            (Vec < ServerPubKeyPackage >, QuotingTarget),
+            // output
+            (SealedSharedSecretsDbClient, SealedUserPrivKeys, UserRegistrationBlob),
+            user::new_user
+        ),
+        (
+            EcallNewUserBatch,
+            // input
+            (Vec < ServerPubKeyPackage >, QuotingTarget, usize),
+            // output
+            Vec<(SealedSharedSecretsDbClient, SealedUserPrivKeys, UserRegistrationBlob)>,
+            user::new_user_batch
+        ),
+        (
+            EcallUserSubmit,
+            (UserSubmissionReq, SealedUserPrivKeys),
+            (UserSubmissionBlob, SealedSharedSecretsDbClient),
+            submit::user_submit_internal
+        ),
+        (
+            EcallUserSubmitBatch,
+            Vec<(UserSubmissionReq, SealedUserPrivKeys)>,
+            Vec<Result<(UserSubmissionBlob, SealedSharedSecretsDbClient), u32>>,
+            submit::user_submit_batch
+        ),
+        (
+            EcallServerNew,
+            DcNetParams,
+            (SealedSigPrivKey, SealedKemPrivKey, SealedSharedSecretsDbServer, ServerPubKeyPackage),
+            server::new_server
+        ),
+        (
+            EcallRecvUserReg,
+            (SealedSharedSecretsDbServer, SealedKemPrivKey, Vec<UserRegistrationBlob>),
+            SealedSharedSecretsDbServer,
+            server::recv_user_registration_batch
+        ),
+        (
+            EcallRecvServerReg,
+            (SealedSharedSecretsDbServer, ServerPubKeyPackage),
+            SealedSharedSecretsDbServer,
+            server::recv_server_registration
+        ),
+        (
+            EcallRecvAggregatorReg,
+            (SealedSharedSecretsDbServer, PublicKey),
+            SealedSharedSecretsDbServer,
+            server::recv_aggregator_registration
+        ),
+        (
+            EcallUnblindAggregatePartial,
+            (u32, SealedSharedSecretsDbServer, DcNetParams, BTreeSet<EntityId>),
+            SealedPartialRoundSecret,
+            server::unblind_aggregate_partial
+        ),
+        (
+            EcallUnblindAggregate,
+            (ServerUnblindReq, SealedSigPrivKey),
+            (RoundSecret, OutputSignature, SealedSharedSecretsDbServer),
+            server::unblind_aggregate
+        ),
+        (
+            EcallSignRoundOutput,
+            (SignRoundOutputReq, SealedSigPrivKey),
+            RoundOutput,
+            server::sign_round_output
+        ),
+        (
+            EcallSignCounterAdvance,
+            (CounterAdvance, SealedSharedSecretsDbServer, SealedSigPrivKey),
+            OutputSignature,
+            server::sign_counter_advance
+        ),
+    };
+    //
+    // warn!("{:?} finished after {:?}", ecall_id, start.elapsed());
+
+    r
+}
+
+macro_rules! unmarshal_or_abort {
+    ( $T:ty, $ptr:expr,$len:expr ) => {
+        match serde_cbor::from_slice::<$T>(unsafe { slice::from_raw_parts($ptr, $len) }) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Err unmarshal: {}", e);
+                return SGX_ERROR_INVALID_PARAMETER;
+            }
+        }
+    };
+}
+
+use env_logger::{Builder, Env};
+use serde::Serialize;
+use sgx_types::SgxError;
+
+/// The most outputs kept for fetching. The untrusted side fetches an output right after the ecall
+/// that made it, so only concurrent ecalls or a caller that gave up leave outputs here
+const MAX_PENDING_OUTPUTS: usize = 64;
+
+/// Outputs that didn't fit in the buffer the untrusted side gave, by ticket. Ecalls can have effects
+/// (e.g., moving a freshness counter), so they must not be run again just to get their output.
+/// Instead the output waits here until the untrusted side fetches it with EcallFetchOutput. Once
+/// there are MAX_PENDING_OUTPUTS of them, the oldest is dropped to make room.
+#[derive(Default)]
+struct PendingOutputs {
+    next_ticket: u64,
+    outputs: BTreeMap<u64, Vec<u8>>,
+}
+
+fn pending_outputs() -> &'static SgxMutex<PendingOutputs> {
+    static PENDING: AtomicPtr<SgxMutex<PendingOutputs>> = AtomicPtr::new(ptr::null_mut());
+
+    let mut pending = PENDING.load(Ordering::Acquire);
+    if pending.is_null() {
+        let new = Box::into_raw(Box::new(SgxMutex::new(PendingOutputs::default())));
+        pending = match PENDING.compare_exchange(
+            ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => new,
+            // someone else got there first
+            Err(existing) => {
+                drop(unsafe { Box::from_raw(new) });
+                existing
+            }
+        };
+    }
+
+    // the map is never freed
+    unsafe { &*pending }
+}
+
+/// serialize v to outbuf. If outbuf_cap is too small, the serialized output is kept in
+/// [`PendingOutputs`] and its ticket is written to outbuf instead. outbuf_used is set to the size
+/// needed and SGX_ERROR_OUT_OF_MEMORY is returned. The caller can then fetch the output with
+/// EcallFetchOutput and a buffer that big.
+fn serialize_to_ptr<T: Serialize>(
+    v: &T,
+    outbuf: *mut u8,
+    outbuf_cap: usize,
+    outbuf_used: *mut usize,
+) -> SgxError {
+    let serialized = serde_cbor::to_vec(v).map_err(|e| {
+        error!("error serializing: {}", e);
+        SGX_ERROR_INVALID_PARAMETER
+    })?;
+
+    if serialized.len() > outbuf_cap {
+        debug!(
+            "not enough output to write serialized message. need {} got {}",
+            serialized.len(),
+            outbuf_cap,
+        );
+        if outbuf_cap < mem::size_of::<u64>() {
+            error!("output buffer can't even hold a ticket");
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+
+        let needed = serialized.len();
+        let ticket = {
+            let mut pending = pending_outputs().lock().map_err(|_| SGX_ERROR_UNEXPECTED)?;
+            let ticket = pending.next_ticket;
+            pending.next_ticket += 1;
+            while pending.outputs.len() >= MAX_PENDING_OUTPUTS {
+                let oldest = *pending.outputs.keys().next().unwrap();
+                let dropped = pending.outputs.remove(&oldest).unwrap();
+                warn!(
+                    "dropping unfetched output with ticket {} ({} bytes)",
+                    oldest,
+                    dropped.len()
+                );
+            }
+            pending.outputs.insert(ticket, serialized);
+            ticket
+        };
+
+        unsafe {
+            outbuf.copy_from(ticket.to_le_bytes().as_ptr(), mem::size_of::<u64>());
+            *outbuf_used = needed;
+        }
+        return Err(SGX_ERROR_OUT_OF_MEMORY);
+    }
+
+    unsafe {
+        // write serialized output to outbuf
+        outbuf.copy_from(serialized.as_ptr(), serialized.len());
+        *outbuf_used = serialized.len();
+    }
+
+    Ok(())
+}
+
+/// Copies out an output that [`serialize_to_ptr`] kept, and forgets it. The input is the ticket
+/// that was written in its place.
+fn fetch_output(
+    inp: *const u8,
+    inp_len: usize,
+    output: *mut u8,
+    output_cap: usize,
+    output_used: *mut usize,
+) -> sgx_status_t {
+    let ticket: u64 = unmarshal_or_abort!(u64, inp, inp_len);
+
+    let mut pending = match pending_outputs().lock() {
+        Ok(p) => p,
+        Err(_) => return SGX_ERROR_UNEXPECTED,
+    };
+
+    let len = match pending.outputs.get(&ticket) {
+        Some(out) => out.len(),
+        None => {
+            error!("no pending output with ticket {}", ticket);
+            return SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+    if len > output_cap {
+        // keep it, so the caller can try again
+        unsafe {
+            *output_used = len;
+        }
+        return SGX_ERROR_OUT_OF_MEMORY;
+    }
+
+    let out = pending.outputs.remove(&ticket).unwrap();
+    unsafe {
+        output.copy_from(out.as_ptr(), out.len());
+        *output_used = out.len();
+    }
+
+    SGX_SUCCESS
+}
+
+use std::untrusted::time::InstantEx; // get time for perf test
+
+fn generic_ecall<I, O>(
+    ecall_id: EcallId,
+    inp: *const u8,
+    inp_len: usize,
+    output: *mut u8,
+    output_cap: usize,
+    output_used: *mut usize,
+    internal_fn: fn(&I) -> SgxResult<O>,
+) -> sgx_status_t
+where
+    I: serde::de::DeserializeOwned,
+    O: serde::Serialize,
+{
+    let start_time = std::time::Instant::now();
+
+    debug!("serving {}", ecall_id.as_str());
+
+    let input: I = unmarshal_or_abort!(I, inp, inp_len);
+
+    debug!("input unmarshalled. {} bytes", inp_len);
+
+    let result = match internal_fn(&input) {
+        Ok(o) => o,
+        Err(e) => return e,
+    };
+
+    let ret = match serialize_to_ptr(&result, output, output_cap, output_used) {
+        Ok(_) => SGX_SUCCESS,
+        // the untrusted side will fetch it with the size we reported
+        Err(SGX_ERROR_OUT_OF_MEMORY) => SGX_ERROR_OUT_OF_MEMORY,
+        Err(e) => {
+            error!("[IN] can't write to untrusted land {}", e);
+            e
+        }
+    };
+    debug!(
+        "done serving {}. took {} us",
+        ecall_id.as_str(),
+        start_time.elapsed().as_micros()
+    );
+
+    ret
+}
@@ -1,5 +1,6 @@
 use core::convert::TryFrom;
 use interface::*;
+use sgx_rand;
 use sgx_rand::{Rand, Rng};
 use sgx_types::sgx_status_t::SGX_ERROR_UNEXPECTED;
 use sgx_types::SgxResult;
@@ -10,36 +11,45 @@ use std::fmt::Formatter;
 use std::string::ToString;
 use std::vec;
 
-use crate::crypto::SgxPrivateKey;
+use crate::attestation::attest_report_data;
+use crate::crypto::UserPrivKeys;
 use crypto::ed25519pk_from_secret;
 use ed25519_dalek::SecretKey;
 use ed25519_dalek::{Keypair, SECRET_KEY_LENGTH};
 use x25519_dalek::PublicKey as xPublicKey;
 use x25519_dalek::StaticSecret;
 
-pub fn new_keypair_ext_internal(role: &str) -> SgxResult<(SgxPrivateKey, AttestedPublicKey)> {
+/// Generates a user's keys and attests to the signing and KEM pubkeys. Inside SGX the attestation
+/// is a report targeted at the given quoting enclave, which the caller must turn into a quote. The
+/// software enclave produces a mock quote directly.
+pub fn new_keypair_ext_internal(
+    role: &str,
+    quoting_target: &QuotingTarget,
+) -> SgxResult<(UserPrivKeys, AttestedPublicKey)> {
     let mut rand = sgx_rand::SgxRng::new().map_err(|e| {
         error!("can't create rand {}", e);
         SGX_ERROR_UNEXPECTED
     })?;
 
-    // generate a random secret key
-    let secret = SgxPrivateKey::rand(&mut rand);
+    // generate random secret keys
+    let keys = UserPrivKeys::rand(&mut rand);
 
-    let x_secret = StaticSecret::from(secret.r);
+    let x_secret = StaticSecret::from(keys.kem.r);
     let xpk = xPublicKey::from(&x_secret);
-    let pk = ed25519pk_from_secret(&secret)?;
+    let pk = ed25519pk_from_secret(&keys.sig)?;
 
     log::debug!("new key pair created");
     log::debug!("xpk {}", hex::encode(xpk.to_bytes()));
     log::debug!(" pk {}", hex::encode(pk.to_bytes()));
 
-    let attested_key = AttestedPublicKey {
+    let mut attested_key = AttestedPublicKey {
         pk: SgxProtectedKeyPub(pk.to_bytes()),
         xpk: SgxProtectedKeyPub(xpk.to_bytes()),
         role: role.to_string(),
-        tee_linkable_attestation: vec![0], // TODO: add attestation
+        tee_linkable_attestation: vec![],
     };
+    attested_key.tee_linkable_attestation =
+        attest_report_data(attested_key.report_data(), quoting_target)?;
 
-    Ok((secret, attested_key))
+    Ok((keys, attested_key))
 }
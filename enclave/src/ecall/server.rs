@@ -0,0 +1,515 @@
+use crate::attestation::Attested;
+use crate::freshness;
+use crypto::{
+    derive_pad, ed25519pk_from_secret, sign_digest, PartialRoundSecret, SgxPrivateKey,
+    SharedSecretsDbServer,
+};
+
+use ed25519_dalek::PublicKey;
+use interface::*;
+use sgx_rand;
+use sgx_rand::Rand;
+use sgx_types::sgx_status_t::{SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_UNEXPECTED};
+use sgx_types::SgxResult;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::vec::Vec;
+use unseal::{SealInto, UnsealableInto};
+
+use x25519_dalek::{PublicKey as xPublicKey, StaticSecret};
+
+/// Makes a new anytrust server. Generates a signing key and a KEM key, and returns them sealed,
+/// along with an empty sealed database of shared secrets and the server's pubkey package.
+pub fn new_server(
+    dc_net_params: &DcNetParams,
+) -> SgxResult<(
+    SealedSigPrivKey,
+    SealedKemPrivKey,
+    SealedSharedSecretsDbServer,
+    ServerPubKeyPackage,
+)> {
+    if let Err(e) = dc_net_params.validate() {
+        error!("invalid DC net parameters: {}", e);
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let mut rand = sgx_rand::SgxRng::new().map_err(|e| {
+        error!("can't create rand {}", e);
+        SGX_ERROR_UNEXPECTED
+    })?;
+    let sig_key = SgxPrivateKey::rand(&mut rand);
+    let kem_key = SgxPrivateKey::rand(&mut rand);
+
+    let kem_xpk = xPublicKey::from(&StaticSecret::from(kem_key.r));
+    let pubkey_pkg = ServerPubKeyPackage {
+        sig: ed25519pk_from_secret(&sig_key)?,
+        kem: ed25519pk_from_secret(&kem_key)?,
+        xkem: SgxProtectedKeyPub(kem_xpk.to_bytes()),
+        dc_net_params: *dc_net_params,
+    };
+
+    let shared_secrets = SharedSecretsDbServer {
+        kem_pk: pubkey_pkg.kem,
+        dc_net_params: *dc_net_params,
+        ..Default::default()
+    };
+
+    Ok((
+        sig_key.seal_into()?,
+        kem_key.seal_into()?,
+        shared_secrets.seal_into()?,
+        pubkey_pkg,
+    ))
+}
+
+/// Adds another server of the anytrust group to the server's database. Its KEM key goes into the
+/// group ID that aggregates are checked against, and it witnesses the server's freshness counter
+/// from then on. A server that registers again replaces its old keys. The group is fixed once the
+/// first round is unblinded, so secrets sealed after that have every server a round output needs.
+pub fn recv_server_registration(
+    (shared_secrets, server_pk): &(SealedSharedSecretsDbServer, ServerPubKeyPackage),
+) -> SgxResult<SealedSharedSecretsDbServer> {
+    let mut shared_secrets: SharedSecretsDbServer = shared_secrets.unseal_into()?;
+    if shared_secrets.round > 0 {
+        error!(
+            "can't add a server after unblinding. the secrets are at round {}",
+            shared_secrets.round
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    if server_pk.dc_net_params != shared_secrets.dc_net_params {
+        error!(
+            "server {} has DC net parameters {:?}, expected {:?}",
+            EntityId::from(server_pk),
+            server_pk.dc_net_params,
+            shared_secrets.dc_net_params
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    if server_pk.kem == shared_secrets.kem_pk {
+        error!("a server can't register with itself");
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    shared_secrets
+        .peers
+        .retain(|p| p.sig != server_pk.sig && p.kem != server_pk.kem);
+    shared_secrets.peers.push(server_pk.clone());
+
+    shared_secrets.seal_into()
+}
+
+/// Adds a top-level aggregator to the server's database. Only aggregates it signs are unblinded.
+/// The aggregators are fixed once the first round is unblinded, so the host can't add a key of its
+/// own later on and sign whatever aggregate it likes.
+pub fn recv_aggregator_registration(
+    (shared_secrets, agg_pk): &(SealedSharedSecretsDbServer, PublicKey),
+) -> SgxResult<SealedSharedSecretsDbServer> {
+    let mut shared_secrets: SharedSecretsDbServer = shared_secrets.unseal_into()?;
+    if shared_secrets.round > 0 {
+        error!(
+            "can't add an aggregator after unblinding. the secrets are at round {}",
+            shared_secrets.round
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    if !shared_secrets.toplevel_aggregators.contains(agg_pk) {
+        shared_secrets.toplevel_aggregators.push(*agg_pk);
+    }
+
+    shared_secrets.seal_into()
+}
+
+/// Derives the secrets shared with the given users and adds them to the server's database. A user
+/// that registers again gets a new secret. The host must have checked the users' attestations
+/// against its allow list; this only checks that each attestation binds the user's keys.
+pub fn recv_user_registration_batch(
+    (shared_secrets, decap_key, user_regs): &(
+        SealedSharedSecretsDbServer,
+        SealedKemPrivKey,
+        Vec<UserRegistrationBlob>,
+    ),
+) -> SgxResult<SealedSharedSecretsDbServer> {
+    let mut shared_secrets: SharedSecretsDbServer = shared_secrets.unseal_into()?;
+    let decap_key: SgxPrivateKey = decap_key.unseal_into()?;
+
+    let mut kem_db: BTreeMap<SgxProtectedKeyPub, SgxProtectedKeyPub> = BTreeMap::new();
+    for u in user_regs {
+        if !u.verify_attestation() {
+            error!("cannot verify attestation of user {}", EntityId::from(u));
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        kem_db.insert(u.xpk, u.pk);
+    }
+
+    // The new secrets are those of the first epoch, like the users'. Ratchet them forward to the
+    // epoch the other secrets are in
+    let new_secrets = SharedSecretsDbServer::derive_shared_secrets(&decap_key, &kem_db)?
+        .ratchet_to(shared_secrets.round)
+        .ok_or(SGX_ERROR_UNEXPECTED)?;
+    shared_secrets.db.extend(new_secrets.db);
+
+    shared_secrets.seal_into()
+}
+
+/// Derives the XOR of the given users' pads for the given round and seals it for
+/// [`unblind_aggregate`]. The host uses this to derive pads on many threads, or ahead of time.
+pub fn unblind_aggregate_partial(
+    (round, shared_secrets, dc_net_params, user_ids): &(
+        u32,
+        SealedSharedSecretsDbServer,
+        DcNetParams,
+        BTreeSet<EntityId>,
+    ),
+) -> SgxResult<SealedPartialRoundSecret> {
+    if let Err(e) = dc_net_params.validate() {
+        error!("invalid DC net parameters: {}", e);
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let secrets = unseal_secrets_for_round(shared_secrets, *round)?.by_entity_id();
+    let partial = PartialRoundSecret {
+        round: *round,
+        dc_net_params: *dc_net_params,
+        user_ids: user_ids.clone(),
+        secrets_digest: digest_user_secrets(&secrets, user_ids)?,
+        round_secret: derive_round_secret_server(*round, &secrets, dc_net_params, user_ids)?,
+    };
+
+    partial.seal_into()
+}
+
+/// Unblinds a top-level aggregate. XORs the pads of the aggregate's users into a key share and
+/// signs the share. Returns the key share, the signature, and the shared secrets ratcheted past
+/// the aggregate's round.
+///
+/// The aggregate must be signed by one of the top-level aggregators in the shared secrets, and be
+/// for this server's anytrust group. The secrets only unblind rounds after the last one they
+/// unblinded, and the other servers in the group witness the server's freshness counter moving on,
+/// so an older copy of the secrets is refused. The same aggregate can be unblinded again from the
+/// same secrets, e.g., if a witness couldn't be reached, since that gives the same share. A server
+/// alone in its group has no one to witness its counter, but the anytrust assumption then means it
+/// is honest anyway.
+pub fn unblind_aggregate(
+    (req, signing_sk): &(ServerUnblindReq, SealedSigPrivKey),
+) -> SgxResult<(RoundSecret, OutputSignature, SealedSharedSecretsDbServer)> {
+    let ServerUnblindReq {
+        round,
+        anytrust_group_id,
+        user_ids,
+        aggregated_msg,
+        aggregator_sig,
+        dc_net_params,
+        shared_secrets,
+        partial_secrets,
+    } = req;
+    let round = *round;
+
+    let signing_sk: SgxPrivateKey = signing_sk.unseal_into()?;
+    let mut shared_secrets = unseal_secrets_for_round(shared_secrets, round)?;
+    let secrets = shared_secrets.by_entity_id();
+
+    // the pads have the group's dimensions. XORing them into anything else produces garbage
+    if *dc_net_params != shared_secrets.dc_net_params {
+        error!(
+            "DC net parameters {:?} are not the group's {:?}",
+            dc_net_params, shared_secrets.dc_net_params
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    if !aggregated_msg.matches_params(dc_net_params) {
+        error!("aggregate does not match the DC net parameters");
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    if *anytrust_group_id != shared_secrets.anytrust_group_id() {
+        error!("aggregate is for anytrust group {}", anytrust_group_id);
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let agg_digest = aggregate_digest(round, anytrust_group_id, user_ids, aggregated_msg);
+    if !shared_secrets
+        .toplevel_aggregators
+        .contains(&aggregator_sig.pk)
+    {
+        error!(
+            "aggregate is from unregistered aggregator {}",
+            EntityId::from(&aggregator_sig.pk)
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    if !aggregator_sig.verify(&agg_digest) {
+        error!("signature on the aggregate does not verify");
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    // A user's pad is in the key share if it is in an odd number of the partials. So the partials
+    // cover the symmetric difference of their users
+    let mut key_share = RoundSecret::new(dc_net_params);
+    let mut covered = BTreeSet::new();
+    for sealed in partial_secrets.iter() {
+        let partial: PartialRoundSecret = sealed.unseal_into()?;
+        if partial.round != round || partial.dc_net_params != *dc_net_params {
+            error!(
+                "partial round secret is for round {} with {:?}",
+                partial.round, partial.dc_net_params
+            );
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        if partial.secrets_digest != digest_user_secrets(&secrets, &partial.user_ids)? {
+            error!("partial round secret was derived from secrets that have since changed");
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+
+        key_share.xor_mut(&partial.round_secret);
+        covered = covered
+            .symmetric_difference(&partial.user_ids)
+            .cloned()
+            .collect();
+    }
+
+    // Derive the pads of the users the partials miss, and XOR out those of users they shouldn't
+    // have covered
+    let missing: BTreeSet<EntityId> = covered.symmetric_difference(user_ids).cloned().collect();
+    debug!(
+        "{} pads were derived ahead of time. deriving {} more",
+        covered.len(),
+        missing.len()
+    );
+    key_share.xor_mut(&derive_round_secret_server(
+        round,
+        &secrets,
+        dc_net_params,
+        &missing,
+    )?);
+
+    let share_digest = unblinded_share_digest(&agg_digest, &key_share);
+    let (sig, pk) = sign_digest(&share_digest, &signing_sk).map_err(|e| {
+        error!("crypto error {}", e);
+        SGX_ERROR_UNEXPECTED
+    })?;
+
+    // The secrets can be used from the next round. There's none after the last one
+    let next_round = round.checked_add(1).ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+
+    // Last, have the other servers witness the counter move on. Once they have, these secrets
+    // can't unblind anything else
+    if !shared_secrets.peers.is_empty() {
+        let witness_pks = shared_secrets.witness_pks();
+        freshness::advance(
+            &EntityId::from(&pk),
+            &signing_sk,
+            &share_digest,
+            &mut shared_secrets.counter_value,
+            &witness_pks,
+        )?;
+    }
+
+    let shared_secrets = shared_secrets
+        .ratchet_to(next_round)
+        .ok_or(SGX_ERROR_UNEXPECTED)?;
+
+    Ok((
+        key_share,
+        OutputSignature { pk, sig },
+        shared_secrets.seal_into()?,
+    ))
+}
+
+/// Derives the output of a round from the aggregate and every server's key share of it, and
+/// signs it. Every server in the anytrust group must have signed exactly one of the shares. This
+/// server only signs its share once it has unblinded the aggregate, so nothing but the real output
+/// of an unblinded round gets signed.
+pub fn sign_round_output(
+    (req, signing_sk): &(SignRoundOutputReq, SealedSigPrivKey),
+) -> SgxResult<RoundOutput> {
+    let signing_sk: SgxPrivateKey = signing_sk.unseal_into()?;
+    let shared_secrets: SharedSecretsDbServer = req.shared_secrets.unseal_into()?;
+
+    // Servers can join until the first round is unblinded, so older secrets may miss some
+    if shared_secrets.round <= req.round {
+        error!(
+            "round {} isn't unblinded. the secrets are at round {}",
+            req.round, shared_secrets.round
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    let dc_net_params = &shared_secrets.dc_net_params;
+    if !req.aggregated_msg.matches_params(dc_net_params) {
+        error!("aggregate does not match the DC net parameters");
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    if req.anytrust_group_id != shared_secrets.anytrust_group_id() {
+        error!("aggregate is for anytrust group {}", req.anytrust_group_id);
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let agg_digest = aggregate_digest(
+        req.round,
+        &req.anytrust_group_id,
+        &req.user_ids,
+        &req.aggregated_msg,
+    );
+    let mut group_pks = shared_secrets.witness_pks();
+    group_pks.push(ed25519pk_from_secret(&signing_sk)?);
+
+    let mut dc_msg = req.aggregated_msg.clone();
+    let mut signers = BTreeSet::new();
+    for (key_share, share_sig) in req.key_shares.iter() {
+        let server_id = EntityId::from(&share_sig.pk);
+        if !group_pks.contains(&share_sig.pk) {
+            error!("share by {} is not from a server in the group", server_id);
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        if !key_share.matches_params(dc_net_params) {
+            error!(
+                "share by {} does not match the DC net parameters",
+                server_id
+            );
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        if !share_sig.verify(&unblinded_share_digest(&agg_digest, key_share)) {
+            error!("share by {} has an invalid signature", server_id);
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        if !signers.insert(server_id) {
+            error!("server {} has more than one share", server_id);
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        dc_msg.xor_mut(key_share);
+    }
+    if signers.len() != group_pks.len() {
+        error!(
+            "round {} has {} shares, expected {}",
+            req.round,
+            signers.len(),
+            group_pks.len()
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let mut round_output = RoundOutput {
+        round: req.round,
+        dc_msg,
+        server_sigs: vec![],
+    };
+    let (sig, pk) = sign_digest(&round_output.digest(), &signing_sk).map_err(|e| {
+        error!("crypto error {}", e);
+        SGX_ERROR_UNEXPECTED
+    })?;
+    round_output.server_sigs.push(OutputSignature { pk, sig });
+
+    Ok(round_output)
+}
+
+/// Signs a user's counter advance. The advance must be signed by a registered user, or by another
+/// server in the group. The host checks that its copy of the counter is at `advance.from` and
+/// moves it on before asking
+pub fn sign_counter_advance(
+    (advance, shared_secrets, signing_sk): &(
+        CounterAdvance,
+        SealedSharedSecretsDbServer,
+        SealedSigPrivKey,
+    ),
+) -> SgxResult<OutputSignature> {
+    let shared_secrets: SharedSecretsDbServer = shared_secrets.unseal_into()?;
+    let user_pk = match shared_secrets
+        .db
+        .keys()
+        .find(|pk| EntityId::from(*pk) == advance.user_id)
+    {
+        Some(pk) => PublicKey::from_bytes(&pk.0).map_err(|e| {
+            error!("user {} has a bad key: {}", advance.user_id, e);
+            SGX_ERROR_UNEXPECTED
+        })?,
+        None => {
+            shared_secrets
+                .peers
+                .iter()
+                .find(|s| EntityId::from(*s) == advance.user_id)
+                .ok_or_else(|| {
+                    error!("{} is not a registered user or server", advance.user_id);
+                    SGX_ERROR_INVALID_PARAMETER
+                })?
+                .sig
+        }
+    };
+    if !advance.verify_user_sig(&user_pk) {
+        error!(
+            "counter advance for {} is not signed by it",
+            advance.user_id
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let signing_sk: SgxPrivateKey = signing_sk.unseal_into()?;
+    let (sig, pk) = sign_digest(&advance.digest(), &signing_sk).map_err(|e| {
+        error!("crypto error {}", e);
+        SGX_ERROR_UNEXPECTED
+    })?;
+
+    Ok(OutputSignature { pk, sig })
+}
+
+/// Unseals the shared secrets and ratchets them forward to the given round
+fn unseal_secrets_for_round(
+    sealed: &SealedSharedSecretsDbServer,
+    round: u32,
+) -> SgxResult<SharedSecretsDbServer> {
+    let shared_secrets: SharedSecretsDbServer = sealed.unseal_into()?;
+    shared_secrets.ratchet_to(round).ok_or_else(|| {
+        error!(
+            "round {} is before shared_secrets.round {}",
+            round, shared_secrets.round
+        );
+        SGX_ERROR_INVALID_PARAMETER
+    })
+}
+
+fn user_secret<'a>(
+    secrets: &'a BTreeMap<EntityId, DiffieHellmanSharedSecret>,
+    user_id: &EntityId,
+) -> SgxResult<&'a DiffieHellmanSharedSecret> {
+    secrets.get(user_id).ok_or_else(|| {
+        error!("user {} is not registered", user_id);
+        SGX_ERROR_INVALID_PARAMETER
+    })
+}
+
+/// Hashes the secrets shared with the given users
+fn digest_user_secrets(
+    secrets: &BTreeMap<EntityId, DiffieHellmanSharedSecret>,
+    user_ids: &BTreeSet<EntityId>,
+) -> SgxResult<[u8; 32]> {
+    let mut h = Sha256::new();
+    h.input(b"partial-round-secret");
+    for id in user_ids.iter() {
+        h.input(id);
+        h.input(user_secret(secrets, id)?);
+    }
+
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&h.result());
+    Ok(digest)
+}
+
+/// Derives the XOR of the given users' pads for the given round. This must match
+/// `derive_round_secret_client`, summed over the users
+fn derive_round_secret_server(
+    round: u32,
+    secrets: &BTreeMap<EntityId, DiffieHellmanSharedSecret>,
+    dc_net_params: &DcNetParams,
+    user_ids: &BTreeSet<EntityId>,
+) -> SgxResult<RoundSecret> {
+    let mut round_secret = RoundSecret::new(dc_net_params);
+    for id in user_ids.iter() {
+        let pad = derive_pad(round, user_secret(secrets, id)?, dc_net_params).map_err(|e| {
+            error!("crypto error {}", e);
+            SGX_ERROR_UNEXPECTED
+        })?;
+        round_secret.xor_mut(&pad);
+    }
+
+    Ok(round_secret)
+}
@@ -6,7 +6,8 @@ use crate::unseal::UnsealableInto;
 use byteorder::ByteOrder;
 use byteorder::LittleEndian;
 use crypto;
-use crypto::{sign_submission, SgxPrivateKey};
+use crypto::{sign_submission, SgxPrivateKey, UserPrivKeys};
+use freshness;
 use log::debug;
 use sgx_types::sgx_status_t::{
     SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_SERVICE_UNAVAILABLE, SGX_ERROR_UNEXPECTED,
@@ -15,7 +16,6 @@ use sgx_types::SgxResult;
 use sha2::Digest;
 use sha2::Sha256;
 use std::convert::TryFrom;
-use std::env;
 use std::prelude::v1::*;
 use unseal::SealInto;
 
@@ -23,6 +23,7 @@ use ed25519_dalek::PublicKey;
 
 fn check_reservation(
     server_sig_pks: &[PublicKey],
+    sig_threshold: usize,
     round: u32,
     prev_round_output: &RoundOutput,
     cur_slot: usize,
@@ -36,13 +37,11 @@ fn check_reservation(
 
     // verify server's signatures on previous output
     let verified_index = prev_round_output
-        .verify_multisig(server_sig_pks)
-        .expect("verify server's signature failed");
-
-    if verified_index.is_empty() {
-        error!("❌ sigs in prev_round_output can't be verified");
-        return Err(SGX_ERROR_INVALID_PARAMETER);
-    }
+        .verify_multisig(server_sig_pks, sig_threshold)
+        .map_err(|_| {
+            error!("❌ sigs in prev_round_output can't be verified");
+            SGX_ERROR_INVALID_PARAMETER
+        })?;
 
     info!("✅ prev_round_output verified against {:?}", verified_index);
 
@@ -66,27 +65,28 @@ fn check_reservation(
 /// scheduling vector to be empty. To save bandwidth we compact the DC net message by skipping
 /// slots that are not scheduled. In short the zeros are discounted from the vector and all the
 /// reserved slots are moved up.
-fn derive_msg_slot(cur_slot: usize, prev_round_output: &RoundOutput) -> SgxResult<usize> {
+fn derive_msg_slot(
+    cur_slot: usize,
+    prev_round_output: &RoundOutput,
+    params: &DcNetParams,
+) -> SgxResult<usize> {
+    if !prev_round_output.dc_msg.matches_params(params) {
+        error!("❌ prev_round_output does not match the DC net parameters");
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
     let num_zeros = prev_round_output.dc_msg.scheduling_msg[..cur_slot]
         .into_iter()
         .filter(|b| **b == 0)
         .count();
     let msg_slot = cur_slot - num_zeros;
-    let dc_net_n_slots = if PARAMETER_FLAG {
-        env::var("DC_NET_N_SLOTS")
-            .unwrap_or_else(|_| "100".to_string())
-            .parse::<usize>()
-            .expect("Invalid DC_NET_N_SLOTS value")
-    } else {
-        DC_NET_N_SLOTS
-    };
 
     // Do a bounds check
-    if msg_slot > dc_net_n_slots {
+    if msg_slot >= params.n_slots {
         error!(
             "❌ can't send. scheduling failure. you need to wait for the next round.
-            \tcur_slot: {}, num_zeros: {}, msg_slot: {}, DC_NET_N_SLOTS:{}",
-            cur_slot, num_zeros, msg_slot, dc_net_n_slots
+            \tcur_slot: {}, num_zeros: {}, msg_slot: {}, n_slots:{}",
+            cur_slot, num_zeros, msg_slot, params.n_slots
         );
         Err(SGX_ERROR_SERVICE_UNAVAILABLE)
     } else {
@@ -94,24 +94,26 @@ fn derive_msg_slot(cur_slot: usize, prev_round_output: &RoundOutput) -> SgxResul
     }
 }
 
-/// Return deterministically derived footprint reservation for the given parameter
+/// Return deterministically derived footprint reservation for the given parameter. `prf_key` is
+/// the user's scheduling PRF key
 ///
 /// ```
 /// if epoch == 0:
-///         Let prev_slot_idx = H("first-slot-idx", usk, anytrust_group_id)
-///         Let prev_slot_val = H("first-slot-val", usk, anytrust_group_id)
+///         Let prev_slot_idx = H("first-slot-idx", prf_key, anytrust_group_id)
+///         Let prev_slot_val = H("first-slot-val", prf_key, anytrust_group_id)
 /// else:
-///         Let prev_slot_idx = H("sched-slot-idx", usk, anytrust_group_id, round-1)
-///         Let prev_slot_val = H("sched-slot-val", usk, anytrust_group_id, round-1)
-/// Let next_slot_idx = H("sched-slot-idx", usk, anytrust_group_id, round)
-/// Let next_slot_val = H("sched-slot-val", usk, anytrust_group_id, round)
+///         Let prev_slot_idx = H("sched-slot-idx", prf_key, anytrust_group_id, round-1)
+///         Let prev_slot_val = H("sched-slot-val", prf_key, anytrust_group_id, round-1)
+/// Let next_slot_idx = H("sched-slot-idx", prf_key, anytrust_group_id, round)
+/// Let next_slot_val = H("sched-slot-val", prf_key, anytrust_group_id, round)
 ///
 /// return (prev_slot_idx, prev_slot_val, next_slot_idx, next_slot_val)
 /// ```
 fn derive_reservation(
-    usk: &SgxPrivateKey,
+    prf_key: &SgxPrivateKey,
     anytrust_group_id: &EntityId,
     round: u32,
+    params: &DcNetParams,
 ) -> (usize, interface::Footprint, usize, interface::Footprint) {
     const FIRST_SLOT_IDX: &[u8; 14] = b"first-slot-idx";
     const FIRST_SLOT_VAL: &[u8; 14] = b"first-slot-val";
@@ -119,10 +121,10 @@ fn derive_reservation(
     const SCHED_SLOT_VAL: &[u8; 14] = b"sched-slot-val";
 
     // hash three things to u32
-    let h3_to_u32 = |label: &[u8; 14], usk: &SgxPrivateKey, anytrust_group_id: &EntityId| {
+    let h3_to_u32 = |label: &[u8; 14], prf_key: &SgxPrivateKey, anytrust_group_id: &EntityId| {
         let mut h = Sha256::new();
         h.input(label);
-        h.input(usk);
+        h.input(prf_key);
         h.input(anytrust_group_id);
 
         let hash = h.result().to_vec();
@@ -132,10 +134,10 @@ fn derive_reservation(
 
     // hash three things to u32
     let h4_to_u32 =
-        |label: &[u8; 14], usk: &SgxPrivateKey, anytrust_group_id: &EntityId, round: u32| {
+        |label: &[u8; 14], prf_key: &SgxPrivateKey, anytrust_group_id: &EntityId, round: u32| {
             let mut h = Sha256::new();
             h.input(label);
-            h.input(usk);
+            h.input(prf_key);
             h.input(anytrust_group_id);
             h.input(round.to_le_bytes());
 
@@ -148,39 +150,23 @@ fn derive_reservation(
         .checked_sub(1)
         .map(|r| {
             (
-                h4_to_u32(SCHED_SLOT_IDX, usk, anytrust_group_id, r) as usize,
-                h4_to_u32(SCHED_SLOT_VAL, usk, anytrust_group_id, r),
+                h4_to_u32(SCHED_SLOT_IDX, prf_key, anytrust_group_id, r) as usize,
+                h4_to_u32(SCHED_SLOT_VAL, prf_key, anytrust_group_id, r),
             )
         })
         .unwrap_or((
-            h3_to_u32(FIRST_SLOT_IDX, usk, anytrust_group_id) as usize,
-            h3_to_u32(FIRST_SLOT_VAL, usk, anytrust_group_id),
+            h3_to_u32(FIRST_SLOT_IDX, prf_key, anytrust_group_id) as usize,
+            h3_to_u32(FIRST_SLOT_VAL, prf_key, anytrust_group_id),
         ));
 
-    let next_slot_idx = h4_to_u32(SCHED_SLOT_IDX, usk, anytrust_group_id, round) as usize;
-    let next_slot_val = h4_to_u32(SCHED_SLOT_VAL, usk, anytrust_group_id, round);
-    let dc_net_n_slots = if PARAMETER_FLAG {
-        env::var("DC_NET_N_SLOTS")
-            .unwrap_or_else(|_| "100".to_string())
-            .parse::<usize>()
-            .expect("Invalid DC_NET_N_SLOTS value")
-    } else {
-        DC_NET_N_SLOTS
-    };
-    let footprint_n_slots = if PARAMETER_FLAG {
-        env::var("FOOTPRINT_N_SLOTS")
-            .unwrap_or_else(|_| "100".to_string())
-            .parse::<usize>()
-            .expect("Invalid FOOTPRINT_N_SLOTS value")
-    } else {
-        FOOTPRINT_N_SLOTS
-    };
+    let next_slot_idx = h4_to_u32(SCHED_SLOT_IDX, prf_key, anytrust_group_id, round) as usize;
+    let next_slot_val = h4_to_u32(SCHED_SLOT_VAL, prf_key, anytrust_group_id, round);
 
     (
-        prev_slot_idx % footprint_n_slots,
-        prev_slot_val % (dc_net_n_slots as u32),
-        next_slot_idx % footprint_n_slots,
-        next_slot_val % (dc_net_n_slots as u32),
+        prev_slot_idx % params.footprint_n_slots,
+        prev_slot_val % (params.n_slots as u32),
+        next_slot_idx % params.footprint_n_slots,
+        next_slot_val % (params.n_slots as u32),
     )
 }
 
@@ -189,22 +175,36 @@ use crypto::ed25519pk_from_secret;
 /// process user submission request
 /// returns a submission and the ratcheted shared secrets
 pub fn user_submit_internal(
-    (send_request, signing_sk): &(UserSubmissionReq, SealedSigPrivKey),
+    (send_request, user_keys): &(UserSubmissionReq, SealedUserPrivKeys),
 ) -> SgxResult<(UserSubmissionBlob, SealedSharedSecretsDbClient)> {
     let UserSubmissionReq {
         user_id,
         anytrust_group_id,
         round,
+        dc_net_params,
         msg,
         shared_secrets,
         server_pks,
     } = send_request;
     let round = *round;
 
-    // unseal user's sk
-    let signing_sk = signing_sk.unseal_into()?;
+    if let Err(e) = dc_net_params.validate() {
+        error!("invalid DC net parameters: {}", e);
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    if dc_net_params.sig_threshold > server_pks.len() {
+        error!(
+            "signature threshold {} exceeds the {} servers",
+            dc_net_params.sig_threshold,
+            server_pks.len()
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    // unseal user's keys
+    let user_keys: UserPrivKeys = user_keys.unseal_into()?;
     //check user signing key matches user_id
-    if EntityId::from(&ed25519pk_from_secret(&signing_sk)?) != *user_id {
+    if EntityId::from(&ed25519pk_from_secret(&user_keys.sig)?) != *user_id {
         error!("user id mismatch");
         return Err(SGX_ERROR_INVALID_PARAMETER);
     }
@@ -216,25 +216,29 @@ pub fn user_submit_internal(
     let mut server_kem_pks: Vec<SgxProtectedKeyPub> = Vec::new();
     // check anytrust group id matches server pubkeys
     for pk_pkg in server_pks.iter() {
+        if pk_pkg.dc_net_params != *dc_net_params {
+            error!(
+                "server {} has different DC net parameters",
+                EntityId::from(pk_pkg)
+            );
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
         server_sig_pks.push(pk_pkg.sig);
         server_kem_pks.push(SgxProtectedKeyPub(pk_pkg.kem.to_bytes()));
     }
 
-    // check anytrust_group_id against the kem keys
-    if *anytrust_group_id != compute_anytrust_group_id(server_kem_pks.as_slice()) {
+    // check anytrust_group_id against the kem keys and the parameters
+    if *anytrust_group_id != compute_anytrust_group_id(server_kem_pks.as_slice(), dc_net_params) {
         error!("reserver_req.anytrust_group_id != EntityId::from(server_sig_pks");
         return Err(SGX_ERROR_INVALID_PARAMETER);
     }
 
     debug!("✅ shared secrets matches anytrust group id");
 
-    // Derive the pseudorandom rate-limit nonce
-    let rate_limit_nonce = crypto::derive_round_nonce(anytrust_group_id, round, &signing_sk, msg)?;
-
     // Get the last footprint and make a new one. If this message is cover traffic, this info won't
     // be used at all
     let (cur_slot, cur_fp, next_slot, next_fp) =
-        derive_reservation(&signing_sk, anytrust_group_id, round);
+        derive_reservation(&user_keys.prf, anytrust_group_id, round, dc_net_params);
 
     // If this user is talking and it's not the first round, check the reservation. Otherwise don't
     if let UserMsg::TalkAndReserve {
@@ -242,9 +246,16 @@ pub fn user_submit_internal(
         ..
     } = msg
     {
-        let msg_slot = derive_msg_slot(cur_slot, prev_round_output)?;
+        let msg_slot = derive_msg_slot(cur_slot, prev_round_output, dc_net_params)?;
         if round > 0 {
-            check_reservation(&server_sig_pks, round, prev_round_output, cur_slot, cur_fp)?;
+            check_reservation(
+                &server_sig_pks,
+                dc_net_params.sig_threshold,
+                round,
+                prev_round_output,
+                cur_slot,
+                cur_fp,
+            )?;
             debug!(
                 "✅ user {} is permitted to send msg at slot {} for round {}",
                 uid, msg_slot, round
@@ -267,7 +278,7 @@ pub fn user_submit_internal(
     }
 
     // Write to the round message. It's all zeros by default
-    let mut round_msg = DcRoundMessage::default();
+    let mut round_msg = DcRoundMessage::new(dc_net_params);
     match msg {
         // If the user is talking and reserving, write to message and reservation slots
         UserMsg::TalkAndReserve {
@@ -275,9 +286,18 @@ pub fn user_submit_internal(
             ref prev_round_output,
             ..
         } => {
-            let msg_slot = derive_msg_slot(cur_slot, prev_round_output)?;
+            let msg_slot = derive_msg_slot(cur_slot, prev_round_output, dc_net_params)?;
             debug!("✅ slot {} will include msg {:?}", msg_slot, msg,);
 
+            if msg.0.len() > dc_net_params.message_length {
+                error!(
+                    "msg is {} bytes, longer than the message length {}",
+                    msg.0.len(),
+                    dc_net_params.message_length
+                );
+                return Err(SGX_ERROR_INVALID_PARAMETER);
+            }
+
             round_msg.scheduling_msg[next_slot] = next_fp;
             // Copy the message into the 2d array
             for (i, b) in msg.0.iter().enumerate() {
@@ -285,7 +305,7 @@ pub fn user_submit_internal(
             }
         }
         // If the user is just reserving, write to reservation slots
-        UserMsg::Reserve { .. } => {
+        UserMsg::Reserve => {
             round_msg.scheduling_msg[next_slot] = next_fp;
         }
         // If this is cover traffic, the round message is all zeros
@@ -295,26 +315,42 @@ pub fn user_submit_internal(
     // Now we encrypt the round message
 
     // Derive the round key from shared secrets
+    // The user may have sat out some rounds, so ratchet the secrets forward to this one. They
+    // can't go back to a round that's already been used
     let shared_secrets = shared_secrets.unseal_into()?;
-    if shared_secrets.round != round {
-        error!(
-            "shared_secrets.round {} != send_request.round {}",
-            shared_secrets.round, round
-        );
-        return Err(SGX_ERROR_INVALID_PARAMETER);
-    }
-    if shared_secrets.anytrust_group_id() != *anytrust_group_id {
+    let shared_secrets = match shared_secrets.ratchet_to(round) {
+        Some(s) => s,
+        None => {
+            error!(
+                "send_request.round {} is before shared_secrets.round {}",
+                round, shared_secrets.round
+            );
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+    };
+    if shared_secrets.anytrust_group_id(dc_net_params) != *anytrust_group_id {
         error!("shared_secrets.anytrust_group_id() != send_request.anytrust_group_id");
         return Err(SGX_ERROR_INVALID_PARAMETER);
     }
 
-    let round_key = match crypto::derive_round_secret_client(round, &shared_secrets, None) {
-        Ok(k) => k,
-        Err(e) => {
-            error!("can't derive round secret {}", e);
-            return Err(SGX_ERROR_INVALID_PARAMETER);
-        }
-    };
+    // Derive the pseudorandom rate-limit nonce from the participation count in the sealed state
+    let window = round_window(round);
+    let rate_limit_nonce = crypto::derive_round_nonce(
+        anytrust_group_id,
+        round,
+        &user_keys.prf,
+        msg,
+        shared_secrets.times_participated_in(window),
+    )?;
+
+    let round_key =
+        match crypto::derive_round_secret_client(round, &shared_secrets, dc_net_params, None) {
+            Ok(k) => k,
+            Err(e) => {
+                error!("can't derive round secret {}", e);
+                return Err(SGX_ERROR_INVALID_PARAMETER);
+            }
+        };
 
     // Encrypt the message with round_key
     let encrypted_msg = round_key.xor(&round_msg);
@@ -331,13 +367,48 @@ pub fn user_submit_internal(
     };
 
     // Sign
-    let (sig, pk) = sign_submission(&agg_msg, &signing_sk).map_err(|e| {
+    let (sig, pk) = sign_submission(&agg_msg, &user_keys.sig).map_err(|e| {
         log::error!("crypto error {}", e);
         SGX_ERROR_UNEXPECTED
     })?;
     agg_msg.tee_pk = pk;
     agg_msg.tee_sig = sig;
 
-    // If everything is fine, we are ready to ratchet
-    Ok((agg_msg, shared_secrets.ratchet().seal_into()?))
+    // If everything is fine, we are ready to ratchet. The secrets can be used from the next round
+    let next_round = round.checked_add(1).ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+    let mut shared_secrets = shared_secrets
+        .ratchet_to(next_round)
+        .ok_or(SGX_ERROR_UNEXPECTED)?;
+    if !msg.is_cover() {
+        shared_secrets.record_participation(window);
+    }
+
+    // Have the servers move the freshness counter on. This refuses the submission if the secrets
+    // we were given are stale, and makes them stale from now on. Retrying this same submission
+    // from the same secrets is let through, since it gives the same output
+    freshness::advance(
+        user_id,
+        &user_keys.sig,
+        &agg_msg.digest(),
+        &mut shared_secrets.counter_value,
+        &shared_secrets.witness_pks,
+    )?;
+
+    Ok((agg_msg, shared_secrets.seal_into()?))
+}
+
+/// process a batch of user submission requests. A request that fails doesn't affect the others.
+/// Its error is returned in its place as the raw `sgx_status_t`
+pub fn user_submit_batch(
+    reqs: &Vec<(UserSubmissionReq, SealedUserPrivKeys)>,
+) -> SgxResult<Vec<Result<(UserSubmissionBlob, SealedSharedSecretsDbClient), u32>>> {
+    Ok(reqs
+        .iter()
+        .map(|req| {
+            user_submit_internal(req).map_err(|e| {
+                error!("❌ submission from user {} failed: {}", req.0.user_id, e);
+                e as u32
+            })
+        })
+        .collect())
 }
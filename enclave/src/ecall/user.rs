@@ -3,6 +3,7 @@ use crate::crypto::SharedSecretsDbClient;
 use ecall::keygen::new_keypair_ext_internal;
 
 use interface::*;
+use sgx_types::sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
 use sgx_types::SgxResult;
 use std::collections::BTreeMap;
 use std::string::ToString;
@@ -13,45 +14,56 @@ use ed25519_dalek::PublicKey;
 
 /// Derives shared secrets with all the given KEM pubkeys, and derived a new signing pubkey.
 /// Returns sealed secrets, a sealed private key, and a registration message to send to an
-/// anytrust node
+/// anytrust node. The registration message is attested for the given quoting enclave.
 pub fn new_user(
-    anytrust_server_pks: &Vec<ServerPubKeyPackage>,
+    (anytrust_server_pks, quoting_target): &(Vec<ServerPubKeyPackage>, QuotingTarget),
 ) -> SgxResult<(
     SealedSharedSecretsDbClient,
-    SealedSigPrivKey,
+    SealedUserPrivKeys,
     UserRegistrationBlob,
 )> {
     // 1. validate the input
     let mut kem_db: BTreeMap<SgxProtectedKeyPub, PublicKey> = BTreeMap::new();
     // let mut kem_pks = vec![];
     for k in anytrust_server_pks {
+        if !k.verify_attestation() {
+            error!("cannot verify attestation of server {}", k.xkem);
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
         // kem_pks.push(k.kem);
         kem_db.insert(k.xkem, k.kem);
     }
 
     let role = "user".to_string();
 
-    // 2. generate a key pair. used for both signing and round key derivation
-    let (sk, pk) = new_keypair_ext_internal(&role)?;
+    // 2. generate the user's signing, KEM and scheduling keys
+    let (keys, pk) = new_keypair_ext_internal(&role, quoting_target)?;
 
-    // 3. derive server secrets
-    let server_secrets = SharedSecretsDbClient::derive_shared_secrets(&sk, &kem_db)?;
+    // 3. derive server secrets with the KEM key. The servers start the user's freshness counter
+    // at 0 when they register it, and witness it from then on
+    let mut server_secrets = SharedSecretsDbClient::derive_shared_secrets(&keys.kem, &kem_db)?;
+    server_secrets.witness_pks = anytrust_server_pks.iter().map(|k| k.sig).collect();
 
-    Ok((server_secrets.seal_into()?, sk.seal_into()?, pk))
+    Ok((server_secrets.seal_into()?, keys.seal_into()?, pk))
 }
 
 pub fn new_user_batch(
-    (anytrust_server_pks, n_user): &(Vec<ServerPubKeyPackage>, usize),
+    (anytrust_server_pks, quoting_target, n_user): &(
+        Vec<ServerPubKeyPackage>,
+        QuotingTarget,
+        usize,
+    ),
 ) -> SgxResult<
     Vec<(
         SealedSharedSecretsDbClient,
-        SealedSigPrivKey,
+        SealedUserPrivKeys,
         UserRegistrationBlob,
     )>,
 > {
+    let input = (anytrust_server_pks.clone(), quoting_target.clone());
     let mut users = vec![];
     for _ in 0..*n_user {
-        let u = new_user(anytrust_server_pks)?;
+        let u = new_user(&input)?;
         users.push(u);
     }
 
@@ -0,0 +1,179 @@
+//! Rollback protection for sealed user state. Sealing hides and authenticates the user's shared
+//! secrets, but it can't stop the host from handing the enclave an old copy of them, and nothing
+//! inside the enclave outlives a restart. So each user's freshness counter is witnessed by the
+//! anytrust servers instead (see [`interface::SealedSharedSecretsDbClient`]). Every server keeps a
+//! copy of the counter and signs a [`CounterAdvance`] only from the value it has, and a submission
+//! needs all of their signatures. State sealed at any other value of the counter is refused, as is
+//! a user the servers don't know.
+//!
+//! A server moves its copy on as soon as it signs, so if another server then fails, or the new
+//! state never makes it out of the enclave, the servers are ahead of the user's state. The nonce of
+//! an advance is derived from the submission it's for, and the servers sign their last advance
+//! again when it's repeated. So retrying the same submission from the same state gets every
+//! signature and brings the user back in line, while any other submission is still refused.
+//!
+//! A server's sealed secrets are protected the same way. Its counter moves on with every round it
+//! unblinds, and the other servers in its anytrust group witness it. Otherwise the host could hand
+//! the enclave the secrets from before a round was unblinded, and unblind it again with other
+//! users to learn their pads.
+//!
+//! The enclave can't reach the servers itself. Inside SGX it asks the host with an ocall. Outside
+//! of it, the host sets a [`WitnessTransport`].
+
+use crypto::{sign_digest, SgxPrivateKey};
+use ed25519_dalek::PublicKey;
+use interface::{CounterAdvance, EntityId, MultiSignable, OutputSignature};
+use sgx_types::sgx_status_t::{SGX_ERROR_INVALID_STATE, SGX_ERROR_UNEXPECTED};
+use sgx_types::SgxResult;
+use sha2::{Digest, Sha256};
+use std::vec::Vec;
+
+#[cfg(feature = "untrusted")]
+use sgx_types::sgx_status_t::SGX_ERROR_SERVICE_UNAVAILABLE;
+#[cfg(feature = "trusted")]
+use sgx_types::sgx_status_t::{self, SGX_ERROR_INVALID_PARAMETER, SGX_SUCCESS};
+#[cfg(feature = "untrusted")]
+use std::sync::RwLock;
+
+/// Gets every anytrust server's signature on a counter advance. Fails with SGX_ERROR_INVALID_STATE
+/// if a server refused, e.g., because the state it was asked for is stale
+pub type WitnessTransport = fn(&CounterAdvance) -> SgxResult<Vec<OutputSignature>>;
+
+#[cfg(feature = "untrusted")]
+static TRANSPORT: RwLock<Option<WitnessTransport>> = RwLock::new(None);
+
+/// Sets how the enclave reaches the anytrust servers when it runs outside of SGX
+#[cfg(feature = "untrusted")]
+pub fn set_witness_transport(transport: WitnessTransport) {
+    *TRANSPORT.write().unwrap() = Some(transport);
+}
+
+#[cfg(feature = "untrusted")]
+fn request_witness(req: &CounterAdvance) -> SgxResult<Vec<OutputSignature>> {
+    // don't hold the lock while the transport runs. It may well make another enclave
+    let transport = *TRANSPORT.read().map_err(|_| SGX_ERROR_UNEXPECTED)?;
+    match transport {
+        Some(transport) => transport(req),
+        None => {
+            error!("no witness transport is set");
+            Err(SGX_ERROR_SERVICE_UNAVAILABLE)
+        }
+    }
+}
+
+/// The most space the servers' signatures can take. Each is about 100 bytes
+#[cfg(feature = "trusted")]
+const WITNESS_RESP_CAP: usize = 16 * 1024;
+
+#[cfg(feature = "trusted")]
+extern "C" {
+    fn ocall_witness_counter_advance(
+        ret: *mut sgx_status_t,
+        req: *const u8,
+        req_len: usize,
+        resp: *mut u8,
+        resp_cap: usize,
+        resp_used: *mut usize,
+    ) -> sgx_status_t;
+}
+
+#[cfg(feature = "trusted")]
+fn request_witness(req: &CounterAdvance) -> SgxResult<Vec<OutputSignature>> {
+    let marshaled_req = serde_cbor::to_vec(req).map_err(|e| {
+        error!("can't marshal counter advance: {}", e);
+        SGX_ERROR_UNEXPECTED
+    })?;
+
+    let mut ret = SGX_SUCCESS;
+    let mut resp = vec![0u8; WITNESS_RESP_CAP];
+    let mut resp_used = 0usize;
+    let call_ret = unsafe {
+        ocall_witness_counter_advance(
+            &mut ret,
+            marshaled_req.as_ptr(),
+            marshaled_req.len(),
+            resp.as_mut_ptr(),
+            resp.len(),
+            &mut resp_used,
+        )
+    };
+    if call_ret != SGX_SUCCESS {
+        return Err(call_ret);
+    }
+    if ret != SGX_SUCCESS {
+        return Err(ret);
+    }
+    if resp_used > resp.len() {
+        error!(
+            "witness response is {} bytes, longer than the buffer",
+            resp_used
+        );
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    serde_cbor::from_slice(&resp[..resp_used]).map_err(|e| {
+        error!("can't unmarshal witness response: {}", e);
+        SGX_ERROR_INVALID_PARAMETER
+    })
+}
+
+/// Has the servers with the given keys witness the counter of `user_id` moving on from
+/// `counter_value`, and moves `counter_value` on. `submission` is the digest of what the sealed
+/// state was used for. The request is signed with `user_sk`, the signing key `user_id` registered
+/// with, so the servers know it's from the owner of the counter. Do this last, once nothing else
+/// can fail, since the servers have moved on once they sign and the old state can't be used after
+/// this
+pub fn advance(
+    user_id: &EntityId,
+    user_sk: &SgxPrivateKey,
+    submission: &[u8],
+    counter_value: &mut u32,
+    witness_pks: &[PublicKey],
+) -> SgxResult<()> {
+    if witness_pks.is_empty() {
+        error!("❌ no servers witness the freshness counter");
+        return Err(SGX_ERROR_INVALID_STATE);
+    }
+
+    let mut req = CounterAdvance {
+        user_id: *user_id,
+        from: *counter_value,
+        nonce: advance_nonce(user_sk, *counter_value, submission),
+        ..Default::default()
+    };
+    req.user_sig = sign_digest(&req.digest(), user_sk)
+        .map_err(|e| {
+            error!("crypto error {}", e);
+            SGX_ERROR_UNEXPECTED
+        })?
+        .0;
+
+    req.server_sigs = request_witness(&req)?;
+
+    // every server must have signed, since any of them may be the honest one
+    req.verify_multisig(witness_pks, witness_pks.len())
+        .map_err(|_| {
+            error!(
+                "❌ stale state. the servers didn't all witness {}'s counter at {}",
+                user_id, req.from
+            );
+            SGX_ERROR_INVALID_STATE
+        })?;
+
+    *counter_value = req.from.checked_add(1).ok_or(SGX_ERROR_UNEXPECTED)?;
+    Ok(())
+}
+
+/// The nonce of the advance from `from` for the given submission. It's keyed with the signing key
+/// of the counter's owner, so no one else can tell which submission an advance is for
+fn advance_nonce(user_sk: &SgxPrivateKey, from: u32, submission: &[u8]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.input(b"counter-advance-nonce");
+    h.input(&user_sk.r);
+    h.input(&from.to_le_bytes());
+    h.input(submission);
+
+    let mut nonce = [0u8; 32];
+    nonce.copy_from_slice(&h.result());
+    nonce
+}
@@ -1,16 +1,60 @@
 #![no_std]
 
-extern crate sgx_types;
-#[macro_use]
-extern crate sgx_tstd as std;
+extern crate cfg_if;
+use cfg_if::cfg_if;
+
 extern crate interface;
-extern crate sgx_tse;
-extern crate sgx_tunittest;
+extern crate sgx_types;
+
+cfg_if! {
+    if #[cfg(feature = "trusted")] {
+        #[macro_use]
+        extern crate sgx_tstd as std;
+        extern crate sgx_tse;
+        extern crate sgx_tunittest;
+
+        #[macro_use]
+        extern crate quick_error;
+        extern crate aes_ctr;
+
+        extern crate ed25519_dalek_trusted as ed25519_dalek;
+        extern crate x25519_dalek_trusted as x25519_dalek;
+
+        #[macro_use]
+        extern crate serde;
+        extern crate serde_cbor;
+
+        #[macro_use]
+        extern crate log;
+        extern crate env_logger;
+
+        extern crate sgx_rand;
+        extern crate sgx_trts;
+        extern crate sgx_tseal;
+    } else if #[cfg(feature = "untrusted")] {
+        #[macro_use]
+        extern crate std;
+
+        #[macro_use]
+        extern crate quick_error_untrusted as quick_error;
+        extern crate aes_ctr_untrusted as aes_ctr;
+
+        extern crate ed25519_dalek_untrusted as ed25519_dalek;
+        extern crate x25519_dalek_untrusted as x25519_dalek;
 
-#[macro_use]
-extern crate quick_error;
+        #[macro_use]
+        extern crate serde_untrusted as serde;
+        extern crate serde_cbor_untrusted as serde_cbor;
+
+        #[macro_use]
+        extern crate log_untrusted as log;
+
+        extern crate rand_untrusted;
+    } else {
+        compile_error!{"must be either trusted or untrusted"}
+    }
+}
 
-extern crate aes_ctr;
 extern crate byteorder;
 extern crate hex;
 extern crate hkdf;
@@ -18,30 +62,26 @@ extern crate rand;
 extern crate rand_core;
 extern crate sha2;
 
-extern crate ed25519_dalek;
-extern crate x25519_dalek;
-
-#[macro_use]
-extern crate serde;
-extern crate serde_cbor;
-
-#[macro_use]
-extern crate log;
-extern crate env_logger;
-
 use sgx_types::*;
 
-extern crate sgx_rand;
-extern crate sgx_trts;
-extern crate sgx_tseal;
+/// sgx_rand is a port of rand 0.4. Outside of an enclave the OS RNG takes the place of RDRAND.
+#[cfg(feature = "untrusted")]
+mod sgx_rand {
+    pub use rand_untrusted::OsRng as SgxRng;
+    pub use rand_untrusted::{Rand, Rng};
+}
 
 mod attestation;
 mod crypto;
-mod ecall;
-mod tests;
+pub mod ecall;
+pub mod freshness;
 mod types;
 mod unseal;
 
+#[cfg(feature = "trusted")]
+mod tests;
+
+#[cfg(feature = "trusted")]
 #[no_mangle]
 pub extern "C" fn test_main_entrance() -> sgx_status_t {
     tests::test_all()
@@ -12,7 +12,6 @@ quick_error! {
             description(err.as_str())
             display("Error {}", err)
             from()
-            cause(err)
         }
         Other(err: String) {
             description(err)
@@ -1,11 +1,16 @@
-use crypto::{SgxPrivateKey, SharedSecretsDbClient};
+use crypto::{
+    PartialRoundSecret, SgxPrivateKey, SharedSecretsDbClient, SharedSecretsDbServer, UserPrivKeys,
+};
 use interface::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_cbor;
+#[cfg(feature = "trusted")]
 use sgx_tseal::SgxSealedData;
+#[cfg(feature = "trusted")]
+use sgx_types::sgx_sealed_data_t;
 use sgx_types::sgx_status_t::{SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_UNEXPECTED};
-use sgx_types::{sgx_sealed_data_t, SgxResult};
+use sgx_types::SgxResult;
 use std::borrow::ToOwned;
 use std::vec::Vec;
 
@@ -23,6 +28,7 @@ fn deserialize_from_vec<T: DeserializeOwned>(bin: &[u8]) -> SgxResult<T> {
     })
 }
 
+#[cfg(feature = "trusted")]
 fn ser_and_seal_to_vec<T: Serialize>(a: &T, ad: &[u8]) -> SgxResult<Vec<u8>> {
     let bin = match serde_cbor::ser::to_vec(a) {
         Ok(b) => b,
@@ -50,6 +56,7 @@ fn ser_and_seal_to_vec<T: Serialize>(a: &T, ad: &[u8]) -> SgxResult<Vec<u8>> {
 
 // TODO: make input generic AsRef<[u8]>
 /// Unseal bytes and unmarshal to a T. Returns (T, additional data)
+#[cfg(feature = "trusted")]
 fn unseal_vec_and_deser<T: DeserializeOwned + Default>(input: &Vec<u8>) -> SgxResult<(T, Vec<u8>)> {
     let mut bin = input.clone();
 
@@ -79,6 +86,32 @@ fn unseal_vec_and_deser<T: DeserializeOwned + Default>(input: &Vec<u8>) -> SgxRe
     Ok((t, unsealed.get_additional_txt().to_vec()))
 }
 
+/// Stand-in for SgxSealedData outside of SGX. There is no sealing key, so the payload is stored
+/// in the clear next to its additional data. Only use it for testing.
+#[cfg(feature = "untrusted")]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde")]
+struct SoftwareSealedData {
+    ad: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+#[cfg(feature = "untrusted")]
+fn ser_and_seal_to_vec<T: Serialize>(a: &T, ad: &[u8]) -> SgxResult<Vec<u8>> {
+    let sealed = SoftwareSealedData {
+        ad: ad.to_vec(),
+        payload: serialize_to_vec(a)?,
+    };
+
+    serialize_to_vec(&sealed)
+}
+
+#[cfg(feature = "untrusted")]
+fn unseal_vec_and_deser<T: DeserializeOwned + Default>(input: &Vec<u8>) -> SgxResult<(T, Vec<u8>)> {
+    let sealed: SoftwareSealedData = deserialize_from_vec(input)?;
+    Ok((deserialize_from_vec(&sealed.payload)?, sealed.ad))
+}
+
 /// a few useful traits
 /// This is a private trait
 ///
@@ -124,16 +157,33 @@ impl UnsealableInto<SgxPrivateKey> for SealedSigPrivKey {
     }
 }
 
+/// The additional data each of a user's sealed shared secrets is bound to. This authenticates
+/// everything `sealed` holds in the clear
+fn client_secret_ad(pk: &SgxProtectedKeyPub, sealed: &SealedSharedSecretsDbClient) -> Vec<u8> {
+    let mut ad = Vec::new();
+    ad.extend_from_slice(&pk.0);
+    ad.extend_from_slice(&sealed.round.to_ne_bytes());
+    ad.extend_from_slice(&sealed.counter_value.to_ne_bytes());
+    for witness_pk in sealed.witness_pks.iter() {
+        ad.extend_from_slice(witness_pk.as_bytes());
+    }
+    ad.extend_from_slice(&sealed.participation_window.to_ne_bytes());
+    ad.extend_from_slice(&sealed.times_participated.to_ne_bytes());
+    ad
+}
+
 impl SealInto<SealedSharedSecretsDbClient> for SharedSecretsDbClient {
     fn seal_into(&self) -> SgxResult<SealedSharedSecretsDbClient> {
         let mut sealed_shared_secrets = SealedSharedSecretsDbClient::default();
         sealed_shared_secrets.round = self.round;
+        sealed_shared_secrets.counter_value = self.counter_value;
+        sealed_shared_secrets.witness_pks = self.witness_pks.clone();
+        sealed_shared_secrets.participation_window = self.participation_window;
+        sealed_shared_secrets.times_participated = self.times_participated;
 
         for (k, s) in self.db.iter() {
-            // authenticate public keys and rounds in "ad"
-            let mut ad = Vec::new();
-            ad.extend_from_slice(&k.0);
-            ad.extend_from_slice(&self.round.to_ne_bytes());
+            // authenticate public keys, rounds and the counters in "ad"
+            let ad = client_secret_ad(k, &sealed_shared_secrets);
 
             sealed_shared_secrets
                 .db
@@ -148,11 +198,13 @@ impl UnsealableInto<SharedSecretsDbClient> for SealedSharedSecretsDbClient {
     fn unseal_into(&self) -> sgx_types::SgxResult<SharedSecretsDbClient> {
         let mut db = SharedSecretsDbClient::default();
         db.round = self.round;
+        db.counter_value = self.counter_value;
+        db.witness_pks = self.witness_pks.clone();
+        db.participation_window = self.participation_window;
+        db.times_participated = self.times_participated;
         for (k, v) in self.db.iter() {
-            // exoected ad = pk || round
-            let mut expected_ad = Vec::new();
-            expected_ad.extend_from_slice(&k.0);
-            expected_ad.extend_from_slice(&self.round.to_ne_bytes());
+            // exoected ad = pk || round || counters
+            let expected_ad = client_secret_ad(k, self);
 
             let (secret, ad) = unseal_vec_and_deser(&v)?;
 
@@ -168,3 +220,66 @@ impl UnsealableInto<SharedSecretsDbClient> for SealedSharedSecretsDbClient {
         Ok(db)
     }
 }
+
+impl SealInto<SealedUserPrivKeys> for UserPrivKeys {
+    fn seal_into(&self) -> SgxResult<SealedUserPrivKeys> {
+        Ok(SealedUserPrivKeys(self.seal(None)?))
+    }
+}
+
+impl UnsealableInto<UserPrivKeys> for SealedUserPrivKeys {
+    fn unseal_into(&self) -> sgx_types::SgxResult<UserPrivKeys> {
+        Ok(unseal_vec_and_deser(&self.0)?.0) // ignore the ad
+    }
+}
+
+impl SealInto<SealedKemPrivKey> for SgxPrivateKey {
+    fn seal_into(&self) -> SgxResult<SealedKemPrivKey> {
+        Ok(SealedKemPrivKey(self.seal(None)?))
+    }
+}
+
+impl UnsealableInto<SgxPrivateKey> for SealedKemPrivKey {
+    fn unseal_into(&self) -> sgx_types::SgxResult<SgxPrivateKey> {
+        Ok(unseal_vec_and_deser(&self.0)?.0) // ignore the ad
+    }
+}
+
+impl SealInto<SealedSharedSecretsDbServer> for SharedSecretsDbServer {
+    fn seal_into(&self) -> SgxResult<SealedSharedSecretsDbServer> {
+        // authenticate the round in "ad"
+        Ok(SealedSharedSecretsDbServer {
+            round: self.round,
+            sealed_db: self.seal(Some(&self.round.to_ne_bytes()))?,
+        })
+    }
+}
+
+impl UnsealableInto<SharedSecretsDbServer> for SealedSharedSecretsDbServer {
+    fn unseal_into(&self) -> sgx_types::SgxResult<SharedSecretsDbServer> {
+        let (db, ad): (SharedSecretsDbServer, _) = unseal_vec_and_deser(&self.sealed_db)?;
+        if ad != self.round.to_ne_bytes() || db.round != self.round {
+            error!("unseal SharedSecretsDbServer failed. Ad not matching");
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+
+        Ok(db)
+    }
+}
+
+impl SealInto<SealedPartialRoundSecret> for PartialRoundSecret {
+    fn seal_into(&self) -> SgxResult<SealedPartialRoundSecret> {
+        Ok(SealedPartialRoundSecret {
+            round: self.round,
+            user_ids: self.user_ids.clone(),
+            sealed_secret: self.seal(None)?,
+        })
+    }
+}
+
+impl UnsealableInto<PartialRoundSecret> for SealedPartialRoundSecret {
+    fn unseal_into(&self) -> sgx_types::SgxResult<PartialRoundSecret> {
+        // the round and users in the clear are only for the host. Use the sealed ones
+        Ok(unseal_vec_and_deser(&self.sealed_secret)?.0)
+    }
+}
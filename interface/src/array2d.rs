@@ -155,6 +155,7 @@
 
 #![deny(missing_docs)]
 
+use crate::sgx_protected_keys::xor_bytes;
 use std::ops::{Index, IndexMut};
 use std::prelude::v1::*;
 use std::vec;
@@ -1216,6 +1217,21 @@ impl<T> Array2D<T> {
     }
 }
 
+impl Array2D<u8> {
+    /// XORs `other` into this array, element by element. The flat arrays are XORed a word at a
+    /// time, starting from the first word boundary in each, so this is much faster than
+    /// XORing over [`as_mut_slice`].
+    ///
+    /// Panics if the arrays have different dimensions.
+    ///
+    /// [`as_mut_slice`]: struct.Array2D.html#method.as_mut_slice
+    pub fn xor_mut(&mut self, other: &Array2D<u8>) {
+        assert_eq!(self.num_rows, other.num_rows);
+        assert_eq!(self.num_columns, other.num_columns);
+        xor_bytes(&mut self.array, &other.array);
+    }
+}
+
 impl<T> Index<(usize, usize)> for Array2D<T> {
     type Output = T;
 
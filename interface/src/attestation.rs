@@ -0,0 +1,134 @@
+use sha2::{Digest, Sha256, Sha512};
+use std::vec;
+use std::vec::Vec;
+
+use crate::sgx_protected_keys::AttestedPublicKey;
+
+/// The number of bytes of user data an SGX report carries
+pub const REPORT_DATA_LENGTH: usize = 64;
+
+// Offsets into an sgx_quote_t. The report body starts after a 48-byte header, and the signature
+// length follows the 384-byte report body.
+const QUOTE_BODY_OFFSET: usize = 48;
+const QUOTE_MR_ENCLAVE_OFFSET: usize = QUOTE_BODY_OFFSET + 64;
+const QUOTE_MR_SIGNER_OFFSET: usize = QUOTE_BODY_OFFSET + 128;
+const QUOTE_REPORT_DATA_OFFSET: usize = QUOTE_BODY_OFFSET + 320;
+const QUOTE_SIG_LEN_OFFSET: usize = QUOTE_BODY_OFFSET + 384;
+/// The size of an sgx_quote_t without its signature
+pub const QUOTE_MIN_LENGTH: usize = QUOTE_SIG_LEN_OFFSET + 4;
+
+/// The measurement the software enclave reports as its MRENCLAVE. It is not the hash of
+/// anything; it only lets an allow-list name the software enclave explicitly.
+pub const SOFTWARE_ENCLAVE_MR_ENCLAVE: [u8; 32] = *b"zipnet software enclave\0\0\0\0\0\0\0\0\0";
+
+/// The key mock quotes are MACed with. It is public, so a mock quote proves nothing about where
+/// it came from. Mock quotes only exist so that the verification path can run without SGX.
+const MOCK_QUOTE_KEY: &[u8] = b"zipnet mock quoting enclave";
+
+/// Opaque target info (an sgx_target_info_t) of the quoting enclave. An SGX report is only
+/// useful to the enclave it targets, so the enclave must be told who will turn it into a quote.
+/// The software enclave ignores this.
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct QuotingTarget(pub Vec<u8>);
+
+/// The parts of a quote that attestation policy is concerned with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuoteBody {
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub report_data: [u8; REPORT_DATA_LENGTH],
+}
+
+impl QuoteBody {
+    /// Reads the measurements and report data out of a serialized sgx_quote_t. This does NOT
+    /// check the quote's signature.
+    pub fn parse(quote: &[u8]) -> Option<QuoteBody> {
+        if quote.len() < QUOTE_MIN_LENGTH {
+            return None;
+        }
+
+        let mut body = QuoteBody {
+            mr_enclave: [0u8; 32],
+            mr_signer: [0u8; 32],
+            report_data: [0u8; REPORT_DATA_LENGTH],
+        };
+        body.mr_enclave
+            .copy_from_slice(&quote[QUOTE_MR_ENCLAVE_OFFSET..QUOTE_MR_ENCLAVE_OFFSET + 32]);
+        body.mr_signer
+            .copy_from_slice(&quote[QUOTE_MR_SIGNER_OFFSET..QUOTE_MR_SIGNER_OFFSET + 32]);
+        body.report_data.copy_from_slice(
+            &quote[QUOTE_REPORT_DATA_OFFSET..QUOTE_REPORT_DATA_OFFSET + REPORT_DATA_LENGTH],
+        );
+
+        Some(body)
+    }
+
+    /// Returns the signature appended to a serialized sgx_quote_t, if its length is consistent
+    fn signature(quote: &[u8]) -> Option<&[u8]> {
+        if quote.len() < QUOTE_MIN_LENGTH {
+            return None;
+        }
+
+        let mut sig_len = [0u8; 4];
+        sig_len.copy_from_slice(&quote[QUOTE_SIG_LEN_OFFSET..QUOTE_MIN_LENGTH]);
+        let sig_len = u32::from_le_bytes(sig_len) as usize;
+
+        if quote.len() != QUOTE_MIN_LENGTH + sig_len {
+            return None;
+        }
+
+        Some(&quote[QUOTE_MIN_LENGTH..])
+    }
+}
+
+impl AttestedPublicKey {
+    /// The report data an enclave puts in the attestation of this key. Binds the role and both
+    /// public keys.
+    pub fn report_data(&self) -> [u8; REPORT_DATA_LENGTH] {
+        let mut h = Sha512::new();
+        h.input("zipnet attested key");
+        h.input((self.role.len() as u64).to_le_bytes());
+        h.input(self.role.as_bytes());
+        h.input(&self.pk);
+        h.input(&self.xpk);
+
+        let mut report_data = [0u8; REPORT_DATA_LENGTH];
+        report_data.copy_from_slice(&h.result());
+        report_data
+    }
+}
+
+fn mock_quote_mac(signed_part: &[u8]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.input(MOCK_QUOTE_KEY);
+    h.input(signed_part);
+
+    let mut mac = [0u8; 32];
+    mac.copy_from_slice(&h.result());
+    mac
+}
+
+/// Makes a quote with the layout of an sgx_quote_t, "signed" with the public mock key
+pub fn make_mock_quote(body: &QuoteBody) -> Vec<u8> {
+    let mut quote = vec![0u8; QUOTE_MIN_LENGTH];
+    quote[QUOTE_MR_ENCLAVE_OFFSET..QUOTE_MR_ENCLAVE_OFFSET + 32].copy_from_slice(&body.mr_enclave);
+    quote[QUOTE_MR_SIGNER_OFFSET..QUOTE_MR_SIGNER_OFFSET + 32].copy_from_slice(&body.mr_signer);
+    quote[QUOTE_REPORT_DATA_OFFSET..QUOTE_REPORT_DATA_OFFSET + REPORT_DATA_LENGTH]
+        .copy_from_slice(&body.report_data);
+    quote[QUOTE_SIG_LEN_OFFSET..QUOTE_MIN_LENGTH].copy_from_slice(&32u32.to_le_bytes());
+
+    let mac = mock_quote_mac(&quote);
+    quote.extend_from_slice(&mac);
+    quote
+}
+
+/// Checks the MAC on a quote made by [`make_mock_quote`] and returns its body
+pub fn verify_mock_quote(quote: &[u8]) -> Option<QuoteBody> {
+    let sig = QuoteBody::signature(quote)?;
+    if sig != mock_quote_mac(&quote[..QUOTE_MIN_LENGTH]) {
+        return None;
+    }
+
+    QuoteBody::parse(quote)
+}
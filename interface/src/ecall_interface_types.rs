@@ -1,8 +1,11 @@
-use crate::params::SHARED_SECRET_LENGTH;
-use crate::sgx_protected_keys::{AttestedPublicKey, OutputSignature, SgxProtectedKeyPub};
-use crate::user_request::DcRoundMessage;
+use crate::params::{DcNetParams, SHARED_SECRET_LENGTH};
+use crate::sgx_protected_keys::{
+    AttestedPublicKey, OutputSignature, SgxProtectedKeyPub, SignatureBytes,
+};
 use crate::user_request::EntityId;
-use std::collections::BTreeMap;
+use crate::user_request::{DcRoundMessage, RoundSecret};
+use ed25519_dalek::PublicKey;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
 use std::vec::Vec;
 
@@ -36,6 +39,16 @@ impl_enum! {
         EcallNewUser = 3,
         EcallNewUserBatch = 16,
         EcallUserSubmit = 5,
+        EcallServerNew = 6,
+        EcallRecvUserReg = 7,
+        EcallUnblindAggregatePartial = 8,
+        EcallUnblindAggregate = 9,
+        EcallSignRoundOutput = 10,
+        EcallUserSubmitBatch = 11,
+        EcallFetchOutput = 12,
+        EcallSignCounterAdvance = 13,
+        EcallRecvServerReg = 14,
+        EcallRecvAggregatorReg = 15,
     }
 }
 
@@ -45,6 +58,16 @@ impl EcallId {
             EcallId::EcallNewUser => "EcallNewUser",
             EcallId::EcallNewUserBatch => "EcallNewUserBatch",
             EcallId::EcallUserSubmit => "EcallUserSubmit",
+            EcallId::EcallServerNew => "EcallServerNew",
+            EcallId::EcallRecvUserReg => "EcallRecvUserReg",
+            EcallId::EcallUnblindAggregatePartial => "EcallUnblindAggregatePartial",
+            EcallId::EcallUnblindAggregate => "EcallUnblindAggregate",
+            EcallId::EcallSignRoundOutput => "EcallSignRoundOutput",
+            EcallId::EcallUserSubmitBatch => "EcallUserSubmitBatch",
+            EcallId::EcallFetchOutput => "EcallFetchOutput",
+            EcallId::EcallSignCounterAdvance => "EcallSignCounterAdvance",
+            EcallId::EcallRecvServerReg => "EcallRecvServerReg",
+            EcallId::EcallRecvAggregatorReg => "EcallRecvAggregatorReg",
         }
     }
 }
@@ -68,13 +91,25 @@ pub struct SealedFootprintTicket(pub Vec<u8>);
 #[derive(Default, Clone, Serialize, Deserialize)]
 pub struct SealedSharedSecretsDbClient {
     pub round: u32,
+    /// The user's freshness counter when these secrets were sealed. Every anytrust server keeps a
+    /// copy of the counter, and the enclave only uses the secrets once all of them have signed a
+    /// [`CounterAdvance`] from this value, so an old copy can't be replayed. Authenticated by the
+    /// seal
+    pub counter_value: u32,
+    /// The anytrust servers' signing keys, which witness the counter. Fixed when the user is made,
+    /// and authenticated by the seal
+    pub witness_pks: Vec<PublicKey>,
+    /// The number of times the user has talked or reserved in `participation_window`. The enclave
+    /// keeps this count for rate limiting. Both are authenticated by the seal
+    pub participation_window: u32,
+    pub times_participated: u32,
     pub db: BTreeMap<SgxProtectedKeyPub, Vec<u8>>,
 }
 
 impl SealedSharedSecretsDbClient {
-    pub fn anytrust_group_id(&self) -> EntityId {
+    pub fn anytrust_group_id(&self, params: &DcNetParams) -> EntityId {
         let keys: Vec<SgxProtectedKeyPub> = self.db.keys().cloned().collect();
-        crate::compute_anytrust_group_id(&keys)
+        crate::compute_anytrust_group_id(&keys, params)
     }
 }
 
@@ -82,11 +117,29 @@ impl Debug for SealedSharedSecretsDbClient {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         let pks: Vec<SgxProtectedKeyPub> = self.db.keys().cloned().collect();
         f.debug_struct("SealedSharedSecretsDbClient")
+            .field("counter_value", &self.counter_value)
             .field("pks", &pks)
             .finish()
     }
 }
 
+/// Asks the anytrust servers to move a user's freshness counter from `from` to `from + 1`. A server
+/// signs this only if its copy of the counter is at `from`, and then moves its copy on. It also
+/// signs its last advance again, so a user whose submission failed part way can retry it. The
+/// enclave derives `nonce` from the submission, so only that same submission can be retried. See
+/// [`SealedSharedSecretsDbClient::counter_value`]
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CounterAdvance {
+    pub user_id: EntityId,
+    pub from: u32,
+    pub nonce: [u8; 32],
+    /// The user's signature on the advance, under the key it registered with. Only the user's
+    /// enclave can move its counter on
+    pub user_sig: SignatureBytes,
+    pub server_sigs: Vec<OutputSignature>,
+}
+
 /// A shared secret is the long-term secret shared between an anytrust server and this user
 #[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
 #[derive(Copy, Clone, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -109,6 +162,98 @@ impl Debug for DiffieHellmanSharedSecret {
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SealedSigPrivKey(pub Vec<u8>);
 
+/// A user's signing, KEM and scheduling PRF keys, sealed together. Only the user's enclave can
+/// unseal them
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SealedUserPrivKeys(pub Vec<u8>);
+
+/// An anytrust server's KEM decapsulation key. Only the server's enclave can unseal it
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SealedKemPrivKey(pub Vec<u8>);
+
+/// Enclave-protected secrets shared between an anytrust server and its users.
+/// This data structure is used by servers only. A server shares a secret with every user, so
+/// unlike [`SealedSharedSecretsDbClient`], the whole database is sealed as one blob. The blob also
+/// holds the server's freshness counter, which the other servers in the group witness like they
+/// witness the users' counters, and the keys of the group's servers and top-level aggregators.
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedSharedSecretsDbServer {
+    /// The first round these secrets can be used for. This is authenticated by the seal
+    pub round: u32,
+    pub sealed_db: Vec<u8>,
+}
+
+impl Debug for SealedSharedSecretsDbServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SealedSharedSecretsDbServer")
+            .field("round", &self.round)
+            .field("sealed_db_len", &self.sealed_db.len())
+            .finish()
+    }
+}
+
+/// The XOR of some users' pads for one round, derived and sealed by a server's enclave. Pads can
+/// be derived in parts on many threads, or ahead of time, and only the enclave that unblinds the
+/// round's aggregate sees them XORed together.
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedPartialRoundSecret {
+    pub round: u32,
+    /// The users whose pads are in this. The enclave only trusts the copy inside the seal
+    pub user_ids: BTreeSet<EntityId>,
+    pub sealed_secret: Vec<u8>,
+}
+
+impl Debug for SealedPartialRoundSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SealedPartialRoundSecret")
+            .field("round", &self.round)
+            .field("num_users", &self.user_ids.len())
+            .finish()
+    }
+}
+
+/// Asks a server's enclave to unblind a top-level aggregate. The fields describing the aggregate
+/// are those an aggregator signs (see [`aggregate_digest`](crate::aggregate_digest)).
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerUnblindReq {
+    pub round: u32,
+    pub anytrust_group_id: EntityId,
+    /// The users whose submissions are in the aggregate
+    pub user_ids: BTreeSet<EntityId>,
+    pub aggregated_msg: DcRoundMessage,
+    /// The top-level aggregator's signature on the aggregate
+    pub aggregator_sig: OutputSignature,
+    /// The DC net parameters of the anytrust group
+    pub dc_net_params: DcNetParams,
+    pub shared_secrets: SealedSharedSecretsDbServer,
+    /// Pads that were already derived for this round. Partials that cover the same user cancel
+    /// out, so the enclave derives the pads of the users in `user_ids` that the partials don't
+    /// cover, and of the users they cover that aren't in `user_ids`.
+    pub partial_secrets: Vec<SealedPartialRoundSecret>,
+}
+
+/// Asks a server's enclave to derive and sign the output of a round. The fields describing the
+/// aggregate are as in [`ServerUnblindReq`]. The output is the aggregate XORed with every server's
+/// key share of it.
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignRoundOutputReq {
+    pub round: u32,
+    pub anytrust_group_id: EntityId,
+    pub user_ids: BTreeSet<EntityId>,
+    pub aggregated_msg: DcRoundMessage,
+    /// Each server's key share of the aggregate, with its signature on the share
+    pub key_shares: Vec<(RoundSecret, OutputSignature)>,
+    /// The server's shared secrets as of some round after this one. Its anytrust group is known
+    /// by then
+    pub shared_secrets: SealedSharedSecretsDbServer,
+}
+
 #[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
 #[derive(Clone, Default, Serialize, Debug, Deserialize)]
 pub struct RoundOutput {
@@ -27,11 +27,13 @@ cfg_if! {
 }
 
 mod array2d;
+mod attestation;
 mod ecall_interface_types;
 mod params;
 mod sgx_protected_keys;
 mod user_request;
 
+pub use attestation::*;
 pub use ecall_interface_types::*;
 pub use params::*;
 pub use sgx_protected_keys::*;
@@ -1,5 +1,4 @@
-/// whether or not DC_NET_N_SLOTS,DC_NET_MESSAGE_LENGTH, FOOTPRINT_N_SLOTS are read from env, or from
-pub const PARAMETER_FLAG: bool = true;
+use std::vec::Vec;
 
 /// Whether this is evaluation mode, or this is normal running mode.
 /// When turning to evaluation mode, aggregator will first save all msg from client to file,
@@ -23,6 +22,87 @@ pub const DC_NUM_USER: usize = 1024;
 pub const DC_NET_N_SLOTS: usize = 100;
 /// The number of bytes in each DC net slot
 pub const DC_NET_MESSAGE_LENGTH: usize = 160;
+/// The number of anytrust servers that must sign a round output before users accept it. This is
+/// only right for single-server groups. Deployments set the threshold when a server is created,
+/// usually to the size of the group
+pub const ROUND_OUTPUT_SIG_THRESHOLD: usize = 1;
+
+/// The dimensions of a DC net round, and how many servers must sign its output. These are fixed
+/// when an anytrust group is created and are hashed into the group's ID, so every client,
+/// aggregator, and server of a group agrees on them. The default is given by the constants above.
+#[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DcNetParams {
+    /// The number of users in the group
+    pub num_users: usize,
+    /// The number of slots in a DC net message
+    pub n_slots: usize,
+    /// The number of bytes in each DC net slot
+    pub message_length: usize,
+    /// The number of scheduling slots. Must be at least n_slots
+    pub footprint_n_slots: usize,
+    /// The number of distinct anytrust servers whose signatures a round output needs. Must be
+    /// positive and at most the size of the group
+    pub sig_threshold: usize,
+}
+
+impl Default for DcNetParams {
+    fn default() -> Self {
+        DcNetParams {
+            num_users: DC_NUM_USER,
+            n_slots: DC_NET_N_SLOTS,
+            message_length: DC_NET_MESSAGE_LENGTH,
+            footprint_n_slots: FOOTPRINT_N_SLOTS,
+            sig_threshold: ROUND_OUTPUT_SIG_THRESHOLD,
+        }
+    }
+}
+
+impl DcNetParams {
+    /// Checks that the parameters describe a usable DC net
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.num_users == 0 {
+            return Err("num_users must be positive");
+        }
+        if self.n_slots == 0 {
+            return Err("n_slots must be positive");
+        }
+        if self.message_length == 0 {
+            return Err("message_length must be positive");
+        }
+        if self.footprint_n_slots < self.n_slots {
+            return Err("footprint_n_slots must be at least n_slots");
+        }
+        // Footprints and slot indices are derived as u32s in the enclave
+        if self.footprint_n_slots > u32::MAX as usize {
+            return Err("footprint_n_slots must fit in a u32");
+        }
+        if self.n_slots.checked_mul(self.message_length).is_none() {
+            return Err("n_slots * message_length overflows");
+        }
+        if self.sig_threshold == 0 {
+            return Err("sig_threshold must be positive");
+        }
+
+        Ok(())
+    }
+
+    /// A canonical encoding of the parameters. This is what goes into the anytrust group ID
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        for x in &[
+            self.num_users,
+            self.n_slots,
+            self.message_length,
+            self.footprint_n_slots,
+            self.sig_threshold,
+        ] {
+            b.extend(&(*x as u64).to_le_bytes());
+        }
+
+        b
+    }
+}
 
 /// There are these many rounds per window
 pub const DC_NET_ROUNDS_PER_WINDOW: u32 = 100;
@@ -49,6 +129,15 @@ pub fn round_window(round: u32) -> u32 {
         .unwrap()
 }
 
+/// Shared secrets are ratcheted forward once every this many rounds. The secrets of an epoch can
+/// derive the round secret of any round in that epoch or a later one
+pub const DC_NET_ROUNDS_PER_EPOCH: u32 = 100;
+
+/// Gets the epoch that this round belongs to
+pub fn round_epoch(round: u32) -> u32 {
+    round / DC_NET_ROUNDS_PER_EPOCH
+}
+
 pub const ENCLAVE_LOG_LEVEL: &str = "off"; // "debug" or "info"
 
 /// Number of threads for deriving round secrets
@@ -7,11 +7,13 @@ use core::fmt;
 use core::fmt::{Debug, Display, Formatter};
 use log;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::vec::Vec;
 use std::{println, vec};
 
-use crate::ecall_interface_types::RoundOutput;
+use crate::ecall_interface_types::{CounterAdvance, RoundOutput};
+use crate::params::DcNetParams;
 use crate::user_request::{DcMessage, DcRoundMessage, EntityId, UserSubmissionMessage};
 
 #[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
@@ -48,7 +50,7 @@ impl SgxProtectedKeyPub {
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct AttestedPublicKey {
     pub pk: SgxProtectedKeyPub,  // sig pub key
-    pub xpk: SgxProtectedKeyPub, // kem pub key. todo: both keys are derived from the same secret.
+    pub xpk: SgxProtectedKeyPub, // kem pub key
     pub role: std::string::String,
     /// role denotes the intended use of this key e.g., "aggregator" "client" "anytrust server"
     pub tee_linkable_attestation: std::vec::Vec<u8>, // binds this key to an enclave
@@ -75,6 +77,9 @@ pub struct ServerPubKeyPackage {
     pub sig: PublicKey,
     pub kem: PublicKey,
     pub xkem: SgxProtectedKeyPub, //todo: why is server key using SGX type?
+    /// The DC net parameters this server was created with. All servers in an anytrust group must
+    /// agree on these.
+    pub dc_net_params: DcNetParams,
 }
 
 /// Store the bytes of signatures
@@ -82,7 +87,7 @@ pub struct ServerPubKeyPackage {
 #[derive(Clone, Default, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
 pub struct SignatureBytes(pub Vec<u8>);
 
-/// Used by servers in round outputs
+/// A server's signature. Used by servers in round outputs
 #[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct OutputSignature {
@@ -124,9 +129,53 @@ pub trait MultiSignable {
 
         Ok((sig, pk))
     }
-    /// verify against a list of public keys
-    /// return indices of keys that verify
-    fn verify_multisig(&self, pks: &[PublicKey]) -> Result<Vec<usize>, ()>;
+    /// Verifies the signatures against a list of public keys. Returns the indices (into `pks`) of
+    /// the distinct keys that signed, or an error if fewer than `threshold` of them did.
+    /// Malformed signatures, invalid signatures, and signatures by keys not in `pks` are ignored,
+    /// and a key that signs twice is only counted once. A threshold of 0 is an error.
+    fn verify_multisig(&self, pks: &[PublicKey], threshold: usize) -> Result<Vec<usize>, ()>;
+}
+
+/// Does [`MultiSignable::verify_multisig`] for a message with the given digest and signatures
+fn verify_server_sigs(
+    msg_hash: &[u8],
+    server_sigs: &[OutputSignature],
+    pks: &[PublicKey],
+    threshold: usize,
+) -> Result<Vec<usize>, ()> {
+    if threshold == 0 {
+        log::error!("a signature threshold of 0 accepts anything");
+        return Err(());
+    }
+
+    let mut verified = BTreeSet::new();
+    for server_sig in server_sigs.iter() {
+        // check if pk is in the server PK list
+        let i = match pks.iter().position(|k| *k == server_sig.pk) {
+            Some(i) => i,
+            None => {
+                log::warn!("PK {:?} is not in the server PK list", server_sig.pk);
+                continue;
+            }
+        };
+
+        // verify the signature
+        let valid = Signature::from_bytes(&server_sig.sig.0)
+            .and_then(|sig| server_sig.pk.verify(msg_hash, &sig));
+        if valid.is_err() {
+            log::warn!("invalid signature from {:?}", server_sig.pk);
+            continue;
+        }
+
+        verified.insert(i);
+    }
+
+    if verified.len() < threshold {
+        log::error!("{} valid signatures, need {}", verified.len(), threshold);
+        return Err(());
+    }
+
+    Ok(verified.into_iter().collect())
 }
 
 impl MultiSignable for RoundOutput {
@@ -134,38 +183,42 @@ impl MultiSignable for RoundOutput {
         self.sha256().to_vec()
     }
 
-    fn verify_multisig(&self, pks: &[PublicKey]) -> Result<Vec<usize>, ()> {
-        // log::debug!(
-        //     "verifying RoundOutput (with {} signatures) against a list of {} server PKs",
-        //     self.server_sigs.len(),
-        //     pks.len()
-        // );
+    fn verify_multisig(&self, pks: &[PublicKey], threshold: usize) -> Result<Vec<usize>, ()> {
+        verify_server_sigs(&self.digest(), &self.server_sigs, pks, threshold)
+    }
+}
 
-        // digest
-        let msg_hash = self.digest();
+impl OutputSignature {
+    /// Whether this is a valid signature on the message with the given digest
+    pub fn verify(&self, msg_hash: &[u8]) -> bool {
+        Signature::from_bytes(&self.sig.0)
+            .and_then(|sig| self.pk.verify(msg_hash, &sig))
+            .is_ok()
+    }
+}
 
-        let mut verified = vec![];
-        for i in 0..self.server_sigs.len() {
-            let sig: Signature =
-                Signature::from_bytes(self.server_sigs[i].sig.0.clone().as_slice())
-                    .expect("failed to generate Signature from bytes");
-            let pk: PublicKey = self.server_sigs[i].pk;
+impl CounterAdvance {
+    /// Whether `user_sig` is a signature on this advance by the given key
+    pub fn verify_user_sig(&self, user_pk: &PublicKey) -> bool {
+        Signature::from_bytes(&self.user_sig.0)
+            .and_then(|sig| user_pk.verify(&self.digest(), &sig))
+            .is_ok()
+    }
+}
 
-            // verify the signature
-            if pk.verify(msg_hash.as_slice(), &sig).is_ok() {
-                // debug!("signature verified against {:?}", pk);
-            }
+impl MultiSignable for CounterAdvance {
+    fn digest(&self) -> Vec<u8> {
+        let mut h = Sha256::new();
+        h.input(b"counter-advance");
+        h.input(&self.user_id);
+        h.input(&self.from.to_le_bytes());
+        h.input(&self.nonce);
 
-            // check if pk is in the server PK list
-            match pks.iter().position(|&k| k == pk) {
-                Some(i) => verified.push(i),
-                None => {
-                    // log::error!("PK {:?} is not in the server PK list", pk);
-                }
-            }
-        }
+        h.result().to_vec()
+    }
 
-        Ok(verified)
+    fn verify_multisig(&self, pks: &[PublicKey], threshold: usize) -> Result<Vec<usize>, ()> {
+        verify_server_sigs(&self.digest(), &self.server_sigs, pks, threshold)
     }
 }
 
@@ -184,11 +237,46 @@ pub trait Xor: Clone {
     }
 }
 
+/// Sets `lhs = lhs XOR rhs`. This works on u64 words rather than bytes, which the compiler
+/// further turns into SIMD instructions where the target has them. Panics if the slices are of
+/// different lengths.
+pub fn xor_bytes(lhs: &mut [u8], rhs: &[u8]) {
+    assert_eq!(lhs.len(), rhs.len());
+
+    // Safety: every bit pattern is a valid u64, so viewing bytes as words is sound
+    let (lhs_head, lhs_words, lhs_tail) = unsafe { lhs.align_to_mut::<u64>() };
+    let (rhs_head, rhs_words, rhs_tail) = unsafe { rhs.align_to::<u64>() };
+    if lhs_head.len() == rhs_head.len() && lhs_words.len() == rhs_words.len() {
+        xor_bytewise(lhs_head, rhs_head);
+        for (l, r) in lhs_words.iter_mut().zip(rhs_words.iter()) {
+            *l ^= *r;
+        }
+        xor_bytewise(lhs_tail, rhs_tail);
+        return;
+    }
+
+    // The slices sit differently relative to word boundaries, so words can't be read in place
+    // from both. Copy them out instead.
+    const WORD_LEN: usize = core::mem::size_of::<u64>();
+    let mut lhs_chunks = lhs.chunks_exact_mut(WORD_LEN);
+    let mut rhs_chunks = rhs.chunks_exact(WORD_LEN);
+    for (l, r) in (&mut lhs_chunks).zip(&mut rhs_chunks) {
+        let word =
+            u64::from_ne_bytes(l.try_into().unwrap()) ^ u64::from_ne_bytes(r.try_into().unwrap());
+        l.copy_from_slice(&word.to_ne_bytes());
+    }
+    xor_bytewise(lhs_chunks.into_remainder(), rhs_chunks.remainder());
+}
+
+fn xor_bytewise(lhs: &mut [u8], rhs: &[u8]) {
+    for (l, r) in lhs.iter_mut().zip(rhs.iter()) {
+        *l ^= r;
+    }
+}
+
 impl Xor for DcMessage {
     fn xor_mut(&mut self, other: &Self) {
-        for (lhs, rhs) in self.0.iter_mut().zip(other.0.iter()) {
-            *lhs ^= rhs
-        }
+        xor_bytes(&mut self.0, &other.0);
     }
 }
 
@@ -214,13 +302,164 @@ impl Xor for DcRoundMessage {
         }
 
         // XOR the round messages
-        for (lhs, rhs) in self
-            .aggregated_msg
-            .as_mut_slice()
-            .iter_mut()
-            .zip(other.aggregated_msg.as_slice().iter())
-        {
-            *lhs ^= rhs;
+        self.aggregated_msg.xor_mut(&other.aggregated_msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn xor_bytes_matches_bytewise() {
+        // Cover every combination of word offsets, and lengths around word multiples
+        for &len in [0usize, 1, 7, 8, 9, 63, 64, 65, 1000].iter() {
+            for lhs_offset in 0..8 {
+                for rhs_offset in 0..8 {
+                    let mut lhs = bytes(len + lhs_offset, 1);
+                    let rhs = bytes(len + rhs_offset, 2);
+
+                    let mut expected = lhs[lhs_offset..].to_vec();
+                    xor_bytewise(&mut expected, &rhs[rhs_offset..]);
+                    xor_bytes(&mut lhs[lhs_offset..], &rhs[rhs_offset..]);
+                    assert_eq!(lhs[lhs_offset..], expected[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn xor_bytes_length_mismatch() {
+        xor_bytes(&mut [0u8; 8], &[0u8; 9]);
+    }
+
+    fn server_keys(n: usize) -> Vec<SecretKey> {
+        (0..n)
+            .map(|i| SecretKey::from_bytes(&[i as u8 + 1; 32]).unwrap())
+            .collect()
+    }
+
+    /// Makes a round output signed by all the given servers
+    fn signed_round_output(round: u32, signers: &[&SecretKey]) -> RoundOutput {
+        let mut output = RoundOutput {
+            round,
+            dc_msg: DcRoundMessage::new(&DcNetParams::default()),
+            server_sigs: vec![],
+        };
+        for sk in signers {
+            let (sig, pk) = output.sign(sk).unwrap();
+            output.server_sigs.push(OutputSignature { pk, sig });
+        }
+
+        output
+    }
+
+    fn pks(keys: &[SecretKey]) -> Vec<PublicKey> {
+        keys.iter().map(PublicKey::from).collect()
+    }
+
+    fn all(keys: &[SecretKey]) -> Vec<&SecretKey> {
+        keys.iter().collect()
+    }
+
+    #[test]
+    fn multisig_threshold() {
+        let keys = server_keys(3);
+        let output = signed_round_output(5, &all(&keys));
+
+        assert_eq!(output.verify_multisig(&pks(&keys), 3), Ok(vec![0, 1, 2]));
+        assert_eq!(output.verify_multisig(&pks(&keys), 1), Ok(vec![0, 1, 2]));
+
+        // only two of three sign
+        let output = signed_round_output(5, &all(&keys[..2]));
+        assert_eq!(output.verify_multisig(&pks(&keys), 2), Ok(vec![0, 1]));
+        assert!(output.verify_multisig(&pks(&keys), 3).is_err());
+
+        // a threshold of 0 would accept an unsigned output, so it's refused outright
+        let unsigned = signed_round_output(5, &[]);
+        assert!(unsigned.verify_multisig(&pks(&keys), 0).is_err());
+    }
+
+    #[test]
+    fn multisig_tampered_signature() {
+        let keys = server_keys(3);
+
+        // flip a bit in one signature
+        let mut output = signed_round_output(5, &all(&keys));
+        output.server_sigs[1].sig.0[0] ^= 1;
+        assert_eq!(output.verify_multisig(&pks(&keys), 2), Ok(vec![0, 2]));
+        assert!(output.verify_multisig(&pks(&keys), 3).is_err());
+
+        // a truncated signature is ignored rather than panicking
+        let mut output = signed_round_output(5, &all(&keys));
+        output.server_sigs[0].sig.0.truncate(10);
+        assert_eq!(output.verify_multisig(&pks(&keys), 2), Ok(vec![1, 2]));
+
+        // signatures over another round don't carry over
+        let mut output = signed_round_output(5, &all(&keys));
+        output.round = 6;
+        assert!(output.verify_multisig(&pks(&keys), 1).is_err());
+
+        // a signature attributed to the wrong server doesn't verify
+        let mut output = signed_round_output(5, &all(&keys));
+        output.server_sigs[0].pk = PublicKey::from(&keys[2]);
+        assert_eq!(output.verify_multisig(&pks(&keys), 2), Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn multisig_duplicate_and_unknown_signers() {
+        let keys = server_keys(3);
+
+        // one server signing three times is still one signer
+        let output = signed_round_output(5, &[&keys[0], &keys[0], &keys[0]]);
+        assert_eq!(output.verify_multisig(&pks(&keys), 1), Ok(vec![0]));
+        assert!(output.verify_multisig(&pks(&keys), 2).is_err());
+
+        // signatures by keys outside the group don't count
+        let outsiders = server_keys(5);
+        let output = signed_round_output(5, &all(&outsiders[3..]));
+        assert!(output.verify_multisig(&pks(&keys), 1).is_err());
+    }
+
+    /// Compares the word-wide XOR with a byte-by-byte one on messages of the default size. Run
+    /// with `cargo test --release -p interface xor_throughput -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn xor_throughput() {
+        let params = DcNetParams::default();
+        let other = DcRoundMessage::new(&params);
+        let mut msg = DcRoundMessage::new(&params);
+        let iters = 100_000;
+        let len = msg.aggregated_msg.as_slice().len();
+
+        let start = std::time::Instant::now();
+        for _ in 0..iters {
+            xor_bytewise(
+                msg.aggregated_msg.as_mut_slice(),
+                other.aggregated_msg.as_slice(),
+            );
+        }
+        let bytewise = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..iters {
+            msg.aggregated_msg.xor_mut(&other.aggregated_msg);
         }
+        let wordwise = start.elapsed();
+
+        std::println!(
+            "{} XORs of {} bytes: {:?} bytewise, {:?} wordwise",
+            iters,
+            len,
+            bytewise,
+            wordwise
+        );
     }
 }
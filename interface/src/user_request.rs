@@ -1,4 +1,3 @@
-use std::env;
 use std::prelude::v1::*;
 use std::{collections::BTreeSet, vec};
 
@@ -9,22 +8,21 @@ use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
 use ed25519_dalek::{PublicKey, Signature, Verifier};
 
-// a wrapper around RawMessage so that we can impl traits. This stores DC_NET_MESSAGE_LENGTH bytes
+// a wrapper around RawMessage so that we can impl traits. This stores params.message_length bytes
 #[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct DcMessage(pub Vec<u8>);
 
+impl DcMessage {
+    /// Makes an all-zero message of the length given by the group parameters
+    pub fn new(params: &DcNetParams) -> DcMessage {
+        DcMessage(vec![0u8; params.message_length])
+    }
+}
+
 impl Default for DcMessage {
     fn default() -> DcMessage {
-        let dc_net_message_length = if PARAMETER_FLAG {
-            env::var("DC_NET_MESSAGE_LENGTH")
-                .unwrap_or_else(|_| "160".to_string())
-                .parse::<usize>()
-                .expect("Invalid DC_NET_MESSAGE_LENGTH value")
-        } else {
-            DC_NET_MESSAGE_LENGTH
-        };
-        DcMessage(vec![0u8; dc_net_message_length])
+        DcMessage::new(&DcNetParams::default())
     }
 }
 
@@ -71,43 +69,15 @@ pub type Footprint = u32;
 #[cfg_attr(feature = "trusted", serde(crate = "serde_sgx"))]
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct DcRoundMessage {
-    // Contains FOOTPRINT_N_SLOTS elements
+    // Contains params.footprint_n_slots elements
     pub scheduling_msg: Vec<Footprint>,
-    // Contains DC_NET_N_SLOTS rows, each of which is DC_NET_MESSAGE_LENGTH bytes
+    // Contains params.n_slots rows, each of which is params.message_length bytes
     pub aggregated_msg: Array2D<u8>,
 }
 
 impl Default for DcRoundMessage {
     fn default() -> Self {
-        let dc_net_message_length = if PARAMETER_FLAG {
-            env::var("DC_NET_MESSAGE_LENGTH")
-                .unwrap_or_else(|_| "160".to_string())
-                .parse::<usize>()
-                .expect("Invalid DC_NET_MESSAGE_LENGTH value")
-        } else {
-            DC_NET_MESSAGE_LENGTH
-        };
-        let dc_net_n_slots = if PARAMETER_FLAG {
-            env::var("DC_NET_N_SLOTS")
-                .unwrap_or_else(|_| "100".to_string())
-                .parse::<usize>()
-                .expect("Invalid DC_NET_N_SLOTS value")
-        } else {
-            DC_NET_N_SLOTS
-        };
-        let footprint_n_slots = if PARAMETER_FLAG {
-            env::var("FOOTPRINT_N_SLOTS")
-                .unwrap_or_else(|_| "400".to_string())
-                .parse::<usize>()
-                .expect("Invalid FOOTPRINT_N_SLOTS value")
-        } else {
-            FOOTPRINT_N_SLOTS
-        };
-
-        DcRoundMessage {
-            scheduling_msg: vec![0; footprint_n_slots],
-            aggregated_msg: Array2D::filled_with(0u8, dc_net_n_slots, dc_net_message_length),
-        }
+        DcRoundMessage::new(&DcNetParams::default())
     }
 }
 
@@ -126,9 +96,26 @@ impl Debug for DcRoundMessage {
     }
 }
 
+use core::convert::TryInto;
 use rand_core::{CryptoRng, RngCore};
 
 impl DcRoundMessage {
+    /// Makes an all-zero round message with the dimensions given by the group parameters
+    pub fn new(params: &DcNetParams) -> Self {
+        DcRoundMessage {
+            scheduling_msg: vec![0; params.footprint_n_slots],
+            aggregated_msg: Array2D::filled_with(0u8, params.n_slots, params.message_length),
+        }
+    }
+
+    /// Returns whether this message has the dimensions given by the group parameters. XORing
+    /// messages of different shapes silently produces garbage, so every role checks this.
+    pub fn matches_params(&self, params: &DcNetParams) -> bool {
+        self.scheduling_msg.len() == params.footprint_n_slots
+            && self.aggregated_msg.num_rows() == params.n_slots
+            && self.aggregated_msg.num_columns() == params.message_length
+    }
+
     /// used by signature
     pub fn digest(&self) -> Vec<u8> {
         let mut b: Vec<u8> = Vec::new();
@@ -141,24 +128,23 @@ impl DcRoundMessage {
         b
     }
 
-    pub fn rand_from_csprng<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
-        let mut m = DcRoundMessage::default();
+    pub fn rand_from_csprng<R: RngCore + CryptoRng>(params: &DcNetParams, rng: &mut R) -> Self {
+        let mut m = DcRoundMessage::new(params);
 
         // Fill msg slots with random bytes
         rng.fill_bytes(m.aggregated_msg.as_mut_slice());
 
-        // Fill scheduling slots with random u32s
-        // This code is taken from the rand::Fill impl for [u32]:
+        // Fill scheduling slots with random u32s. The bytes are read as little-endian, which gives
+        // the same slots as the rand::Fill impl for [u32] on every platform:
         // https://github.com/rust-random/rand/blob/f0f15b5ece4dabca62520bac936970a8b3e25d2f/src/rng.rs#L348-L364=
-        let buf = &mut m.scheduling_msg;
-        rng.fill_bytes(unsafe {
-            core::slice::from_raw_parts_mut(
-                buf.as_mut_ptr() as *mut u8,
-                buf.len() * core::mem::size_of::<u32>(),
-            )
-        });
-        for x in buf {
-            *x = x.to_le();
+        let mut buf = vec![0u8; m.scheduling_msg.len() * core::mem::size_of::<Footprint>()];
+        rng.fill_bytes(&mut buf);
+        for (x, bytes) in m
+            .scheduling_msg
+            .iter_mut()
+            .zip(buf.chunks_exact(core::mem::size_of::<Footprint>()))
+        {
+            *x = Footprint::from_le_bytes(bytes.try_into().unwrap());
         }
 
         m
@@ -253,9 +239,35 @@ pub fn compute_group_id(ids: &BTreeSet<EntityId>) -> EntityId {
     id
 }
 
-/// An anytrust_group_id is computed from server pub keys
-pub fn compute_anytrust_group_id(keys: &[SgxProtectedKeyPub]) -> EntityId {
-    compute_group_id(&keys.iter().map(|k| EntityId::from(k)).collect())
+/// An anytrust_group_id is computed from server pub keys and the DC net parameters of the group
+pub fn compute_anytrust_group_id(keys: &[SgxProtectedKeyPub], params: &DcNetParams) -> EntityId {
+    let server_ids = compute_group_id(&keys.iter().map(|k| EntityId::from(k)).collect());
+
+    let mut hasher = Sha256::new();
+    hasher.input(b"anytrust-grp");
+    hasher.input(&server_ids.0);
+    hasher.input(&params.to_bytes());
+    let digest = hasher.result();
+
+    let mut id = EntityId::default();
+    id.0.copy_from_slice(&digest);
+    id
+}
+
+/// Returns the DC net parameters that all the given servers agree on. Returns None if the list is
+/// empty, the servers disagree, or the parameters are invalid. The signature threshold may not
+/// exceed the number of servers.
+pub fn agreed_dc_net_params(server_pks: &[ServerPubKeyPackage]) -> Option<DcNetParams> {
+    let params = server_pks.first()?.dc_net_params;
+    if server_pks.iter().any(|pk| pk.dc_net_params != params) {
+        return None;
+    }
+    params.validate().ok()?;
+    if params.sig_threshold > server_pks.len() {
+        return None;
+    }
+
+    Some(params)
 }
 
 /// This is a token that's intended to be used for rate limiting. It's just the sha256 hash of the
@@ -298,13 +310,8 @@ pub enum UserMsg {
         msg: DcMessage,
         /// Output of previous round signed by one or more anytrust server
         prev_round_output: RoundOutput,
-        /// The number of times the user has already talked or reserved this window
-        times_participated: u32,
-    },
-    Reserve {
-        /// The number of times the user has already talked or reserved this window
-        times_participated: u32,
     },
+    Reserve,
     Cover,
 }
 
@@ -323,6 +330,8 @@ pub struct UserSubmissionReq {
     pub user_id: EntityId,
     pub anytrust_group_id: EntityId,
     pub round: u32,
+    /// The DC net parameters of the anytrust group. These are checked against anytrust_group_id
+    pub dc_net_params: DcNetParams,
     pub msg: UserMsg,
     /// A map from server KEM public key to sealed shared secret
     pub shared_secrets: SealedSharedSecretsDbClient,
@@ -380,3 +389,36 @@ impl UserSubmissionMessage {
         }
     }
 }
+
+/// The digest an aggregator signs over the aggregate of the given users' submissions. Server
+/// enclaves compute it too, to sign their shares of the unblinded aggregate.
+pub fn aggregate_digest(
+    round: u32,
+    anytrust_group_id: &EntityId,
+    user_ids: &BTreeSet<EntityId>,
+    aggregated_msg: &DcRoundMessage,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(b"Begin AggregatedMessage");
+    hasher.input(round.to_le_bytes());
+    hasher.input(anytrust_group_id);
+    for id in user_ids.iter() {
+        hasher.input(id);
+    }
+    hasher.input(&aggregated_msg.digest());
+    hasher.input(b"End AggregatedMessage");
+
+    hasher.result().to_vec()
+}
+
+/// The digest a server signs over its share of an unblinded aggregate. `aggregate_digest` is the
+/// digest of the aggregate and `key_share` is the XOR of the server's pads for its users.
+pub fn unblinded_share_digest(aggregate_digest: &[u8], key_share: &RoundSecret) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(b"Begin UnblindedAggregateShare");
+    hasher.input(aggregate_digest);
+    hasher.input(key_share.digest());
+    hasher.input(b"End UnblindedAggregateShare");
+
+    hasher.result().to_vec()
+}
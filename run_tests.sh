@@ -17,7 +17,10 @@ SERVER_ROUNDOUTPUT="server/round_output.txt"
 
 AGG_SERVICE_ADDR="localhost:8785"
 SERVER_SERVICE_ADDR="localhost:8122"
+SERVER_WITNESS_PORT=8130
 
+# A lone server unblinds without witnesses. With more, each offline unblind-aggregate would need
+# --peer-urls pointing at the other servers' running services
 NUM_SERVERS=1
 NUM_USERS=5
 NUM_AGGREGATORS=1
@@ -25,14 +28,41 @@ NUM_USERS_PER_AGGREGATOR=5
 
 NUM_TEST_ROUNDS=2
 
+# The DC net parameters. These are fixed when the servers are created
+DC_NET_MESSAGE_LENGTH=160
+DC_NET_N_SLOTS=$NUM_USERS
+FOOTPRINT_N_SLOTS=$((4 * $DC_NET_N_SLOTS))
+
 # We define four messages, separated by semicolons. The leading ; is because we index by 1
 # MSGS_STR=";testing;hello;world;yo"
 
+# SGX_MODE=HW runs the SGX enclave, and user attestations are verified with the Intel Attestation
+# Service. That needs IAS_API_KEY and IAS_ROOT_CA to be set. Otherwise everything runs the software
+# enclave, and its mock attestations are accepted
+if [[ "${SGX_MODE:-SIM}" == "HW" ]]; then
+    CARGO_FEATURES=""
+else
+    CARGO_FEATURES="--no-default-features --features common/mock-attestation"
+fi
+
+# Prints the MRENCLAVE that users must attest with. Registrations are refused without one
+allowed_mrenclave() {
+    if [[ "${SGX_MODE:-SIM}" == "HW" ]]; then
+        ${SGX_SDK:-/opt/sgxsdk}/bin/x64/sgx_sign dump -enclave /sgxdcnet/lib/enclave.signed.so \
+            -dumpfile /tmp/enclave_metadata.txt > /dev/null
+        sed -n '/enclave_hash.m:/,/isv_prod_id/p' /tmp/enclave_metadata.txt \
+            | grep -v metadata | tr -d ' \n' | sed 's/0x//g'
+    else
+        # The software enclave's measurement, "zipnet software enclave" padded to 32 bytes
+        echo "7a69706e657420736f66747761726520656e636c617665000000000000000000"
+    fi
+}
+
 build() {
     make -C enclave
     for d in "client" "server" "aggregator"; do
-        # pushd $d && cargo build --release && popd
-        pushd $d && cargo build && popd
+        # pushd $d && cargo build --release $CARGO_FEATURES && popd
+        pushd $d && cargo build $CARGO_FEATURES && popd
     done
 }
 
@@ -96,7 +126,13 @@ setup_servers() {
 
         # Make a new server and save the registration data
         SERVER_REG=$(
-            $CMD_PREFIX new --server-state "../$STATE"
+            $CMD_PREFIX new --server-state "../$STATE" \
+                --num-users $NUM_USERS \
+                --num-slots $DC_NET_N_SLOTS \
+                --msg-length $DC_NET_MESSAGE_LENGTH \
+                --footprint-slots $FOOTPRINT_N_SLOTS \
+                --sig-threshold $NUM_SERVERS \
+                --allow-mrenclave "$(allowed_mrenclave)"
         )
         # Append
         if [[ i -eq 1 ]]; then
@@ -150,7 +186,8 @@ setup_aggregators() {
     for i in $(seq 1 $NUM_AGGREGATORS); do
         STATE="${AGG_STATE%.txt}$i.txt"
         AGG_REG=$(
-            $CMD_PREFIX new --agg-number $i --level 1 --agg-state "../$STATE" --server-keys "../$AGG_SERVERKEYS"
+            $CMD_PREFIX new --agg-number $i --level 1 --agg-state "../$STATE" --server-keys "../$AGG_SERVERKEYS" \
+                --allow-mrenclave "$(allowed_mrenclave)"
         )
 
         # Append
@@ -162,10 +199,10 @@ setup_aggregators() {
     done
 
     # Make a new root aggregator and capture the registration data
-    AGG_REG=$(
-        $CMD_PREFIX new --agg-number 0 --level 0 --agg-state "../$AGG_ROOTSTATE" --server-keys "../$AGG_SERVERKEYS"
+    AGG_ROOTREG=$(
+        $CMD_PREFIX new --agg-number 0 --level 0 --agg-state "../$AGG_ROOTSTATE" --server-keys "../$AGG_SERVERKEYS" \
+            --allow-mrenclave "$(allowed_mrenclave)"
     )
-    AGG_REGS="$AGG_REGS;$AGG_REG"
 
     # Now do the registrations
     cd ../server
@@ -183,6 +220,12 @@ setup_aggregators() {
         done
     done
 
+    # The servers only unblind aggregates from the root aggregator
+    for i in $(seq 1 $NUM_SERVERS); do
+        STATE="${SERVER_STATE%.txt}$i.txt"
+        echo $AGG_ROOTREG | $CMD_PREFIX register-aggregator --top-level --server-state "../$STATE"
+    done
+
     echo "Set up aggregators"
     cd ..
 }
@@ -214,18 +257,21 @@ setup_clients() {
         echo "$USER_REG" | $CMD_PREFIX register-user --server-state "../$STATE"
     done
 
+    # Aggregators only accept submissions from registered users
+    cd ../aggregator
+    # CMD_PREFIX=/tmp/sgxdcnet/target/release/sgxdcnet-aggregator
+    CMD_PREFIX=/tmp/sgxdcnet/target/debug/sgxdcnet-aggregator
+
+    for i in $(seq 1 $NUM_AGGREGATORS); do
+        STATE="${AGG_STATE%.txt}$i.txt"
+        echo "$USER_REG" | $CMD_PREFIX register-user --agg-state "../$STATE"
+    done
+    echo "$USER_REG" | $CMD_PREFIX register-user --agg-state "../$AGG_ROOTSTATE"
+
     echo "Set up clients"
     cd ..
 }
 
-setup_env(){
-    footprint_n_slots=$(expr 4 \* $NUM_USERS)
-    export DC_NET_MESSAGE_LENGTH=160
-    export DC_NET_N_SLOTS=$NUM_USERS
-    export FOOTPRINT_N_SLOTS=$footprint_n_slots
-    export DC_NUM_USER=$NUM_USERS
-}
-
 # Starts round $ROUND with all the aggregators
 start_round() {
     cd aggregator
@@ -245,6 +291,32 @@ start_round() {
     cd ..
 }
 
+# Starts every server's service so that they can witness the users' freshness counters. The i-th
+# server listens on SERVER_WITNESS_PORT + i
+start_witnesses() {
+    cd server
+    # CMD_PREFIX=/tmp/sgxdcnet/target/release/sgxdcnet-server
+    CMD_PREFIX=/tmp/sgxdcnet/target/debug/sgxdcnet-server
+
+    SERVER_URLS=""
+    for i in $(seq 1 $NUM_SERVERS); do
+        STATE="${SERVER_STATE%.txt}$i.txt"
+        PORT=$(($SERVER_WITNESS_PORT + $i))
+        $CMD_PREFIX start-service --server-state "../$STATE" --bind "localhost:$PORT" &
+        SERVER_URLS="$SERVER_URLS,http://localhost:$PORT"
+    done
+    SERVER_URLS=${SERVER_URLS#,}
+    sleep 2
+
+    cd ..
+}
+
+# Stops the services started by start_witnesses. They saved the new counters to the server states
+stop_witnesses() {
+    kill $(jobs -p) 2> /dev/null || true
+    wait 2> /dev/null || true
+}
+
 # Encrypts the messages and sends them to the aggregators
 encrypt_msgs() {
     cd client
@@ -282,6 +354,7 @@ encrypt_msgs() {
             | RUST_LOG=debug $CMD_PREFIX encrypt-msg \
                   --user-state "../$STATE" \
                   --round $ROUND \
+                  --server-urls "$SERVER_URLS" \
                   --prev-round-output $PREV_ROUND_OUTPUT
         )
 
@@ -406,11 +479,12 @@ setup_servers
 #exit 0
 setup_aggregators
 setup_clients
-setup_env
 
 for ROUND in $(seq 0 $(($NUM_TEST_ROUNDS - 1))); do
     start_round
+    start_witnesses
     encrypt_msgs
+    stop_witnesses
     propagate_aggregates
     decrypt_msgs
 done
\ No newline at end of file
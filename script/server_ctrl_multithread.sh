@@ -4,6 +4,7 @@ USER_STATE="../client/user-state.txt"
 AGG_FINALAGG="../aggregator/final-agg.txt"
 AGG_ROOTSTATE="../aggregator/agg-root-state.txt"
 AGG_STATE_PREFIX="../aggregator/agg_state_"
+AGG_CHILDKEYS="../aggregator/child-keys.txt"
 SERVER_STATE="../server/server-state.txt"
 SERVER_SHARES="../server/shares.txt"
 SERVER_SHARES_PARTIAL="../server/partial_shares.txt"
@@ -27,10 +28,46 @@ CLIENT_SERVICE_PORT="9330"
 AGGREGATOR_PORT="18300"
 SERVER_PORT="28942"
 SERVER_IP=("3.137.191.31" "13.38.37.45" "54.176.5.119" "43.207.114.246" "34.221.6.203")
+# Every anytrust server witnesses the users' freshness counters
+SERVER_URLS=$(printf "http://%s:$SERVER_PORT," "${SERVER_IP[@]}")
+SERVER_URLS=${SERVER_URLS%,}
+
+# Prints the URLs of every anytrust server but the i-th. They witness the rounds it unblinds
+peer_urls() {
+    PEERS=""
+    for j in "${!SERVER_IP[@]}"; do
+        if [[ $j -ne $(($1-1)) ]]; then
+            PEERS="$PEERS,http://${SERVER_IP[$j]}:$SERVER_PORT"
+        fi
+    done
+    echo "${PEERS#,}"
+}
 
-# CMD_PREFIX="cargo run --release -- "
+# SGX_MODE=HW runs the SGX enclave, and user attestations are verified with the Intel Attestation
+# Service. That needs IAS_API_KEY and IAS_ROOT_CA to be set. Otherwise everything runs the software
+# enclave, and its mock attestations are accepted
+if [[ "${SGX_MODE:-SIM}" == "HW" ]]; then
+    CARGO_FEATURES=""
+else
+    CARGO_FEATURES="--no-default-features --features common/mock-attestation"
+fi
+
+# Prints the MRENCLAVE that users must attest with. Registrations are refused without one
+allowed_mrenclave() {
+    if [[ "${SGX_MODE:-SIM}" == "HW" ]]; then
+        ${SGX_SDK:-/opt/sgxsdk}/bin/x64/sgx_sign dump -enclave /sgxdcnet/lib/enclave.signed.so \
+            -dumpfile /tmp/enclave_metadata.txt > /dev/null
+        sed -n '/enclave_hash.m:/,/isv_prod_id/p' /tmp/enclave_metadata.txt \
+            | grep -v metadata | tr -d ' \n' | sed 's/0x//g'
+    else
+        # The software enclave's measurement, "zipnet software enclave" padded to 32 bytes
+        echo "7a69706e657420736f66747761726520656e636c617665000000000000000000"
+    fi
+}
+
+# CMD_PREFIX="cargo run --release $CARGO_FEATURES -- "
 # [onlytest]
-CMD_PREFIX="cargo run -- "
+CMD_PREFIX="cargo run $CARGO_FEATURES -- "
 
 SERVER_CMD_PREFIX="/home/ubuntu/.cargo/bin/cargo cargo run -- "
 # Assume wlog that the leading anytrust node is the first one
@@ -61,6 +98,7 @@ clean() {
     rm -f $ERROR_LOG || true
     rm -f $SUCCESS_LOG || true
     rm -f $AGG_STATE_PREFIX*.txt || true
+    rm -f $AGG_CHILDKEYS || true
     rm -f ${AGG_DATA%.txt}*.txt || true
     rm -f $AGG_TIME_LOG || true
     rm -f $RESULT_TIME || true
@@ -90,7 +128,13 @@ setup_server() {
 
         # Make a new server and save the registration data
         SERVER_REG=$(
-            $CMD_PREFIX new --server-state "$STATE"
+            $CMD_PREFIX new --server-state "$STATE" \
+                --num-users $DC_NUM_USER \
+                --num-slots $DC_NET_N_SLOTS \
+                --msg-length $DC_NET_MESSAGE_LENGTH \
+                --footprint-slots $FOOTPRINT_N_SLOTS \
+                --sig-threshold $NUM_SERVERS \
+                --allow-mrenclave "$(allowed_mrenclave)"
         )
         # Append
         if [[ i -eq 1 ]]; then
@@ -138,24 +182,29 @@ setup_aggregator() {
     NUM_LEAF_AGGREGATORS=$THREAD_NUM
     # Make a new root aggregator and capture the registration data
     AGG_REG=$(
-        $CMD_PREFIX new --level 0 --agg-number 0 --agg-state "$AGG_ROOTSTATE" --server-keys "$AGG_SERVERKEYS"
+        $CMD_PREFIX new --level 0 --agg-number 0 --agg-state "$AGG_ROOTSTATE" --server-keys "$AGG_SERVERKEYS" \
+            --allow-mrenclave "$(allowed_mrenclave)"
     )
      # Now do the registration
     cd ../server
     for i in $(seq 1 $NUM_SERVERS); do
         STATE="${SERVER_STATE%.txt}$i.txt"
-        echo $AGG_REG | $CMD_PREFIX register-aggregator --server-state "$STATE"
+        echo $AGG_REG | $CMD_PREFIX register-aggregator --top-level --server-state "$STATE"
     done
     echo "Set up root aggregator"
     cd ../script
 
-    # step 2: setup aggregator
+    # step 2: setup aggregator. The root only takes aggregates signed by the keys in $AGG_CHILDKEYS
+    rm -f $AGG_CHILDKEYS
+    touch $AGG_CHILDKEYS
     if [[ $NUM_LEAF_AGGREGATORS -gt 0 ]]; then
         for i in $(seq 1 $NUM_LEAF_AGGREGATORS); do
             cd ../aggregator
             file_name="$AGG_STATE_PREFIX$i.txt"
             AGG_REG=$(
-                $CMD_PREFIX new --level 1 --agg-number $i --agg-state "$file_name" --server-keys "$AGG_SERVERKEYS")
+                $CMD_PREFIX new --level 1 --agg-number $i --agg-state "$file_name" --server-keys "$AGG_SERVERKEYS" \
+                    --allow-mrenclave "$(allowed_mrenclave)")
+            $CMD_PREFIX get-pubkey --agg-state "$file_name" >> "$AGG_CHILDKEYS"
             cd ../server
             for i in $(seq 1 $NUM_SERVERS); do
                 STATE="${SERVER_STATE%.txt}$i.txt"
@@ -187,6 +236,13 @@ setup_client() {
         STATE="${SERVER_STATE%.txt}$i.txt"
         echo "$USER_REG" | $CMD_PREFIX register-user --server-state "$STATE"
     done
+
+    # Aggregators only accept submissions from registered users
+    cd ../aggregator
+    echo "$USER_REG" | $CMD_PREFIX register-user --agg-state "$AGG_ROOTSTATE"
+    for i in $(seq 1 $THREAD_NUM); do
+        echo "$USER_REG" | $CMD_PREFIX register-user --agg-state "$AGG_STATE_PREFIX$i.txt"
+    done
     sleep 5
     echo "Set up clients"
     cd ../script
@@ -227,7 +283,13 @@ client_eval(){
 
         # Make a new server and save the registration data
         SERVER_REG=$(
-            $CMD_PREFIX new --server-state "$STATE"
+            $CMD_PREFIX new --server-state "$STATE" \
+                --num-users $DC_NUM_USER \
+                --num-slots $DC_NET_N_SLOTS \
+                --msg-length $DC_NET_MESSAGE_LENGTH \
+                --footprint-slots $FOOTPRINT_N_SLOTS \
+                --sig-threshold $NUM_SERVERS \
+                --allow-mrenclave "$(allowed_mrenclave)"
         )
         # Append
         if [[ i -eq 1 ]]; then
@@ -283,7 +345,8 @@ start_client() {
             --user-state $STATE \
             --round $ROUND \
             --bind localhost:$USER_PORT \
-            --agg-url http://localhost:$AGGREGATOR_PORT &
+            --agg-url http://localhost:$AGGREGATOR_PORT \
+            --server-urls "$SERVER_URLS" &
             # --no-persist \
     done
     sleep 10
@@ -339,7 +402,8 @@ single_client_send() {
         --user-state "$STATE" \
         --round $ROUND \
         --bind "localhost:$USER_PORT" \
-        --agg-url "http://localhost:$aggre_port" &
+        --agg-url "http://localhost:$aggre_port" \
+        --server-urls "$SERVER_URLS" &
 
     cd ../script
     
@@ -394,7 +458,8 @@ single_client_send_cover() {
             --user-state "$STATE" \
             --round $ROUND \
             --bind "localhost:$USER_PORT" \
-            --agg-url "http://localhost:$aggre_port" &
+            --agg-url "http://localhost:$aggre_port" \
+            --server-urls "$SERVER_URLS" &
 
         cd ../script
 
@@ -471,6 +536,7 @@ start_agg() {
         --bind "localhost:$AGGREGATOR_PORT" \
         --start-time $START_TIME \
         --round-duration $ROUND_DURATION \
+        --child-keys "$AGG_CHILDKEYS" \
         --forward-to $FORWARD_TO &
         # --no-persist \
     sleep 1
@@ -513,6 +579,7 @@ start_leader() {
     echo "leader addr: $leader_addr"
     RUST_LOG=$LOG_TYPE $CMD_PREFIX start-service \
         --server-state "$STATE" \
+        --peer-urls "$(peer_urls 1)" \
         --bind $leader_addr &
         # --no-persist \
     sleep 1
@@ -534,6 +601,7 @@ start_follower() {
     follower_addr="0.0.0.0:$SERVER_PORT"
     RUST_LOG=$LOG_TYPE $CMD_PREFIX start-service \
         --server-state "$STATE" \
+        --peer-urls "$(peer_urls $1)" \
         --bind $follower_addr \
         --leader-url $leader_addr &
 
@@ -661,12 +729,16 @@ re_setup_aggregator(){
     cd ../script
 
     # step 2: generate the aggregator
+    rm -f $AGG_CHILDKEYS
+    touch $AGG_CHILDKEYS
     if [[ $NUM_LEAF_AGGREGATORS -gt 0 ]]; then
         for i in $(seq 1 $NUM_LEAF_AGGREGATORS); do
             cd ../aggregator
             file_name="$AGG_STATE_PREFIX$i.txt"
             AGG_REG=$(
-                $CMD_PREFIX new --level 1 --agg-number $i --agg-state "$file_name" --server-keys "$AGG_SERVERKEYS")
+                $CMD_PREFIX new --level 1 --agg-number $i --agg-state "$file_name" --server-keys "$AGG_SERVERKEYS" \
+                    --allow-mrenclave "$(allowed_mrenclave)")
+            $CMD_PREFIX get-pubkey --agg-state "$file_name" >> "$AGG_CHILDKEYS"
             cd ../server
             # for i in $(seq 1 $NUM_SERVERS); do
             #     STATE="${SERVER_STATE%.txt}$i.txt"
@@ -0,0 +1,272 @@
+use crate::util::{Result, ServerError};
+use interface::RoundOutput;
+
+use common::cli_util;
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+
+/// An append-only file of signed round outputs, one base64-encoded output per line, indexed by
+/// round. The index is rebuilt from the file on startup, so outputs survive restarts.
+pub(crate) struct RoundOutputArchive {
+    path: PathBuf,
+    file: File,
+    /// Maps round to the offset of that round's line in the file
+    index: BTreeMap<u32, u64>,
+    /// If set, only the outputs of the last `keep_rounds` rounds are kept
+    keep_rounds: Option<u32>,
+    /// The number of lines in the file that are no longer in the index
+    num_dropped: usize,
+}
+
+impl RoundOutputArchive {
+    /// Opens the archive at the given path, creating it if it doesn't exist. If `keep_rounds` is
+    /// set, outputs more than `keep_rounds` rounds older than the latest one are dropped.
+    pub(crate) fn open<P: AsRef<Path>>(
+        path: P,
+        keep_rounds: Option<u32>,
+    ) -> Result<RoundOutputArchive> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        // Read every output and note where it starts. A crash during an append can leave a
+        // partial last line. Cut it off so the next append starts on a fresh line.
+        let mut index = BTreeMap::new();
+        let mut num_dropped = 0;
+        let mut offset = 0u64;
+        {
+            let mut reader = BufReader::new(&mut file);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let len = reader.read_line(&mut line)? as u64;
+                if len == 0 {
+                    break;
+                }
+                if !line.ends_with('\n') {
+                    warn!("dropping partial round output at the end of {:?}", path);
+                    break;
+                }
+
+                let output: RoundOutput = cli_util::load(line.as_bytes())?;
+                if index.insert(output.round, offset).is_some() {
+                    num_dropped += 1;
+                }
+                offset += len;
+            }
+        }
+        file.set_len(offset)?;
+
+        let mut archive = RoundOutputArchive {
+            path,
+            file,
+            index,
+            keep_rounds,
+            num_dropped,
+        };
+        archive.apply_retention()?;
+        info!(
+            "Loaded {} round outputs from {:?}",
+            archive.index.len(),
+            archive.path
+        );
+
+        Ok(archive)
+    }
+
+    /// The latest round that has an output
+    pub(crate) fn latest_round(&self) -> Option<u32> {
+        self.index.keys().next_back().cloned()
+    }
+
+    /// Appends the given output. Every round has at most one output, so this fails if the round
+    /// already has one.
+    pub(crate) fn append(&mut self, output: &RoundOutput) -> Result<()> {
+        if self.index.contains_key(&output.round) {
+            error!("round {} already has an archived output", output.round);
+            return Err(ServerError::UnexpectedError);
+        }
+
+        let mut line = Vec::new();
+        cli_util::save(&mut line, output)?;
+        line.push(b'\n');
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.index.insert(output.round, offset);
+
+        self.apply_retention()
+    }
+
+    /// Returns the output of the given round, if it's in the archive
+    pub(crate) fn get(&self, round: u32) -> Result<Option<RoundOutput>> {
+        match self.index.get(&round) {
+            Some(&offset) => Ok(Some(self.read_at(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the outputs of the rounds from `from` to `to`, inclusive, that are in the archive
+    pub(crate) fn range(&self, from: u32, to: u32) -> Result<Vec<RoundOutput>> {
+        if from > to {
+            return Ok(Vec::new());
+        }
+
+        self.index
+            .range(from..=to)
+            .map(|(_, &offset)| self.read_at(offset))
+            .collect()
+    }
+
+    fn read_at(&self, offset: u64) -> Result<RoundOutput> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut line = String::new();
+        BufReader::new(file).read_line(&mut line)?;
+        Ok(cli_util::load(line.as_bytes())?)
+    }
+
+    /// Drops the outputs that are too old to keep. Once the file holds more dropped outputs than
+    /// kept ones, it is rewritten with only the kept ones.
+    fn apply_retention(&mut self) -> Result<()> {
+        let (keep_rounds, latest_round) = match (self.keep_rounds, self.latest_round()) {
+            (Some(k), Some(r)) => (k, r),
+            _ => return Ok(()),
+        };
+
+        let oldest_kept = latest_round.saturating_sub(keep_rounds.saturating_sub(1));
+        let kept = self.index.split_off(&oldest_kept);
+        self.num_dropped += self.index.len();
+        self.index = kept;
+
+        if self.num_dropped > self.index.len() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the file with only the outputs in the index. The new file is moved into place
+    /// once it's complete, so a crash leaves either the old file or the new one.
+    fn compact(&mut self) -> Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut new_index = BTreeMap::new();
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            let mut offset = 0u64;
+            for (&round, &old_offset) in self.index.iter() {
+                let mut line = Vec::new();
+                cli_util::save(&mut line, &self.read_at(old_offset)?)?;
+                line.push(b'\n');
+
+                tmp.write_all(&line)?;
+                new_index.insert(round, offset);
+                offset += line.len() as u64;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.index = new_index;
+        self.num_dropped = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::{DcNetParams, DcRoundMessage};
+
+    fn output(round: u32) -> RoundOutput {
+        RoundOutput {
+            round,
+            dc_msg: DcRoundMessage::new(&DcNetParams::default()),
+            server_sigs: vec![],
+        }
+    }
+
+    fn archive_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "zipnet-archive-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn archive_survives_reopen() {
+        let path = archive_path("reopen");
+
+        let mut archive = RoundOutputArchive::open(&path, None).unwrap();
+        for round in (0..12).chain(15..17) {
+            archive.append(&output(round)).unwrap();
+        }
+        // outputs can't be replaced
+        assert!(archive.append(&output(3)).is_err());
+        drop(archive);
+
+        let archive = RoundOutputArchive::open(&path, None).unwrap();
+        assert_eq!(archive.latest_round(), Some(16));
+        assert_eq!(archive.get(10).unwrap().unwrap().round, 10);
+        assert!(archive.get(13).unwrap().is_none());
+
+        let rounds: Vec<u32> = archive
+            .range(10, 15)
+            .unwrap()
+            .iter()
+            .map(|o| o.round)
+            .collect();
+        assert_eq!(rounds, vec![10, 11, 15]);
+        assert!(archive.range(15, 10).unwrap().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn archive_retention() {
+        let path = archive_path("retention");
+
+        let mut archive = RoundOutputArchive::open(&path, Some(3)).unwrap();
+        for round in 0..10 {
+            archive.append(&output(round)).unwrap();
+        }
+        assert!(archive.get(6).unwrap().is_none());
+        let rounds: Vec<u32> = archive
+            .range(0, 10)
+            .unwrap()
+            .iter()
+            .map(|o| o.round)
+            .collect();
+        assert_eq!(rounds, vec![7, 8, 9]);
+
+        // the file was compacted along the way, and reopening keeps the same outputs
+        drop(archive);
+        let archive = RoundOutputArchive::open(&path, Some(3)).unwrap();
+        assert_eq!(archive.range(0, 10).unwrap().len(), 3);
+        assert_eq!(archive.get(9).unwrap().unwrap().round, 9);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,27 +1,36 @@
 extern crate common;
 extern crate interface;
 
-mod aes_prng;
+mod archive;
 mod server;
 mod server_state;
 mod service;
 mod util;
 
 use crate::{
+    archive::RoundOutputArchive,
     server_state::ServerState,
     service::start_service,
     util::{load_from_stdin, load_multi_from_stdin, load_state, save_state, save_to_stdout},
 };
 
-use common::cli_util;
-use interface::UserRegistrationBlob;
+use common::{
+    attestation, cli_util,
+    enclave::{DcNetEnclave, EnclaveBackend},
+    freshness::{set_counter_witness, HttpCounterWitness},
+};
+use interface::{DcNetParams, UserRegistrationBlob};
 use pretty_hex;
 
 use common::types::{
     AggRegistrationBlob, RoundSubmissionBlob, ServerRegistrationBlob, UnblindedAggregateShareBlob,
 };
 
-use std::{error::Error, fs::File};
+use std::{
+    error::Error,
+    fs::File,
+    path::{Path, PathBuf},
+};
 
 use clap::{App, AppSettings, Arg, SubCommand};
 use log::info;
@@ -29,6 +38,7 @@ use log::info;
 fn main() -> Result<(), Box<dyn Error>> {
     // Do setup
     env_logger::init();
+    let enclave = DcNetEnclave::init("/sgxdcnet/lib/enclave.signed.so")?;
 
     let state_arg = Arg::with_name("server-state")
         .short("s")
@@ -38,6 +48,24 @@ fn main() -> Result<(), Box<dyn Error>> {
         .takes_value(true)
         .help("A file that contains this server's previous state");
 
+    let peer_urls_arg = Arg::with_name("peer-urls")
+        .long("peer-urls")
+        .value_name("URLS")
+        .required(false)
+        .takes_value(true)
+        .help(
+            "A comma-separated list of the URLs of the other anytrust servers. They witness every \
+            round this server unblinds. Needed unless this server is alone in its group. Example: \
+            \"http://192.168.0.11:9000,http://192.168.0.12:9000\"",
+        );
+
+    // The DC net parameters are fixed when the server is created. Render the defaults for clap
+    let default_params = DcNetParams::default();
+    let default_num_users = default_params.num_users.to_string();
+    let default_n_slots = default_params.n_slots.to_string();
+    let default_message_length = default_params.message_length.to_string();
+    let default_footprint_n_slots = default_params.footprint_n_slots.to_string();
+
     let matches = App::new("SGX DCNet Anytrust Node")
         .version("0.1.0")
         .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -55,6 +83,77 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .required(true)
                         .takes_value(true)
                         .help("The file to which the new server state will be written"),
+                )
+                .arg(
+                    Arg::with_name("num-users")
+                        .long("num-users")
+                        .value_name("INTEGER")
+                        .takes_value(true)
+                        .default_value(&default_num_users)
+                        .help("The number of users in the DC net"),
+                )
+                .arg(
+                    Arg::with_name("num-slots")
+                        .long("num-slots")
+                        .value_name("INTEGER")
+                        .takes_value(true)
+                        .default_value(&default_n_slots)
+                        .help("The number of message slots in a DC net round"),
+                )
+                .arg(
+                    Arg::with_name("msg-length")
+                        .long("msg-length")
+                        .value_name("INTEGER")
+                        .takes_value(true)
+                        .default_value(&default_message_length)
+                        .help("The number of bytes in each message slot"),
+                )
+                .arg(
+                    Arg::with_name("footprint-slots")
+                        .long("footprint-slots")
+                        .value_name("INTEGER")
+                        .takes_value(true)
+                        .default_value(&default_footprint_n_slots)
+                        .help(
+                            "The number of scheduling slots. Must be at least the number of \
+                            message slots. All servers in an anytrust group must be created with \
+                            the same parameters.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("sig-threshold")
+                        .long("sig-threshold")
+                        .value_name("INTEGER")
+                        .takes_value(true)
+                        .required(true)
+                        .help(
+                            "The number of anytrust servers that must sign a round output before \
+                            users accept it. At most the size of the anytrust group. This is \
+                            usually the size of the group, so that every server vouches for \
+                            every output.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("allow-mrenclave")
+                        .long("allow-mrenclave")
+                        .value_name("HEX")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required_unless("allow-mrsigner")
+                        .help("An MRENCLAVE value whose attestations users may register with"),
+                )
+                .arg(
+                    Arg::with_name("allow-mrsigner")
+                        .long("allow-mrsigner")
+                        .value_name("HEX")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "An MRSIGNER value whose attestations users may register with. At \
+                            least one of this and --allow-mrenclave must be given.",
+                        ),
                 ),
         )
         .subcommand(
@@ -73,7 +172,17 @@ fn main() -> Result<(), Box<dyn Error>> {
         .subcommand(
             SubCommand::with_name("register-aggregator")
                 .about("Registers an aggregator with this server")
-                .arg(state_arg.clone()),
+                .arg(state_arg.clone())
+                .arg(
+                    Arg::with_name("top-level")
+                        .long("top-level")
+                        .required(false)
+                        .takes_value(false)
+                        .help(
+                            "Marks the aggregator as the root of an aggregation tree. This server \
+                            only unblinds aggregates sent by top-level aggregators.",
+                        ),
+                ),
         )
         .subcommand(
             SubCommand::with_name("register-server")
@@ -83,7 +192,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         .subcommand(
             SubCommand::with_name("unblind-aggregate")
                 .about("Unblinds the given top-level aggregate value")
-                .arg(state_arg.clone()),
+                .arg(state_arg.clone())
+                .arg(peer_urls_arg.clone()),
         )
         .subcommand(
             SubCommand::with_name("combine-shares")
@@ -109,6 +219,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                     aggregate to the aggregator or server at FORWARD_ADDR.",
                 )
                 .arg(state_arg.clone())
+                .arg(peer_urls_arg.clone())
                 .arg(
                     Arg::with_name("bind")
                         .short("b")
@@ -136,13 +247,53 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .required(false)
                         .takes_value(false)
                         .help("If this is set, the service will not persist its state to disk"),
+                )
+                .arg(
+                    Arg::with_name("round-archive")
+                        .long("round-archive")
+                        .value_name("FILE")
+                        .required(false)
+                        .help(
+                            "The file the leader archives signed round outputs in. Outputs \
+                            already in the file are served after a restart. Defaults to the \
+                            server state path with a .rounds extension. Followers don't keep an \
+                            archive.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("keep-rounds")
+                        .long("keep-rounds")
+                        .value_name("INTEGER")
+                        .required(false)
+                        .help(
+                            "Only keep the outputs of this many of the most recent rounds in the \
+                            archive. Must be positive. If this is not given, all outputs are kept.",
+                        ),
                 ),
         )
         .get_matches();
 
+    // Every unblind has the other servers witness this server's freshness counter
+    if let Some(urls) = matches.subcommand().1.and_then(|m| m.value_of("peer-urls")) {
+        set_counter_witness(Box::new(HttpCounterWitness::new(urls)));
+    }
+
     if let Some(matches) = matches.subcommand_matches("new") {
         // Make a new state and registration message
-        let (state, reg_blob) = ServerState::new()?;
+        let dc_net_params = DcNetParams {
+            num_users: cli_util::parse_u32(matches.value_of("num-users").unwrap())? as usize,
+            n_slots: cli_util::parse_u32(matches.value_of("num-slots").unwrap())? as usize,
+            message_length: cli_util::parse_u32(matches.value_of("msg-length").unwrap())? as usize,
+            footprint_n_slots: cli_util::parse_u32(matches.value_of("footprint-slots").unwrap())?
+                as usize,
+            sig_threshold: cli_util::parse_u32(matches.value_of("sig-threshold").unwrap())?
+                as usize,
+        };
+        let enclave_allow_list = cli_util::parse_allow_list(
+            matches.values_of("allow-mrenclave").into_iter().flatten(),
+            matches.values_of("allow-mrsigner").into_iter().flatten(),
+        )?;
+        let (state, reg_blob) = ServerState::new(&enclave, dc_net_params, enclave_allow_list)?;
         // Save the state and output the registration blob
         let state_path = matches.value_of("server-state").unwrap();
         save_state(&state_path, &state)?;
@@ -157,13 +308,16 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     if let Some(matches) = matches.subcommand_matches("register-user") {
+        // Users' attestations are checked against the allow-list with the build's quote verifier
+        attestation::set_default_quote_verifier();
+
         // Parse user registration blobs from stdin
         let reg_blobs: Vec<UserRegistrationBlob> = load_multi_from_stdin()?;
 
         // Feed them to the state and save the new state
         let state_path = matches.value_of("server-state").unwrap();
         let mut state = load_state(&state_path)?;
-        state.recv_user_registrations(&reg_blobs)?;
+        state.recv_user_registrations(&enclave, &reg_blobs)?;
 
         save_state(&state_path, &state)?;
 
@@ -177,7 +331,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         // Feed it to the state and save the new state
         let state_path = matches.value_of("server-state").unwrap();
         let mut state = load_state(&state_path)?;
-        state.recv_aggregator_registration(&reg_blob)?;
+        let top_level = matches.is_present("top-level");
+        state.recv_aggregator_registration(&enclave, &reg_blob, top_level)?;
         save_state(&state_path, &state)?;
 
         println!("OK");
@@ -190,7 +345,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         // Feed it to the state and save the new state
         let state_path = matches.value_of("server-state").unwrap();
         let mut state = load_state(&state_path)?;
-        state.recv_server_registration(&reg_blob)?;
+        state.recv_server_registration(&enclave, &reg_blob)?;
         save_state(&state_path, &state)?;
 
         println!("OK");
@@ -203,7 +358,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         // Feed it to the state and print the result
         let state_path = matches.value_of("server-state").unwrap();
         let mut state = load_state(&state_path)?;
-        let agg = state.unblind_aggregate(&agg_blob)?;
+        let agg = state.unblind_aggregate(&enclave, &agg_blob, None)?;
         save_to_stdout(&agg)?;
 
         // The shared secrets were ratcheted, so we have to save the new state
@@ -219,7 +374,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         // Feed it to the state and output the result
         let state_path = matches.value_of("server-state").unwrap();
         let state = load_state(&state_path)?;
-        let round_output = state.derive_round_output(shares.as_slice())?;
+        let round_output = state.derive_round_output(&enclave, shares.as_slice())?;
         save_to_stdout(&round_output)?;
 
         // Log the raw round result in base64
@@ -255,12 +410,37 @@ fn main() -> Result<(), Box<dyn Error>> {
         let server_state_path = if matches.is_present("no-persist") {
             None
         } else {
-            Some(state_path)
+            Some(state_path.clone())
         };
 
-        let state = service::ServiceState::new(server_state, server_state_path, leader_url);
+        // Only the leader publishes round outputs, so only the leader opens the archive
+        let round_archive = if leader_url.is_none() {
+            let keep_rounds = match matches.value_of("keep-rounds") {
+                Some(k) => Some(cli_util::parse_u32(k)?),
+                None => None,
+            };
+            if keep_rounds == Some(0) {
+                return Err("--keep-rounds must be positive".into());
+            }
+            let archive_path = match matches.value_of("round-archive") {
+                Some(p) => PathBuf::from(p),
+                None => Path::new(&state_path).with_extension("rounds"),
+            };
+            Some(RoundOutputArchive::open(archive_path, keep_rounds)?)
+        } else {
+            None
+        };
+
+        let state = service::ServiceState::new(
+            server_state,
+            enclave.clone(),
+            server_state_path,
+            leader_url,
+            round_archive,
+        );
         start_service(bind_addr, state).unwrap();
     }
 
+    enclave.destroy();
     Ok(())
 }
@@ -1,103 +1,78 @@
 use crate::util::{Result, ServerError};
-use std::{vec, vec::Vec};
+use std::vec::Vec;
 
 use interface::{
-    DcRoundMessage, EntityId, MultiSignable, OutputSignature, RoundOutput, RoundSecret,
-    ServerPubKeyPackage, SgxProtectedKeyPub, UserRegistrationBlob, Xor,
+    DcNetParams, EntityId, MultiSignable, OutputSignature, RoundOutput, SealedKemPrivKey,
+    SealedPartialRoundSecret, SealedSharedSecretsDbServer, SealedSigPrivKey, ServerPubKeyPackage,
+    ServerUnblindReq, SignRoundOutputReq, SignatureBytes, UserRegistrationBlob,
 };
 
-use ed25519_dalek::{PublicKey, SecretKey, Signature};
-
-use x25519_dalek::{PublicKey as xPublicKey, StaticSecret};
-
-use rand::rngs::OsRng;
+use ed25519_dalek::{PublicKey, Signature, Verifier};
 
 use std::time::Instant;
 
+use common::attestation::{verify_user_registration, EnclaveAllowList};
+use common::enclave::{DcNetEnclave, EnclaveBackend};
 use common::types::{
-    AggRegistrationBlob, AggregatedMessage, MarshallAs, RoundSubmissionBlob,
-    ServerRegistrationBlob, SharedSecretsDbServer, SignMutable, SignedPubKeyDb,
-    UnblindedAggregateShare, UnblindedAggregateShareBlob, UnmarshalledAs,
+    AggRegistrationBlob, AggregatedMessage, MarshallAs, RoundOutputCandidate,
+    ServerRegistrationBlob, Signable, SignedPubKeyDb, UnblindedAggregateShare,
+    UnblindedAggregateShareBlob, UnmarshalledAs,
 };
 
 use log::{debug, error, info};
 
 use itertools::Itertools;
-use std::collections::{BTreeMap, BTreeSet};
-use std::iter::FromIterator;
+use std::collections::BTreeSet;
 use std::sync::mpsc;
 use std::thread;
 
-pub fn new_server() -> Result<(SecretKey, SecretKey, EntityId, ServerPubKeyPackage)> {
-    let mut csprng = OsRng {};
-    let sig_key = SecretKey::generate(&mut csprng);
-    let kem_key = SecretKey::generate(&mut csprng);
-
-    let kem_secret = StaticSecret::from(kem_key.to_bytes());
-
-    // The standard hash function used for most ed25519 libraries is SHA-512
-    let sig_key_pk: PublicKey = (&sig_key).into();
-    let kem_key_pk: PublicKey = (&kem_key).into();
-    let kem_key_xpk: xPublicKey = xPublicKey::from(&kem_secret);
-
-    let reg = ServerPubKeyPackage {
-        sig: sig_key_pk,
-        kem: kem_key_pk,
-        xkem: SgxProtectedKeyPub(kem_key_xpk.to_bytes()),
-    };
-
-    Ok((sig_key, kem_key, EntityId::from(&reg), reg))
+/// Makes a new server in the enclave. Returns the sealed signing key, KEM key and shared secrets,
+/// the server's ID, and its pubkey package.
+pub fn new_server(
+    enclave: &DcNetEnclave,
+    dc_net_params: &DcNetParams,
+) -> Result<(
+    SealedSigPrivKey,
+    SealedKemPrivKey,
+    SealedSharedSecretsDbServer,
+    EntityId,
+    ServerPubKeyPackage,
+)> {
+    let (sig_key, kem_key, shared_secrets, reg) = enclave.new_server(dc_net_params)?;
+    Ok((sig_key, kem_key, shared_secrets, EntityId::from(&reg), reg))
 }
 
+/// Registers the given users. Their attestations are checked against the allow list, and the
+/// enclave derives the secrets this server shares with them.
 pub fn recv_user_registration_batch(
+    enclave: &DcNetEnclave,
     pubkeys: &mut SignedPubKeyDb,
-    shared_secrets: &mut SharedSecretsDbServer,
-    decap_key: &SecretKey,
+    shared_secrets: &mut SealedSharedSecretsDbServer,
+    decap_key: &SealedKemPrivKey,
+    enclave_allow_list: &EnclaveAllowList,
     input_blob: &[UserRegistrationBlob],
 ) -> Result<()> {
-    let (new_pubkey_db, new_secrets_db) =
-        recv_user_reg_batch((pubkeys, decap_key, &input_blob.to_vec()))?;
-
-    pubkeys.users = new_pubkey_db.users;
-    shared_secrets.db = new_secrets_db.db;
-
-    Ok(())
-}
-
-fn recv_user_reg_batch(
-    input: (&SignedPubKeyDb, &SecretKey, &Vec<UserRegistrationBlob>),
-) -> Result<(SignedPubKeyDb, SharedSecretsDbServer)> {
-    let mut pk_db: SignedPubKeyDb = input.0.clone();
-    let my_kem_sk = input.1;
-
-    for u in input.2.iter() {
+    for u in input_blob.iter() {
         // verify user key
-        match verify_user_attestation(&u) {
+        match verify_user_registration(enclave_allow_list, &u) {
             Ok(()) => {
                 debug!("verify user registration attestation succeeded");
             }
             Err(e) => {
-                error!("cannot verify user registration attestation: {:?}", e);
-                return Err(ServerError::UnexpectedError);
+                error!("cannot verify user registration attestation: {}", e);
+                return Err(e.into());
             }
         }
-
-        // add user key to pubkey db
-        pk_db.users.insert(EntityId::from(&u.pk), u.clone());
     }
 
-    // Derive secrets
-    // let mut others_kem_pks = vec![];
-    let mut others_kem_db: BTreeMap<SgxProtectedKeyPub, SgxProtectedKeyPub> = BTreeMap::new();
-    for (_, k) in pk_db.users.iter() {
-        // others_kem_pks.push(k.pk);
-        others_kem_db.insert(k.xpk, k.pk);
-    }
+    *shared_secrets = enclave.recv_user_registrations(shared_secrets, decap_key, input_blob)?;
 
-    let shared_secrets = SharedSecretsDbServer::derive_shared_secrets(&my_kem_sk, &others_kem_db)
-        .expect("failed to derive shared secrets for server");
+    // add user keys to pubkey db
+    for u in input_blob.iter() {
+        pubkeys.users.insert(EntityId::from(&u.pk), u.clone());
+    }
 
-    Ok((pk_db, shared_secrets))
+    Ok(())
 }
 
 pub fn recv_aggregator_registration(
@@ -118,13 +93,78 @@ pub fn recv_aggregator_registration(
     Ok(())
 }
 
+/// How many rounds past the first round that hasn't been unblinded an aggregate may be. Unblinding
+/// a later round uses up the secrets of every round before it, so this bounds how many rounds a
+/// single aggregate can throw away
+pub const MAX_ROUND_SKIP: u32 = 64;
+
+/// Checks that a top-level aggregate can be unblinded by this server. It must be signed by one of
+/// `toplevel_aggregators`, and be for this server's anytrust group. Its round may not be before
+/// `round`, the first round that hasn't been unblinded yet, or more than `MAX_ROUND_SKIP` rounds
+/// after it.
+pub fn verify_toplevel_aggregate(
+    pubkeys: &SignedPubKeyDb,
+    toplevel_aggregators: &BTreeSet<EntityId>,
+    anytrust_group_id: &EntityId,
+    round: u32,
+    toplevel_agg: &AggregatedMessage,
+) -> Result<()> {
+    if toplevel_agg.verify().is_err() {
+        error!("signature on the aggregate does not verify");
+        return Err(ServerError::AggregateSignature);
+    }
+
+    let agg_id = EntityId::from(&toplevel_agg.pk);
+    if !toplevel_aggregators.contains(&agg_id) || !pubkeys.aggregators.contains_key(&agg_id) {
+        error!("aggregate is from unregistered aggregator {}", agg_id);
+        return Err(ServerError::UnknownAggregator(agg_id));
+    }
+
+    if toplevel_agg.round < round {
+        error!(
+            "aggregate is for round {}, expected round {} or later",
+            toplevel_agg.round, round
+        );
+        return Err(ServerError::WrongRound(toplevel_agg.round, round));
+    }
+    if toplevel_agg.round > round.saturating_add(MAX_ROUND_SKIP) {
+        error!(
+            "aggregate is for round {}, more than {} rounds after round {}",
+            toplevel_agg.round, MAX_ROUND_SKIP, round
+        );
+        return Err(ServerError::RoundTooFar(toplevel_agg.round, round));
+    }
+
+    if toplevel_agg.anytrust_group_id != *anytrust_group_id {
+        error!(
+            "aggregate is for anytrust group {}",
+            toplevel_agg.anytrust_group_id
+        );
+        return Err(ServerError::WrongGroup(toplevel_agg.anytrust_group_id));
+    }
+
+    Ok(())
+}
+
 pub fn recv_server_registration(
     pubkeys: &mut SignedPubKeyDb,
+    dc_net_params: &DcNetParams,
     input_blob: &ServerRegistrationBlob,
 ) -> Result<()> {
     let mut new_db = pubkeys.clone();
     let server_pk = input_blob;
 
+    // all servers in an anytrust group must run the DC net with the same parameters
+    if server_pk.dc_net_params != *dc_net_params {
+        error!(
+            "server {} has DC net parameters {:?}, expected {:?}",
+            EntityId::from(server_pk),
+            server_pk.dc_net_params,
+            dc_net_params
+        );
+        return Err(ServerError::UnexpectedError);
+    }
+
     // add server key to pubkey db
     new_db
         .servers
@@ -136,227 +176,628 @@ pub fn recv_server_registration(
     Ok(())
 }
 
+/// Unblinds the given aggregate. The pads in `precomputed`, if they're for the aggregate's round,
+/// are used as is. The rest are derived here.
 pub fn unblind_aggregate(
+    enclave: &DcNetEnclave,
     toplevel_agg: &AggregatedMessage,
-    signing_key: &SecretKey,
-    shared_secrets: &SharedSecretsDbServer,
-) -> Result<(UnblindedAggregateShareBlob, SharedSecretsDbServer)> {
+    signing_key: &SealedSigPrivKey,
+    shared_secrets: &SealedSharedSecretsDbServer,
+    dc_net_params: &DcNetParams,
+    precomputed: Option<&PrecomputedPads>,
+) -> Result<(UnblindedAggregateShareBlob, SealedSharedSecretsDbServer)> {
     unblind_aggregate_mt(
+        enclave,
         toplevel_agg,
         signing_key,
         shared_secrets,
+        dc_net_params,
+        precomputed,
         interface::N_THREADS_DERIVE_ROUND_SECRET,
     )
 }
 
 pub fn unblind_aggregate_mt(
+    enclave: &DcNetEnclave,
     toplevel_agg: &AggregatedMessage,
-    signing_key: &SecretKey,
-    shared_secrets: &SharedSecretsDbServer,
+    signing_key: &SealedSigPrivKey,
+    shared_secrets: &SealedSharedSecretsDbServer,
+    dc_net_params: &DcNetParams,
+    precomputed: Option<&PrecomputedPads>,
     n_threads: usize,
-) -> Result<(UnblindedAggregateShareBlob, SharedSecretsDbServer)> {
+) -> Result<(UnblindedAggregateShareBlob, SealedSharedSecretsDbServer)> {
+    // Users may have skipped rounds, and so may we, but we can't unblind a round that's already
+    // been unblinded
+    let round = toplevel_agg.round;
+    if round < shared_secrets.round {
+        error!(
+            "round {} is before shared_secrets.round {}",
+            round, shared_secrets.round
+        );
+        return Err(ServerError::WrongRound(round, shared_secrets.round));
+    }
+
+    if let Some(pads) = precomputed.filter(|p| p.round == round) {
+        match unblind_aggregate_with(
+            enclave,
+            toplevel_agg,
+            signing_key,
+            shared_secrets,
+            dc_net_params,
+            Some(pads),
+            n_threads,
+        ) {
+            Ok(res) => return Ok(res),
+            // A user may have registered again since the pads were derived. Derive them all
+            Err(e) => error!("could not unblind with precomputed pads: {}", e),
+        }
+    }
+
+    unblind_aggregate_with(
+        enclave,
+        toplevel_agg,
+        signing_key,
+        shared_secrets,
+        dc_net_params,
+        None,
+        n_threads,
+    )
+}
+
+fn unblind_aggregate_with(
+    enclave: &DcNetEnclave,
+    toplevel_agg: &AggregatedMessage,
+    signing_key: &SealedSigPrivKey,
+    shared_secrets: &SealedSharedSecretsDbServer,
+    dc_net_params: &DcNetParams,
+    precomputed: Option<&PrecomputedPads>,
+    n_threads: usize,
+) -> Result<(UnblindedAggregateShareBlob, SealedSharedSecretsDbServer)> {
     let start = Instant::now();
-    let chunk_size = (toplevel_agg.user_ids.len() + n_threads - 1) / n_threads;
-    assert_ne!(chunk_size, 0);
+    let round = toplevel_agg.round;
+
+    // The enclave XORs together the pads in the partials it's given, and derives the rest. Pads
+    // derived ahead of time only need XORing, and the pads of absent users XOR out of them. So
+    // they're used if that leaves fewer pads to derive.
+    let mut partial_secrets = Vec::new();
+    let mut user_ids = toplevel_agg.user_ids.clone();
+    if let Some(pads) = precomputed {
+        let left: BTreeSet<EntityId> = pads
+            .user_ids
+            .symmetric_difference(&toplevel_agg.user_ids)
+            .cloned()
+            .collect();
+        if left.len() < user_ids.len() {
+            info!(
+                "========= {} pads were precomputed. {} left to derive",
+                pads.user_ids.len(),
+                left.len()
+            );
+            partial_secrets.extend(pads.partials.iter().cloned());
+            user_ids = left;
+        }
+    }
 
-    let round = shared_secrets.round;
+    partial_secrets.extend(derive_partials(
+        enclave,
+        round,
+        shared_secrets,
+        dc_net_params,
+        &user_ids,
+        n_threads,
+    )?);
+    info!("========= threads join after {:?}", start.elapsed());
+
+    let req = ServerUnblindReq {
+        round,
+        anytrust_group_id: toplevel_agg.anytrust_group_id,
+        user_ids: toplevel_agg.user_ids.clone(),
+        aggregated_msg: toplevel_agg.aggregated_msg.clone(),
+        aggregator_sig: OutputSignature {
+            pk: toplevel_agg.pk,
+            sig: SignatureBytes(toplevel_agg.sig.to_bytes().to_vec()),
+        },
+        dc_net_params: *dc_net_params,
+        shared_secrets: shared_secrets.clone(),
+        partial_secrets,
+    };
+    let (key_share, sig, shared_secrets) = enclave.unblind_aggregate(&req, signing_key)?;
+
+    let unblind_agg = UnblindedAggregateShare {
+        encrypted_msg: toplevel_agg.clone(),
+        key_share,
+        sig: Signature::from_bytes(&sig.sig.0)?,
+        pk: sig.pk,
+    };
+
+    info!(
+        "========= {} partial round secrets merged after {:?}.",
+        req.partial_secrets.len(),
+        start.elapsed()
+    );
+
+    Ok((
+        unblind_agg.marshal().expect("marshal unblind agg failed"),
+        shared_secrets,
+    ))
+}
 
-    // make a mpsc channel
+/// Derives the pads of the given users for the given round in the enclave, on `n_threads`
+/// threads. Returns the sealed XOR of each thread's pads.
+fn derive_partials(
+    enclave: &DcNetEnclave,
+    round: u32,
+    shared_secrets: &SealedSharedSecretsDbServer,
+    dc_net_params: &DcNetParams,
+    user_ids: &BTreeSet<EntityId>,
+    n_threads: usize,
+) -> Result<Vec<SealedPartialRoundSecret>> {
     let (tx, rx) = mpsc::channel();
 
-    // // partition the user ids into N batches
-    let user_keys: Vec<EntityId> = toplevel_agg.user_ids.iter().cloned().collect();
-    for uks in &user_keys.into_iter().chunks(chunk_size) {
-        let uks_vec = uks.collect_vec();
+    // partition the user ids into N batches
+    let chunk_size = std::cmp::max((user_ids.len() + n_threads - 1) / n_threads, 1);
+    for uks in &user_ids.iter().cloned().chunks(chunk_size) {
+        let uks: BTreeSet<EntityId> = uks.collect();
 
-        let db_cloned = shared_secrets.clone();
-        let tx_cloned = mpsc::Sender::clone(&tx);
+        let enclave = enclave.clone();
+        let shared_secrets = shared_secrets.clone();
+        let params = *dc_net_params;
+        let tx = tx.clone();
 
         thread::spawn(move || {
-            info!("thread working on {} ids", uks_vec.len());
-            let user_ids: BTreeSet<EntityId> = BTreeSet::from_iter(uks_vec.into_iter());
-            let rs = unblind_aggregate_partial(&(round, db_cloned, user_ids)).unwrap();
-            tx_cloned.send(rs).unwrap();
+            debug!("thread working on {} ids", uks.len());
+            let partial = enclave.unblind_aggregate_partial(round, &shared_secrets, &params, &uks);
+            // The receiver stops listening after the first error
+            let _ = tx.send(partial);
         });
     }
+    drop(tx);
 
-    info!("========= set up threads after {:?}", start.elapsed());
+    let mut partials = Vec::new();
+    for partial in rx.iter() {
+        partials.push(partial?);
+    }
+    Ok(partials)
+}
 
-    drop(tx);
+/// Every registered user's pad for one round, derived ahead of time. Deriving pads is the bulk of
+/// unblinding, and a round's pads only depend on the shared secrets and the round number. So
+/// they can be derived while waiting for the round's aggregate. The pads never leave the enclave
+/// unsealed: they are kept as sealed XORs of a few users' pads each.
+pub struct PrecomputedPads {
+    /// The round the pads are for
+    pub round: u32,
+    /// The users whose pads are XORed into the partials
+    user_ids: BTreeSet<EntityId>,
+    /// The sealed XORs of the users' pads, one per thread that derived them
+    partials: Vec<SealedPartialRoundSecret>,
+}
 
-    let round_secrets: Vec<RoundSecret> = rx.iter().collect();
-    info!("========= threads join after {:?}", start.elapsed());
+impl PrecomputedPads {
+    /// Derives the pads of the given users for the given round, using `n_threads` threads
+    pub fn derive(
+        enclave: &DcNetEnclave,
+        shared_secrets: &SealedSharedSecretsDbServer,
+        user_ids: BTreeSet<EntityId>,
+        round: u32,
+        dc_net_params: &DcNetParams,
+        n_threads: usize,
+    ) -> Result<PrecomputedPads> {
+        let start = Instant::now();
+        if round < shared_secrets.round {
+            error!(
+                "round {} is before shared_secrets.round {}",
+                round, shared_secrets.round
+            );
+            return Err(ServerError::WrongRound(round, shared_secrets.round));
+        }
 
-    let result = unblind_aggregate_merge(toplevel_agg, &round_secrets, signing_key, shared_secrets);
+        let partials = derive_partials(
+            enclave,
+            round,
+            shared_secrets,
+            dc_net_params,
+            &user_ids,
+            n_threads,
+        )?;
+        info!(
+            "precomputed {} pads for round {} in {:?}",
+            user_ids.len(),
+            round,
+            start.elapsed()
+        );
 
-    info!(
-        "========= {} round secrets merged after {:?}.",
-        round_secrets.len(),
-        start.elapsed()
-    );
+        Ok(PrecomputedPads {
+            round,
+            user_ids,
+            partials,
+        })
+    }
+}
 
-    result
+/// Whether two aggregates are the same round's aggregate from the same aggregator
+fn same_aggregate(a: &AggregatedMessage, b: &AggregatedMessage) -> bool {
+    a.round == b.round && a.pk == b.pk && a.digest() == b.digest()
 }
 
-use crate::aes_prng::Aes128Rng;
-use byteorder::{ByteOrder, LittleEndian};
-use hkdf::{Hkdf, InvalidLength};
-use rand_core::SeedableRng;
-use sha2::Sha256;
+/// Checks a share sent by another server in the anytrust group. The share must be signed by a
+/// registered server and, if `expected_agg` is given, must be a share of that aggregate. Returns
+/// the ID of the server that made the share and the round the share is for.
+pub fn verify_share(
+    pubkeys: &SignedPubKeyDb,
+    share_blob: &UnblindedAggregateShareBlob,
+    expected_agg: Option<&AggregatedMessage>,
+) -> Result<(EntityId, u32)> {
+    let share = share_blob
+        .unmarshal()
+        .map_err(|e| ServerError::BadShare("unknown server".to_string(), e.to_string()))?;
+
+    let server_id = match pubkeys.servers.iter().find(|(_, pkg)| pkg.sig == share.pk) {
+        Some((id, _)) => *id,
+        None => {
+            return Err(ServerError::BadShare(
+                format!("signing key {:?}", share.pk),
+                "not a server in the anytrust group".to_string(),
+            ))
+        }
+    };
+    let bad_share = |reason: String| ServerError::BadShare(format!("server {}", server_id), reason);
 
-fn verify_user_attestation(_reg_blob: &UserRegistrationBlob) -> std::result::Result<(), ()> {
-    log::warn!("verify_user_attestation is not implemented"); // XXX
-    Ok(())
-}
+    if share.verify().is_err() {
+        return Err(bad_share("invalid signature".to_string()));
+    }
 
-fn derive_round_secret_server(
-    round: u32,
-    shared_secrets: &SharedSecretsDbServer,
-    entity_ids_to_use: Option<&BTreeSet<EntityId>>,
-) -> std::result::Result<RoundSecret, InvalidLength> {
-    type MyRng = Aes128Rng;
-
-    let mut round_secret = RoundSecret::default();
-
-    for (pk, shared_secret) in shared_secrets.db.iter() {
-        // skip entries not in entity_ids_to_use
-        if let Some(eids) = entity_ids_to_use {
-            if !eids.contains(&EntityId::from(pk)) {
-                log::trace!("entity id of client {} is not in entity_ids_to_use", pk);
-                continue;
-            }
+    if let Some(agg) = expected_agg {
+        if share.encrypted_msg.round != agg.round {
+            return Err(bad_share(format!(
+                "share is for round {}, expected round {}",
+                share.encrypted_msg.round, agg.round
+            )));
+        }
+        if !same_aggregate(&share.encrypted_msg, agg) {
+            return Err(bad_share(format!(
+                "share is not of this server's round {} aggregate",
+                agg.round
+            )));
         }
+    }
 
-        let hk = Hkdf::<Sha256>::new(None, &shared_secret.as_ref());
-        // For cryptographic RNG's a seed of 256 bits is recommended, [u8; 32].
-        let mut seed = <MyRng as SeedableRng>::Seed::default();
+    Ok((server_id, share.encrypted_msg.round))
+}
 
-        // info contains round and window
-        let mut info = [0; 32];
-        let cursor = &mut info;
-        LittleEndian::write_u32(cursor, round);
-        hk.expand(&info, &mut seed)?;
+/// Derives the round output and signs it. The other servers must co-sign it before it is
+/// published (see [`sign_round_output_candidate`]).
+pub fn derive_round_output(
+    enclave: &DcNetEnclave,
+    sig_sk: &SealedSigPrivKey,
+    shared_secrets: &SealedSharedSecretsDbServer,
+    server_aggs: &[UnblindedAggregateShareBlob],
+) -> Result<RoundOutput> {
+    let round_output = sign_output_of_shares(enclave, sig_sk, shared_secrets, server_aggs)?;
 
-        let mut rng = MyRng::from_seed(seed);
-        round_secret.xor_mut(&DcRoundMessage::rand_from_csprng(&mut rng));
-    }
+    debug!(
+        "⏰ round {} concluded with output {:?}",
+        round_output.round, round_output
+    );
 
-    Ok(round_secret)
+    Ok(round_output)
 }
 
-pub fn unblind_aggregate_partial(
-    input: &(u32, SharedSecretsDbServer, BTreeSet<EntityId>),
-) -> Result<RoundSecret> {
-    let round = input.0;
-    let shared_secrets = input.1.clone();
-    let user_ids_in_batch = &input.2;
+/// Has the enclave XOR all the key shares into the final aggregate and sign the result. The
+/// enclave checks that every server in the group signed exactly one of the shares
+fn sign_output_of_shares(
+    enclave: &DcNetEnclave,
+    sig_sk: &SealedSigPrivKey,
+    shared_secrets: &SealedSharedSecretsDbServer,
+    server_aggs: &[UnblindedAggregateShareBlob],
+) -> Result<RoundOutput> {
+    let shares = server_aggs
+        .iter()
+        .map(|blob| blob.unmarshal())
+        .collect::<std::result::Result<Vec<UnblindedAggregateShare>, _>>()
+        .map_err(|e| {
+            error!("cannot unmarshal share: {}", e);
+            ServerError::UnexpectedError
+        })?;
+    let agg = match shares.first() {
+        Some(share) => &share.encrypted_msg,
+        None => {
+            error!("empty shares array");
+            return Err(ServerError::UnexpectedError);
+        }
+    };
 
-    if round != shared_secrets.round {
-        error!(
-            "wrong round. round {} != shared_secrets.round {}",
-            round, shared_secrets.round
-        );
+    let req = SignRoundOutputReq {
+        round: agg.round,
+        anytrust_group_id: agg.anytrust_group_id,
+        user_ids: agg.user_ids.clone(),
+        aggregated_msg: agg.aggregated_msg.clone(),
+        key_shares: shares
+            .iter()
+            .map(|share| {
+                let sig = OutputSignature {
+                    pk: share.pk,
+                    sig: SignatureBytes(share.sig.to_bytes().to_vec()),
+                };
+                (share.key_share.clone(), sig)
+            })
+            .collect(),
+        shared_secrets: shared_secrets.clone(),
+    };
+
+    Ok(enclave.sign_round_output(&req, sig_sk)?)
+}
+
+/// Checks a round output candidate from the anytrust leader and co-signs it. The candidate must
+/// include `my_share`, and its output must be what its shares combine to. The enclave checks that
+/// they are the shares of every server in the group.
+pub fn sign_round_output_candidate(
+    enclave: &DcNetEnclave,
+    sig_sk: &SealedSigPrivKey,
+    shared_secrets: &SealedSharedSecretsDbServer,
+    my_share: &UnblindedAggregateShareBlob,
+    candidate: &RoundOutputCandidate,
+) -> Result<OutputSignature> {
+    if !candidate.shares.contains(my_share) {
+        error!("round output candidate does not include my share");
         return Err(ServerError::UnexpectedError);
     }
 
-    // check that user ids in this batch is a subset of all known user ids
-    let user_ids_in_secret_db = BTreeSet::from_iter(shared_secrets.db.keys().map(EntityId::from));
-    if !(user_ids_in_batch.is_subset(&user_ids_in_secret_db)) {
-        error!("user_ids_in_batch is not a subset of user_ids in sercret_db. user_ids_in_batch = {:?}, user_ids_in_secret_db = {:?}", 
-        user_ids_in_batch,
-        user_ids_in_secret_db);
+    let mut expected = sign_output_of_shares(enclave, sig_sk, shared_secrets, &candidate.shares)?;
+    if expected.round != candidate.output.round || expected.dc_msg != candidate.output.dc_msg {
+        error!(
+            "round output candidate for round {} does not match its shares",
+            candidate.output.round
+        );
         return Err(ServerError::UnexpectedError);
     }
 
-    // decrypt key is derived from secret shares with users (identified by round_msg.user_ids)
-    derive_round_secret_server(round, &shared_secrets, Some(&user_ids_in_batch)).map_err(|_| {
-        error!("crypto error");
-        ServerError::UnexpectedError
-    })
+    expected
+        .server_sigs
+        .pop()
+        .ok_or(ServerError::UnexpectedError)
 }
 
-pub fn unblind_aggregate_merge(
-    toplevel_agg: &RoundSubmissionBlob,
-    round_secrets: &Vec<RoundSecret>,
-    sig_key: &SecretKey,
-    shared_secrets: &SharedSecretsDbServer,
-) -> Result<(UnblindedAggregateShareBlob, SharedSecretsDbServer)> {
-    let mut round_secret = RoundSecret::default();
-    for rs in round_secrets.iter() {
-        round_secret.xor_mut(rs);
+/// Adds a co-signature to the round output. The signer must be in the group and must not have
+/// signed already
+pub fn add_output_signature(
+    round_output: &mut RoundOutput,
+    group_sig_pks: &[PublicKey],
+    output_sig: OutputSignature,
+) -> Result<()> {
+    if !group_sig_pks.contains(&output_sig.pk) {
+        error!("{:?} is not a server in the group", output_sig.pk);
+        return Err(ServerError::UnexpectedError);
+    }
+    if round_output
+        .server_sigs
+        .iter()
+        .any(|s| s.pk == output_sig.pk)
+    {
+        error!(
+            "{:?} already signed round {}",
+            output_sig.pk, round_output.round
+        );
+        return Err(ServerError::UnexpectedError);
     }
 
-    let mut unblind_agg = UnblindedAggregateShare {
-        encrypted_msg: toplevel_agg.clone(),
-        key_share: round_secret,
-        sig: Signature::from_bytes(&[0u8; 64]).expect("failed to generate Signature from bytes"),
-        pk: PublicKey::default(),
-    };
-
-    // sign the final output and rachet the shared secrets
-    let shared_secrets = shared_secrets.clone();
+    let sig = Signature::from_bytes(&output_sig.sig.0)?;
+    if output_sig
+        .pk
+        .verify(round_output.digest().as_slice(), &sig)
+        .is_err()
+    {
+        error!(
+            "invalid signature by {:?} on round {}",
+            output_sig.pk, round_output.round
+        );
+        return Err(ServerError::UnexpectedError);
+    }
 
-    // sign
-    unblind_agg.sign_mut(sig_key).map_err(|e| {
-        error!("sign the unblind aggregate message failed: {}", e);
-        return ServerError::UnexpectedError;
-    });
+    round_output.server_sigs.push(output_sig);
 
-    Ok((
-        unblind_agg.marshal().expect("marshal unblind agg failed"),
-        shared_secrets.ratchet(),
-    ))
+    Ok(())
 }
 
-pub fn derive_round_output(
-    sig_sk: &SecretKey,
-    server_aggs: &[UnblindedAggregateShareBlob],
-) -> Result<RoundOutput> {
-    if server_aggs.is_empty() {
-        error!("empty shares array");
-        return Err(ServerError::UnexpectedError);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_state::mock_attestation_allow_list;
+    use common::types::SignMutable;
+    use ed25519_dalek::SecretKey;
+    use interface::{compute_anytrust_group_id, DcRoundMessage, SgxProtectedKeyPub};
+
+    const TEST_ENCLAVE_PATH: &'static str = "/sgxdcnet/lib/enclave.signed.so";
+
+    /// A server alone in its group and its sealed state, with `num_users` users registered and
+    /// one top-level aggregator
+    struct TestServer {
+        signing_key: SealedSigPrivKey,
+        decap_key: SealedKemPrivKey,
+        shared_secrets: SealedSharedSecretsDbServer,
+        pubkey_pkg: ServerPubKeyPackage,
+        pubkeys: SignedPubKeyDb,
+        agg_sk: SecretKey,
     }
 
-    // Xor of all server secrets
-    let mut final_msg = DcRoundMessage::default();
-
-    // We require all s in shares should have the same aggregated_msg
-    let first_msg = server_aggs[0]
-        .unmarshal()
-        .expect("failed to unmarshal the unblinded aggregated share");
-    let final_aggregation = first_msg.encrypted_msg.aggregated_msg;
-    let round = first_msg.encrypted_msg.round;
-
-    for s in server_aggs.iter() {
-        let share = s
-            .unmarshal()
-            .expect("failed to unmarshal the unblinded aggregated share");
-        if share.encrypted_msg.aggregated_msg != final_aggregation {
-            error!("share {:?} has a different final agg", share);
-            return Err(ServerError::UnexpectedError);
+    impl TestServer {
+        fn new(enclave: &DcNetEnclave, params: &DcNetParams, num_users: usize) -> TestServer {
+            let (signing_key, decap_key, shared_secrets, _, pubkey_pkg) =
+                new_server(enclave, params).unwrap();
+            let agg_sk = SecretKey::from_bytes(&[1u8; 32]).unwrap();
+            let shared_secrets = enclave
+                .recv_aggregator_registration(&shared_secrets, &PublicKey::from(&agg_sk))
+                .unwrap();
+            let mut server = TestServer {
+                signing_key,
+                decap_key,
+                shared_secrets,
+                pubkey_pkg,
+                pubkeys: SignedPubKeyDb::default(),
+                agg_sk,
+            };
+            server.register_users(enclave, num_users);
+            server
         }
-        final_msg.xor_mut(&share.key_share);
-    }
 
-    // Finally xor secrets with the message
-    final_msg.xor_mut(&final_aggregation);
+        fn register_users(&mut self, enclave: &DcNetEnclave, n: usize) {
+            let regs: Vec<UserRegistrationBlob> = enclave
+                .new_user_batch(&[self.pubkey_pkg.clone()], n)
+                .unwrap()
+                .into_iter()
+                .map(|(_, _, reg)| reg)
+                .collect();
+            recv_user_registration_batch(
+                enclave,
+                &mut self.pubkeys,
+                &mut self.shared_secrets,
+                &self.decap_key,
+                &mock_attestation_allow_list(),
+                &regs,
+            )
+            .unwrap();
+        }
 
-    let mut round_output = RoundOutput {
-        round,
-        dc_msg: final_msg,
-        server_sigs: vec![],
-    };
+        fn user_ids(&self) -> BTreeSet<EntityId> {
+            self.pubkeys.users.keys().cloned().collect()
+        }
 
-    let (sig, pk) = round_output
-        .sign(sig_sk)
-        .expect("failed to sign the round output");
+        /// Unblinds an aggregate of the given users and returns the key share
+        fn key_share(
+            &self,
+            enclave: &DcNetEnclave,
+            round: u32,
+            user_ids: &BTreeSet<EntityId>,
+            precomputed: Option<&PrecomputedPads>,
+        ) -> DcRoundMessage {
+            let params = self.pubkey_pkg.dc_net_params;
+            let kem_pk = SgxProtectedKeyPub(self.pubkey_pkg.kem.to_bytes());
+            let mut agg = AggregatedMessage {
+                round,
+                anytrust_group_id: compute_anytrust_group_id(&[kem_pk], &params),
+                user_ids: user_ids.clone(),
+                aggregated_msg: DcRoundMessage::new(&params),
+                ..Default::default()
+            };
+            agg.sign_mut(&self.agg_sk).unwrap();
+            let (share, ratcheted) = unblind_aggregate_mt(
+                enclave,
+                &agg,
+                &self.signing_key,
+                &self.shared_secrets,
+                &params,
+                precomputed,
+                4,
+            )
+            .unwrap();
+            assert_eq!(ratcheted.round, round + 1);
+
+            let share = share.unmarshal().unwrap();
+            share.verify().unwrap();
+            assert_eq!(share.pk, self.pubkey_pkg.sig);
+            share.key_share
+        }
+    }
 
-    round_output.server_sigs.push(OutputSignature { pk, sig });
+    #[test]
+    fn precomputed_pads_match_derived() {
+        let enclave = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+        let params = DcNetParams::default();
+        let round = 7;
+        let mut server = TestServer::new(&enclave, &params, 6);
+
+        let pads = PrecomputedPads::derive(
+            &enclave,
+            &server.shared_secrets,
+            server.user_ids(),
+            round,
+            &params,
+            4,
+        )
+        .unwrap();
+        assert_eq!(pads.user_ids.len(), 6);
+
+        // One more user registered after the pads were derived, so its pad is derived during
+        // unblinding, and one of the precomputed users is absent
+        let old_user_ids = server.user_ids();
+        server.register_users(&enclave, 1);
+        let new_user = *server.user_ids().difference(&old_user_ids).next().unwrap();
+        let absent = *old_user_ids.iter().next().unwrap();
+        let user_ids: BTreeSet<EntityId> = server
+            .user_ids()
+            .into_iter()
+            .filter(|u| *u != absent)
+            .collect();
+        assert!(user_ids.contains(&new_user));
+
+        let expected = server.key_share(&enclave, round, &user_ids, None);
+        assert!(server.key_share(&enclave, round, &user_ids, Some(&pads)) == expected);
+
+        // With few participants, the precomputed pads aren't used. Either way gives the same
+        // secret
+        let few: BTreeSet<EntityId> = user_ids.iter().take(1).cloned().collect();
+        let expected = server.key_share(&enclave, round, &few, None);
+        assert!(server.key_share(&enclave, round, &few, Some(&pads)) == expected);
+
+        // Pads for another round aren't used either
+        let next_round = server.key_share(&enclave, round + 1, &user_ids, Some(&pads));
+        assert!(next_round == server.key_share(&enclave, round + 1, &user_ids, None));
+        assert!(next_round != server.key_share(&enclave, round, &user_ids, None));
+
+        enclave.destroy();
+    }
 
-    debug!(
-        "⏰ round {} concluded with output {:?}",
-        round, round_output
-    );
+    /// Measures how fast a server unblinds an aggregate, for a few numbers of users. Each user's
+    /// pad is expanded from its shared secret and XORed in. This is compared to unblinding with
+    /// precomputed pads. Run with
+    /// `cargo test --release -p sgxdcnet-server round_secret_throughput -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn round_secret_throughput() {
+        let enclave = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+        let params = DcNetParams::default();
+
+        for &num_users in [100usize, 1000, 5000].iter() {
+            let server = TestServer::new(&enclave, &params, num_users);
+            let all_users = server.user_ids();
+
+            let start = Instant::now();
+            server.key_share(&enclave, 0, &all_users, None);
+            let elapsed = start.elapsed();
+
+            println!(
+                "{:>5} users: {:>8.2?} total, {:>8.0} pads/s",
+                num_users,
+                elapsed,
+                num_users as f64 / elapsed.as_secs_f64()
+            );
+
+            // With the pads precomputed, and all but every 20th user participating
+            let pads = PrecomputedPads::derive(
+                &enclave,
+                &server.shared_secrets,
+                all_users.clone(),
+                0,
+                &params,
+                1,
+            )
+            .unwrap();
+            let user_ids: BTreeSet<EntityId> = all_users
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| i % 20 != 0)
+                .map(|(_, id)| *id)
+                .collect();
+            let start = Instant::now();
+            server.key_share(&enclave, 0, &user_ids, Some(&pads));
+            let elapsed = start.elapsed();
+
+            println!(
+                "{:>5} users: {:>8.2?} from precomputed pads at 95% participation",
+                num_users, elapsed
+            );
+        }
 
-    Ok(round_output)
+        enclave.destroy();
+    }
 }
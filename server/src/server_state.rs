@@ -1,45 +1,79 @@
-use crate::util::Result;
+use crate::util::{Result, ServerError};
 
-use interface::{EntityId, RoundOutput, ServerPubKeyPackage, UserRegistrationBlob};
+use interface::{
+    compute_anytrust_group_id, CounterAdvance, DcNetParams, EntityId, OutputSignature, RoundOutput,
+    SealedKemPrivKey, SealedSharedSecretsDbServer, SealedSigPrivKey, ServerPubKeyPackage,
+    SgxProtectedKeyPub, UserRegistrationBlob,
+};
+
+use std::collections::{BTreeMap, BTreeSet};
 
-use log::info;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 
-use ed25519_dalek::SecretKey;
+use ed25519_dalek::PublicKey;
 
+use common::attestation::EnclaveAllowList;
+use common::enclave::{DcNetEnclave, EnclaveBackend};
 use common::types::{
-    AggRegistrationBlob, AggregatedMessage, RoundSubmissionBlob, ServerRegistrationBlob,
-    SharedSecretsDbServer, SignedPubKeyDb, UnblindedAggregateShareBlob,
+    AggRegistrationBlob, AggregatedMessage, RoundOutputCandidate, RoundSubmissionBlob,
+    ServerRegistrationBlob, SignedPubKeyDb, UnblindedAggregateShareBlob,
 };
 
 use crate::server::{
-    derive_round_output, new_server, recv_aggregator_registration, recv_server_registration,
-    recv_user_registration_batch, unblind_aggregate,
+    add_output_signature, derive_round_output, new_server, recv_aggregator_registration,
+    recv_server_registration, recv_user_registration_batch, sign_round_output_candidate,
+    unblind_aggregate, verify_share, verify_toplevel_aggregate, PrecomputedPads,
 };
 
 #[derive(Serialize, Deserialize)]
 pub struct ServerState {
     /// A unique identifier for this aggregator. Computed as the hash of the server's KEM pubkey.
     pub server_id: EntityId,
-    /// This server's's signing key.
-    pub signing_key: SecretKey,
-    /// This server's KEM decapsulation key.
-    pub decap_key: SecretKey,
+    /// This server's's signing key. Can only be accessed from within the enclave.
+    pub signing_key: SealedSigPrivKey,
+    /// This server's KEM decapsulation key. Can only be accessed from within the enclave.
+    pub decap_key: SealedKemPrivKey,
     /// The KEM and signing public keys of this server
     pub pubkey_pkg: ServerPubKeyPackage,
+    /// The DC net parameters of this server's anytrust group
+    pub dc_net_params: DcNetParams,
+    /// The enclaves whose attestations this server accepts from users
+    pub enclave_allow_list: EnclaveAllowList,
     /// A partial aggregate of received user messages
     pub partial_agg: Option<AggregatedMessage>,
-    /// A sealed database of secrets shared with users. Maps entity ID to shared secret.
-    pub shared_secrets: SharedSecretsDbServer,
+    /// A sealed database of secrets shared with users. Maps entity ID to shared secret. Can only
+    /// be accessed from within the enclave.
+    pub shared_secrets: SealedSharedSecretsDbServer,
     /// A map of EntityIds to the corresponding public key
     pub pubkeys: SignedPubKeyDb,
+    /// The registered aggregators whose aggregates this server unblinds, i.e., the roots of the
+    /// aggregation trees
+    pub toplevel_aggregators: BTreeSet<EntityId>,
     /// The size of this anytrust group, including this node
     pub anytrust_group_size: usize,
+    /// This server's copy of each registered user's freshness counter. A user that isn't here is
+    /// at 0
+    #[serde(default)]
+    pub freshness_counters: BTreeMap<EntityId, FreshnessCounter>,
+}
+
+/// A server's copy of a user's freshness counter
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct FreshnessCounter {
+    pub value: u32,
+    /// The nonce of the advance that moved the counter to `value`. That advance is witnessed again
+    /// if it's asked for again, since the user may not have got every server's signature on it
+    pub last_nonce: [u8; 32],
 }
 
 impl ServerState {
-    pub fn new() -> Result<(ServerState, ServerPubKeyPackage)> {
-        let (ssk, ksk, server_id, reg_blob) = new_server()?;
+    pub fn new(
+        enclave: &DcNetEnclave,
+        dc_net_params: DcNetParams,
+        enclave_allow_list: EnclaveAllowList,
+    ) -> Result<(ServerState, ServerPubKeyPackage)> {
+        let (ssk, ksk, shared_secrets, server_id, reg_blob) = new_server(enclave, &dc_net_params)?;
         // Group size starts out as 1. This will increment every time an anytrust node is
         // registered with this node.
         let anytrust_group_size = 1;
@@ -51,23 +85,51 @@ impl ServerState {
             signing_key: ssk,
             decap_key: ksk,
             pubkey_pkg,
+            dc_net_params,
+            enclave_allow_list,
             partial_agg: None,
-            shared_secrets: SharedSecretsDbServer::default(),
+            shared_secrets,
             pubkeys: SignedPubKeyDb::default(),
+            toplevel_aggregators: BTreeSet::new(),
             anytrust_group_size,
+            freshness_counters: BTreeMap::new(),
         };
 
         Ok((state, reg_blob))
     }
 
+    /// The ID of this server's anytrust group
+    pub fn anytrust_group_id(&self) -> EntityId {
+        let mut kem_pubkeys = vec![SgxProtectedKeyPub(self.pubkey_pkg.kem.to_bytes())];
+        kem_pubkeys.extend(
+            self.pubkeys
+                .servers
+                .values()
+                .map(|pk| SgxProtectedKeyPub(pk.kem.to_bytes())),
+        );
+        compute_anytrust_group_id(&kem_pubkeys, &self.dc_net_params)
+    }
+
     /// XORs the shared secrets into the given aggregate. Returns the server's share of the
-    /// unblinded aggregate as well as the ratcheted shared secrets
+    /// unblinded aggregate as well as the ratcheted shared secrets. The aggregate must come from a
+    /// top-level aggregator and be for this group's current round. Pads in `precomputed` are used
+    /// instead of deriving them again.
     pub fn unblind_aggregate(
         &mut self,
+        enclave: &DcNetEnclave,
         toplevel_agg: &RoundSubmissionBlob,
+        precomputed: Option<&PrecomputedPads>,
     ) -> Result<UnblindedAggregateShareBlob> {
-        let (share, ratcheted_secrets) =
-            unblind_aggregate(toplevel_agg, &self.signing_key, &self.shared_secrets)?;
+        self.verify_toplevel_aggregate(toplevel_agg)?;
+
+        let (share, ratcheted_secrets) = unblind_aggregate(
+            enclave,
+            toplevel_agg,
+            &self.signing_key,
+            &self.shared_secrets,
+            &self.dc_net_params,
+            precomputed,
+        )?;
 
         // Ratchet the secrets forward
         self.shared_secrets = ratcheted_secrets;
@@ -75,38 +137,185 @@ impl ServerState {
         Ok(share)
     }
 
+    /// Checks that a top-level aggregate can be unblinded by this server. The enclave checks this
+    /// again, but this says why an aggregate is refused
+    pub fn verify_toplevel_aggregate(&self, toplevel_agg: &AggregatedMessage) -> Result<()> {
+        verify_toplevel_aggregate(
+            &self.pubkeys,
+            &self.toplevel_aggregators,
+            &self.anytrust_group_id(),
+            self.shared_secrets.round,
+            toplevel_agg,
+        )
+    }
+
     /// Derives the final round output given all the shares of the unblinded aggregates
     pub fn derive_round_output(
         &self,
+        enclave: &DcNetEnclave,
         server_aggs: &[UnblindedAggregateShareBlob],
     ) -> Result<RoundOutput> {
-        derive_round_output(&self.signing_key, server_aggs)
+        derive_round_output(
+            enclave,
+            &self.signing_key,
+            &self.shared_secrets,
+            server_aggs,
+        )
     }
 
-    /// Registers a user with this server
-    pub fn recv_user_registrations(&mut self, input_blobs: &[UserRegistrationBlob]) -> Result<()> {
+    /// Checks a share sent by another server in the anytrust group and returns that server's ID
+    /// and the share's round. If `expected_agg` is given, the share must be of that aggregate.
+    pub fn verify_share(
+        &self,
+        share: &UnblindedAggregateShareBlob,
+        expected_agg: Option<&AggregatedMessage>,
+    ) -> Result<(EntityId, u32)> {
+        verify_share(&self.pubkeys, share, expected_agg)
+    }
+
+    /// The signing keys of every server in the anytrust group, including this one
+    pub fn group_sig_pks(&self) -> Vec<PublicKey> {
+        let mut pks = vec![self.pubkey_pkg.sig];
+        pks.extend(self.pubkeys.servers.values().map(|s| s.sig));
+        pks
+    }
+
+    /// Checks the leader's round output candidate against this server's share of the round and
+    /// returns this server's signature on the output
+    pub fn sign_round_output(
+        &self,
+        enclave: &DcNetEnclave,
+        my_share: &UnblindedAggregateShareBlob,
+        candidate: &RoundOutputCandidate,
+    ) -> Result<OutputSignature> {
+        sign_round_output_candidate(
+            enclave,
+            &self.signing_key,
+            &self.shared_secrets,
+            my_share,
+            candidate,
+        )
+    }
+
+    /// Adds another server's signature to a round output
+    pub fn add_output_signature(
+        &self,
+        round_output: &mut RoundOutput,
+        output_sig: OutputSignature,
+    ) -> Result<()> {
+        add_output_signature(round_output, &self.group_sig_pks(), output_sig)
+    }
+
+    /// Whether every server in the anytrust group has signed the round output. Signatures are
+    /// checked as they are added, so this only counts them.
+    pub fn output_fully_signed(&self, round_output: &RoundOutput) -> bool {
+        round_output.server_sigs.len() == self.anytrust_group_size
+    }
+
+    /// Witnesses a user's freshness counter moving on from `advance.from`. Only the counters of
+    /// registered users and of the other servers in the group are witnessed, only on their own
+    /// signed request, and only from the value this server has, so state sealed before the last
+    /// submission or unblind is refused. The counter is
+    /// moved on before the signature is returned, and the state must be saved before it is sent.
+    ///
+    /// The last advance is witnessed again if it's repeated with the same nonce. The user's enclave
+    /// picks the same nonce when it retries the same submission, so a server that signed before
+    /// another server failed doesn't lock the user out.
+    pub fn witness_counter_advance(
+        &mut self,
+        enclave: &DcNetEnclave,
+        advance: &CounterAdvance,
+    ) -> Result<OutputSignature> {
+        let user_pk = match self.pubkeys.users.get(&advance.user_id) {
+            Some(user_reg) => PublicKey::from_bytes(&user_reg.pk.0)?,
+            // The other servers have the rounds they unblind witnessed
+            None => {
+                self.pubkeys
+                    .servers
+                    .values()
+                    .find(|s| EntityId::from(*s) == advance.user_id)
+                    .ok_or(ServerError::UnknownUser(advance.user_id))?
+                    .sig
+            }
+        };
+        if !advance.verify_user_sig(&user_pk) {
+            error!(
+                "counter advance for {} is not signed by it",
+                advance.user_id
+            );
+            return Err(ServerError::AdvanceSignature(advance.user_id));
+        }
+
+        let counter = self.freshness_counters.entry(advance.user_id).or_default();
+        let next = advance
+            .from
+            .checked_add(1)
+            .ok_or(ServerError::UnexpectedError)?;
+        let repeated = counter.value == next && counter.last_nonce == advance.nonce;
+        if counter.value != advance.from && !repeated {
+            return Err(ServerError::StaleCounter(advance.from, counter.value));
+        }
+
+        let sig = enclave.sign_counter_advance(advance, &self.shared_secrets, &self.signing_key)?;
+        if !repeated {
+            info!("user {} counter moved on to {}", advance.user_id, next);
+            *counter = FreshnessCounter {
+                value: next,
+                last_nonce: advance.nonce,
+            };
+        }
+
+        Ok(sig)
+    }
+
+    /// Registers users with this server
+    pub fn recv_user_registrations(
+        &mut self,
+        enclave: &DcNetEnclave,
+        input_blobs: &[UserRegistrationBlob],
+    ) -> Result<()> {
         recv_user_registration_batch(
+            enclave,
             &mut self.pubkeys,
             &mut self.shared_secrets,
             &self.decap_key,
+            &self.enclave_allow_list,
             input_blobs,
         )?;
 
         Ok(())
     }
 
-    /// Registers an aggregator with this server
-    pub fn recv_aggregator_registration(&mut self, input_blob: &AggRegistrationBlob) -> Result<()> {
+    /// Registers an aggregator with this server. If `top_level` is set, this server unblinds the
+    /// aggregates it sends. The enclave only takes top-level aggregators before the first round is
+    /// unblinded.
+    pub fn recv_aggregator_registration(
+        &mut self,
+        enclave: &DcNetEnclave,
+        input_blob: &AggRegistrationBlob,
+        top_level: bool,
+    ) -> Result<()> {
+        if top_level {
+            self.shared_secrets =
+                enclave.recv_aggregator_registration(&self.shared_secrets, &input_blob.pk)?;
+            self.toplevel_aggregators
+                .insert(EntityId::from(&input_blob.pk));
+        }
         recv_aggregator_registration(&mut self.pubkeys, input_blob)?;
 
         Ok(())
     }
 
     /// Registers another anytrust server with this server. This will be added to the server's
-    /// anytrust group
-    pub fn recv_server_registration(&mut self, input_blob: &ServerRegistrationBlob) -> Result<()> {
+    /// anytrust group, and witness the rounds this server unblinds
+    pub fn recv_server_registration(
+        &mut self,
+        enclave: &DcNetEnclave,
+        input_blob: &ServerRegistrationBlob,
+    ) -> Result<()> {
         // Input the registration and increment the size of the group
-        recv_server_registration(&mut self.pubkeys, input_blob)?;
+        recv_server_registration(&mut self.pubkeys, &self.dc_net_params, input_blob)?;
+        self.shared_secrets = enclave.recv_server_registration(&self.shared_secrets, input_blob)?;
         self.anytrust_group_size += 1;
 
         info!(
@@ -118,8 +327,364 @@ impl ServerState {
     }
 }
 
+#[cfg(test)]
+const TEST_ENCLAVE_PATH: &'static str = "/sgxdcnet/lib/enclave.signed.so";
+
 /// Tests that making a new server succeeds
 #[test]
 fn test_new_server() {
-    ServerState::new().unwrap();
+    use common::enclave::EnclaveBackend;
+
+    let enclave = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+    let (state, pkg) = ServerState::new(
+        &enclave,
+        DcNetParams::default(),
+        EnclaveAllowList::default(),
+    )
+    .unwrap();
+    assert_eq!(state.server_id, EntityId::from(&pkg));
+    assert_eq!(state.shared_secrets.round, 0);
+    enclave.destroy();
+}
+
+/// Tests that only shares signed by a registered server, of the expected aggregate, are accepted
+#[test]
+fn test_verify_share() {
+    use common::enclave::EnclaveBackend;
+    use common::types::{MarshallAs, SignMutable, UnblindedAggregateShare};
+    use ed25519_dalek::SecretKey;
+    use interface::DcRoundMessage;
+    use x25519_dalek::{PublicKey as xPublicKey, StaticSecret};
+
+    let enclave = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+    let params = DcNetParams::default();
+    let (mut leader, _) = ServerState::new(&enclave, params, EnclaveAllowList::default()).unwrap();
+
+    // The other servers' keys are sealed in their enclaves. Make keys in the clear that shares
+    // can be signed with here
+    let make_server = |i: u8| {
+        let sig_key = SecretKey::from_bytes(&[2 * i; 32]).unwrap();
+        let kem_key = SecretKey::from_bytes(&[2 * i + 1; 32]).unwrap();
+        let kem_xpk = xPublicKey::from(&StaticSecret::from(kem_key.to_bytes()));
+        let pkg = ServerPubKeyPackage {
+            sig: PublicKey::from(&sig_key),
+            kem: PublicKey::from(&kem_key),
+            xkem: SgxProtectedKeyPub(kem_xpk.to_bytes()),
+            dc_net_params: params,
+        };
+        (sig_key, pkg)
+    };
+    let (follower, follower_pkg) = make_server(1);
+    let (outsider, _) = make_server(2);
+    leader
+        .recv_server_registration(&enclave, &follower_pkg)
+        .unwrap();
+
+    let agg = AggregatedMessage {
+        round: 3,
+        aggregated_msg: DcRoundMessage::new(&params),
+        ..Default::default()
+    };
+    let make_share = |server_sk: &SecretKey, agg: &AggregatedMessage| {
+        let mut share = UnblindedAggregateShare {
+            encrypted_msg: agg.clone(),
+            key_share: DcRoundMessage::new(&params),
+            sig: agg.sig,
+            pk: agg.pk,
+        };
+        share.sign_mut(server_sk).unwrap();
+        share.marshal().unwrap()
+    };
+
+    let share = make_share(&follower, &agg);
+    let (id, round) = leader.verify_share(&share, Some(&agg)).unwrap();
+    assert_eq!(id, EntityId::from(&follower_pkg.kem));
+    assert_eq!(round, 3);
+
+    // shares of another round are rejected, but only if we know which round it is
+    let next_agg = AggregatedMessage {
+        round: 4,
+        ..agg.clone()
+    };
+    let share = make_share(&follower, &next_agg);
+    assert!(leader.verify_share(&share, Some(&agg)).is_err());
+    assert!(leader.verify_share(&share, None).is_ok());
+
+    // so are shares of another aggregate
+    let mut other_agg = agg.clone();
+    other_agg.user_ids.insert(EntityId::default());
+    let share = make_share(&follower, &other_agg);
+    assert!(leader.verify_share(&share, Some(&agg)).is_err());
+
+    // and shares from servers outside the group
+    let share = make_share(&outsider, &agg);
+    assert!(leader.verify_share(&share, None).is_err());
+
+    enclave.destroy();
+}
+
+/// Tests that aggregates are only unblinded if they're from a top-level aggregator and for this
+/// group's current round or one shortly after
+#[test]
+fn test_verify_toplevel_aggregate() {
+    use crate::server::MAX_ROUND_SKIP;
+    use crate::util::ServerError;
+    use common::enclave::EnclaveBackend;
+    use common::types::SignMutable;
+    use ed25519_dalek::{PublicKey, SecretKey};
+    use interface::DcRoundMessage;
+
+    let enclave = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+    let params = DcNetParams::default();
+    let (mut state, _) = ServerState::new(&enclave, params, EnclaveAllowList::default()).unwrap();
+
+    let agg_sk = SecretKey::from_bytes(&[1u8; 32]).unwrap();
+    let agg_reg = AggRegistrationBlob {
+        pk: PublicKey::from(&agg_sk),
+        role: "agg".to_string(),
+    };
+    let make_agg = |round: u32, anytrust_group_id: EntityId| {
+        let mut agg = AggregatedMessage {
+            round,
+            anytrust_group_id,
+            aggregated_msg: DcRoundMessage::new(&params),
+            ..Default::default()
+        };
+        agg.sign_mut(&agg_sk).unwrap();
+        agg
+    };
+    let verify = |state: &ServerState, agg: &AggregatedMessage| {
+        verify_toplevel_aggregate(
+            &state.pubkeys,
+            &state.toplevel_aggregators,
+            &state.anytrust_group_id(),
+            state.shared_secrets.round,
+            agg,
+        )
+    };
+    let group_id = state.anytrust_group_id();
+
+    // only registered top-level aggregators are accepted
+    let agg = make_agg(0, group_id);
+    assert!(matches!(
+        verify(&state, &agg),
+        Err(ServerError::UnknownAggregator(_))
+    ));
+    state
+        .recv_aggregator_registration(&enclave, &agg_reg, false)
+        .unwrap();
+    assert!(matches!(
+        verify(&state, &agg),
+        Err(ServerError::UnknownAggregator(_))
+    ));
+    state
+        .recv_aggregator_registration(&enclave, &agg_reg, true)
+        .unwrap();
+    verify(&state, &agg).unwrap();
+
+    let mut tampered = agg.clone();
+    tampered.round = 1;
+    assert!(matches!(
+        verify(&state, &tampered),
+        Err(ServerError::AggregateSignature)
+    ));
+
+    // rounds can be skipped, but not revisited
+    verify(&state, &make_agg(5, group_id)).unwrap();
+    state.shared_secrets.round = 2;
+    assert!(matches!(
+        verify(&state, &make_agg(1, group_id)),
+        Err(ServerError::WrongRound(1, 2))
+    ));
+    assert!(matches!(
+        verify(&state, &make_agg(2, EntityId::default())),
+        Err(ServerError::WrongGroup(_))
+    ));
+
+    // nor skipped too far ahead
+    verify(&state, &make_agg(2 + MAX_ROUND_SKIP, group_id)).unwrap();
+    assert!(matches!(
+        verify(&state, &make_agg(3 + MAX_ROUND_SKIP, group_id)),
+        Err(ServerError::RoundTooFar(_, 2))
+    ));
+    state.shared_secrets.round = u32::MAX;
+    verify(&state, &make_agg(u32::MAX, group_id)).unwrap();
+
+    enclave.destroy();
+}
+
+/// Has users' attestations checked with the mock verifier, and returns an allow-list of the
+/// software enclave
+#[cfg(test)]
+pub(crate) fn mock_attestation_allow_list() -> EnclaveAllowList {
+    use common::attestation::{set_quote_verifier, MockQuoteVerifier};
+    use interface::SOFTWARE_ENCLAVE_MR_ENCLAVE;
+
+    set_quote_verifier(Box::new(MockQuoteVerifier));
+    EnclaveAllowList {
+        mr_enclaves: vec![SOFTWARE_ENCLAVE_MR_ENCLAVE],
+        mr_signers: vec![],
+    }
+}
+
+/// A user registration for the given signing key, attested to by the software enclave
+#[cfg(test)]
+fn mock_user_reg(pk: &PublicKey) -> UserRegistrationBlob {
+    use interface::{make_mock_quote, QuoteBody, SOFTWARE_ENCLAVE_MR_ENCLAVE};
+
+    let mut reg = UserRegistrationBlob {
+        pk: SgxProtectedKeyPub(pk.to_bytes()),
+        xpk: SgxProtectedKeyPub([2u8; 32]),
+        role: "user".to_string(),
+        tee_linkable_attestation: vec![],
+    };
+    reg.tee_linkable_attestation = make_mock_quote(&QuoteBody {
+        mr_enclave: SOFTWARE_ENCLAVE_MR_ENCLAVE,
+        mr_signer: [0u8; 32],
+        report_data: reg.report_data(),
+    });
+    reg
+}
+
+/// Tests that counters are only witnessed for registered users, on their own request, from the
+/// value this server has
+#[test]
+fn test_witness_counter_advance() {
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use interface::{MultiSignable, SignatureBytes};
+
+    let enclave = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+    let params = DcNetParams::default();
+    let (mut state, pkg) =
+        ServerState::new(&enclave, params, mock_attestation_allow_list()).unwrap();
+
+    // The counter only looks at the user's signing key, so register one we hold
+    let user_sk = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+    let user_keypair = Keypair {
+        public: PublicKey::from(&user_sk),
+        secret: user_sk,
+    };
+    let user_reg = mock_user_reg(&user_keypair.public);
+    let user_id = EntityId::from(&user_reg);
+
+    let advance = |from: u32, nonce: u8| {
+        let mut advance = CounterAdvance {
+            user_id,
+            from,
+            nonce: [nonce; 32],
+            ..Default::default()
+        };
+        advance.user_sig = SignatureBytes(user_keypair.sign(&advance.digest()).to_bytes().to_vec());
+        advance
+    };
+    assert!(matches!(
+        state.witness_counter_advance(&enclave, &advance(0, 0)),
+        Err(ServerError::UnknownUser(_))
+    ));
+    state
+        .recv_user_registrations(&enclave, &[user_reg])
+        .unwrap();
+
+    // only the user can move its counter on
+    let mut forged = advance(0, 0);
+    forged.from = 1;
+    assert!(matches!(
+        state.witness_counter_advance(&enclave, &forged),
+        Err(ServerError::AdvanceSignature(_))
+    ));
+    let mut unsigned = advance(0, 0);
+    unsigned.user_sig = SignatureBytes::default();
+    assert!(matches!(
+        state.witness_counter_advance(&enclave, &unsigned),
+        Err(ServerError::AdvanceSignature(_))
+    ));
+
+    let mut first = advance(0, 1);
+    first
+        .server_sigs
+        .push(state.witness_counter_advance(&enclave, &first).unwrap());
+    assert_eq!(first.verify_multisig(&[pkg.sig], 1), Ok(vec![0]));
+
+    // the counter has moved on, so no other advance from 0 can be witnessed
+    assert!(matches!(
+        state.witness_counter_advance(&enclave, &advance(0, 2)),
+        Err(ServerError::StaleCounter(0, 1))
+    ));
+    state
+        .witness_counter_advance(&enclave, &advance(1, 3))
+        .unwrap();
+
+    enclave.destroy();
+}
+
+/// Tests that a user locked out by a server failing mid-advance gets back in by retrying the same
+/// advance, and that nothing else can use the retry
+#[test]
+fn test_witness_counter_advance_retry() {
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use interface::{MultiSignable, SignatureBytes};
+
+    let enclave = DcNetEnclave::init(TEST_ENCLAVE_PATH).unwrap();
+    let params = DcNetParams::default();
+    let (mut first, first_pkg) =
+        ServerState::new(&enclave, params, mock_attestation_allow_list()).unwrap();
+    let (mut second, second_pkg) =
+        ServerState::new(&enclave, params, mock_attestation_allow_list()).unwrap();
+    let pks = [first_pkg.sig, second_pkg.sig];
+
+    let user_sk = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+    let user_keypair = Keypair {
+        public: PublicKey::from(&user_sk),
+        secret: user_sk,
+    };
+    let user_reg = mock_user_reg(&user_keypair.public);
+    let user_id = EntityId::from(&user_reg);
+    first
+        .recv_user_registrations(&enclave, &[user_reg.clone()])
+        .unwrap();
+    second
+        .recv_user_registrations(&enclave, &[user_reg])
+        .unwrap();
+
+    let advance = |from: u32, nonce: u8| {
+        let mut advance = CounterAdvance {
+            user_id,
+            from,
+            nonce: [nonce; 32],
+            ..Default::default()
+        };
+        advance.user_sig = SignatureBytes(user_keypair.sign(&advance.digest()).to_bytes().to_vec());
+        advance
+    };
+
+    // The first server signs, then the second one can't be reached
+    let mut req = advance(0, 1);
+    req.server_sigs
+        .push(first.witness_counter_advance(&enclave, &req).unwrap());
+    assert!(req.verify_multisig(&pks, 2).is_err());
+
+    // A different advance from the same value is refused by the first server...
+    assert!(matches!(
+        first.witness_counter_advance(&enclave, &advance(0, 2)),
+        Err(ServerError::StaleCounter(0, 1))
+    ));
+
+    // ...but the same one is signed again, and now the second server signs too
+    let mut retry = advance(0, 1);
+    retry
+        .server_sigs
+        .push(first.witness_counter_advance(&enclave, &retry).unwrap());
+    retry
+        .server_sigs
+        .push(second.witness_counter_advance(&enclave, &retry).unwrap());
+    assert_eq!(retry.verify_multisig(&pks, 2), Ok(vec![0, 1]));
+    assert_eq!(first.freshness_counters[&user_id].value, 1);
+    assert_eq!(second.freshness_counters[&user_id].value, 1);
+
+    // Once both have moved on, the next advance goes through as usual
+    let next = advance(1, 3);
+    first.witness_counter_advance(&enclave, &next).unwrap();
+    second.witness_counter_advance(&enclave, &next).unwrap();
+
+    enclave.destroy();
 }
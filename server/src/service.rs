@@ -1,29 +1,49 @@
 use crate::{
+    archive::RoundOutputArchive,
+    server::{unblind_aggregate, PrecomputedPads},
     util::{save_output, save_state, ServerError},
     ServerState,
 };
-use common::{cli_util, log_time::log_time};
-use interface::{RoundOutput, RETRIES, TIMEOUT_SEC};
+use common::{cli_util, enclave::DcNetEnclave, log_time::log_time};
+use interface::{
+    CounterAdvance, DcNetParams, EntityId, RoundOutput, SealedSharedSecretsDbServer,
+    N_THREADS_DERIVE_ROUND_SECRET, RETRIES, TIMEOUT_SEC,
+};
 
-use common::types::{RoundSubmissionBlob, UnblindedAggregateShareBlob};
+use common::types::{
+    RoundOutputCandidate, RoundOutputSignature, RoundSubmissionBlob, UnblindedAggregateShareBlob,
+    UnmarshalledAs,
+};
 
 use core::ops::DerefMut;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     sync::{Arc, Mutex},
+    thread,
     time::{Duration, Instant},
 };
 
+use actix_rt::time::delay_for;
 use actix_web::{
     client::Client,
+    error::BlockingError,
     get,
     http::{StatusCode, Uri},
     post, rt as actix_rt, web, App, HttpResponse, HttpServer, ResponseError, Result,
 };
 // use futures_util::future::ok;
 use log::{debug, error, info};
+use serde::Deserialize;
 use thiserror::Error;
 
+/// The most round outputs a single round_results request can return
+const MAX_ROUND_RESULTS: u32 = 1000;
+
+/// How long to wait before resending a request to the leader. The wait doubles with every retry,
+/// up to RETRY_MAX_DELAY
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Error)]
 enum ApiError {
     #[error("internal error")]
@@ -38,67 +58,150 @@ impl ResponseError for ApiError {}
 // #[derive(Clone)]
 pub(crate) struct ServiceState {
     pub(crate) server_state: ServerState,
-    pub(crate) round_shares: Vec<UnblindedAggregateShareBlob>,
+    pub(crate) enclave: DcNetEnclave,
+    /// The current round's shares, keyed by the ID of the server that made them
+    pub(crate) round_shares: BTreeMap<EntityId, UnblindedAggregateShareBlob>,
     /// Contains the URL of the anytrust leader. If `None`, it's you.
     pub(crate) leader_url: Option<String>,
-    /// A map from round to the round's output
-    pub(crate) round_outputs: BTreeMap<u32, RoundOutput>,
+    /// The on-disk archive of round outputs. Outputs are only put here once every anytrust server
+    /// has signed them. Only the leader has one.
+    pub(crate) round_outputs: Option<RoundOutputArchive>,
+    /// A map from round to the round's output that is still waiting for co-signatures
+    pub(crate) round_candidates: BTreeMap<u32, RoundOutputCandidate>,
     /// The path to this server's state file. If `None`, state is not persisted to disk
     pub(crate) server_state_path: Option<String>,
+    /// The users' pads for the upcoming round, if they've been derived yet
+    pub(crate) precomputed_pads: Option<PrecomputedPads>,
 }
 
 impl ServiceState {
     pub(crate) fn new(
         server_state: ServerState,
+        enclave: DcNetEnclave,
         server_state_path: Option<String>,
         leader_url: Option<String>,
+        round_outputs: Option<RoundOutputArchive>,
     ) -> ServiceState {
         ServiceState {
             server_state,
+            enclave,
             server_state_path,
             leader_url,
-            round_outputs: BTreeMap::new(),
-            round_shares: Vec::new(),
+            round_outputs,
+            round_candidates: BTreeMap::new(),
+            round_shares: BTreeMap::new(),
+            precomputed_pads: None,
         }
     }
 }
 
+/// Derives the given users' pads for the round `shared_secrets` starts at, on a separate thread,
+/// and caches them in the service state. Unblinding that round's aggregate then only has to XOR
+/// them.
+fn precompute_pads(
+    state: Arc<Mutex<ServiceState>>,
+    enclave: DcNetEnclave,
+    shared_secrets: SealedSharedSecretsDbServer,
+    user_ids: BTreeSet<EntityId>,
+    dc_net_params: DcNetParams,
+) {
+    thread::spawn(move || {
+        let round = shared_secrets.round;
+        let pads = match PrecomputedPads::derive(
+            &enclave,
+            &shared_secrets,
+            user_ids,
+            round,
+            &dc_net_params,
+            N_THREADS_DERIVE_ROUND_SECRET,
+        ) {
+            Ok(pads) => pads,
+            Err(e) => {
+                error!("could not precompute pads for round {}: {:?}", round, e);
+                return;
+            }
+        };
+
+        // Only keep the pads if their round hasn't been unblinded in the meantime
+        let mut handle = state.lock().unwrap();
+        if handle.server_state.shared_secrets.round <= round {
+            handle.precomputed_pads = Some(pads);
+        }
+    });
+}
+
 /// Finish the round as the anytrust leader. This means computing the round output and clearing the
-/// caches.
+/// caches. The output is published once the other servers have co-signed it.
 fn leader_finish_round(state: &mut ServiceState) {
     let start = Instant::now();
 
     let ServiceState {
         ref server_state,
+        ref enclave,
         ref mut round_outputs,
+        ref mut round_candidates,
         ref mut round_shares,
         ..
     } = state;
 
-    // Derive the round output and save it to the state. This can be queries in the round_result
-    // function. If this fails, use the default value. The last thing we want to do is get stuck in
-    // a state that cannot progress
-    let output = server_state.derive_round_output(&round_shares).unwrap();
+    // Derive the round output and sign it. The other servers fetch it from round_candidate and
+    // co-sign it. If this fails, use the default value. The last thing we want to do is get stuck
+    // in a state that cannot progress
+    let shares: Vec<UnblindedAggregateShareBlob> = round_shares.values().cloned().collect();
+    let output = server_state.derive_round_output(enclave, &shares).unwrap();
     let round = output.round;
 
     debug!("output: {:?}", output);
 
-    let mut output_path = "../server/round_output.txt".to_owned();
-    output_path.insert(22, std::char::from_digit(round, 10).unwrap());
+    if server_state.output_fully_signed(&output) {
+        publish_round_output(round_outputs, output);
+    } else {
+        let candidate = RoundOutputCandidate { output, shares };
+        round_candidates.insert(round, candidate);
+        info!("Output of round {} is waiting for co-signatures", round);
+    }
+
+    // Clear the state
+    round_shares.clear();
+
+    let duration = start.elapsed();
+    debug!("[server] leader_finish_round: {:?}", duration);
+}
+
+/// Archives the fully signed round output, which makes it available at round_result
+fn publish_round_output(round_outputs: &mut Option<RoundOutputArchive>, output: RoundOutput) {
+    let round = output.round;
+    let round_outputs = match round_outputs {
+        Some(a) => a,
+        None => {
+            error!(
+                "can't publish output of round {}: only the leader archives outputs",
+                round
+            );
+            return;
+        }
+    };
+
+    // The scripts read the previous round's output from this file
+    let output_path = format!("../server/round_output{}.txt", round);
     debug!("output path: {:?}", output_path);
     match save_output(&output_path[..], &output) {
         Err(e) => error!("failed to save round output: {:?}", e),
         _ => (),
     };
 
-    round_outputs.insert(round, output);
-    info!("Output of round {} now available", round);
-
-    // Clear the state
-    round_shares.clear();
+    match round_outputs.append(&output) {
+        Ok(()) => info!("Output of round {} now available", round),
+        Err(e) => error!("failed to archive output of round {}: {:?}", round, e),
+    }
+}
 
-    let duration = start.elapsed();
-    debug!("[server] leader_finish_round: {:?}", duration);
+/// Waits before the given retry of a request, counting from 0
+async fn retry_backoff(retry: usize) {
+    let delay = RETRY_BASE_DELAY
+        .checked_mul(1 << retry.min(16))
+        .map_or(RETRY_MAX_DELAY, |d| d.min(RETRY_MAX_DELAY));
+    delay_for(delay).await;
 }
 
 /// Sends the given unblinded share to `base_url/submit-share`
@@ -120,13 +223,15 @@ async fn send_share_to_leader(base_url: String, share: UnblindedAggregateShareBl
         .parse()
         .expect("Couldn't not append '/submit-share' to forward URL");
 
-    let mut retries = RETRIES;
-    loop {
+    for retry in 0..RETRIES {
+        if retry > 0 {
+            retry_backoff(retry - 1).await;
+        }
         match client.post(post_path.clone()).send_body(body.clone()).await {
             Ok(res) => {
                 if res.status() == StatusCode::OK {
                     debug!("Share sent successfully");
-                    break;
+                    return;
                 } else {
                     error!("Could not send share No.1: {:?}", res);
                 }
@@ -135,15 +240,92 @@ async fn send_share_to_leader(base_url: String, share: UnblindedAggregateShareBl
                 error!("Could not send share No.2: {:?}", e);
             }
         }
+    }
+    error!("Failed to send share after multiple attempts");
+}
 
-        retries -= 1;
-        if retries == 0 {
-            error!("Failed to send share after multiple attempts");
-            break;
+/// Waits for the leader's output candidate for the given round, checks it against our share, and
+/// sends our signature on it to `base_url/submit-output-sig`
+async fn cosign_round_output(
+    base_url: String,
+    round: u32,
+    share: UnblindedAggregateShareBlob,
+    state: Arc<Mutex<ServiceState>>,
+) {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(TIMEOUT_SEC))
+        .finish();
+    let get_path: Uri = format!("{}/round-candidate/{}", base_url, round)
+        .parse()
+        .expect("Couldn't not append '/round-candidate' to leader URL");
+    let post_path: Uri = [&base_url, "/submit-output-sig"]
+        .concat()
+        .parse()
+        .expect("Couldn't not append '/submit-output-sig' to leader URL");
+
+    // The leader only has a candidate once every share is in, so poll for it
+    let mut candidate = None;
+    for _ in 0..(RETRIES as u64 * TIMEOUT_SEC) {
+        match client.get(get_path.clone()).send().await {
+            Ok(mut res) if res.status() == StatusCode::OK => {
+                let body = match res.body().limit(10 << 21).await {
+                    Ok(b) => b,
+                    Err(e) => {
+                        error!("Could not read round output candidate: {:?}", e);
+                        return;
+                    }
+                };
+                match cli_util::load::<_, RoundOutputCandidate>(&mut body.as_ref()) {
+                    Ok(c) => candidate = Some(c),
+                    Err(e) => error!("Could not parse round output candidate: {:?}", e),
+                }
+                break;
+            }
+            Ok(_) => debug!("round {} output candidate is not ready", round),
+            Err(e) => error!("Could not fetch round output candidate: {:?}", e),
         }
-        // Wait for 50ms before retrying
-        // actix::clock::sleep(Duration::from_millis(50)).await;
+        delay_for(Duration::from_secs(1)).await;
     }
+    let candidate = match candidate {
+        Some(c) => c,
+        None => {
+            error!("Did not get an output candidate for round {}", round);
+            return;
+        }
+    };
+
+    // Check the candidate and sign it
+    let sig = {
+        let handle = state.lock().unwrap();
+        match handle
+            .server_state
+            .sign_round_output(&handle.enclave, &share, &candidate)
+        {
+            Ok(sig) => sig,
+            Err(e) => {
+                error!("Refusing to sign output of round {}: {:?}", round, e);
+                return;
+            }
+        }
+    };
+    let mut body = Vec::new();
+    cli_util::save(&mut body, &RoundOutputSignature { round, sig })
+        .expect("could not serialize output signature");
+
+    for retry in 0..RETRIES {
+        if retry > 0 {
+            retry_backoff(retry - 1).await;
+        }
+        match client.post(post_path.clone()).send_body(body.clone()).await {
+            Ok(res) if res.status() == StatusCode::OK => {
+                debug!("Signature on round {} output sent successfully", round);
+                return;
+            }
+            Ok(res) => error!("Could not send output signature: {:?}", res),
+            Err(e) => error!("Could not send output signature: {:?}", e),
+        }
+    }
+    error!("Failed to send output signature after multiple attempts");
 }
 
 /// Receives an aggregate from a top-level aggregator
@@ -159,34 +341,111 @@ async fn submit_agg(
     // Parse aggregation
     let agg_data: RoundSubmissionBlob = cli_util::load(&mut payload.as_bytes())?;
 
-    // Do the processing step. Unblind the input, add the share, and if we're the leader we finish
-    // the round by combining the shares
+    // log input time
+    let input_duration = input_start.elapsed();
+    debug!("[server] uinput: {:?}", input_duration);
+
+    // Check the aggregate, and take what the enclave needs to unblind it. Aggregates that fail the
+    // checks are rejected with a code saying why
+    let (enclave, signing_key, shared_secrets, dc_net_params, pads) = {
+        let mut handle = state.get_ref().lock().unwrap();
+        if let Err(e) = handle.server_state.verify_toplevel_aggregate(&agg_data) {
+            let msg = e.to_string();
+            let res = match e {
+                ServerError::AggregateSignature => HttpResponse::Unauthorized().body(msg),
+                ServerError::UnknownAggregator(_) => HttpResponse::Forbidden().body(msg),
+                ServerError::WrongRound(_, _) => HttpResponse::Conflict().body(msg),
+                ServerError::RoundTooFar(_, _) => HttpResponse::BadRequest().body(msg),
+                ServerError::WrongGroup(_) => HttpResponse::UnprocessableEntity().body(msg),
+                e => return Err(e.into()),
+            };
+            return Ok(res);
+        }
+        (
+            handle.enclave.clone(),
+            handle.server_state.signing_key.clone(),
+            handle.server_state.shared_secrets.clone(),
+            handle.server_state.dc_net_params,
+            handle.precomputed_pads.take(),
+        )
+    };
+
+    // Unblind the input off the worker and without the lock. The other servers witness the round
+    // moving on while this runs, and they may be waiting on us to witness theirs
+    let unblind_start = Instant::now();
+    let agg = agg_data.clone();
+    let (share, ratcheted_secrets) = web::block(move || {
+        unblind_aggregate(
+            &enclave,
+            &agg,
+            &signing_key,
+            &shared_secrets,
+            &dc_net_params,
+            pads.as_ref(),
+        )
+        .map(|(share, ratcheted)| (share, (shared_secrets, ratcheted)))
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => ApiError::from(e),
+        BlockingError::Canceled => ApiError::from(ServerError::UnexpectedError),
+    })?;
+    let unblind_duration = unblind_start.elapsed();
+    debug!("[server] unblind_aggregate: {:?}", unblind_duration);
+    debug!("unblinded share: {:?}", share);
+
+    // Add the share, and if we're the leader we finish the round by combining the shares
     let mut state_handle = state.get_ref().lock().unwrap();
     {
         let ServiceState {
             ref leader_url,
             ref mut round_shares,
             ref mut server_state,
+            ref enclave,
+            ref mut precomputed_pads,
             ..
         } = state_handle.deref_mut();
         let group_size = server_state.anytrust_group_size;
 
-        // log input time
-        let input_duration = input_start.elapsed();
-        debug!("[server] uinput: {:?}", input_duration);
-
-        let unblind_start = Instant::now();
-        // Unblind the input
-        let share = server_state.unblind_aggregate(&agg_data)?;
-        let unblind_duration = unblind_start.elapsed();
-        debug!("[server] unblind_aggregate: {:?}", unblind_duration);
-        debug!("unblinded share: {:?}", share);
+        // Another aggregate may have been unblinded in the meantime
+        let (unblinded_from, ratcheted_secrets) = ratcheted_secrets;
+        if server_state.shared_secrets.round != unblinded_from.round
+            || server_state.shared_secrets.sealed_db != unblinded_from.sealed_db
+        {
+            let msg = format!(
+                "the shared secrets moved on while round {} was unblinded",
+                agg_data.round
+            );
+            error!("{}", msg);
+            return Ok(HttpResponse::Conflict().body(msg));
+        }
+        server_state.shared_secrets = ratcheted_secrets;
+
+        // The secrets have moved on to the next round. Get its pads ready while we wait for it
+        *precomputed_pads = None;
+        precompute_pads(
+            state.get_ref().clone(),
+            enclave.clone(),
+            server_state.shared_secrets.clone(),
+            server_state.pubkeys.users.keys().cloned().collect(),
+            server_state.dc_net_params,
+        );
 
         match leader_url {
             // We're the leader
             None => {
+                // Shares that arrived before ours were only checked for a valid signature. Now
+                // that we know the round's aggregate, drop the ones that aren't of it
+                round_shares.retain(|_, s| match server_state.verify_share(s, Some(&agg_data)) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error!("{}", e);
+                        false
+                    }
+                });
+
                 // Since we're the leader, add this share to the current round's shares
-                round_shares.push(share);
+                round_shares.insert(server_state.server_id, share);
                 info!(
                     "I now have {}/{} round shares",
                     round_shares.len(),
@@ -200,11 +459,17 @@ async fn submit_agg(
                     log_time();
                 }
             }
-            // We're a follower. Send the unblinded aggregate to the leader
+            // We're a follower. Send the unblinded aggregate to the leader, then co-sign the
+            // output the leader derives from it
             Some(url) => {
                 // This might take a while so do it in a separate thread
-                // let state_for_spawn = state.clone();
-                actix_rt::spawn(send_share_to_leader(url.clone(), share));
+                let url = url.clone();
+                let round = agg_data.round;
+                let state_for_spawn = state.get_ref().clone();
+                actix_rt::spawn(async move {
+                    send_share_to_leader(url.clone(), share.clone()).await;
+                    cosign_round_output(url, round, share, state_for_spawn).await;
+                });
             }
         }
     }
@@ -236,8 +501,11 @@ async fn submit_share(
     let mut handle = state.get_ref().lock().unwrap();
     let group_size = handle.server_state.anytrust_group_size;
     let ServiceState {
+        ref server_state,
         ref leader_url,
         ref mut round_shares,
+        ref round_outputs,
+        ref round_candidates,
         ..
     } = handle.deref_mut();
 
@@ -251,8 +519,47 @@ async fn submit_share(
     // Parse the share and add it to our shares
     debug!("payload len:{}", payload.len());
     let share: UnblindedAggregateShareBlob = cli_util::load(&mut payload.as_bytes())?;
-    round_shares.push(share);
-    info!("Got share. Number of shares is now {}", round_shares.len());
+
+    // If we have our own share, we know the round's aggregate, and this share must be of it.
+    // Otherwise it's checked once our share comes in
+    let my_agg = round_shares
+        .get(&server_state.server_id)
+        .and_then(|s| s.unmarshal().ok())
+        .map(|s| s.encrypted_msg);
+    let (sender, round) = match server_state.verify_share(&share, my_agg.as_ref()) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{}", e);
+            return Ok(HttpResponse::BadRequest().body(e.to_string()));
+        }
+    };
+
+    let last_round = round_outputs
+        .as_ref()
+        .and_then(|a| a.latest_round())
+        .into_iter()
+        .chain(round_candidates.keys().cloned())
+        .max();
+    if last_round.map_or(false, |r| round <= r) {
+        let msg = format!(
+            "share from server {} is for finished round {}",
+            sender, round
+        );
+        error!("{}", msg);
+        return Ok(HttpResponse::BadRequest().body(msg));
+    }
+    if round_shares.contains_key(&sender) {
+        let msg = format!("server {} already sent a share this round", sender);
+        error!("{}", msg);
+        return Ok(HttpResponse::BadRequest().body(msg));
+    }
+
+    round_shares.insert(sender, share);
+    info!(
+        "Got share from server {}. Number of shares is now {}",
+        sender,
+        round_shares.len()
+    );
 
     let duration = start.elapsed();
     debug!("[server] aggregate_share: {:?}", duration);
@@ -267,30 +574,151 @@ async fn submit_share(
     Ok(HttpResponse::Ok().body("OK\n"))
 }
 
-/// Returns the output of the specified round
-#[get("/round-result/{round}")]
-async fn round_result(
+/// Returns the output candidate of the specified round, for the other servers to co-sign
+#[get("/round-candidate/{round}")]
+async fn round_candidate(
     (round, state): (web::Path<u32>, web::Data<Arc<Mutex<ServiceState>>>),
 ) -> Result<HttpResponse, ApiError> {
-    // Unwrap the round and make it a struct
     let web::Path(round) = round;
 
-    // Unpack state
     let handle = state.get_ref().lock().unwrap();
     let ServiceState {
-        ref round_outputs,
+        ref round_candidates,
         ref leader_url,
         ..
     } = *handle;
 
-    // I am not the leader. Don't ask me for round results
     if leader_url.is_some() {
-        return Ok(HttpResponse::NotFound().body("Followers don't store round results"));
+        return Ok(HttpResponse::NotFound().body("Followers don't derive round outputs"));
     }
 
+    let res = match round_candidates.get(&round) {
+        Some(candidate) => {
+            let mut body = Vec::new();
+            cli_util::save(&mut body, candidate)?;
+            HttpResponse::Ok().body(body)
+        }
+        None => HttpResponse::NotFound().body("No output candidate for this round"),
+    };
+
+    Ok(res)
+}
+
+/// Receives another anytrust server's signature on a round output candidate. Once every server has
+/// signed, the output is published.
+#[post("/submit-output-sig")]
+async fn submit_output_sig(
+    (payload, state): (String, web::Data<Arc<Mutex<ServiceState>>>),
+) -> Result<HttpResponse, ApiError> {
+    let mut handle = state.get_ref().lock().unwrap();
+    let ServiceState {
+        ref server_state,
+        ref leader_url,
+        ref mut round_candidates,
+        ref mut round_outputs,
+        ..
+    } = handle.deref_mut();
+
+    if leader_url.is_some() {
+        let msg = "followers aren't supposed to receive output signatures";
+        error!("{}", msg);
+        return Ok(HttpResponse::BadRequest().body(msg));
+    }
+
+    let payload = payload.split_whitespace().next().unwrap_or("");
+    let RoundOutputSignature { round, sig } = cli_util::load(&mut payload.as_bytes())?;
+
+    let candidate = match round_candidates.get_mut(&round) {
+        Some(c) => c,
+        None => {
+            let msg = format!("no output candidate for round {}", round);
+            error!("{}", msg);
+            return Ok(HttpResponse::NotFound().body(msg));
+        }
+    };
+    if let Err(e) = server_state.add_output_signature(&mut candidate.output, sig) {
+        let msg = format!("bad signature on output of round {}: {:?}", round, e);
+        error!("{}", msg);
+        return Ok(HttpResponse::BadRequest().body(msg));
+    }
+    info!(
+        "Output of round {} has {}/{} signatures",
+        round,
+        candidate.output.server_sigs.len(),
+        server_state.anytrust_group_size
+    );
+
+    if server_state.output_fully_signed(&candidate.output) {
+        let candidate = round_candidates.remove(&round).unwrap();
+        publish_round_output(round_outputs, candidate.output);
+        log_time();
+    }
+
+    Ok(HttpResponse::Ok().body("OK\n"))
+}
+
+/// Witnesses a user's freshness counter moving on, and returns this server's signature on the
+/// advance. The new counter is saved before the signature is sent.
+#[post("/witness-counter")]
+async fn witness_counter(
+    (payload, state): (String, web::Data<Arc<Mutex<ServiceState>>>),
+) -> Result<HttpResponse, ApiError> {
+    let payload = payload.split_whitespace().next().unwrap_or("");
+    let advance: CounterAdvance = cli_util::load(&mut payload.as_bytes())?;
+
+    let mut handle = state.get_ref().lock().unwrap();
+    let ServiceState {
+        ref mut server_state,
+        ref enclave,
+        ref server_state_path,
+        ..
+    } = handle.deref_mut();
+
+    let sig = match server_state.witness_counter_advance(enclave, &advance) {
+        Ok(sig) => sig,
+        Err(e) => {
+            let msg = e.to_string();
+            let res = match e {
+                ServerError::UnknownUser(_) => HttpResponse::Forbidden().body(msg),
+                ServerError::AdvanceSignature(_) => HttpResponse::Unauthorized().body(msg),
+                ServerError::StaleCounter(_, _) => HttpResponse::Conflict().body(msg),
+                e => return Err(e.into()),
+            };
+            return Ok(res);
+        }
+    };
+
+    // Don't hand out the signature unless the counter it moved on will survive a restart
+    if let Some(path) = server_state_path {
+        save_state(path, server_state)?;
+    }
+
+    let mut body = Vec::new();
+    cli_util::save(&mut body, &sig)?;
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// Returns the output of the specified round, once all the anytrust servers have signed it
+#[get("/round-result/{round}")]
+async fn round_result(
+    (round, state): (web::Path<u32>, web::Data<Arc<Mutex<ServiceState>>>),
+) -> Result<HttpResponse, ApiError> {
+    // Unwrap the round and make it a struct
+    let web::Path(round) = round;
+
+    // Unpack state
+    let handle = state.get_ref().lock().unwrap();
+    // I am not the leader. Don't ask me for round results
+    let round_outputs = match handle.round_outputs {
+        Some(ref a) => a,
+        None => {
+            return Ok(HttpResponse::NotFound().body("Followers don't store round results"));
+        }
+    };
+
     // Try to get the requested output
-    let res = match round_outputs.get(&round) {
-        // If the given round's output exists in memory, return it
+    let res = match round_outputs.get(round)? {
+        // If the given round's output is in the archive, return it
         Some(round_output) => {
             // Give the signed round output, not just the raw payload
             /*
@@ -306,7 +734,7 @@ async fn round_result(
             cli_util::save(&mut body, &round_output)?;
             HttpResponse::Ok().body(body)
         }
-        // If the given round's output doesn't exist in memory, error out
+        // If the given round's output isn't in the archive, error out
         None => {
             info!("received request for invalid round {}", round);
             HttpResponse::NotFound().body("Invalid round")
@@ -316,6 +744,45 @@ async fn round_result(
     Ok(res)
 }
 
+#[derive(Deserialize)]
+struct RoundRange {
+    from: u32,
+    to: u32,
+}
+
+/// Returns the outputs of the rounds from `from` to `to`, inclusive, as newline-separated blobs.
+/// Rounds without an output are skipped.
+#[get("/round-results")]
+async fn round_results(
+    (range, state): (web::Query<RoundRange>, web::Data<Arc<Mutex<ServiceState>>>),
+) -> Result<HttpResponse, ApiError> {
+    let RoundRange { from, to } = range.into_inner();
+
+    // Unpack state
+    let handle = state.get_ref().lock().unwrap();
+    // I am not the leader. Don't ask me for round results
+    let round_outputs = match handle.round_outputs {
+        Some(ref a) => a,
+        None => {
+            return Ok(HttpResponse::NotFound().body("Followers don't store round results"));
+        }
+    };
+
+    if from > to || to - from >= MAX_ROUND_RESULTS {
+        let msg = format!(
+            "the range must be nonempty and span at most {} rounds",
+            MAX_ROUND_RESULTS
+        );
+        return Ok(HttpResponse::BadRequest().body(msg));
+    }
+
+    let outputs = round_outputs.range(from, to)?;
+    let mut body = Vec::new();
+    cli_util::save_multi(&mut body, &outputs)?;
+
+    Ok(HttpResponse::Ok().body(body))
+}
+
 /// Returns just the base64-encoded message of the specified round
 #[get("/round-msg/{round}")]
 async fn round_msg(
@@ -326,20 +793,17 @@ async fn round_msg(
 
     // Unpack state
     let handle = state.get_ref().lock().unwrap();
-    let ServiceState {
-        ref round_outputs,
-        ref leader_url,
-        ..
-    } = *handle;
-
     // I am not the leader. Don't ask me for round results
-    if leader_url.is_some() {
-        return Ok(HttpResponse::NotFound().body("Followers don't store round results"));
-    }
+    let round_outputs = match handle.round_outputs {
+        Some(ref a) => a,
+        None => {
+            return Ok(HttpResponse::NotFound().body("Followers don't store round results"));
+        }
+    };
 
     // Try to get the requested output
-    let res = match round_outputs.get(&round) {
-        // If the given round's output exists in memory, return it
+    let res = match round_outputs.get(round)? {
+        // If the given round's output is in the archive, return it
         Some(round_output) => {
             // Give the raw payload
             let blob = round_output.dc_msg.aggregated_msg.as_row_major();
@@ -352,7 +816,7 @@ async fn round_msg(
             // };
             HttpResponse::Ok().body(body)
         }
-        // If the given round's output doesn't exist in memory, error out
+        // If the given round's output isn't in the archive, error out
         None => {
             info!("received request for invalid round {}", round);
             HttpResponse::NotFound().body("Invalid round")
@@ -368,8 +832,21 @@ pub(crate) async fn start_service(bind_addr: String, state: ServiceState) -> std
         "Server group size is {}",
         state.server_state.anytrust_group_size
     );
+    let enclave = state.enclave.clone();
+    let shared_secrets = state.server_state.shared_secrets.clone();
+    let user_ids = state.server_state.pubkeys.users.keys().cloned().collect();
+    let dc_net_params = state.server_state.dc_net_params;
     let state = Arc::new(Mutex::new(state));
 
+    // Get the first round's pads ready
+    precompute_pads(
+        state.clone(),
+        enclave,
+        shared_secrets,
+        user_ids,
+        dc_net_params,
+    );
+
     info!("Making new server on {}", bind_addr);
 
     // Start the web server
@@ -380,8 +857,12 @@ pub(crate) async fn start_service(bind_addr: String, state: ServiceState) -> std
             .configure(|cfg| {
                 cfg.service(submit_agg)
                     .service(submit_share)
+                    .service(round_candidate)
+                    .service(submit_output_sig)
                     .service(round_result)
-                    .service(round_msg);
+                    .service(round_results)
+                    .service(round_msg)
+                    .service(witness_counter);
             })
     })
     .workers(1)
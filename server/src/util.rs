@@ -1,7 +1,9 @@
 use crate::server_state::ServerState;
-use interface::RoundOutput;
+use interface::{EntityId, RoundOutput};
 
+use common::attestation::AttestationError;
 use common::cli_util;
+use common::enclave::EnclaveError;
 
 use std::fs::File;
 
@@ -19,6 +21,28 @@ pub enum ServerError {
     Io(#[from] std::io::Error),
     #[error("error in serialization/deserialization")]
     Ser(#[from] cli_util::SerializationError),
+    #[error("attestation did not verify")]
+    Attestation(#[from] AttestationError),
+    #[error("error from enclave")]
+    Enclave(#[from] EnclaveError),
+    #[error("share from {0} rejected: {1}")]
+    BadShare(String, String),
+    #[error("the aggregate's signature does not verify")]
+    AggregateSignature,
+    #[error("aggregator {0} is not a registered top-level aggregator")]
+    UnknownAggregator(EntityId),
+    #[error("aggregate is for round {0}, expected round {1} or later")]
+    WrongRound(u32, u32),
+    #[error("aggregate is for round {0}, too far past round {1}")]
+    RoundTooFar(u32, u32),
+    #[error("aggregate is for anytrust group {0}")]
+    WrongGroup(EntityId),
+    #[error("user {0} is not registered")]
+    UnknownUser(EntityId),
+    #[error("the counter advance is not signed by user {0}")]
+    AdvanceSignature(EntityId),
+    #[error("the counter is not at {0}")]
+    StaleCounter(u32, u32),
     #[error("Unexpected Error")]
     UnexpectedError,
 }
@@ -29,12 +53,6 @@ impl From<SignatureError> for ServerError {
     }
 }
 
-impl From<rand::Error> for ServerError {
-    fn from(_error: rand::Error) -> Self {
-        ServerError::UnexpectedError
-    }
-}
-
 pub(crate) fn load_state(save_path: &str) -> Result<ServerState> {
     let save_file = File::open(save_path)?;
     Ok(cli_util::load(save_file)?)